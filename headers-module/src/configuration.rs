@@ -20,12 +20,14 @@
 use http::{
     header,
     header::{HeaderName, HeaderValue},
+    StatusCode,
 };
 use pandora_module_utils::merger::{HostPathMatcher, PathMatch, PathMatchResult};
 use pandora_module_utils::router::{Path, EMPTY_PATH};
 use pandora_module_utils::{DeserializeMap, OneOrMany};
+use serde::Deserialize;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
 /// Include and exclude rules applying to a configuration entry
@@ -45,9 +47,22 @@ use std::fmt::Debug;
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct MatchRules {
     /// Rules determining the locations where the configuration entry should apply
+    #[pandora(deserialize_with_seed = "crate::deserialize::deserialize_match_rules")]
     pub include: OneOrMany<HostPathMatcher>,
     /// Rules determining the locations where the configuration entry should not apply
+    #[pandora(deserialize_with_seed = "crate::deserialize::deserialize_match_rules")]
     pub exclude: OneOrMany<HostPathMatcher>,
+    /// If set, the configuration entry only applies to request paths with at most this many
+    /// non-empty segments. The root path has depth 0, `/about` and `/about/` have depth 1.
+    pub max_depth: Option<usize>,
+}
+
+/// Determines the depth of a request path: the number of non-empty `/`-separated segments. The
+/// root path `/` has depth 0, `/about` and `/about/` have depth 1, `/docs/intro` has depth 2.
+pub(crate) fn path_depth(path: &str) -> usize {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .count()
 }
 
 impl PathMatch for MatchRules {
@@ -301,6 +316,81 @@ impl_conf! {csp:
     }
 }
 
+impl ContentSecurityPolicyConf {
+    /// Translates the configuration into a `Content-Security-Policy-Report-Only` header: the
+    /// policy is only reported on by the browser, not enforced.
+    pub(crate) fn into_report_only_headers(self) -> Vec<Header> {
+        self.into_headers()
+            .into_iter()
+            .map(|(_, value)| (header::CONTENT_SECURITY_POLICY_REPORT_ONLY, value))
+            .collect()
+    }
+}
+
+/// Splits a header value like `max-age=60, private="Set-Cookie, X-Token"` into its
+/// comma-separated directives, ignoring commas within quoted strings.
+fn split_directives(value: &[u8]) -> Vec<&[u8]> {
+    fn trim(mut value: &[u8]) -> &[u8] {
+        while let [first, rest @ ..] = value {
+            if !first.is_ascii_whitespace() {
+                break;
+            }
+            value = rest;
+        }
+        while let [rest @ .., last] = value {
+            if !last.is_ascii_whitespace() {
+                break;
+            }
+            value = rest;
+        }
+        value
+    }
+
+    let mut directives = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, byte) in value.iter().enumerate() {
+        match byte {
+            b'"' => quoted = !quoted,
+            b',' if !quoted => {
+                directives.push(trim(&value[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    directives.push(trim(&value[start..]));
+    directives.retain(|directive| !directive.is_empty());
+    directives
+}
+
+/// Merges two values of a header consisting of directives like `Cache-Control`. Directives from
+/// `other` replace directives with the same name in `value`, other directives are kept.
+pub(crate) fn merge_directives(value: &HeaderValue, other: &HeaderValue) -> HeaderValue {
+    fn name(directive: &[u8]) -> &[u8] {
+        directive
+            .split(|byte| *byte == b'=')
+            .next()
+            .unwrap_or_default()
+    }
+
+    let mut directives = split_directives(value.as_bytes());
+    for directive in split_directives(other.as_bytes()) {
+        if let Some(existing) = directives
+            .iter_mut()
+            .find(|existing| name(existing).eq_ignore_ascii_case(name(directive)))
+        {
+            *existing = directive;
+        } else {
+            directives.push(directive);
+        }
+    }
+
+    // Directives are taken from valid header values and joined with valid characters, ignore
+    // errors.
+    HeaderValue::from_bytes(&directives.join(&b", "[..])).unwrap_or_else(|_| other.clone())
+}
+
 /// Custom headers configuration
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct CustomHeadersConf {
@@ -309,12 +399,15 @@ pub struct CustomHeadersConf {
 
 impl IntoHeaders for CustomHeadersConf {
     fn merge_with(&mut self, other: &Self) {
-        self.headers.extend(
-            other
-                .headers
-                .iter()
-                .map(|(name, value)| (name.clone(), value.clone())),
-        );
+        for (name, value) in &other.headers {
+            let value = match self.headers.get(name) {
+                Some(existing) if name == header::CACHE_CONTROL => {
+                    merge_directives(existing, value)
+                }
+                _ => value.clone(),
+            };
+            self.headers.insert(name.clone(), value);
+        }
     }
 
     fn into_headers(self) -> Vec<Header> {
@@ -322,6 +415,72 @@ impl IntoHeaders for CustomHeadersConf {
     }
 }
 
+/// Class of a response status code like `4xx`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+pub enum StatusClass {
+    /// Informational responses (100–199)
+    #[serde(rename = "1xx")]
+    Informational,
+    /// Successful responses (200–299)
+    #[serde(rename = "2xx")]
+    Success,
+    /// Redirection messages (300–399)
+    #[serde(rename = "3xx")]
+    Redirection,
+    /// Client error responses (400–499)
+    #[serde(rename = "4xx")]
+    ClientError,
+    /// Server error responses (500–599)
+    #[serde(rename = "5xx")]
+    ServerError,
+}
+
+impl StatusClass {
+    /// Determines the class of a status code, `None` for codes outside the 100–599 range
+    pub fn of(status: StatusCode) -> Option<Self> {
+        match status.as_u16() {
+            100..=199 => Some(Self::Informational),
+            200..=299 => Some(Self::Success),
+            300..=399 => Some(Self::Redirection),
+            400..=499 => Some(Self::ClientError),
+            500..=599 => Some(Self::ServerError),
+            _ => None,
+        }
+    }
+}
+
+/// Header settings overriding the general settings for responses of a particular status class
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct StatusHeadersConf {
+    /// Cache-Control header
+    pub cache_control: OneOrMany<WithMatchRules<CacheControlConf>>,
+
+    /// Content-Security-Policy header
+    pub content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
+
+    /// Content-Security-Policy-Report-Only header
+    pub content_security_policy_report_only: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
+
+    /// Custom headers, headers configures as name => value map here
+    pub custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
+}
+
+/// Header settings applying everywhere unless overridden by more specific rules
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct DefaultHeadersConf {
+    /// Cache-Control header
+    pub cache_control: CacheControlConf,
+
+    /// Content-Security-Policy header
+    pub content_security_policy: ContentSecurityPolicyConf,
+
+    /// Content-Security-Policy-Report-Only header
+    pub content_security_policy_report_only: ContentSecurityPolicyConf,
+
+    /// Custom headers, headers configures as name => value map here
+    pub custom: CustomHeadersConf,
+}
+
 /// Various settings to configure HTTP response headers
 #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
 pub struct HeadersInnerConf {
@@ -331,8 +490,21 @@ pub struct HeadersInnerConf {
     /// Content-Security-Policy header
     pub content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
 
+    /// Content-Security-Policy-Report-Only header
+    pub content_security_policy_report_only: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
+
     /// Custom headers, headers configures as name => value map here
     pub custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
+
+    /// Header settings used as a starting point for all locations, rules above override these
+    pub defaults: DefaultHeadersConf,
+
+    /// Header settings to be applied on top of the general settings, depending on the status
+    /// class of the response
+    pub status_overrides: BTreeMap<StatusClass, StatusHeadersConf>,
+
+    /// If `true`, count how often each set of header rules is applied
+    pub rule_metrics: bool,
 }
 
 /// Configuration file settings of the headers module
@@ -15,12 +15,85 @@
 //! Custom deserialization code for the configuration
 
 use http::header::{HeaderName, HeaderValue};
-use pandora_module_utils::{DeserializeMap, MapVisitor};
-use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error as _, MapAccess, Visitor};
+use pandora_module_utils::merger::HostPathMatcher;
+use pandora_module_utils::{DeserializeMap, MapVisitor, OneOrMany};
+use serde::de::{
+    Deserialize, DeserializeSeed, Deserializer, Error as _, MapAccess, SeqAccess, Visitor,
+};
 use std::collections::HashMap;
 
 use crate::configuration::CustomHeadersConf;
 
+/// Deserializes `include` and `exclude` rules, adding them to the existing ones. Accepts a single
+/// rule, multiple rules separated by whitespace or a list of such values (lists can be nested).
+pub(crate) fn deserialize_match_rules<'de, D>(
+    mut seed: OneOrMany<HostPathMatcher>,
+    deserializer: D,
+) -> Result<OneOrMany<HostPathMatcher>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct MatchRulesSeed<'a> {
+        rules: &'a mut Vec<HostPathMatcher>,
+    }
+
+    impl<'de> DeserializeSeed<'de> for MatchRulesSeed<'_> {
+        type Value = ();
+
+        fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for MatchRulesSeed<'_> {
+        type Value = ();
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            formatter.write_str("a string or a list of strings")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            if v.trim().is_empty() {
+                // Empty string is a valid rule matching everything
+                self.rules.push(HostPathMatcher::from(""));
+            } else {
+                self.rules
+                    .extend(v.split_whitespace().map(HostPathMatcher::from));
+            }
+            Ok(())
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(())
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            while seq
+                .next_element_seed(MatchRulesSeed {
+                    rules: &mut *self.rules,
+                })?
+                .is_some()
+            {}
+            Ok(())
+        }
+    }
+
+    MatchRulesSeed { rules: &mut seed }.deserialize(deserializer)?;
+    Ok(seed)
+}
+
 impl<'de> DeserializeSeed<'de> for CustomHeadersConf {
     type Value = Self;
 
@@ -264,4 +337,69 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn match_rules_deserialization() {
+        let expected = MatchRules {
+            include: vec![
+                HostPathMatcher::from("example.com/*"),
+                HostPathMatcher::from("example.net"),
+            ]
+            .into(),
+            exclude: vec![HostPathMatcher::from("example.com/private/*")].into(),
+            max_depth: None,
+        };
+
+        assert_eq!(
+            MatchRules::from_yaml(
+                r#"
+                    include: example.com/* example.net
+                    exclude: example.com/private/*
+                "#
+            )
+            .unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            MatchRules::from_yaml(
+                r#"
+                    include: [example.com/*, example.net]
+                    exclude: [example.com/private/*]
+                "#
+            )
+            .unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            MatchRules::from_yaml(
+                r#"
+                    include:
+                    - example.com/*
+                    - [example.net]
+                    exclude:
+                    -
+                    - "  example.com/private/*  "
+                "#
+            )
+            .unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            MatchRules::from_yaml("include: example.com/*")
+                .unwrap()
+                .merge_from_yaml("include: example.net\nexclude: example.com/private/*")
+                .unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            MatchRules::from_yaml("exclude: \"\"").unwrap().exclude,
+            vec![HostPathMatcher::from("")].into()
+        );
+
+        assert!(MatchRules::from_yaml("include: {example.com: 1}").is_err());
+    }
 }
@@ -13,39 +13,331 @@
 // limitations under the License.
 
 use async_trait::async_trait;
-use http::{HeaderName, HeaderValue};
+use http::{header, HeaderName, HeaderValue};
 use log::{debug, trace};
+use pandora_module_utils::counters::HitCounters;
 use pandora_module_utils::merger::{Merger, StrictHostPathMatcher};
 use pandora_module_utils::pingora::{Error, ResponseHeader, SessionWrapper};
-use pandora_module_utils::router::Router;
+use pandora_module_utils::router::{LookupResult, Router};
 use pandora_module_utils::{OneOrMany, RequestFilter, RequestFilterResult};
+use std::collections::BTreeSet;
+use std::sync::Arc;
 
-use crate::configuration::{Header, HeadersConf, IntoHeaders, WithMatchRules};
+use crate::configuration::{
+    merge_directives, path_depth, CacheControlConf, ContentSecurityPolicyConf, CustomHeadersConf,
+    DefaultHeadersConf, Header, HeadersConf, IntoHeaders, MatchRules, StatusClass, WithMatchRules,
+};
 
-fn merge_rules<C>(rules: OneOrMany<WithMatchRules<C>>) -> Merger<StrictHostPathMatcher, Vec<Header>>
+/// Merges the rules of a section on top of the defaults. Only rules applying to paths of the given
+/// depth are considered, with `None` meaning paths deeper than any `max_depth` setting. The merged
+/// configuration is translated into headers by calling `into_headers`.
+fn merge_rules<C>(
+    rules: &[WithMatchRules<C>],
+    defaults: C,
+    depth: Option<usize>,
+    first_index: Option<usize>,
+    into_headers: fn(C) -> Vec<Header>,
+) -> Merger<StrictHostPathMatcher, (Vec<Header>, Vec<usize>)>
 where
     C: Default + Clone + Eq + IntoHeaders,
 {
     let mut merger = Merger::new();
-    for rule in rules {
-        merger.push(rule.match_rules, rule.conf);
+    if defaults != C::default() {
+        // Make sure that defaults apply to locations without any rules as well.
+        merger.push(MatchRules::default(), (None, C::default()));
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        let applies = match (rule.match_rules.max_depth, depth) {
+            (None, _) => true,
+            (Some(max_depth), Some(depth)) => depth <= max_depth,
+            (Some(_), None) => false,
+        };
+        if applies {
+            let index = first_index.map(|first_index| first_index + i);
+            merger.push(rule.match_rules.clone(), (index, rule.conf.clone()));
+        }
     }
     merger.merge_into_merger(|values| {
-        let mut result = C::default();
-        for conf in values {
+        let mut result = defaults.clone();
+        let mut indices = Vec::new();
+        for (index, conf) in values {
             result.merge_with(conf);
+            indices.extend(index);
+        }
+        (into_headers(result), indices)
+    })
+}
+
+/// Router mapping locations to their headers and the indices of the rules producing them
+type HeadersRouter = Router<(Vec<Header>, Vec<usize>)>;
+
+/// Merges the rules of all sections applying to the given path depth into a router, on top of the
+/// defaults. If `count` is `true`, router values will list the indices of all rules applying to
+/// the location.
+fn merge_sections(
+    cache_control: &[WithMatchRules<CacheControlConf>],
+    content_security_policy: &[WithMatchRules<ContentSecurityPolicyConf>],
+    content_security_policy_report_only: &[WithMatchRules<ContentSecurityPolicyConf>],
+    custom: &[WithMatchRules<CustomHeadersConf>],
+    defaults: DefaultHeadersConf,
+    depth: Option<usize>,
+    count: bool,
+) -> HeadersRouter {
+    let cache_control_len = cache_control.len();
+    let content_security_policy_len = content_security_policy.len();
+    let content_security_policy_report_only_len = content_security_policy_report_only.len();
+    let first_index = |offset| count.then_some(offset);
+
+    let cache_control = merge_rules(
+        cache_control,
+        defaults.cache_control,
+        depth,
+        first_index(0),
+        IntoHeaders::into_headers,
+    );
+    let content_security_policy = merge_rules(
+        content_security_policy,
+        defaults.content_security_policy,
+        depth,
+        first_index(cache_control_len),
+        IntoHeaders::into_headers,
+    );
+    let content_security_policy_report_only = merge_rules(
+        content_security_policy_report_only,
+        defaults.content_security_policy_report_only,
+        depth,
+        first_index(cache_control_len + content_security_policy_len),
+        ContentSecurityPolicyConf::into_report_only_headers,
+    );
+    let custom = merge_rules(
+        custom,
+        defaults.custom,
+        depth,
+        first_index(
+            cache_control_len
+                + content_security_policy_len
+                + content_security_policy_report_only_len,
+        ),
+        IntoHeaders::into_headers,
+    );
+
+    let mut merged = cache_control;
+    merged.extend([
+        content_security_policy,
+        content_security_policy_report_only,
+        custom,
+    ]);
+    trace!("Merged headers configuration into: {merged:#?}");
+
+    merged.merge(|values| {
+        let mut result = Vec::<(HeaderName, HeaderValue)>::new();
+        let mut indices = Vec::new();
+        for (headers, header_indices) in values {
+            for (name, value) in headers {
+                if let Some(existing) = result.iter().position(|(n, _)| n == name) {
+                    if name == header::CACHE_CONTROL {
+                        // Conflicting directives shouldn’t be sent twice
+                        result[existing].1 = merge_directives(&result[existing].1, value);
+                        continue;
+                    }
+
+                    // Combine duplicate headers
+                    // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.2
+                    let mut new_value = result[existing].1.as_bytes().to_vec();
+                    new_value.extend_from_slice(b", ");
+                    new_value.extend_from_slice(value.as_bytes());
+                    result[existing].1 = HeaderValue::from_bytes(&new_value).unwrap();
+                } else {
+                    result.push((name.clone(), value.clone()))
+                }
+            }
+            indices.extend_from_slice(header_indices);
         }
-        result.into_headers()
+        indices.sort();
+        indices.dedup();
+        (result, indices)
     })
 }
 
+/// Routers for the headers configuration, one per path depth where `max_depth` settings make a
+/// difference
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DepthRouters {
+    /// Routers for paths up to the given depth, sorted by depth
+    limited: Vec<(usize, HeadersRouter)>,
+    /// Router for paths deeper than any `max_depth` setting
+    unlimited: HeadersRouter,
+}
+
+impl DepthRouters {
+    fn new(
+        cache_control: OneOrMany<WithMatchRules<CacheControlConf>>,
+        content_security_policy: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
+        content_security_policy_report_only: OneOrMany<WithMatchRules<ContentSecurityPolicyConf>>,
+        custom: OneOrMany<WithMatchRules<CustomHeadersConf>>,
+        defaults: DefaultHeadersConf,
+        count: bool,
+    ) -> Self {
+        let depths = cache_control
+            .iter()
+            .map(|rule| &rule.match_rules)
+            .chain(content_security_policy.iter().map(|rule| &rule.match_rules))
+            .chain(
+                content_security_policy_report_only
+                    .iter()
+                    .map(|rule| &rule.match_rules),
+            )
+            .chain(custom.iter().map(|rule| &rule.match_rules))
+            .filter_map(|match_rules| match_rules.max_depth)
+            .collect::<BTreeSet<_>>();
+
+        let merge = |depth| {
+            merge_sections(
+                &cache_control,
+                &content_security_policy,
+                &content_security_policy_report_only,
+                &custom,
+                defaults.clone(),
+                depth,
+                count,
+            )
+        };
+        Self {
+            limited: depths
+                .into_iter()
+                .map(|depth| (depth, merge(Some(depth))))
+                .collect(),
+            unlimited: merge(None),
+        }
+    }
+
+    fn lookup(
+        &self,
+        host: &str,
+        path: &str,
+    ) -> Option<LookupResult<'_, (Vec<Header>, Vec<usize>)>> {
+        let depth = path_depth(path);
+        let router = self
+            .limited
+            .iter()
+            .find(|(max_depth, _)| depth <= *max_depth)
+            .map(|(_, router)| router)
+            .unwrap_or(&self.unlimited);
+        router.lookup(host, path)
+    }
+}
+
+/// Copies the headers, replacing `${path}` in header values by the request path.
+fn resolve_variables(headers: &[Header], path: &str) -> Vec<Header> {
+    const PATH_VARIABLE: &[u8] = b"${path}";
+
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let bytes = value.as_bytes();
+            if !bytes
+                .windows(PATH_VARIABLE.len())
+                .any(|window| window == PATH_VARIABLE)
+            {
+                return (name.clone(), value.clone());
+            }
+
+            let mut resolved = Vec::with_capacity(bytes.len());
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                if let Some(remaining) = rest.strip_prefix(PATH_VARIABLE) {
+                    resolved.extend_from_slice(path.as_bytes());
+                    rest = remaining;
+                } else {
+                    resolved.push(rest[0]);
+                    rest = &rest[1..];
+                }
+            }
+
+            // Request path only contains characters valid in header values, ignore errors.
+            let value = HeaderValue::from_bytes(&resolved).unwrap_or_else(|_| value.clone());
+            (name.clone(), value)
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
-struct HeadersList(Vec<Header>);
+struct HeadersList {
+    headers: Vec<Header>,
+    /// Host and path to look up status overrides for once the response status is known, `None`
+    /// if there are no status overrides
+    location: Option<(String, String)>,
+}
 
-/// Handler for Pingora’s `request_filter` phase
+/// Number of times each set of header rules has been applied, in the order the rules are listed
+/// in the configuration
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadersRuleMetrics {
+    /// Counts for the `cache_control` rules
+    pub cache_control: Vec<u64>,
+
+    /// Counts for the `content_security_policy` rules
+    pub content_security_policy: Vec<u64>,
+
+    /// Counts for the `content_security_policy_report_only` rules
+    pub content_security_policy_report_only: Vec<u64>,
+
+    /// Counts for the `custom` rules
+    pub custom: Vec<u64>,
+}
+
+/// Handler for Pingora’s `request_filter` phase
+#[derive(Debug, Clone)]
 pub struct HeadersHandler {
-    router: Router<Vec<Header>>,
+    router: DepthRouters,
+    status_overrides: Vec<(StatusClass, DepthRouters)>,
+    counters: Option<(Arc<HitCounters>, [usize; 3])>,
+}
+
+// Hit counters change with every request, only whether these are enabled is compared
+impl PartialEq for HeadersHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.router == other.router
+            && self.status_overrides == other.status_overrides
+            && self.counters.as_ref().map(|(_, sections)| sections)
+                == other.counters.as_ref().map(|(_, sections)| sections)
+    }
+}
+
+impl Eq for HeadersHandler {}
+
+impl HeadersHandler {
+    /// Determines the headers to be added to a response for the given host/path combination.
+    ///
+    /// This performs the full lookup: host-specific rules are preferred over rules applying to
+    /// all hosts, the closest exact or prefix match is selected and variables like `${path}` are
+    /// resolved. Status overrides aren’t considered as these depend on the response.
+    pub fn headers_for_request(&self, host: &str, path: &str) -> Vec<Header> {
+        self.router
+            .lookup(host, path)
+            .map(|list| resolve_variables(&list.as_value().0, path))
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of times each set of header rules has been applied. Returns `None`
+    /// unless `rule_metrics` setting is enabled.
+    pub fn rule_metrics(&self) -> Option<HeadersRuleMetrics> {
+        let (
+            counters,
+            [cache_control_len, content_security_policy_len, content_security_policy_report_only_len],
+        ) = self.counters.as_ref()?;
+        let snapshot = counters.snapshot();
+        let (cache_control, rest) = snapshot.split_at(*cache_control_len);
+        let (content_security_policy, rest) = rest.split_at(*content_security_policy_len);
+        let (content_security_policy_report_only, custom) =
+            rest.split_at(*content_security_policy_report_only_len);
+        Some(HeadersRuleMetrics {
+            cache_control: cache_control.to_vec(),
+            content_security_policy: content_security_policy.to_vec(),
+            content_security_policy_report_only: content_security_policy_report_only.to_vec(),
+            custom: custom.to_vec(),
+        })
+    }
 }
 
 impl TryFrom<HeadersConf> for HeadersHandler {
@@ -54,34 +346,56 @@ impl TryFrom<HeadersConf> for HeadersHandler {
     fn try_from(value: HeadersConf) -> Result<Self, Self::Error> {
         debug!("Headers configuration received: {value:#?}");
 
-        let cache_control = merge_rules(value.response_headers.cache_control);
-        let content_security_policy = merge_rules(value.response_headers.content_security_policy);
-        let custom = merge_rules(value.response_headers.custom);
-
-        let mut merged = cache_control;
-        merged.extend([content_security_policy, custom]);
-        trace!("Merged headers configuration into: {merged:#?}");
-
-        let router = merged.merge(|values| {
-            let mut result = Vec::<(HeaderName, HeaderValue)>::new();
-            for headers in values {
-                for (name, value) in headers {
-                    if let Some(existing) = result.iter().position(|(n, _)| n == name) {
-                        // Combine duplicate headers
-                        // https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.2
-                        let mut new_value = result[existing].1.as_bytes().to_vec();
-                        new_value.extend_from_slice(b", ");
-                        new_value.extend_from_slice(value.as_bytes());
-                        result[existing].1 = HeaderValue::from_bytes(&new_value).unwrap();
-                    } else {
-                        result.push((name.clone(), value.clone()))
-                    }
-                }
-            }
-            result
+        let conf = value.response_headers;
+        let cache_control_len = conf.cache_control.len();
+        let content_security_policy_len = conf.content_security_policy.len();
+        let content_security_policy_report_only_len =
+            conf.content_security_policy_report_only.len();
+        let counters = conf.rule_metrics.then(|| {
+            let len = cache_control_len
+                + content_security_policy_len
+                + content_security_policy_report_only_len
+                + conf.custom.len();
+            (
+                Arc::new(HitCounters::new(len)),
+                [
+                    cache_control_len,
+                    content_security_policy_len,
+                    content_security_policy_report_only_len,
+                ],
+            )
         });
 
-        Ok(Self { router })
+        let router = DepthRouters::new(
+            conf.cache_control,
+            conf.content_security_policy,
+            conf.content_security_policy_report_only,
+            conf.custom,
+            conf.defaults,
+            counters.is_some(),
+        );
+
+        let status_overrides = conf
+            .status_overrides
+            .into_iter()
+            .map(|(class, conf)| {
+                let router = DepthRouters::new(
+                    conf.cache_control,
+                    conf.content_security_policy,
+                    conf.content_security_policy_report_only,
+                    conf.custom,
+                    DefaultHeadersConf::default(),
+                    false,
+                );
+                (class, router)
+            })
+            .collect();
+
+        Ok(Self {
+            router,
+            status_overrides,
+            counters,
+        })
     }
 }
 
@@ -107,14 +421,28 @@ impl RequestFilter for HeadersHandler {
         );
 
         let host = session.host().unwrap_or_default();
-        let list = if let Some(list) = self.router.lookup(host.as_ref(), path) {
-            list.as_value()
+        let headers = if let Some(list) = self.router.lookup(host.as_ref(), path) {
+            let (headers, indices) = list.as_value();
+            if let Some((counters, _)) = &self.counters {
+                for index in indices {
+                    counters.increment(*index);
+                }
+            }
+            resolve_variables(headers, path)
         } else {
-            return Ok(RequestFilterResult::Unhandled);
+            Vec::new()
         };
 
-        session.extensions_mut().insert(HeadersList(list.clone()));
-        trace!("Prepared headers for response: {list:?}");
+        if headers.is_empty() && self.status_overrides.is_empty() {
+            return Ok(RequestFilterResult::Unhandled);
+        }
+
+        trace!("Prepared headers for response: {headers:?}");
+        let location =
+            (!self.status_overrides.is_empty()).then(|| (host.into_owned(), path.to_owned()));
+        session
+            .extensions_mut()
+            .insert(HeadersList { headers, location });
 
         Ok(RequestFilterResult::Unhandled)
     }
@@ -125,8 +453,21 @@ impl RequestFilter for HeadersHandler {
         response: &mut ResponseHeader,
         _ctx: Option<&mut <Self as RequestFilter>::CTX>,
     ) {
-        if let Some(HeadersList(list)) = session.extensions().get() {
-            for (name, value) in list.iter() {
+        if let Some(list) = session.extensions().get::<HeadersList>() {
+            let overrides = StatusClass::of(response.status)
+                .zip(list.location.as_ref())
+                .and_then(|(class, (host, path))| {
+                    let (_, router) = self
+                        .status_overrides
+                        .iter()
+                        .find(|(override_class, _)| *override_class == class)?;
+                    let (headers, _) = router.lookup(host, path)?.as_value();
+                    Some(resolve_variables(headers, path))
+                })
+                .unwrap_or_default();
+
+            // Status overrides come last, replacing general headers with the same name.
+            for (name, value) in list.headers.iter().chain(&overrides) {
                 // Conversion from HeaderName/HeaderValue is infallible, ignore errors.
                 let _ = response.insert_header(name, value);
             }
@@ -611,4 +952,490 @@ mod tests {
 
         Ok(())
     }
+
+    #[test(tokio::test)]
+    async fn status_overrides() -> Result<(), Box<Error>> {
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        cache_control:
+                            max-age: 3600
+                        custom:
+                            X-Me: example.com
+                        status_overrides:
+                            2xx:
+                                custom:
+                                    X-Status: success
+                                    include: example.com/subdir/*
+                            3xx:
+                                cache_control:
+                                    max-age: 60
+                            4xx:
+                                cache_control:
+                                    private: true
+                            5xx:
+                                cache_control:
+                                    no-cache: true
+                                custom:
+                                    X-Me: error
+                "#,
+            )
+            .unwrap(),
+        )?;
+
+        async fn headers_for(
+            handler: &HeadersHandler,
+            path: &str,
+            status: u16,
+        ) -> Result<ResponseHeader, Box<Error>> {
+            let mut session = make_session(path).await;
+            handler
+                .request_filter(&mut session, &mut HeadersHandler::new_ctx())
+                .await?;
+            let mut header = ResponseHeader::build(status, None)?;
+            handler.response_filter(&mut session, &mut header, None);
+            Ok(header)
+        }
+
+        assert_headers(
+            &headers_for(&handler, "https://example.com/", 200).await?,
+            vec![("Cache-Control", "max-age=3600"), ("X-Me", "example.com")],
+        );
+        assert_headers(
+            &headers_for(&handler, "https://example.com/subdir/", 204).await?,
+            vec![
+                ("Cache-Control", "max-age=3600"),
+                ("X-Me", "example.com"),
+                ("X-Status", "success"),
+            ],
+        );
+        assert_headers(
+            &headers_for(&handler, "https://example.com/subdir/", 301).await?,
+            vec![("Cache-Control", "max-age=60"), ("X-Me", "example.com")],
+        );
+        assert_headers(
+            &headers_for(&handler, "https://example.com/", 404).await?,
+            vec![("Cache-Control", "private"), ("X-Me", "example.com")],
+        );
+        assert_headers(
+            &headers_for(&handler, "https://example.com/", 503).await?,
+            vec![("Cache-Control", "no-cache"), ("X-Me", "error")],
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn path_variable() -> Result<(), Box<Error>> {
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        custom:
+                        -
+                            X-Path: "${path}"
+                        -
+                            X-Matched-Rule: "subdir rule for ${path}, ${unknown}"
+                            include: example.com/subdir/*
+                "#,
+            )
+            .unwrap(),
+        )?;
+
+        let mut session = make_session("https://example.com/").await;
+        handler
+            .request_filter(&mut session, &mut HeadersHandler::new_ctx())
+            .await?;
+        let mut header = ResponseHeader::build(200, None)?;
+        handler.response_filter(&mut session, &mut header, None);
+        assert_headers(&header, vec![("X-Path", "/")]);
+
+        let mut session = make_session("https://example.com/subdir/file.txt?a=b").await;
+        handler
+            .request_filter(&mut session, &mut HeadersHandler::new_ctx())
+            .await?;
+        let mut header = ResponseHeader::build(200, None)?;
+        handler.response_filter(&mut session, &mut header, None);
+        assert_headers(
+            &header,
+            vec![
+                ("X-Path", "/subdir/file.txt"),
+                (
+                    "X-Matched-Rule",
+                    "subdir rule for /subdir/file.txt, ${unknown}",
+                ),
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn headers_for_request() {
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        custom:
+                        -
+                            X-Rule: fallback
+                        -
+                            X-Rule: fallback subdir
+                            include: /subdir/*
+                        -
+                            X-Rule: host
+                            include: example.com
+                        -
+                            X-Rule: exact
+                            include: example.com/file.txt
+                        -
+                            X-Rule: prefix
+                            include: example.com/dir/*
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let rule = |host, path| {
+            handler
+                .headers_for_request(host, path)
+                .into_iter()
+                .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(rule("example.com", "/file.txt"), vec!["x-rule: exact"]);
+        assert_eq!(rule("example.com", "/file.txt/"), vec!["x-rule: exact"]);
+        assert_eq!(rule("example.com", "/file.txt/other"), vec!["x-rule: host"]);
+        assert_eq!(rule("example.com", "/dir"), vec!["x-rule: prefix"]);
+        assert_eq!(rule("example.com", "/dir/file.txt"), vec!["x-rule: prefix"]);
+        assert_eq!(rule("example.com", "/other"), vec!["x-rule: host"]);
+        assert_eq!(
+            rule("example.com", "/subdir/file.txt"),
+            vec!["x-rule: host"]
+        );
+        assert_eq!(rule("example.net", "/"), vec!["x-rule: fallback"]);
+        assert_eq!(
+            rule("example.net", "/subdir/file.txt"),
+            vec!["x-rule: fallback subdir"]
+        );
+        assert_eq!(rule("", "/subdir/"), vec!["x-rule: fallback subdir"]);
+    }
+
+    #[test]
+    fn cache_control_merging() {
+        let merge = |value, other| {
+            merge_directives(
+                &HeaderValue::from_static(value),
+                &HeaderValue::from_static(other),
+            )
+        };
+        assert_eq!(
+            merge("max-age=60, public", "max-age=120"),
+            "max-age=120, public"
+        );
+        assert_eq!(
+            merge("no-transform,Max-Age=60", " public , max-age=0"),
+            "no-transform, max-age=0, public"
+        );
+        assert_eq!(
+            merge("private=\"Set-Cookie, X-Token\", max-age=60", "private"),
+            "private, max-age=60"
+        );
+        assert_eq!(merge("", "no-store"), "no-store");
+
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        cache_control:
+                            max-age: 60
+                            public: true
+                            include: example.com/static/*
+                        custom:
+                        -
+                            Cache-Control: max-age=60, public
+                        -
+                            Cache-Control: max-age=120
+                            include: example.com/dir/*
+                        -
+                            Cache-Control: no-transform, max-age=3600
+                            include: example.com/static/*
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let cache_control = |host, path| {
+            handler
+                .headers_for_request(host, path)
+                .into_iter()
+                .find(|(name, _)| name == header::CACHE_CONTROL)
+                .map(|(_, value)| value.to_str().unwrap().to_owned())
+        };
+        assert_eq!(
+            cache_control("example.com", "/file.txt").as_deref(),
+            Some("max-age=60, public")
+        );
+        assert_eq!(
+            cache_control("example.com", "/dir/file.txt").as_deref(),
+            Some("max-age=120, public")
+        );
+        assert_eq!(
+            cache_control("example.com", "/static/file.txt").as_deref(),
+            Some("max-age=3600, public, no-transform")
+        );
+    }
+
+    #[test]
+    fn content_security_policy_report_only() {
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        defaults:
+                            content_security_policy_report_only:
+                                report-uri: https://example.com/csp-report
+                        content_security_policy:
+                            default-src: "'self'"
+                            script-src: ["'self'", "'unsafe-inline'"]
+                            include: example.com
+                        content_security_policy_report_only:
+                        -
+                            default-src: "'self'"
+                            script-src: "'self'"
+                            include: example.com
+                        -
+                            script-src: https://cdn.example.com
+                            upgrade-insecure-requests: true
+                            include: example.com/app/*
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let headers = |path| {
+            let mut headers = handler
+                .headers_for_request("example.com", path)
+                .into_iter()
+                .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+                .collect::<Vec<_>>();
+            headers.sort();
+            headers
+        };
+
+        assert_eq!(
+            headers("/"),
+            vec![
+                "content-security-policy-report-only: default-src 'self'; script-src 'self'; report-uri https://example.com/csp-report",
+                "content-security-policy: default-src 'self'; script-src 'self' 'unsafe-inline'",
+            ]
+        );
+        assert_eq!(
+            headers("/app/index.html"),
+            vec![
+                "content-security-policy-report-only: default-src 'self'; script-src 'self' https://cdn.example.com; report-uri https://example.com/csp-report; upgrade-insecure-requests",
+                "content-security-policy: default-src 'self'; script-src 'self' 'unsafe-inline'",
+            ]
+        );
+        assert_eq!(
+            handler
+                .headers_for_request("example.net", "/")
+                .into_iter()
+                .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+                .collect::<Vec<_>>(),
+            vec!["content-security-policy-report-only: report-uri https://example.com/csp-report"]
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        use std::collections::BTreeSet;
+
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        defaults:
+                            cache_control:
+                                max-age: 60
+                            custom:
+                                X-Frame-Options: DENY
+                                X-Rule: default
+                        cache_control:
+                            max-age: 3600
+                            public: true
+                            include: example.com/static/*
+                        custom:
+                            X-Rule: app
+                            include: example.com/app/*
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let rule = |host, path| {
+            handler
+                .headers_for_request(host, path)
+                .into_iter()
+                .map(|(name, value)| format!("{name}: {}", value.to_str().unwrap()))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        let defaults = vec![
+            "cache-control: max-age=60",
+            "x-frame-options: DENY",
+            "x-rule: default",
+        ];
+        assert_eq!(rule("example.com", "/"), defaults);
+        assert_eq!(rule("example.com", "/file.txt"), defaults);
+        assert_eq!(rule("example.net", "/static/file.txt"), defaults);
+        assert_eq!(rule("", "/"), defaults);
+        assert_eq!(
+            rule("example.com", "/static/file.txt"),
+            vec![
+                "cache-control: max-age=3600, public",
+                "x-frame-options: DENY",
+                "x-rule: default",
+            ]
+        );
+        assert_eq!(
+            rule("example.com", "/app/"),
+            vec![
+                "cache-control: max-age=60",
+                "x-frame-options: DENY",
+                "x-rule: app",
+            ]
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let handler = HeadersHandler::try_from(
+            HeadersConf::from_yaml(
+                r#"
+                    response_headers:
+                        custom:
+                        -
+                            X-Frame-Options: DENY
+                            max_depth: 1
+                        -
+                            X-Top-Level: "true"
+                            include: example.com/*
+                            max_depth: 0
+                        -
+                            X-Rule: docs
+                            include: /docs/*
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let names = |host, path| {
+            let mut names = handler
+                .headers_for_request(host, path)
+                .into_iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(path_depth(""), 0);
+        assert_eq!(path_depth("/"), 0);
+        assert_eq!(path_depth("/about"), 1);
+        assert_eq!(path_depth("/about/"), 1);
+        assert_eq!(path_depth("/docs/intro"), 2);
+        assert_eq!(path_depth("//docs//intro/"), 2);
+
+        // Depth 0
+        assert_eq!(
+            names("example.com", "/"),
+            vec!["x-frame-options", "x-top-level"]
+        );
+        assert_eq!(names("example.net", "/"), vec!["x-frame-options"]);
+
+        // Depth 1
+        assert_eq!(names("example.com", "/about"), vec!["x-frame-options"]);
+        assert_eq!(names("example.com", "/about/"), vec!["x-frame-options"]);
+        assert_eq!(
+            names("example.com", "/docs/"),
+            vec!["x-frame-options", "x-rule"]
+        );
+
+        // Depth 2
+        assert_eq!(names("example.com", "/assets/app.js"), Vec::<String>::new());
+        assert_eq!(names("example.com", "/docs/intro"), vec!["x-rule"]);
+    }
+
+    #[test(tokio::test)]
+    async fn rule_metrics() -> Result<(), Box<Error>> {
+        let mut conf = HeadersConf::from_yaml(
+            r#"
+                response_headers:
+                    cache_control:
+                    -
+                        max-age: 200
+                        include: example.com
+                    -
+                        max-age: 300
+                        include: example.com/subdir/*
+                    content_security_policy:
+                        script-src: ["'self'"]
+                        include: example.net
+                    content_security_policy_report_only:
+                        script-src: ["'none'"]
+                        include: example.com/subdir/*
+                    custom:
+                        Server: My very own web server
+            "#,
+        )
+        .unwrap();
+
+        let handler = HeadersHandler::try_from(conf.clone())?;
+        assert_eq!(handler.rule_metrics(), None);
+
+        conf.response_headers.rule_metrics = true;
+        let handler = HeadersHandler::try_from(conf)?;
+        assert_eq!(
+            handler.rule_metrics(),
+            Some(HeadersRuleMetrics {
+                cache_control: vec![0, 0],
+                content_security_policy: vec![0],
+                content_security_policy_report_only: vec![0],
+                custom: vec![0],
+            })
+        );
+
+        for path in [
+            "https://example.com/",
+            "https://example.com/subdir/file.txt",
+            "https://example.net/",
+            "https://localhost/",
+        ] {
+            let mut session = make_session(path).await;
+            handler
+                .request_filter(&mut session, &mut HeadersHandler::new_ctx())
+                .await?;
+        }
+        assert_eq!(
+            handler.rule_metrics(),
+            Some(HeadersRuleMetrics {
+                cache_control: vec![2, 1],
+                content_security_policy: vec![1],
+                content_security_policy_report_only: vec![1],
+                custom: vec![4],
+            })
+        );
+
+        Ok(())
+    }
 }
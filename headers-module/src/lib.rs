@@ -72,7 +72,9 @@
 //! ## Include/exclude rule format
 //!
 //! The include and exclude rules can contain either a single rule (a string) or a list with
-//! multiple rules. The individual rules have the following format:
+//! multiple rules. Multiple rules can also be put into one string separated by spaces, e.g.
+//! `include: example.com example.net`, and nested lists are flattened. The individual rules have
+//! the following format:
 //!
 //! * `""` (empty string): This rule applies to everything. Putting this into the `include` list is
 //!   equivalent to omitting it, applying to everything is the default behavior.
@@ -85,6 +87,30 @@
 //!   and `/path/` are considered equivalent.
 //! * `host/path/*`: This rule applies to the specified host/path combination and everything
 //!   contained within it such as `host/path/subdir/file.txt`.
+//! * `*`, `*/path` or `*/path/*`: Catch-all rules applying to all hosts like `""`, `/path` or
+//!   `/path/*` respectively. Unlike these, catch-all rules always have the lowest precedence: any
+//!   other rule applying to a location overrides their settings, regardless of the order of the
+//!   rules. These rules aren’t copied into the configuration of each host when the configuration
+//!   is loaded, which keeps it small for configurations with many hosts.
+//!
+//! ## Path depth limits
+//!
+//! In addition to include and exclude rules, a set of header rules can be limited to shallow
+//! paths with the `max_depth` setting. This is useful for headers that only make sense for the
+//! top-level HTML documents but not for nested assets:
+//!
+//! ```yaml
+//! response_headers:
+//!     custom:
+//!         X-Frame-Options: DENY
+//!         include: example.com
+//!         max_depth: 1
+//! ```
+//!
+//! The depth of a path is the number of non-empty segments separated by `/`. The root path `/`
+//! has depth 0, `/about` and `/about/` both have depth 1 (trailing slashes don’t count), and
+//! `/docs/intro` has depth 2. A rule with `max_depth` only applies to paths with a depth up to
+//! the given value, and only if its include and exclude rules apply to the location as well.
 //!
 //! ## Rule specificity
 //!
@@ -124,14 +150,82 @@
 //! Finally, `upgrade-insecure-requests` directive is a boolean value. It should be set to `true`
 //! to enable this directive in the output. Setting it to `false` has no effect.
 //!
+//! ## `content_security_policy_report_only`
+//!
+//! The `content_security_policy_report_only` section has the same format as the
+//! `content_security_policy` section but produces a `Content-Security-Policy-Report-Only` header.
+//! Browsers report violations of this policy without enforcing it. Both sections can be used
+//! together to roll out a stricter policy gradually:
+//!
+//! ```yaml
+//! response_headers:
+//!     content_security_policy:
+//!         script-src: ["'self'", "'unsafe-inline'"]
+//!     content_security_policy_report_only:
+//!         script-src: "'self'"
+//!         report-uri: https://example.com/csp-report
+//! ```
+//!
+//! Rules within this section are merged in the same way as `content_security_policy` rules, yet
+//! independently of them.
+//!
 //! ## `custom` section
 //!
 //! The `custom` section maps header names to header values. These headers will be sent to the
-//! client verbatim.
+//! client verbatim, with the exception of the `${path}` variable. It will be replaced by the path
+//! of the request, e.g. `X-Requested-Path: ${path}` will produce a header like
+//! `X-Requested-Path: /dir/file.txt`.
+//!
+//! In the unlikely scenario that you might need to send a header named `include`, `exclude` or
+//! `max_depth`, you can add the header as `Include`, `Exclude` or `Max_Depth` to the configuration
+//! file. Unlike the rule settings, header names are case-insensitive.
+//!
+//! ## `defaults` section
 //!
-//! In the unlikely scenario that you might need to send a header named `include` or `exclude`, you
-//! can add the header as `Include` or `Exclude` to the configuration file. Unlike the rule
-//! settings, header names are case-insensitive.
+//! The `defaults` section defines site-wide headers without having to repeat them in a catch-all
+//! rule. It contains `cache_control`, `content_security_policy`,
+//! `content_security_policy_report_only` and `custom` settings without any include or exclude
+//! rules:
+//!
+//! ```yaml
+//! response_headers:
+//!     defaults:
+//!         cache_control:
+//!             max-age: 60
+//!         custom:
+//!             X-Frame-Options: DENY
+//!     cache_control:
+//!         max-age: 604800
+//!         include: example.com/static/*
+//! ```
+//!
+//! Default settings apply to all locations. Any rule applying to a location is merged on top of
+//! them, with its settings taking precedence. In the example above, files under
+//! `example.com/static` are cached for a week, everything else for a minute.
+//!
+//! ## `status_overrides` section
+//!
+//! The `status_overrides` section allows changing headers depending on the status code of the
+//! response. It maps status classes (`1xx`, `2xx`, `3xx`, `4xx`, `5xx`) to sections with the same
+//! format as the general settings:
+//!
+//! ```yaml
+//! response_headers:
+//!     cache_control:
+//!         max-age: 604800
+//!     status_overrides:
+//!         4xx:
+//!             cache_control:
+//!                 no-cache: true
+//!             custom:
+//!                 X-Error: "true"
+//!                 include: example.com
+//! ```
+//!
+//! The headers for a status class are determined in the same way as the general headers. They are
+//! applied on top of the general headers once the response status is known, replacing any general
+//! header with the same name. In the example above, error responses will have `no-cache` instead of
+//! the `max-age` caching directive.
 //!
 //! ## A note on duplicate header values
 //!
@@ -140,13 +234,27 @@
 //! overwritten. Rule processing within the `custom` section also makes sure that only the most
 //! specific rule producing a particular header applies.
 //!
-//! If multiple sections produce the same header name (e.g. `cache_control` section present and
-//! `custom` section also defining a `Cache-Control` header), the values are combined as defined in
+//! If multiple sections produce the same header name (e.g. `content_security_policy` section
+//! present and `custom` section also defining a `Content-Security-Policy` header), the values are
+//! combined as defined in
 //! [RFC 7230 section 3.2.2](https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.2).
 //!
+//! `Cache-Control` is an exception: its values are merged directive by directive, both when
+//! multiple `custom` rules apply and when combining sections. Directives from the more specific
+//! rule replace conflicting directives, other directives are kept. For example, `max-age=60,
+//! public` and `max-age=120` result in `max-age=120, public`.
+//!
 //! The only header where this limitation might become problematic is `Set-Cookie`, and this module
 //! isn’t the right tool for handling cookies.
 //!
+//! ## Rule metrics
+//!
+//! Setting `rule_metrics: true` within the `response_headers` section makes the handler count how
+//! often each set of header rules is applied to a request. `HeadersHandler::rule_metrics()`
+//! returns these counts per section, in the order the rules are listed in the configuration. A
+//! rule is counted whenever it applies to the request location, even if a more specific rule
+//! overrides its settings.
+//!
 //! ## Code example
 //!
 //! You would normally combine the handler of this module with the handlers of other modules. The
@@ -200,4 +308,4 @@ pub mod configuration;
 mod deserialize;
 mod handler;
 
-pub use handler::HeadersHandler;
+pub use handler::{HeadersHandler, HeadersRuleMetrics};
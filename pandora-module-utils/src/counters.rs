@@ -0,0 +1,81 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counters recording how often configuration rules were applied.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A fixed number of hit counters, one per configuration rule
+///
+/// Counters are updated with relaxed atomic operations and can be shared between threads. The
+/// values are only meant for introspection, no ordering guarantees are made relative to other
+/// operations.
+#[derive(Debug, Default)]
+pub struct HitCounters {
+    counters: Box<[AtomicU64]>,
+}
+
+impl HitCounters {
+    /// Creates a set of `len` counters, all initialized to zero
+    pub fn new(len: usize) -> Self {
+        Self {
+            counters: (0..len).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Number of counters in the set
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    /// Checks whether the set contains no counters
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Increments the counter with the given index, indexes out of range are ignored.
+    pub fn increment(&self, index: usize) {
+        if let Some(counter) = self.counters.get(index) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the current values of all counters
+    pub fn snapshot(&self) -> Vec<u64> {
+        self.counters
+            .iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment() {
+        let counters = HitCounters::new(3);
+        assert_eq!(counters.len(), 3);
+        assert_eq!(counters.snapshot(), vec![0, 0, 0]);
+
+        counters.increment(0);
+        counters.increment(2);
+        counters.increment(2);
+        counters.increment(3);
+        assert_eq!(counters.snapshot(), vec![1, 0, 2]);
+
+        assert!(HitCounters::new(0).is_empty());
+    }
+}
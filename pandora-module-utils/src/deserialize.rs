@@ -16,7 +16,9 @@
 //! #[serde(flatten)]
 
 use pingora::server::configuration::ServerConf;
-use serde::de::value::{MapAccessDeserializer, StrDeserializer, StringDeserializer};
+use serde::de::value::{
+    I64Deserializer, MapAccessDeserializer, StrDeserializer, StringDeserializer, U64Deserializer,
+};
 use serde::de::{Deserialize, DeserializeSeed, Deserializer, Error, SeqAccess, Visitor};
 use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
@@ -133,7 +135,8 @@ impl_deserialize_map!(ServerConf {
 /// A wrapper around the `Vec` type allowing more comfortable deserialization.
 ///
 /// If a list is encountered in the configuration file, it is deserialized into `Vec` directly.
-/// String or map values are deserialized as a `Vec` instance with one element instead.
+/// String or map values are deserialized as a `Vec` instance with one element instead. Null values
+/// (e.g. list entries left empty after commenting out their contents) are skipped.
 #[derive(Clone, PartialEq, Eq)]
 pub struct OneOrMany<T> {
     inner: Vec<T>,
@@ -230,12 +233,37 @@ impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for OneOrMany<T> {
                 A: SeqAccess<'de>,
             {
                 let mut list = self.seed;
-                while let Some(entry) = seq.next_element()? {
-                    list.push(entry);
+                while let Some(entry) = seq.next_element::<Option<T>>()? {
+                    list.extend(entry);
                 }
                 Ok(list)
             }
 
+            fn visit_unit<E>(self) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                Ok(self.seed)
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let mut list = self.seed;
+                list.push(T::deserialize(I64Deserializer::new(v))?);
+                Ok(list)
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                let mut list = self.seed;
+                list.push(T::deserialize(U64Deserializer::new(v))?);
+                Ok(list)
+            }
+
             fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
             where
                 E: Error,
@@ -497,6 +525,37 @@ mod tests {
         assert_eq!(&*conf.value, &vec!["hi".to_owned(), "another".to_owned()]);
     }
 
+    #[test]
+    fn one_or_many_numbers() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            value: OneOrMany<u16>,
+            signed: OneOrMany<i32>,
+        }
+
+        let conf = Conf::from_yaml(
+            r#"
+                value: 8080
+                signed: -1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(&*conf.value, &vec![8080]);
+        assert_eq!(&*conf.signed, &vec![-1]);
+
+        let conf = conf
+            .merge_from_yaml(
+                r#"
+                    value: [8443, 9000]
+                "#,
+            )
+            .unwrap();
+        assert_eq!(&*conf.value, &vec![8080, 8443, 9000]);
+
+        assert!(Conf::from_yaml("value: 70000").is_err());
+    }
+
     #[test]
     fn one_or_many_maps() {
         #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
@@ -555,4 +614,44 @@ mod tests {
             &vec![InnerConf { value: 1 }, InnerConf { value: 2 }]
         );
     }
+
+    #[test]
+    fn one_or_many_nulls() {
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct Conf {
+            value: OneOrMany<InnerConf>,
+        }
+
+        #[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+        #[pandora(crate = "crate")]
+        struct InnerConf {
+            value: usize,
+        }
+
+        let conf = Conf::from_yaml(
+            r#"
+                value:
+                - value: 1
+                -
+                #   value: 2
+                - ~
+                - value: 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            &*conf.value,
+            &vec![InnerConf { value: 1 }, InnerConf { value: 3 }]
+        );
+
+        let conf = Conf::from_yaml(
+            r#"
+                value:
+                #   value: 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(&*conf.value, &vec![]);
+    }
 }
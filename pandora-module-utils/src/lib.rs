@@ -18,6 +18,7 @@
 
 #![allow(non_ascii_idents)]
 
+pub mod counters;
 mod deserialize;
 #[doc(hidden)]
 pub mod jar;
@@ -16,8 +16,10 @@
 
 use enumset::{EnumSet, EnumSetType};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
 use std::ops::{Deref, DerefMut};
-use std::{collections::HashMap, fmt::Debug};
+use std::sync::Arc;
 
 use crate::router::{Path, Router};
 
@@ -109,6 +111,12 @@ pub trait PathMatch {
     fn matches(&self, host: &[u8], path: &Path, force_prefix: bool) -> PathMatchResult;
 }
 
+/// Host name of a catch-all matcher. Like the empty fallback host it applies to all hosts, but with
+/// the lowest precedence: both fallback and host-specific configurations take precedence over it.
+/// Unlike fallback configurations, catch-all configurations aren’t added to the entries of each
+/// host, these are only considered when merging.
+pub const ANY_HOST: &[u8] = b"*";
+
 /// A basic path matcher, applying to a single host/path combination
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 #[serde(from = "String")]
@@ -147,7 +155,7 @@ impl From<&str> for HostPathMatcher {
     ///   directory.
     ///
     /// Both `host` and `path` can be empty, the former indicating the fallback host, the latter
-    /// the root directory of the host.
+    /// the root directory of the host. Host `*` indicates a catch-all matcher, see [`ANY_HOST`].
     fn from(path: &str) -> Self {
         if path.contains('/') {
             let (path, exact) = if let Some(path) = path.strip_suffix("/*") {
@@ -186,7 +194,7 @@ impl PathMatch for HostPathMatcher {
     fn matches(&self, host: &[u8], path: &Path, _force_prefix: bool) -> PathMatchResult {
         let result = if self.host == host {
             PathMatchResult::EMPTY
-        } else if self.host.is_empty() {
+        } else if self.host.is_empty() || self.host == ANY_HOST {
             PathMatchResult::EMPTY.set_fallback()
         } else {
             return PathMatchResult::EMPTY;
@@ -339,6 +347,8 @@ impl Debug for StrictHostPathMatcher {
 /// Intermediate node type used by `Merger`
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct MergerEntry<Matcher, Conf> {
+    /// Position of the configuration in the merging pool, determines precedence
+    seq: usize,
     matcher: Matcher,
     conf: Conf,
 }
@@ -349,6 +359,15 @@ type MergerEntriesInner<Matcher, Conf> = (
     Vec<MergerEntry<Matcher, Conf>>,
 );
 
+/// Path to be merged along with the configurations that might apply and a flag indicating whether
+/// it is only relevant for catch-all configurations
+type MergePath<'a, Matcher, Conf> = (
+    &'a Path,
+    bool,
+    &'a [MergerEntry<Matcher, Conf>],
+    &'a [MergerEntry<Matcher, Conf>],
+);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct MergerEntries<Matcher, Conf> {
     inner: Vec<MergerEntriesInner<Matcher, Conf>>,
@@ -374,6 +393,23 @@ impl<Matcher, Conf> Default for MergerEntries<Matcher, Conf> {
     }
 }
 
+/// Exact and prefix values merged for the paths of a host
+type MergedValues<M> = HashMap<Path, (Arc<M>, Arc<M>)>;
+
+/// Merged values of an `IncrementalMerger` from a previous `IncrementalMerger::merge_cached` call
+#[derive(Debug, Clone)]
+pub struct MergeCache<M> {
+    values: HashMap<Vec<u8>, MergedValues<M>>,
+}
+
+impl<M> Default for MergeCache<M> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+        }
+    }
+}
+
 /// A type allowing a number of configurations with their specific path-based restrictions to be
 /// merged, producing a single configuration for each relevant path.
 ///
@@ -397,9 +433,15 @@ impl<Matcher, Conf> Default for MergerEntries<Matcher, Conf> {
 /// Rather than directly producing a `Router` instance, `merge_into_merger` method can be called to
 /// produce an intermediate `Merger`. Multiple mergers of the same type can be combined by calling
 /// `extend` and turned into a `Router` instance then.
+///
+/// If configurations need to be removed or replaced later, use `IncrementalMerger` instead.
 #[derive(Debug, Clone, Default)]
 pub struct Merger<Matcher, Conf> {
     hosts: HashMap<Vec<u8>, MergerEntries<Matcher, Conf>>,
+    /// Catch-all configurations, ordered by precedence. These are considered for all hosts when
+    /// merging, see [`ANY_HOST`].
+    any_host: Vec<MergerEntry<Matcher, Conf>>,
+    next_seq: usize,
 }
 
 impl<Matcher, Conf> Merger<Matcher, Conf>
@@ -411,6 +453,8 @@ where
     pub fn new() -> Self {
         Self {
             hosts: HashMap::new(),
+            any_host: Vec::new(),
+            next_seq: 0,
         }
     }
 
@@ -473,8 +517,27 @@ where
     /// Adds a configuration to the merging pool, along with the matcher encapsulating its
     /// path-based restrictions.
     pub fn push(&mut self, matcher: Matcher, conf: Conf) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.push_entry(MergerEntry { seq, matcher, conf }, |_, _| {});
+    }
+
+    /// Adds a configuration to the lists it applies to, keeping these ordered by precedence. The
+    /// callback is called for each host/path combination with a modified list.
+    fn push_entry(
+        &mut self,
+        entry: MergerEntry<Matcher, Conf>,
+        mut on_change: impl FnMut(&[u8], &Path),
+    ) {
         // Make sure entries for all relevant host/path combinations exist
-        for (host, path) in matcher.iter() {
+        let mut any_host = false;
+        for (host, path) in entry.matcher.iter() {
+            if host == ANY_HOST {
+                // Catch-all paths are only considered when merging.
+                any_host = true;
+                continue;
+            }
+
             Self::ensure_entry(self.ensure_host(host), host, path);
 
             if host.is_empty() {
@@ -487,8 +550,17 @@ where
             }
         }
 
+        if any_host {
+            // Make sure that the fallback host exists, it applies to hosts without entries.
+            self.ensure_host(b"");
+            let index = self
+                .any_host
+                .partition_point(|existing| existing.seq < entry.seq);
+            self.any_host.insert(index, entry);
+            return;
+        }
+
         // Add this conf to any entries it applies to
-        let entry = MergerEntry { matcher, conf };
         for (host, entries) in self.hosts.iter_mut() {
             for (path, list_fallback, list_main) in entries.iter_mut() {
                 let result = entry.matcher.matches(host, path, false);
@@ -498,34 +570,92 @@ where
                     } else {
                         list_main
                     };
-                    list.push(entry.clone())
+                    let index = list.partition_point(|existing| existing.seq < entry.seq);
+                    list.insert(index, entry.clone());
+                    on_change(host, path);
                 }
             }
         }
     }
 
+    /// Lists the paths of catch-all configurations.
+    fn any_host_paths(&self) -> Vec<&Path> {
+        let mut paths = self
+            .any_host
+            .iter()
+            .flat_map(|entry| entry.matcher.iter())
+            .filter(|(host, _)| *host == ANY_HOST)
+            .map(|(_, path)| path)
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Lists the paths to be merged for a host along with the fallback and regular configurations
+    /// that might apply there. Besides the entries of the host, this includes the paths of
+    /// catch-all configurations. The configurations for these are taken from the closest parent
+    /// entry like `ensure_entry` does, `true` is returned along with such paths.
+    fn merge_paths<'a>(
+        host: &[u8],
+        entries: &'a MergerEntries<Matcher, Conf>,
+        any_host_paths: &[&'a Path],
+    ) -> Vec<MergePath<'a, Matcher, Conf>> {
+        let mut result = entries
+            .iter()
+            .map(|(path, list_fallback, list_main)| {
+                (path, false, list_fallback.as_slice(), list_main.as_slice())
+            })
+            .collect::<Vec<_>>();
+
+        for &path in any_host_paths {
+            let index = match entries.binary_search_by_key(&path, |(path, _, _)| path) {
+                Ok(_) => continue,
+                Err(index) => index,
+            };
+
+            let parent = entries[0..index]
+                .iter()
+                .rev()
+                .find(|(parent_path, _, _)| parent_path.is_prefix_of(path));
+            if let Some((_, parent_fallback, parent_main)) = parent {
+                // Parent configurations that don’t apply are filtered out when merging.
+                result.push((path, true, parent_fallback, parent_main));
+            } else if host.is_empty() {
+                result.push((path, true, &[], &[]));
+            }
+            // Otherwise lookups for this path will use the fallback host.
+        }
+
+        result.sort_by_key(|(path, _, _, _)| *path);
+        result
+    }
+
     fn merge_entry<C, M>(
         host: &[u8],
         path: &Path,
-        list_fallback: Vec<MergerEntry<Matcher, Conf>>,
-        list_main: Vec<MergerEntry<Matcher, Conf>>,
+        any_host: &[MergerEntry<Matcher, Conf>],
+        list_fallback: &[MergerEntry<Matcher, Conf>],
+        list_main: &[MergerEntry<Matcher, Conf>],
         callback: &C,
     ) -> (M, M)
     where
         C: for<'a> Fn(Box<dyn Iterator<Item = &'a Conf> + 'a>) -> M,
-        M: Clone,
     {
-        // Iterate over fallback entries first, so that regular entries take precedence.
+        // Iterate over catch-all and fallback entries first, so that regular entries take
+        // precedence.
         let value_exact = callback(Box::new(
-            list_fallback
+            any_host
                 .iter()
+                .chain(list_fallback.iter())
                 .chain(list_main.iter())
                 .filter(|entry| entry.matcher.matches(host, path, false).any())
                 .map(|entry| &entry.conf),
         ));
         let value_prefix = callback(Box::new(
-            list_fallback
+            any_host
                 .iter()
+                .chain(list_fallback.iter())
                 .chain(list_main.iter())
                 .filter(|entry| entry.matcher.matches(host, path, true).prefix())
                 .map(|entry| &entry.conf),
@@ -533,21 +663,15 @@ where
         (value_exact, value_prefix)
     }
 
-    /// Merges the configurations using the given merging callback, producing a router.
-    pub fn merge<C, M>(self, callback: C) -> Router<M>
+    /// Produces a router from the merged values of each host, sorted by path.
+    fn build_router<'a, M>(
+        hosts: impl Iterator<Item = (&'a [u8], Vec<(&'a Path, M, M)>)>,
+    ) -> Router<M>
     where
-        C: for<'a> Fn(Box<dyn Iterator<Item = &'a Conf> + 'a>) -> M,
         M: Clone + Eq,
     {
         let mut builder = Router::builder();
-        for (host, entries) in self.hosts {
-            let mut values = Vec::new();
-            for (path, list_fallback, list_main) in entries.inner {
-                let (value_exact, value_prefix) =
-                    Self::merge_entry(&host, &path, list_fallback, list_main, &callback);
-                values.push((path, value_exact, value_prefix));
-            }
-
+        for (host, mut values) in hosts {
             // Remove unnecessary states
             for i in (0..values.len()).rev() {
                 let (path, value_exact, value_prefix) = &values[i];
@@ -571,12 +695,38 @@ where
             }
 
             for (path, value_exact, value_prefix) in values {
-                builder.push(&host, path.deref(), value_exact, Some(value_prefix));
+                builder.push(host, path.deref(), value_exact, Some(value_prefix));
             }
         }
         builder.build()
     }
 
+    /// Merges the configurations using the given merging callback, producing a router.
+    pub fn merge<C, M>(self, callback: C) -> Router<M>
+    where
+        C: for<'a> Fn(Box<dyn Iterator<Item = &'a Conf> + 'a>) -> M,
+        M: Clone + Eq,
+    {
+        let any_host_paths = self.any_host_paths();
+        Self::build_router(self.hosts.iter().map(|(host, entries)| {
+            let values = Self::merge_paths(host, entries, &any_host_paths)
+                .into_iter()
+                .map(|(path, _, list_fallback, list_main)| {
+                    let (value_exact, value_prefix) = Self::merge_entry(
+                        host,
+                        path,
+                        &self.any_host,
+                        list_fallback,
+                        list_main,
+                        &callback,
+                    );
+                    (path, value_exact, value_prefix)
+                })
+                .collect();
+            (host.as_slice(), values)
+        }))
+    }
+
     /// Merges the configurations using the given merging callback and produces a new merger.
     ///
     /// The result can be combined with other mergers of the same type and turned into a router
@@ -589,14 +739,25 @@ where
         M: Clone,
     {
         let mut new_hosts = HashMap::new();
+        let mut seq = 0;
 
-        for (host, entries) in self.hosts {
+        let any_host_paths = self.any_host_paths();
+        for (host, entries) in &self.hosts {
             let mut new_entries = MergerEntries::default();
-            for (path, list_fallback, list_main) in entries.inner {
-                let (value_exact, value_prefix) =
-                    Self::merge_entry(&host, &path, list_fallback, list_main, &callback);
+            for (path, _, list_fallback, list_main) in
+                Self::merge_paths(host, entries, &any_host_paths)
+            {
+                let (value_exact, value_prefix) = Self::merge_entry(
+                    host,
+                    path,
+                    &self.any_host,
+                    list_fallback,
+                    list_main,
+                    &callback,
+                );
 
                 let entry_exact = MergerEntry {
+                    seq,
                     matcher: StrictHostPathMatcher {
                         host: host.clone(),
                         path: path.clone(),
@@ -605,6 +766,7 @@ where
                     conf: value_exact,
                 };
                 let entry_prefix = MergerEntry {
+                    seq: seq + 1,
                     matcher: StrictHostPathMatcher {
                         host: host.clone(),
                         path: path.clone(),
@@ -612,13 +774,18 @@ where
                     },
                     conf: value_prefix,
                 };
+                seq += 2;
 
-                new_entries.push((path, Vec::new(), vec![entry_exact, entry_prefix]));
+                new_entries.push((path.clone(), Vec::new(), vec![entry_exact, entry_prefix]));
             }
-            new_hosts.insert(host, new_entries);
+            new_hosts.insert(host.clone(), new_entries);
         }
 
-        Merger { hosts: new_hosts }
+        Merger {
+            hosts: new_hosts,
+            any_host: Vec::new(),
+            next_seq: seq,
+        }
     }
 
     /// Combines the data in the two mergers.
@@ -639,7 +806,14 @@ where
             }
         }
 
-        // Combine entries
+        // Combine entries, configurations from `other` take precedence
+        let seq_offset = self.next_seq;
+        self.next_seq += other.next_seq;
+        let shift = |mut entry: MergerEntry<Matcher, Conf>| {
+            entry.seq += seq_offset;
+            entry
+        };
+        self.any_host.extend(other.any_host.into_iter().map(shift));
         for (host, other_entries) in other.hosts.into_iter() {
             let self_entries = self.hosts.get_mut(&host).unwrap();
             for (self_entry, other_entry) in
@@ -647,8 +821,8 @@ where
             {
                 let (_, list_fallback, list_main) = self_entry;
                 let (_, other_fallback, other_main) = other_entry;
-                list_fallback.extend(other_fallback);
-                list_main.extend(other_main);
+                list_fallback.extend(other_fallback.into_iter().map(shift));
+                list_main.extend(other_main.into_iter().map(shift));
             }
         }
     }
@@ -667,6 +841,239 @@ where
     }
 }
 
+/// A merger that can be kept around when configurations change one at a time. After calls to
+/// `push`, `remove` or `replace`, `merge_cached` will produce a new router while only merging
+/// the values of the host/path combinations affected by the changes:
+///
+/// ```rust
+/// use pandora_module_utils::merger::{IncrementalMerger, MergeCache, HostPathMatcher};
+///
+/// let mut merger = IncrementalMerger::new();
+/// merger.push(HostPathMatcher::from("localhost"), "a");
+/// merger.push(HostPathMatcher::from("localhost/abc/"), "b");
+///
+/// let mut cache = MergeCache::default();
+/// let merge = |values: Box<dyn Iterator<Item = &&str> + '_>| values.copied().collect::<String>();
+/// let router = merger.merge_cached(&mut cache, merge);
+/// assert_eq!(**router.lookup("localhost", "/abc").unwrap(), "ab");
+///
+/// merger.replace(&HostPathMatcher::from("localhost"), &"a", HostPathMatcher::from("localhost"), "c");
+/// let router = merger.merge_cached(&mut cache, merge);
+/// assert_eq!(**router.lookup("localhost", "/abc").unwrap(), "cb");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct IncrementalMerger<Matcher, Conf> {
+    merger: Merger<Matcher, Conf>,
+    /// All configurations in the merging pool, ordered by precedence
+    rules: Vec<MergerEntry<Matcher, Conf>>,
+    /// Host/path combinations with lists changed since the last `merge_cached` call
+    changed: HashMap<Vec<u8>, HashSet<Path>>,
+    /// Whether catch-all configurations changed since the last `merge_cached` call
+    any_host_changed: bool,
+}
+
+impl<Matcher, Conf> IncrementalMerger<Matcher, Conf>
+where
+    Matcher: Clone + PartialEq + PathMatch,
+    Conf: Clone + PartialEq,
+{
+    /// Creates a new `IncrementalMerger` instance.
+    pub fn new() -> Self {
+        Self {
+            merger: Merger::new(),
+            rules: Vec::new(),
+            changed: HashMap::new(),
+            any_host_changed: false,
+        }
+    }
+
+    fn mark_changed(changed: &mut HashMap<Vec<u8>, HashSet<Path>>, host: &[u8], path: &Path) {
+        if let Some(paths) = changed.get_mut(host) {
+            paths.insert(path.clone());
+        } else {
+            changed.insert(host.to_owned(), HashSet::from([path.clone()]));
+        }
+    }
+
+    /// Adds a configuration to the merging pool, along with the matcher encapsulating its
+    /// path-based restrictions.
+    pub fn push(&mut self, matcher: Matcher, conf: Conf) {
+        let seq = self.merger.next_seq;
+        self.merger.next_seq += 1;
+        self.push_entry(MergerEntry { seq, matcher, conf });
+    }
+
+    fn push_entry(&mut self, entry: MergerEntry<Matcher, Conf>) {
+        let index = self.rules.partition_point(|rule| rule.seq < entry.seq);
+        self.rules.insert(index, entry.clone());
+
+        if entry.matcher.iter().any(|(host, _)| host == ANY_HOST) {
+            self.any_host_changed = true;
+        }
+
+        let changed = &mut self.changed;
+        self.merger
+            .push_entry(entry, |host, path| Self::mark_changed(changed, host, path));
+    }
+
+    /// Removes hosts and paths that are no longer required by any configuration, so that the
+    /// result is the same as if the remaining configurations were added to a new merger.
+    fn prune(&mut self) {
+        let mut fallback_paths = HashSet::new();
+        let mut required = HashMap::<Vec<u8>, HashSet<Path>>::new();
+        for rule in &self.rules {
+            for (host, path) in rule.matcher.iter() {
+                if host == ANY_HOST {
+                    continue;
+                }
+                if host.is_empty() {
+                    fallback_paths.insert(path.clone());
+                }
+                required
+                    .entry(host.to_owned())
+                    .or_default()
+                    .insert(path.clone());
+            }
+        }
+
+        let any_host = !self.merger.any_host.is_empty();
+        let mut removed = Vec::new();
+        self.merger.hosts.retain(|host, entries| {
+            let paths = required.get(host);
+            entries.retain(|(path, _, _)| {
+                let keep = paths.is_some_and(|paths| {
+                    paths.contains(path) || (!host.is_empty() && fallback_paths.contains(path))
+                });
+                if !keep {
+                    removed.push((host.clone(), path.clone()));
+                }
+                keep
+            });
+            paths.is_some() || (host.is_empty() && any_host)
+        });
+
+        // Removed entries might be added again later, their merged values cannot be reused.
+        for (host, path) in removed {
+            Self::mark_changed(&mut self.changed, &host, &path);
+        }
+    }
+
+    /// Removes the configuration from the entry lists, returns its position in the merging pool.
+    fn remove_entry(&mut self, matcher: &Matcher, conf: &Conf) -> Option<usize> {
+        // If the same configuration has been added multiple times, remove the earliest one.
+        let index = self
+            .rules
+            .iter()
+            .position(|rule| &rule.matcher == matcher && &rule.conf == conf)?;
+        let seq = self.rules.remove(index).seq;
+
+        let any_host_len = self.merger.any_host.len();
+        self.merger.any_host.retain(|entry| entry.seq != seq);
+        if self.merger.any_host.len() != any_host_len {
+            self.any_host_changed = true;
+        }
+
+        for (host, entries) in self.merger.hosts.iter_mut() {
+            for (path, list_fallback, list_main) in entries.iter_mut() {
+                for list in [list_fallback, list_main] {
+                    let len = list.len();
+                    list.retain(|entry| entry.seq != seq);
+                    if list.len() != len {
+                        Self::mark_changed(&mut self.changed, host, path);
+                    }
+                }
+            }
+        }
+
+        self.prune();
+        Some(seq)
+    }
+
+    /// Removes a configuration previously added via `push`. Returns `false` if the configuration
+    /// wasn’t found.
+    pub fn remove(&mut self, matcher: &Matcher, conf: &Conf) -> bool {
+        self.remove_entry(matcher, conf).is_some()
+    }
+
+    /// Replaces a configuration previously added via `push`, keeping its precedence relative to
+    /// other configurations. Returns `false` if the configuration to be replaced wasn’t found,
+    /// the new configuration isn’t added then.
+    pub fn replace(
+        &mut self,
+        old_matcher: &Matcher,
+        old_conf: &Conf,
+        matcher: Matcher,
+        conf: Conf,
+    ) -> bool {
+        if let Some(seq) = self.remove_entry(old_matcher, old_conf) {
+            self.push_entry(MergerEntry { seq, matcher, conf });
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Merges the configurations using the given merging callback, producing a router. Unlike
+    /// `Merger::merge`, this keeps the merger intact so that it can be modified further.
+    ///
+    /// The callback is only called for host/path combinations changed since the previous call
+    /// with the same cache, merged values of other combinations are shared with the cache. The
+    /// result is identical to calling `Merger::merge` as long as the cache is always used with
+    /// the same merger and callback.
+    pub fn merge_cached<C, M>(&mut self, cache: &mut MergeCache<M>, callback: C) -> Router<Arc<M>>
+    where
+        C: for<'a> Fn(Box<dyn Iterator<Item = &'a Conf> + 'a>) -> M,
+        M: Eq,
+    {
+        let merger = &self.merger;
+        let any_host_paths = merger.any_host_paths();
+        let mut values = Vec::new();
+        let mut new_cache = HashMap::new();
+        for (host, entries) in &merger.hosts {
+            let mut host_cache = cache.values.remove(host).unwrap_or_default();
+            let mut new_host_cache = HashMap::new();
+            let changed = self.changed.get(host);
+            let host_values = Merger::merge_paths(host, entries, &any_host_paths)
+                .into_iter()
+                .map(|(path, derived, list_fallback, list_main)| {
+                    // Values of catch-all paths depend on the parent entry, these are only reused
+                    // if nothing changed for the host.
+                    let valid = !self.any_host_changed
+                        && !changed.is_some_and(|changed| {
+                            if derived {
+                                !changed.is_empty()
+                            } else {
+                                changed.contains(path)
+                            }
+                        });
+                    let cached = host_cache.remove(path).filter(|_| valid);
+                    let (value_exact, value_prefix) = cached.unwrap_or_else(|| {
+                        let (value_exact, value_prefix) = Merger::merge_entry(
+                            host,
+                            path,
+                            &merger.any_host,
+                            list_fallback,
+                            list_main,
+                            &callback,
+                        );
+                        (Arc::new(value_exact), Arc::new(value_prefix))
+                    });
+                    new_host_cache
+                        .insert(path.clone(), (value_exact.clone(), value_prefix.clone()));
+                    (path, value_exact, value_prefix)
+                })
+                .collect::<Vec<_>>();
+            new_cache.insert(host.clone(), new_host_cache);
+            values.push((host.as_slice(), host_values));
+        }
+        cache.values = new_cache;
+        self.changed.clear();
+        self.any_host_changed = false;
+
+        Merger::<Matcher, Conf>::build_router(values.into_iter())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -830,6 +1237,194 @@ mod tests {
         assert_eq!(lookup(&router, "", "/abc/def/x"), Some("gkm".to_owned()));
     }
 
+    #[test]
+    fn any_host() {
+        let push_rules = |merger: &mut Merger<HostPathMatcher, String>, catch_all: &str| {
+            merger.push("localhost".into(), "a".to_owned());
+            merger.push("example.com/abc/*".into(), "b".to_owned());
+            merger.push(catch_all.into(), "c".to_owned());
+            merger.push(format!("{catch_all}/xyz/*").as_str().into(), "d".to_owned());
+        };
+
+        // Fallback rules are merged into the configuration of each host.
+        let mut merger = Merger::new();
+        push_rules(&mut merger, "");
+        assert_eq!(merger.hosts.len(), 3);
+        assert_eq!(merger.hosts[b"localhost".as_slice()].len(), 2);
+        assert_eq!(merger.hosts[b"example.com".as_slice()].len(), 3);
+        let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+        assert_eq!(lookup(&router, "localhost", "/"), Some("ca".to_owned()));
+        assert_eq!(lookup(&router, "localhost", "/xyz"), Some("cda".to_owned()));
+        assert_eq!(lookup(&router, "example.com", "/"), Some("c".to_owned()));
+        assert_eq!(
+            lookup(&router, "example.com", "/abc"),
+            Some("cb".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "example.net", "/xyz"),
+            Some("cd".to_owned())
+        );
+
+        // Catch-all rules apply to all hosts as well but don’t produce entries for each host.
+        let mut merger = Merger::new();
+        push_rules(&mut merger, "*");
+        assert_eq!(merger.hosts.len(), 3);
+        assert_eq!(merger.hosts[b"localhost".as_slice()].len(), 1);
+        assert_eq!(merger.hosts[b"example.com".as_slice()].len(), 1);
+        assert_eq!(merger.hosts[b"".as_slice()].len(), 0);
+        assert_eq!(merger.any_host.len(), 2);
+        let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+        assert_eq!(lookup(&router, "localhost", "/"), Some("ca".to_owned()));
+        assert_eq!(lookup(&router, "localhost", "/xyz"), Some("cda".to_owned()));
+        assert_eq!(lookup(&router, "example.com", "/"), Some("c".to_owned()));
+        assert_eq!(
+            lookup(&router, "example.com", "/abc"),
+            Some("cb".to_owned())
+        );
+        assert_eq!(
+            lookup(&router, "example.net", "/xyz"),
+            Some("cd".to_owned())
+        );
+        assert_eq!(lookup(&router, "", "/"), Some("c".to_owned()));
+
+        // Fallback rules take precedence based on the order, catch-all rules always have the
+        // lowest precedence.
+        for (catch_all, expected) in [("", "ab"), ("*", "ba")] {
+            let mut merger = Merger::<HostPathMatcher, String>::new();
+            merger.push("/*".into(), "a".to_owned());
+            merger.push(catch_all.into(), "b".to_owned());
+            merger.push("localhost/abc".into(), "c".to_owned());
+            let router = merger.merge(|values| values.map(String::as_str).collect::<String>());
+            assert_eq!(lookup(&router, "localhost", "/"), Some(expected.to_owned()));
+            assert_eq!(
+                lookup(&router, "localhost", "/abc"),
+                Some(format!("{expected}c"))
+            );
+            assert_eq!(
+                lookup(&router, "example.com", "/x"),
+                Some(expected.to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn merge_cached() {
+        const MATCHERS: &[&str] = &[
+            "",
+            "/*",
+            "/abc",
+            "/abc/*",
+            "/abc/def/*",
+            "*",
+            "*/abc/*",
+            "localhost",
+            "localhost/abc",
+            "localhost/abc/*",
+            "example.com/abc/def/*",
+            "example.com/x",
+        ];
+        const HOSTS: &[&str] = &["", "localhost", "example.com", "example.net"];
+        const PATHS: &[&str] = &[
+            "/",
+            "/x",
+            "/x/y",
+            "/abc",
+            "/abc/x",
+            "/abc/def",
+            "/abc/def/x",
+        ];
+
+        // Simple xorshift generator, sufficient to produce reproducible edit sequences
+        struct Random(u64);
+        impl Random {
+            fn next(&mut self, max: usize) -> usize {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                (self.0 % max as u64) as usize
+            }
+        }
+
+        let calls = std::cell::Cell::new(0);
+        let callback = |values: Box<dyn Iterator<Item = &String> + '_>| {
+            calls.set(calls.get() + 1);
+            values.map(String::as_str).collect::<String>()
+        };
+
+        for seed in 1..=50 {
+            let mut random = Random(seed);
+            let mut rules = Vec::new();
+            let mut merger = IncrementalMerger::<HostPathMatcher, String>::new();
+            let mut cache = MergeCache::default();
+
+            for step in 0..40 {
+                let new_rule = |random: &mut Random| {
+                    (
+                        HostPathMatcher::from(MATCHERS[random.next(MATCHERS.len())]),
+                        format!("{step},"),
+                    )
+                };
+
+                match random.next(3) {
+                    1 if !rules.is_empty() => {
+                        let (matcher, conf) = rules.remove(random.next(rules.len()));
+                        assert!(merger.remove(&matcher, &conf));
+                    }
+                    2 if !rules.is_empty() => {
+                        let index = random.next(rules.len());
+                        let (matcher, conf) = new_rule(&mut random);
+                        let (old_matcher, old_conf) = &rules[index];
+                        assert!(merger.replace(
+                            old_matcher,
+                            old_conf,
+                            matcher.clone(),
+                            conf.clone()
+                        ));
+                        rules[index] = (matcher, conf);
+                    }
+                    _ => {
+                        let (matcher, conf) = new_rule(&mut random);
+                        merger.push(matcher.clone(), conf.clone());
+                        rules.push((matcher, conf));
+                    }
+                }
+
+                let router = merger.merge_cached(&mut cache, callback);
+
+                let mut expected = Merger::new();
+                for (matcher, conf) in &rules {
+                    expected.push(matcher.clone(), conf.clone());
+                }
+                let expected = expected.merge(callback);
+
+                for host in HOSTS {
+                    for path in PATHS {
+                        assert_eq!(
+                            router.lookup(host, path).map(|value| value.to_string()),
+                            lookup(&expected, host, path),
+                            "seed {seed}, step {step}, rules {rules:?}, location {host}{path}"
+                        );
+                    }
+                }
+            }
+
+            // Without changes, nothing needs to be merged again
+            calls.set(0);
+            merger.merge_cached(&mut cache, callback);
+            assert_eq!(calls.get(), 0);
+        }
+
+        let mut merger = IncrementalMerger::<HostPathMatcher, String>::new();
+        merger.push("localhost".into(), "a".to_owned());
+        assert!(!merger.remove(&"localhost".into(), &"b".to_owned()));
+        assert!(!merger.replace(
+            &"example.com".into(),
+            &"a".to_owned(),
+            "localhost".into(),
+            "c".to_owned()
+        ));
+    }
+
     #[test]
     fn redundant_states() {
         #[derive(Debug, Clone)]
@@ -49,8 +49,9 @@ async fn response(
     status: StatusCode,
     location: Option<&str>,
     cookie: Option<&str>,
+    body: Option<&str>,
 ) -> Result<(), Box<Error>> {
-    let text = response_text(status);
+    let text = body.map_or_else(|| response_text(status), str::to_owned);
 
     let mut header = ResponseHeader::build(status, Some(4))?;
     header.append_header(header::CONTENT_LENGTH, text.len().to_string())?;
@@ -75,7 +76,7 @@ pub async fn error_response(
     session: &mut impl SessionWrapper,
     status: StatusCode,
 ) -> Result<(), Box<Error>> {
-    response(session, status, None, None).await
+    response(session, status, None, None, None).await
 }
 
 /// Responds with a redirect to the given location.
@@ -84,7 +85,17 @@ pub async fn redirect_response(
     status: StatusCode,
     location: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), None).await
+    response(session, status, Some(location), None, None).await
+}
+
+/// Responds with a redirect to the given location, using a custom HTML page as response body.
+pub async fn redirect_response_with_body(
+    session: &mut impl SessionWrapper,
+    status: StatusCode,
+    location: &str,
+    body: &str,
+) -> Result<(), Box<Error>> {
+    response(session, status, Some(location), None, Some(body)).await
 }
 
 /// Responds with a redirect to the given location and setting a cookie.
@@ -94,5 +105,5 @@ pub async fn redirect_response_with_cookie(
     location: &str,
     cookie: &str,
 ) -> Result<(), Box<Error>> {
-    response(session, status, Some(location), Some(cookie)).await
+    response(session, status, Some(location), Some(cookie), None).await
 }
@@ -0,0 +1,46 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures how long it takes to set up the handler for a large generated configuration where
+//! many rules repeat the same regular expressions. Run with `cargo bench --bench config_load`.
+
+use pandora_module_utils::FromYaml;
+use rewrite_module::configuration::RewriteConf;
+use rewrite_module::RewriteHandler;
+use std::hint::black_box;
+use std::time::Instant;
+
+const RULES: usize = 1000;
+const ITERATIONS: usize = 10;
+
+fn main() {
+    let rules = (0..RULES)
+        .map(|index| {
+            format!(
+                "- {{from: /section{index}/*, from_regex: \"\\\\.(png|jpe?g|webp)$\", \
+                 case_insensitive: true, to: /images{index}${{tail}}}}\n"
+            )
+        })
+        .collect::<String>();
+    let conf = RewriteConf::from_yaml(format!("rewrite_rules:\n{rules}")).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(RewriteHandler::try_from(conf.clone()).unwrap());
+    }
+    println!(
+        "{:?} to set up the handler with {RULES} rules",
+        start.elapsed() / ITERATIONS as u32
+    );
+}
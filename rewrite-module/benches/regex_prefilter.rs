@@ -0,0 +1,70 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares evaluating many rules differing only by `from_regex` with and without the regular
+//! expression prefilter. Rules with `raw_path` enabled aren’t prefiltered, for the paths used
+//! here they produce the same results otherwise. Run with `cargo bench --bench regex_prefilter`.
+
+use pandora_module_utils::FromYaml;
+use rewrite_module::configuration::RewriteConf;
+use rewrite_module::{RewriteHandler, RewriteRequest};
+use std::hint::black_box;
+use std::time::Instant;
+
+const RULES: usize = 300;
+const ITERATIONS: usize = 2000;
+
+fn make_handler(raw_path: bool) -> RewriteHandler {
+    let rules = (0..RULES)
+        .map(|index| {
+            format!(
+                "- {{from_regex: \"^/section{index}/.*\\\\.html$\", to: /target{index}, \
+                 raw_path: {raw_path}}}\n"
+            )
+        })
+        .collect::<String>();
+    RewriteConf::from_yaml(format!("rewrite_rules:\n{rules}"))
+        .unwrap()
+        .try_into()
+        .unwrap()
+}
+
+fn main() {
+    let requests = [
+        "/section0/index.html",
+        "/section150/page.html",
+        "/unknown.html",
+    ]
+    .into_iter()
+    .map(|uri| RewriteRequest {
+        uri: uri.try_into().unwrap(),
+        ..Default::default()
+    })
+    .collect::<Vec<_>>();
+
+    for (name, raw_path) in [("individual", true), ("prefiltered", false)] {
+        let handler = make_handler(raw_path);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            for request in &requests {
+                black_box(handler.apply(black_box(request)));
+            }
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{name}: {:?} per request with {RULES} rules",
+            elapsed / (ITERATIONS * requests.len()) as u32
+        );
+    }
+}
@@ -14,28 +14,146 @@
 
 //! Structures required to deserialize Rewrite Module configuration from YAML configuration files.
 
+use http::{HeaderName, Method, StatusCode};
+use log::warn;
 use pandora_module_utils::merger::PathMatcher;
-use pandora_module_utils::{DeserializeMap, OneOrMany};
-use regex::Regex;
-use serde::Deserialize;
+use pandora_module_utils::{serde_yaml, DeserializeMap, OneOrMany};
+use regex::bytes::{Regex, RegexBuilder};
+use serde::de::{DeserializeSeed, Error as _};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::default::Default;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::filters::Filter;
+use crate::regex_engine::{self, RegexError};
+
+fn deserialize_delimiter<'de, D>(d: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let delimiter = String::deserialize(d)?;
+    if delimiter.is_empty() {
+        Err(D::Error::custom("variable delimiters cannot be empty"))
+    } else {
+        Ok(delimiter)
+    }
+}
+
+/// Delimiters marking variables in values like the `to` field of the rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct VariableDelimiters {
+    /// Start of a variable, `${` by default
+    #[pandora(deserialize_with = "deserialize_delimiter")]
+    pub prefix: String,
+
+    /// End of a variable, `}` by default
+    #[pandora(deserialize_with = "deserialize_delimiter")]
+    pub suffix: String,
+}
+
+impl Default for VariableDelimiters {
+    fn default() -> Self {
+        Self {
+            prefix: "${".to_owned(),
+            suffix: "}".to_owned(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum VariableInterpolationPart {
     Literal(Vec<u8>),
-    Variable(String),
+    Variable {
+        name: String,
+        filters: Vec<Filter>,
+        /// Literal value used if the variable is empty or cannot be resolved, like in
+        /// `${http_x_forwarded_host:-example.com}`
+        default: Option<Vec<u8>>,
+        source: Vec<u8>,
+    },
+    /// Map lookup like `${map_articles:${1}}`, `name` including the `map_` prefix
+    Map {
+        name: String,
+        key: VariableInterpolation,
+        source: Vec<u8>,
+    },
 }
 
+/// Resolves a map variable from the map name and the resolved key
+pub(crate) type MapLookup<'f, 'a> = dyn Fn(&str, &[u8]) -> Option<Cow<'a, [u8]>> + 'f;
+
+/// Escapes the value of the named variable
+pub(crate) type EscapeValue<'f, 'a> = dyn Fn(&str, Cow<'a, [u8]>) -> Cow<'a, [u8]> + 'f;
+
 /// Parsed representation of a string with variable interpolation like the `to` field of the
 /// rewrite rule
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(from = "String")]
 pub struct VariableInterpolation {
+    source: String,
     parts: Vec<VariableInterpolationPart>,
+    /// First unknown filter applied to a variable, such variables are kept as literal text
+    unknown_filter: Option<String>,
 }
 
 impl From<&str> for VariableInterpolation {
-    fn from(mut value: &str) -> Self {
+    fn from(value: &str) -> Self {
+        Self::with_delimiters(value, &VariableDelimiters::default())
+    }
+}
+
+impl From<String> for VariableInterpolation {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+impl VariableInterpolation {
+    const FILTER_SEPARATOR: char = '|';
+    const DEFAULT_SEPARATOR: &'static str = ":-";
+    const MAP_PREFIX: &'static str = "map_";
+    const MAP_SEPARATOR: char = ':';
+
+    /// Parses the contents of a map variable like `map_articles:${1}}`, following the variable
+    /// prefix. Returns the variable name, the key and the position of the variable suffix.
+    fn parse_map(value: &str, delimiters: &VariableDelimiters) -> Option<(String, Self, usize)> {
+        let (name, _) = value.split_once(Self::MAP_SEPARATOR)?;
+        let map = name.strip_prefix(Self::MAP_PREFIX)?;
+        if map.is_empty() || !map.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+
+        // The key might contain further variables, find the matching suffix
+        let key_start = name.len() + 1;
+        let mut depth = 1;
+        let mut position = key_start;
+        while position < value.len() {
+            let rest = &value[position..];
+            if rest.starts_with(&delimiters.suffix) {
+                depth -= 1;
+                if depth == 0 {
+                    let key = Self::with_delimiters(&value[key_start..position], delimiters);
+                    return Some((name.to_owned(), key, position));
+                }
+                position += delimiters.suffix.len();
+            } else if rest.starts_with(&delimiters.prefix) {
+                depth += 1;
+                position += delimiters.prefix.len();
+            } else {
+                position += rest.chars().next()?.len_utf8();
+            }
+        }
+        None
+    }
+
+    /// Parses a string, recognizing variables marked by the given delimiters. A prefix preceded
+    /// by its first character, e.g. `$${`, is an escaped literal prefix.
+    pub fn with_delimiters(source: &str, delimiters: &VariableDelimiters) -> Self {
         trait FindAt {
             fn find_at(&self, pattern: &str, start: usize) -> Option<usize>;
         }
@@ -45,69 +163,236 @@ impl From<&str> for VariableInterpolation {
             }
         }
 
+        // Merges literals into the preceding literal part, so that interpolation can copy static
+        // data in one go.
+        fn push_literal(parts: &mut Vec<VariableInterpolationPart>, literal: &str) {
+            if literal.is_empty() {
+                return;
+            }
+            if let Some(VariableInterpolationPart::Literal(previous)) = parts.last_mut() {
+                previous.extend_from_slice(literal.as_bytes());
+            } else {
+                parts.push(VariableInterpolationPart::Literal(
+                    literal.as_bytes().to_vec(),
+                ));
+            }
+        }
+
+        let prefix = delimiters.prefix.as_str();
+        let suffix = delimiters.suffix.as_str();
+
+        // Repeating the first character of the prefix escapes it, e.g. `$${` produces `${`
+        let escape = prefix.chars().next().unwrap_or('$');
+
+        let mut value = source;
         let mut parts = Vec::new();
+        let mut unknown_filter = None;
         while !value.is_empty() {
             let mut search_start = 0;
             loop {
-                let variable_start = value.find_at(Self::VARIABLE_PREFIX, search_start);
+                let variable_start = value.find_at(prefix, search_start);
+                if let Some(start) = variable_start {
+                    if value[..start].ends_with(escape) {
+                        push_literal(&mut parts, &value[..start - escape.len_utf8()]);
+                        push_literal(&mut parts, prefix);
+                        value = &value[start + prefix.len()..];
+                        break;
+                    }
+
+                    let contents = &value[start + prefix.len()..];
+                    if let Some((name, key, end)) = Self::parse_map(contents, delimiters) {
+                        let end = start + prefix.len() + end + suffix.len();
+                        push_literal(&mut parts, &value[0..start]);
+                        parts.push(VariableInterpolationPart::Map {
+                            name,
+                            key,
+                            source: value.as_bytes()[start..end].to_vec(),
+                        });
+                        value = &value[end..];
+                        break;
+                    }
+                }
                 let variable_end =
-                    variable_start.and_then(|start| value.find_at(Self::VARIABLE_SUFFIX, start));
+                    variable_start.and_then(|start| value.find_at(suffix, start + prefix.len()));
 
                 if let (Some(start), Some(end)) = (variable_start, variable_end) {
-                    // Found variable start and end, check whether name is alphanumeric
-                    let name = &value[start + Self::VARIABLE_PREFIX.len()..end];
-                    if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
-                        if start > 0 {
-                            parts.push(VariableInterpolationPart::Literal(
-                                value[0..start].as_bytes().to_vec(),
-                            ));
-                        }
-                        parts.push(VariableInterpolationPart::Variable(name.to_owned()));
-                        value = &value[end + Self::VARIABLE_SUFFIX.len()..];
+                    // Found variable start and end, check whether name is alphanumeric and all
+                    // filters are known. The default value extends to the variable suffix and
+                    // cannot contain further variables.
+                    let contents = &value[start + prefix.len()..end];
+                    let (expression, default) = match contents.split_once(Self::DEFAULT_SEPARATOR) {
+                        Some((expression, default)) => (expression, Some(default)),
+                        None => (contents, None),
+                    };
+                    let mut segments = expression.split(Self::FILTER_SEPARATOR);
+                    let name = segments.next().unwrap_or_default();
+                    let filters = segments
+                        .map(|filter| Filter::from_name(filter).ok_or(filter))
+                        .collect::<Result<Vec<_>, _>>();
+                    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    let valid_default = !default.is_some_and(|default| default.contains(prefix));
+                    if let (true, true, Err(filter)) = (valid_name, valid_default, &filters) {
+                        unknown_filter.get_or_insert_with(|| (*filter).to_owned());
+                    }
+                    if let (true, true, Ok(filters)) = (valid_name, valid_default, filters) {
+                        push_literal(&mut parts, &value[0..start]);
+                        parts.push(VariableInterpolationPart::Variable {
+                            name: name.to_owned(),
+                            filters,
+                            default: default.map(|default| default.as_bytes().to_vec()),
+                            source: value.as_bytes()[start..end + suffix.len()].to_vec(),
+                        });
+                        value = &value[end + suffix.len()..];
                         break;
                     }
 
                     // This variable name is invalid, look for another variable start further ahead
-                    search_start = start + Self::VARIABLE_PREFIX.len();
+                    search_start = start + prefix.len();
                 } else {
                     // No variable found, take the entire value as literal
-                    parts.push(VariableInterpolationPart::Literal(
-                        value.as_bytes().to_vec(),
-                    ));
+                    push_literal(&mut parts, value);
                     value = "";
                     break;
                 }
             }
         }
-        Self { parts }
+        Self {
+            source: source.to_owned(),
+            parts,
+            unknown_filter,
+        }
     }
-}
 
-impl From<String> for VariableInterpolation {
-    fn from(value: String) -> Self {
-        value.as_str().into()
+    /// Checks whether a variable like `query` is used, with or without filters.
+    pub(crate) fn uses_variable(&self, name: &str) -> bool {
+        self.parts.iter().any(|part| match part {
+            VariableInterpolationPart::Variable { name: variable, .. } => variable == name,
+            VariableInterpolationPart::Map { key, .. } => key.uses_variable(name),
+            VariableInterpolationPart::Literal(_) => false,
+        })
     }
-}
 
-impl VariableInterpolation {
-    const VARIABLE_PREFIX: &'static str = "${";
-    const VARIABLE_SUFFIX: &'static str = "}";
+    /// Lists the names of the maps looked up, including those in map keys.
+    pub(crate) fn maps(&self) -> Vec<&str> {
+        self.parts
+            .iter()
+            .flat_map(|part| match part {
+                VariableInterpolationPart::Map { name, key, .. } => {
+                    let mut maps = key.maps();
+                    maps.push(&name[Self::MAP_PREFIX.len()..]);
+                    maps
+                }
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Lists the names of the variables used like `tail` for `${tail}`, including those in map
+    /// keys. Map lookups themselves aren’t listed.
+    pub fn variables(&self) -> Vec<&str> {
+        self.parts
+            .iter()
+            .flat_map(|part| match part {
+                VariableInterpolationPart::Variable { name, .. } => vec![name.as_str()],
+                VariableInterpolationPart::Map { key, .. } => key.variables(),
+                VariableInterpolationPart::Literal(_) => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Returns the name of the first unknown filter applied to a variable, including map keys.
+    pub(crate) fn unknown_filter(&self) -> Option<&str> {
+        self.unknown_filter.as_deref().or_else(|| {
+            self.parts.iter().find_map(|part| match part {
+                VariableInterpolationPart::Map { key, .. } => key.unknown_filter(),
+                _ => None,
+            })
+        })
+    }
+
+    /// Checks whether the string contains no variables.
+    pub(crate) fn is_literal(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, VariableInterpolationPart::Literal(_)))
+    }
+
+    /// Returns the literal text preceding the first variable.
+    pub(crate) fn literal_prefix(&self) -> &[u8] {
+        match self.parts.first() {
+            Some(VariableInterpolationPart::Literal(value)) => value,
+            _ => b"",
+        }
+    }
+
+    /// Parses the original string again, recognizing variables marked by the given delimiters
+    pub fn set_delimiters(&mut self, delimiters: &VariableDelimiters) {
+        *self = Self::with_delimiters(&self.source, delimiters);
+    }
 
+    /// Resolves the variables via the lookup function and applies their filters. Variables that
+    /// cannot be resolved or where a filter fails (e.g. invalid base64 data) are left unchanged
+    /// unless they have a default value.
     pub(crate) fn interpolate<'a, L>(&self, lookup: L) -> Vec<u8>
     where
         L: Fn(&str) -> Option<&'a [u8]>,
     {
-        let mut result = Vec::new();
+        self.interpolate_escaped(lookup, |_, value| value)
+    }
+
+    /// Resolves the variables like [`interpolate`](Self::interpolate), passing the resulting
+    /// values through the `escape` function along with the variable name.
+    pub(crate) fn interpolate_escaped<'a, L, E>(&self, lookup: L, escape: E) -> Vec<u8>
+    where
+        L: Fn(&str) -> Option<&'a [u8]>,
+        E: Fn(&str, Cow<'a, [u8]>) -> Cow<'a, [u8]>,
+    {
+        self.interpolate_with_maps(
+            &|name| lookup(name).map(Cow::Borrowed),
+            &|_, _| None,
+            &escape,
+        )
+    }
+
+    /// Resolves the variables like [`interpolate_escaped`](Self::interpolate_escaped). Map
+    /// variables are resolved via the `map` function, called with the map name and the resolved
+    /// key. Map keys aren’t escaped, map variables that cannot be resolved are left unchanged.
+    pub(crate) fn interpolate_with_maps<'a>(
+        &self,
+        lookup: &dyn Fn(&str) -> Option<Cow<'a, [u8]>>,
+        map: &MapLookup<'_, 'a>,
+        escape: &EscapeValue<'_, 'a>,
+    ) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.source.len());
         for part in &self.parts {
             match &part {
                 VariableInterpolationPart::Literal(value) => result.extend_from_slice(value),
-                VariableInterpolationPart::Variable(name) => {
-                    if let Some(value) = lookup(name) {
-                        result.extend_from_slice(value);
+                VariableInterpolationPart::Variable {
+                    name,
+                    filters,
+                    default,
+                    source,
+                } => {
+                    let value = lookup(name).and_then(|value| {
+                        filters
+                            .iter()
+                            .try_fold(value, |value, filter| filter.apply(&value).map(Cow::Owned))
+                    });
+                    match (value, default) {
+                        (Some(value), Some(default)) if value.is_empty() => {
+                            result.extend_from_slice(default)
+                        }
+                        (Some(value), _) => result.extend_from_slice(&escape(name, value)),
+                        (None, Some(default)) => result.extend_from_slice(default),
+                        (None, None) => result.extend_from_slice(source),
+                    }
+                }
+                VariableInterpolationPart::Map { name, key, source } => {
+                    let key = key.interpolate_with_maps(lookup, map, &|_, value| value);
+                    if let Some(value) = map(&name[Self::MAP_PREFIX.len()..], &key) {
+                        result.extend_from_slice(&escape(name, value));
                     } else {
-                        result.extend_from_slice(Self::VARIABLE_PREFIX.as_bytes());
-                        result.extend_from_slice(name.as_bytes());
-                        result.extend_from_slice(Self::VARIABLE_SUFFIX.as_bytes());
+                        result.extend_from_slice(source);
                     }
                 }
             }
@@ -116,9 +401,76 @@ impl VariableInterpolation {
     }
 }
 
-/// URI rewriting type
+/// Handling of trailing slashes for exact `from` patterns like `/about`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    /// Paths with and without a trailing slash are considered equivalent
+    #[default]
+    Tolerant,
+    /// Path has to end with a slash if and only if the pattern does
+    Strict,
+}
+
+/// Handling of encoded slashes (`%2F`) in request paths if `rewrite_decode_path` is enabled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodedSlash {
+    /// Encoded slashes stay encoded, these aren’t treated as path separators
+    #[default]
+    Keep,
+    /// Requests with encoded slashes in the path are rejected with 400 Bad Request
+    Reject,
+}
+
+/// Handling of requests exceeding `rewrite_max_internal`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitExceeded {
+    /// Processing stops, the URI produced by the last rewrite is used
+    #[default]
+    Serve,
+    /// Requests are rejected with 500 Internal Server Error
+    Error,
+}
+
+/// URI scheme of a request, as used by the `scheme` field of the rewrite rule
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "lowercase")]
+pub enum RequestScheme {
+    /// Plaintext HTTP
+    Http,
+    /// HTTP over TLS
+    Https,
+}
+
+/// Logging verbosity of a rewrite rule
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLog {
+    /// No log messages for the rule
+    #[default]
+    Off,
+    /// Log a message whenever the rule is applied
+    Match,
+    /// Log a message whenever the rule is evaluated, whether it applies or not
+    Always,
+}
+
+impl RuleLog {
+    /// Checks whether an evaluation with the given outcome should be logged
+    pub(crate) fn applies(self, matched: bool) -> bool {
+        match self {
+            Self::Off => false,
+            Self::Match => matched,
+            Self::Always => true,
+        }
+    }
+}
+
+/// URI rewriting type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RewriteType {
     /// An internal rewrite, URI change for internal processing only
     Internal,
@@ -126,155 +478,2260 @@ pub enum RewriteType {
     Redirect,
     /// A 308 Permanent Redirect response
     Permanent,
+    /// A 410 Gone response
+    Gone,
+    /// A 403 Forbidden response
+    Forbidden,
+    /// A fixed response with the rule’s `status`, `content_type` and `body`
+    Response,
+    /// A 308 Permanent Redirect to the request URI with a trailing slash added to the path
+    AddSlash,
+    /// A 308 Permanent Redirect to the request URI with the trailing slash removed from the path
+    RemoveSlash,
 }
 
-/// A parsed representation of a field like `from_regex` of the rewrite rule
-#[derive(Debug, Clone, Deserialize)]
-#[serde(try_from = "String")]
-pub struct RegexMatch {
-    /// Regular expression to apply to the value
-    pub regex: Regex,
-    /// If `true`, the result should be negated
-    pub negate: bool,
+impl RewriteType {
+    /// Status code of the response sent for this rewrite type, `None` for internal rewrites
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Self::Internal => None,
+            Self::Redirect => Some(StatusCode::TEMPORARY_REDIRECT),
+            Self::Permanent | Self::AddSlash | Self::RemoveSlash => {
+                Some(StatusCode::PERMANENT_REDIRECT)
+            }
+            Self::Gone => Some(StatusCode::GONE),
+            Self::Forbidden => Some(StatusCode::FORBIDDEN),
+            Self::Response => Some(StatusCode::OK),
+        }
+    }
+
+    /// `true` for rewrite types producing a redirect response
+    pub fn is_redirect(&self) -> bool {
+        matches!(
+            self,
+            Self::Redirect | Self::Permanent | Self::AddSlash | Self::RemoveSlash
+        )
+    }
+
+    /// `true` for rewrite types redirecting to the request URI with the trailing slash changed
+    pub fn is_slash(&self) -> bool {
+        matches!(self, Self::AddSlash | Self::RemoveSlash)
+    }
 }
 
-impl RegexMatch {
-    /// Checks whether the given value is matched
-    pub(crate) fn matches(&self, value: &str) -> bool {
-        let result = self.regex.is_match(value);
-        if self.negate {
-            !result
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathPatternSegment {
+    Literal(Vec<u8>),
+    Wildcard,
+}
+
+/// A path pattern like `/dir/*` or `/dir/*/subdir/*` as used by the `from` field of the rewrite
+/// rule
+///
+/// A `*` at the end of the pattern matches any number of path segments, a `*` anywhere else
+/// matches exactly one path segment. A pattern starting with `!` like `!/dir/internal/*` excludes
+/// paths from the rule.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct PathPattern {
+    /// Matcher for the pattern up to the first wildcard, used to look up the rule
+    pub matcher: PathMatcher,
+    /// Segments following the first wildcard, empty for simple patterns like `/dir/*`
+    segments: Vec<PathPatternSegment>,
+    /// `true` if the pattern ends with a slash like `/dir/`, relevant for strict matching only
+    pub(crate) trailing_slash: bool,
+    /// `true` if the pattern starts with `!`, excluding the paths matched
+    pub(crate) negate: bool,
+}
+
+impl From<&str> for PathPattern {
+    fn from(value: &str) -> Self {
+        let (value, negate) = match value.strip_prefix('!') {
+            Some(value) => (value, true),
+            None => (value, false),
+        };
+
+        let wildcard = value
+            .match_indices("/*")
+            .map(|(index, _)| index)
+            .find(|index| {
+                let rest = &value[index + 2..];
+                !rest.is_empty() && rest.starts_with('/')
+            });
+
+        if let Some(index) = wildcard {
+            let segments = value[index + 2..]
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| {
+                    if segment == "*" {
+                        PathPatternSegment::Wildcard
+                    } else {
+                        PathPatternSegment::Literal(segment.as_bytes().to_vec())
+                    }
+                })
+                .collect();
+            Self {
+                matcher: format!("{}/*", &value[..index]).into(),
+                segments,
+                trailing_slash: value.ends_with('/'),
+                negate,
+            }
         } else {
-            result
+            Self {
+                matcher: value.into(),
+                segments: Vec::new(),
+                trailing_slash: value.len() > 1 && value.ends_with('/'),
+                negate,
+            }
         }
     }
 }
 
-impl PartialEq for RegexMatch {
-    fn eq(&self, other: &Self) -> bool {
-        self.regex.as_str() == other.regex.as_str() && self.negate == other.negate
+impl From<String> for PathPattern {
+    fn from(value: String) -> Self {
+        value.as_str().into()
     }
 }
 
-impl Eq for RegexMatch {}
-
-impl TryFrom<&str> for RegexMatch {
-    type Error = regex::Error;
+impl PathPattern {
+    /// `true` for patterns without wildcards before the end like `/dir/*`, these are fully
+    /// described by `matcher`.
+    pub(crate) fn is_simple(&self) -> bool {
+        self.segments.is_empty()
+    }
 
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let (regex, negate) = if let Some(regex) = value.strip_prefix('!') {
-            (regex, true)
+    /// Number of values captured by the wildcards of the pattern, zero for exact patterns like
+    /// `/file.html`.
+    pub(crate) fn wildcards(&self) -> usize {
+        if self.matcher.exact {
+            0
         } else {
-            (value, false)
-        };
-        Ok(Self {
-            regex: Regex::new(regex)?,
-            negate,
-        })
+            1 + self
+                .segments
+                .iter()
+                .filter(|segment| matches!(segment, PathPatternSegment::Wildcard))
+                .count()
+        }
     }
-}
-
-impl TryFrom<String> for RegexMatch {
-    type Error = regex::Error;
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        value.as_str().try_into()
+    /// Number of literal segments following the first wildcard, patterns with more literal
+    /// segments are considered more specific.
+    pub(crate) fn specificity(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, PathPatternSegment::Literal(_)))
+            .count()
     }
-}
 
-/// A rewrite rule resulting in either request URI change or redirect
-#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
-pub struct RewriteRule {
-    /// Path or a set of paths to rewrite
-    ///
-    /// By default, an exact path match is required. A value like `/path/*` indicates a prefix
-    /// match, both `/path/` and `/path/subdir/file.txt` will be matched.
-    ///
-    /// When multiple rules potentially apply to a location, the closest matches will be evaluated
-    /// first. Rules with a longer path are considered closer matches than shorter paths. Exact
-    /// matches are considered closer matches than prefix matches for the same path.
-    pub from: PathMatcher,
+    /// Checks whether a path exists that is matched by both this pattern and the other one.
+    pub(crate) fn overlaps(&self, other: &Self) -> bool {
+        fn overlaps(a: &[PathPatternSegment], b: &[PathPatternSegment]) -> bool {
+            match (a, b) {
+                ([], []) => true,
+                // Wildcard at the end matches any number of segments
+                ([PathPatternSegment::Wildcard], _) | (_, [PathPatternSegment::Wildcard]) => true,
+                ([], _) | (_, []) => false,
+                (
+                    [PathPatternSegment::Literal(a_literal), ..],
+                    [PathPatternSegment::Literal(b_literal), ..],
+                ) if a_literal != b_literal => false,
+                ([_, a_rest @ ..], [_, b_rest @ ..]) => overlaps(a_rest, b_rest),
+            }
+        }
 
-    /// Additional regular expression to further restrict matching paths, e.g. `\.png$` to match
-    /// only PNG files. Prefixing the regular expression with `!` will negate its effect, e.g.
-    /// `!\.png` will match all files but PNG files.
-    ///
-    /// Note that restricting the path as much as possible via `from` setting first is recommended
-    /// for reasons of performance.
-    pub from_regex: Option<RegexMatch>,
+        if self.matcher != other.matcher {
+            return false;
+        }
 
-    /// Additional regular expression to restrict matches to particular query strings only. For
-    /// example `file=` will only match queries containing a `file` parameter. Prefixing the
-    /// regular expression with `!` will negate its effect, e.g. `!file=` will match all queries
-    /// but those containing a `file` parameter.
-    pub query_regex: Option<RegexMatch>,
+        let first = [PathPatternSegment::Wildcard];
+        let a = first
+            .iter()
+            .chain(&self.segments)
+            .cloned()
+            .collect::<Vec<_>>();
+        let b = first
+            .iter()
+            .chain(&other.segments)
+            .cloned()
+            .collect::<Vec<_>>();
+        overlaps(&a, &b)
+    }
 
-    /// New URI to be set on match
-    ///
-    /// The following variables will be resolved:
-    ///
-    /// * `${tail}`: Only valid when matching a path prefix. This will be replaced by the path part
-    ///   matched by `*`. For example, if `from` is `/dir/*`, `to` is `/another/${tail}` and the
-    ///   actual path matched is `/dir/file.txt`, then the URI will be rewritten into
-    ///   `/another/file.txt`.
-    /// * `${query}`: This allows considering the original query which is removed by default. For
-    ///   example, if `from` is `/file.txt` and `to` is `/file.html?${query}` then a request to
-    ///   `/file.txt?a=b` will be rewritten into `/file.html?a=b`.
-    /// * `${http_<header>}`: This allows inserting arbitrary HTTP headers into the redirect
-    ///   target.
-    pub to: VariableInterpolation,
+    /// Matches the pattern against the part of the path following `matcher`. Returns the values
+    /// captured by the wildcards on success. If `case_insensitive` is `true`, literal segments
+    /// are compared ignoring ASCII case.
+    pub(crate) fn capture(&self, mut tail: &[u8], case_insensitive: bool) -> Option<Vec<Vec<u8>>> {
+        fn next_segment(path: &[u8]) -> Option<(&[u8], &[u8])> {
+            let start = path.iter().position(|b| *b != b'/')?;
+            let path = &path[start..];
+            let end = path.iter().position(|b| *b == b'/').unwrap_or(path.len());
+            Some(path.split_at(end))
+        }
 
-    /// Rewriting type, one of `internal` (default), `redirect` or `permanent`
-    pub r#type: RewriteType,
-}
+        fn as_tail(path: &[u8]) -> Vec<u8> {
+            if path.is_empty() {
+                b"/".to_vec()
+            } else {
+                path.to_vec()
+            }
+        }
 
-impl Default for RewriteRule {
-    fn default() -> Self {
-        Self {
-            from: "/*".into(),
-            from_regex: None,
-            query_regex: None,
-            to: "/".into(),
-            r#type: RewriteType::Internal,
+        if self.segments.is_empty() {
+            return Some(vec![as_tail(tail)]);
+        }
+
+        let mut captures = Vec::new();
+        let first = std::iter::once(&PathPatternSegment::Wildcard);
+        let mut segments = first.chain(self.segments.iter()).peekable();
+        while let Some(segment) = segments.next() {
+            match segment {
+                PathPatternSegment::Wildcard if segments.peek().is_none() => {
+                    captures.push(as_tail(tail));
+                    return Some(captures);
+                }
+                PathPatternSegment::Wildcard => {
+                    let (value, rest) = next_segment(tail)?;
+                    let mut capture = b"/".to_vec();
+                    capture.extend_from_slice(value);
+                    captures.push(capture);
+                    tail = rest;
+                }
+                PathPatternSegment::Literal(literal) => {
+                    let (value, rest) = next_segment(tail)?;
+                    let matches = if case_insensitive {
+                        value.eq_ignore_ascii_case(literal)
+                    } else {
+                        value == literal.as_slice()
+                    };
+                    if !matches {
+                        return None;
+                    }
+                    tail = rest;
+                }
+            }
+        }
+
+        if next_segment(tail).is_some() {
+            None
+        } else {
+            Some(captures)
         }
     }
 }
 
-/// Configuration file settings of the rewrite module
-#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
-pub struct RewriteConf {
-    /// A list of rewrite rules
-    pub rewrite_rules: OneOrMany<RewriteRule>,
+/// A shell-like glob such as `/docs/**/*.md` as used by the `from_glob` field of the rewrite rule
+///
+/// Supported are `?` (any character but `/`), `*` (any number of characters but `/`), `**` (any
+/// number of characters including `/`) and character classes like `[abc]`, `[a-z]` or `[!abc]`.
+/// The glob has to match the entire path.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Glob {
+    /// The glob as configured
+    pub glob: String,
+    /// Regular expression equivalent to the glob
+    regex: Regex,
+    /// Length of the literal directory prefix, e.g. `/docs` in `/docs/**/*.md`
+    prefix_len: usize,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Glob {
+    fn is_special(c: char) -> bool {
+        matches!(c, '*' | '?' | '[')
+    }
 
-    use test_log::test;
+    /// Creates a case-insensitive copy of this glob
+    pub(crate) fn case_insensitive(&self) -> Result<Self, regex::Error> {
+        Ok(Self {
+            glob: self.glob.clone(),
+            regex: RegexBuilder::new(self.regex.as_str())
+                .case_insensitive(true)
+                .build()?,
+            prefix_len: self.prefix_len,
+        })
+    }
 
-    #[test]
-    fn variable_interpolation() {
-        assert_eq!(
-            VariableInterpolation::from("abcd").interpolate(|_| panic!("Unexpected lookup call")),
-            b"abcd".to_vec()
-        );
+    /// Checks whether the given path is matched
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        self.regex.is_match(path.as_bytes())
+    }
 
-        assert_eq!(
-            VariableInterpolation::from("ab${xyz}cd").interpolate(|_| None),
-            b"ab${xyz}cd".to_vec()
-        );
+    /// Path pattern used to look up the rule: the literal directory prefix of the glob followed
+    /// by `/*`, or the glob itself if it doesn’t contain any special characters
+    pub(crate) fn pattern(&self) -> PathPattern {
+        if self.glob.contains(Self::is_special) {
+            format!("{}/*", &self.glob[..self.prefix_len])
+                .as_str()
+                .into()
+        } else {
+            self.glob.as_str().into()
+        }
+    }
 
-        assert_eq!(
-            VariableInterpolation::from("ab${xyz}cd").interpolate(|name| {
-                if name == "xyz" {
-                    Some(b"resolved")
-                } else {
-                    None
-                }
-            }),
-            b"abresolvedcd".to_vec()
-        );
+    /// Number of literal segments following the directory prefix, globs with more literal
+    /// segments are considered more specific.
+    pub(crate) fn specificity(&self) -> usize {
+        self.glob[self.prefix_len..]
+            .split('/')
+            .filter(|segment| !segment.is_empty() && !segment.contains(Self::is_special))
+            .count()
+    }
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Self) -> bool {
+        self.glob == other.glob
+    }
+}
+
+impl Eq for Glob {}
+
+impl TryFrom<&str> for Glob {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut regex = "^".to_owned();
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        // `/**/` also matches a single slash
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    regex.push('[');
+                    if chars.next_if(|c| *c == '!' || *c == '^').is_some() {
+                        regex.push_str("^/");
+                    }
+                    let mut empty = true;
+                    loop {
+                        match chars.next() {
+                            Some(']') if !empty => break,
+                            Some(c @ ('\\' | '[' | ']' | '&' | '~' | '^')) => {
+                                regex.push('\\');
+                                regex.push(c);
+                            }
+                            Some(c) => regex.push(c),
+                            None => {
+                                return Err(format!("unclosed character class in glob `{value}`"))
+                            }
+                        }
+                        empty = false;
+                    }
+                    regex.push(']');
+                }
+                c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push('$');
+
+        let special = value.find(Self::is_special).unwrap_or(value.len());
+        let prefix_len = value[..special].rfind('/').unwrap_or(0);
+
+        Ok(Self {
+            glob: value.to_owned(),
+            regex: Regex::new(&regex).map_err(|err| format!("invalid glob `{value}`: {err}"))?,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for Glob {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// Apache mod_rewrite compatible flags like `[R=301,L]`, translated into the corresponding rule
+/// settings
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RewriteFlags {
+    /// Rewrite type set via the `R` flag
+    pub r#type: Option<RewriteType>,
+    /// Status code set via the `R` flag, `None` if the default status code of the type applies
+    pub status: Option<StatusCode>,
+    /// `true` if `L` flag is present
+    pub last: bool,
+    /// `true` if `NC` flag is present
+    pub case_insensitive: bool,
+    /// `true` if `QSA` flag is present
+    pub append_query: bool,
+}
+
+impl TryFrom<&str> for RewriteFlags {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let value = value
+            .strip_prefix('[')
+            .and_then(|value| value.strip_suffix(']'))
+            .unwrap_or(value);
+
+        let mut result = Self::default();
+        for flag in value
+            .split(',')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+        {
+            let (name, flag_value) = match flag.split_once('=') {
+                Some((name, flag_value)) => (name, Some(flag_value)),
+                None => (flag, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), flag_value) {
+                ("r" | "redirect", None | Some("302")) => {
+                    result.r#type = Some(RewriteType::Redirect);
+                    result.status = Some(StatusCode::FOUND);
+                }
+                ("r" | "redirect", Some("307")) => {
+                    result.r#type = Some(RewriteType::Redirect);
+                    result.status = None;
+                }
+                ("r" | "redirect", Some("301")) => {
+                    result.r#type = Some(RewriteType::Permanent);
+                    result.status = Some(StatusCode::MOVED_PERMANENTLY);
+                }
+                ("r" | "redirect", Some("308")) => {
+                    result.r#type = Some(RewriteType::Permanent);
+                    result.status = None;
+                }
+                ("l" | "last", None) => result.last = true,
+                ("nc" | "nocase", None) => result.case_insensitive = true,
+                ("qsa" | "qsappend", None) => result.append_query = true,
+                _ => return Err(format!("unsupported rewrite flag `{flag}`")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl TryFrom<String> for RewriteFlags {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// A parsed representation of a field like `from_regex` of the rewrite rule
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RegexMatch {
+    /// Regular expression to apply to the value, see [`RegexMatch::regex`]
+    pub(crate) regex: Arc<regex_engine::Regex>,
+    /// If `true`, the result should be negated
+    pub negate: bool,
+}
+
+impl RegexMatch {
+    /// Regular expression to apply to the value
+    ///
+    /// The regular expression operates on bytes, so that it can also be applied to values that
+    /// aren’t valid UTF-8. Unicode-aware expressions like `.` or `\w` only match valid UTF-8
+    /// sequences however. Invalid bytes can be matched by disabling Unicode mode, e.g.
+    /// `(?-u:\xFF)` or `(?-u:.)`.
+    ///
+    /// With the `fancy-regex` feature, look-around and backreferences are supported. Values are
+    /// then matched as strings, with invalid UTF-8 sequences replaced by U+FFFD.
+    ///
+    /// The handler shares the compiled regular expression between all rules using the same one.
+    pub fn regex(&self) -> &regex_engine::Regex {
+        &self.regex
+    }
+
+    /// Creates a case-insensitive copy of this regular expression
+    pub(crate) fn case_insensitive(&self) -> Result<Self, RegexError> {
+        Ok(Self {
+            regex: Arc::new(regex_engine::Regex::new_case_insensitive(
+                self.regex.as_str(),
+            )?),
+            negate: self.negate,
+        })
+    }
+
+    /// Checks whether the given value is matched
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        self.matches_bytes(value.as_bytes())
+    }
+
+    /// Returns the capture groups of the regular expression applied to the value. Returns `None`
+    /// if the value isn’t matched or the regular expression is negated.
+    pub(crate) fn captures<'a>(&self, value: &'a str) -> Option<regex_engine::Captures<'a>> {
+        if self.negate {
+            None
+        } else {
+            self.regex.captures(value)
+        }
+    }
+
+    /// Checks whether the given byte sequence is matched, it doesn’t have to be valid UTF-8.
+    ///
+    /// If the regular expression cannot be evaluated for the value, e.g. because the backtracking
+    /// limit of the `fancy-regex` feature is exceeded, the value is considered not matched
+    /// regardless of negation.
+    pub(crate) fn matches_bytes(&self, value: &[u8]) -> bool {
+        match self.regex.is_match(value) {
+            Ok(result) => result != self.negate,
+            Err(err) => {
+                warn!(
+                    "Failed matching regular expression {}: {err}",
+                    self.regex.as_str()
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Combination of multiple regular expressions in `from_regex` or `query_regex`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegexMode {
+    /// All regular expressions have to match
+    #[default]
+    All,
+    /// At least one regular expression has to match
+    Any,
+}
+
+impl RegexMode {
+    /// Checks whether the given value is matched by the regular expressions, an empty list always
+    /// matches.
+    pub(crate) fn matches(self, regexes: &[RegexMatch], value: &str) -> bool {
+        match self {
+            _ if regexes.is_empty() => true,
+            Self::All => regexes.iter().all(|regex| regex.matches(value)),
+            Self::Any => regexes.iter().any(|regex| regex.matches(value)),
+        }
+    }
+}
+
+impl PartialEq for RegexMatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str() && self.negate == other.negate
+    }
+}
+
+impl Eq for RegexMatch {}
+
+impl TryFrom<&str> for RegexMatch {
+    type Error = RegexError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (regex, negate) = if let Some(regex) = value.strip_prefix('!') {
+            (regex, true)
+        } else {
+            (value, false)
+        };
+        Ok(Self {
+            regex: Arc::new(regex_engine::Regex::new(regex)?),
+            negate,
+        })
+    }
+}
+
+impl TryFrom<String> for RegexMatch {
+    type Error = RegexError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// Restriction on the presence of a query string, as used by the `query` field
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryPresence {
+    /// Any query string or none at all
+    #[default]
+    Any,
+    /// A query string, possibly empty, has to be present
+    Present,
+    /// No query string may be present, not even an empty one
+    Absent,
+    /// The query string has to be present but empty, as in `/path?`
+    Empty,
+}
+
+impl QueryPresence {
+    /// Checks whether the query string of a URI (`None` if the URI has no `?`) is acceptable.
+    pub(crate) fn matches(self, query: Option<&str>) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Present => query.is_some(),
+            Self::Absent => query.is_none(),
+            Self::Empty => query == Some(""),
+        }
+    }
+}
+
+/// A sed-like substitution such as `s/^\/old\/(.*)/\/new\/$1/` as used by the `subst` field
+/// of the rewrite rule
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Substitution {
+    /// Regular expression to be replaced
+    pub regex: Regex,
+    /// Replacement text, can refer to capture groups as `$1` or `${name}`
+    pub replacement: String,
+    /// If `true`, all matches are replaced rather than only the first one (`g` flag)
+    pub global: bool,
+    /// If `true`, the regular expression is case-insensitive (`i` flag)
+    pub case_insensitive: bool,
+}
+
+impl Substitution {
+    /// Checks whether the given value is matched
+    pub(crate) fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value.as_bytes())
+    }
+
+    /// Applies the substitution to the given value
+    pub(crate) fn apply(&self, value: &str) -> Vec<u8> {
+        let replacement = self.replacement.as_bytes();
+        if self.global {
+            self.regex.replace_all(value.as_bytes(), replacement)
+        } else {
+            self.regex.replace(value.as_bytes(), replacement)
+        }
+        .into_owned()
+    }
+}
+
+impl PartialEq for Substitution {
+    fn eq(&self, other: &Self) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+            && self.replacement == other.replacement
+            && self.global == other.global
+            && self.case_insensitive == other.case_insensitive
+    }
+}
+
+impl Eq for Substitution {}
+
+impl TryFrom<&str> for Substitution {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!("`{value}` is not a valid substitution, expected something like `s/old/new/g`")
+        };
+
+        let mut chars = value.strip_prefix('s').ok_or_else(invalid)?.chars();
+        let delimiter = chars
+            .next()
+            .filter(|c| !c.is_alphanumeric() && *c != '\\')
+            .ok_or_else(invalid)?;
+
+        // Split into pattern, replacement and flags, `\` escapes the delimiter.
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            if c == delimiter {
+                parts.push(String::new());
+                continue;
+            }
+
+            let part = parts.last_mut().unwrap();
+            if c == '\\' {
+                match chars.next() {
+                    Some(next) if next == delimiter => part.push(next),
+                    Some(next) => {
+                        part.push(c);
+                        part.push(next);
+                    }
+                    None => part.push(c),
+                }
+            } else {
+                part.push(c);
+            }
+        }
+
+        let [pattern, replacement, flags] =
+            <[String; 3]>::try_from(parts).map_err(|_| invalid())?;
+        let mut global = false;
+        let mut case_insensitive = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => case_insensitive = true,
+                _ => {
+                    return Err(format!(
+                        "unsupported substitution flag `{flag}` in `{value}`"
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            regex: RegexBuilder::new(&pattern)
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(|err| err.to_string())?,
+            replacement,
+            global,
+            case_insensitive,
+        })
+    }
+}
+
+impl TryFrom<String> for Substitution {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// A media type like `application/json` as used by the `accept` field of the rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct MediaType {
+    /// Top-level type, e.g. `application`
+    pub r#type: String,
+    /// Subtype, e.g. `json`
+    pub subtype: String,
+}
+
+impl TryFrom<&str> for MediaType {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        fn is_token(value: &str) -> bool {
+            !value.is_empty()
+                && value
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&b))
+        }
+
+        match value.trim().split_once('/') {
+            Some((r#type, subtype)) if is_token(r#type) && is_token(subtype) => Ok(Self {
+                r#type: r#type.to_ascii_lowercase(),
+                subtype: subtype.to_ascii_lowercase(),
+            }),
+            _ => Err(format!(
+                "`{value}` is not a valid media type, expected something like `application/json`"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for MediaType {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// An HTTP request method like `GET` as used by the `methods` field of the rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RequestMethod {
+    /// The method
+    pub method: Method,
+}
+
+impl TryFrom<&str> for RequestMethod {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        const KNOWN_METHODS: [Method; 9] = [
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::CONNECT,
+            Method::OPTIONS,
+            Method::TRACE,
+            Method::PATCH,
+        ];
+
+        KNOWN_METHODS
+            .into_iter()
+            .find(|method| method.as_str().eq_ignore_ascii_case(value.trim()))
+            .map(|method| Self { method })
+            .ok_or_else(|| {
+                format!(
+                    "`{value}` is not a known HTTP method, expected one of {}",
+                    KNOWN_METHODS
+                        .iter()
+                        .map(Method::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for RequestMethod {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// An IP address range like `192.168.0.0/16` or `!fd00::/8` as used by the `from_ip` field of the
+/// rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpMatch {
+    /// Network address, IPv4-mapped IPv6 addresses are converted to IPv4
+    pub network: IpAddr,
+    /// Number of leading bits of the network address that have to match
+    pub prefix_len: u8,
+    /// If `true`, the result should be negated
+    pub negate: bool,
+}
+
+impl IpMatch {
+    /// Converts IPv4-mapped IPv6 addresses like `::ffff:127.0.0.1` to IPv4.
+    pub(crate) fn canonical(addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            addr => addr,
+        }
+    }
+
+    /// Checks whether the address is within the range, ignoring `negate`.
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+            let bytes = usize::from(prefix_len / 8);
+            let bits = prefix_len % 8;
+            a[..bytes] == b[..bytes]
+                && (bits == 0 || (a[bytes] ^ b[bytes]) & (0xFF << (8 - bits)) == 0)
+        }
+
+        match (self.network, Self::canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(&network.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<&str> for IpMatch {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (range, negate) = if let Some(range) = value.strip_prefix('!') {
+            (range, true)
+        } else {
+            (value, false)
+        };
+        let (address, prefix_len) = match range.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (range.trim(), None),
+        };
+
+        let address = address
+            .parse::<IpAddr>()
+            .map_err(|err| format!("`{value}` doesn’t contain a valid IP address: {err}"))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let mut prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| {
+                    format!("`{value}` has an invalid prefix length, expected 0 to {max_len}")
+                })?,
+            None => max_len,
+        };
+
+        let network = Self::canonical(address);
+        if network != address {
+            // IPv4-mapped IPv6 range, the first 96 bits are the mapping prefix
+            prefix_len = prefix_len.checked_sub(96).ok_or_else(|| {
+                format!("`{value}` is an IPv4-mapped range with a prefix length below 96")
+            })?;
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+            negate,
+        })
+    }
+}
+
+impl TryFrom<String> for IpMatch {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// An RFC 3339 timestamp like `2024-06-01T22:00:00Z` or `2024-06-02T00:00:00+02:00` as used by
+/// the `active_from` and `active_until` fields of the rewrite rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Timestamp {
+    /// Point in time the timestamp refers to
+    pub time: SystemTime,
+}
+
+impl TryFrom<&str> for Timestamp {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let time = chrono::DateTime::parse_from_rfc3339(value.trim())
+            .map_err(|err| format!("`{value}` is not a valid RFC 3339 timestamp: {err}"))?;
+        Ok(Self { time: time.into() })
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+/// Query parameters that a request has to contain like `{v: "2", format: json}`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, serde_yaml::Value>")]
+pub struct QueryMatch {
+    /// Expected parameter values by parameter name
+    pub params: BTreeMap<String, String>,
+}
+
+impl TryFrom<BTreeMap<String, serde_yaml::Value>> for QueryMatch {
+    type Error = String;
+
+    fn try_from(value: BTreeMap<String, serde_yaml::Value>) -> Result<Self, Self::Error> {
+        let params = value
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    serde_yaml::Value::String(value) => value,
+                    serde_yaml::Value::Number(value) => value.to_string(),
+                    serde_yaml::Value::Bool(value) => value.to_string(),
+                    serde_yaml::Value::Null => String::new(),
+                    _ => return Err(format!("value of query parameter `{name}` isn't a string")),
+                };
+                Ok((name, value))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { params })
+    }
+}
+
+impl QueryMatch {
+    /// Checks whether the decoded query parameters contain all expected parameters. If `strict`
+    /// is `true`, parameters that aren't expected aren't allowed either.
+    pub fn matches<N, V>(&self, query: &[(N, V)], strict: bool) -> bool
+    where
+        N: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let expected = |name: &str, value: &str| self.params.get(name).is_some_and(|v| v == value);
+
+        self.params.iter().all(|(name, value)| {
+            query
+                .iter()
+                .any(|(n, v)| n.as_ref() == name && v.as_ref() == value)
+        }) && (!strict
+            || query
+                .iter()
+                .all(|(name, value)| expected(name.as_ref(), value.as_ref())))
+    }
+}
+
+fn deserialize_header_name<'de, D>(d: D) -> Result<Option<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(d)?;
+    let name = HeaderName::try_from(name.as_str())
+        .map_err(|err| D::Error::custom(format!("invalid header name {name}: {err}")))?;
+    Ok(Some(name))
+}
+
+fn deserialize_status<'de, D>(d: D) -> Result<Option<StatusCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let status = u16::deserialize(d)?;
+    StatusCode::from_u16(status)
+        .ok()
+        .filter(|status| !status.is_informational())
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("{status} is not a response status code")))
+}
+
+/// A rewrite rule resulting in either request URI change or redirect
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RewriteRule {
+    /// Path or a set of paths to rewrite
+    ///
+    /// By default, an exact path match is required. A value like `/path/*` indicates a prefix
+    /// match, both `/path/` and `/path/subdir/file.txt` will be matched.
+    ///
+    /// The pattern can contain additional wildcards like `/dir/*/subdir/*`. A `*` in the middle of
+    /// the pattern matches exactly one path segment, here `/dir/a/subdir/b/c` would be matched but
+    /// not `/dir/a/b/subdir/c`.
+    ///
+    /// When multiple rules potentially apply to a location, the closest matches will be evaluated
+    /// first. Rules with a longer path are considered closer matches than shorter paths. Exact
+    /// matches are considered closer matches than prefix matches for the same path. For paths
+    /// identical up to the first wildcard, patterns with more literal segments are considered
+    /// closer matches.
+    ///
+    /// A list of patterns like `[/old/*, /legacy/*]` makes the rule apply to each of them, with
+    /// the same precedence as separate rules for these patterns would have. If no patterns are
+    /// given, `/*` is used.
+    ///
+    /// Patterns starting with `!` exclude paths from the rule, e.g. `[/app/*, "!/app/internal/*"]`.
+    /// These work like `from_exclude` and cannot contain wildcards other than `/*` at the end.
+    /// If all patterns are negated, the rule applies to all other paths.
+    pub from: OneOrMany<PathPattern>,
+
+    /// Shell-like glob such as `/docs/**/*.md` that the entire path has to match, an alternative
+    /// to `from`. Supported are `?`, `*` (within a path segment), `**` (across path segments) and
+    /// character classes like `[abc]` or `[!abc]`.
+    ///
+    /// For precedence, the rule is treated like a prefix rule for the directory preceding the
+    /// first special character, e.g. `from: /docs/*` for the glob above. `${tail}` refers to the
+    /// path following this directory. Among these rules, globs with more literal path segments
+    /// are considered closer matches.
+    pub from_glob: Option<Glob>,
+
+    /// File extensions like `[png, jpg, webp]` the rule is restricted to. The last segment of
+    /// the path has to end with a dot followed by one of these, compared case-insensitively. Paths
+    /// ending with a slash never match.
+    pub extensions: OneOrMany<String>,
+
+    /// Paths excluded from the rule, with the same semantics as `from`: `/app/static/*` excludes
+    /// `/app/static` and everything within this directory, `/app/static` excludes only this path.
+    pub from_exclude: OneOrMany<PathMatcher>,
+
+    /// Host names the rule is restricted to, e.g. `example.com` or `*.example.com` (any subdomain
+    /// of `example.com`). Host names are compared case-insensitively, the port is ignored unless
+    /// given explicitly like `example.com:8080`. If empty, the rule applies to all hosts.
+    pub from_host: OneOrMany<String>,
+
+    /// HTTP methods like `GET` the rule is restricted to, compared case-insensitively. If empty,
+    /// the rule applies regardless of the request method.
+    pub methods: OneOrMany<RequestMethod>,
+
+    /// Request scheme the rule is restricted to, `http` or `https`. If unset, the rule applies
+    /// regardless of the scheme.
+    pub scheme: Option<RequestScheme>,
+
+    /// Client IP address ranges the rule is restricted to, e.g. `192.168.0.0/16` or `fd00::/8`.
+    /// Ranges prefixed with `!` exclude addresses. The rule applies if the client address is
+    /// within any of the ranges (if any are listed without `!`) and not within any of the
+    /// excluded ranges.
+    pub from_ip: OneOrMany<IpMatch>,
+
+    /// Local ports the rule is restricted to. The port is taken from the local address the
+    /// connection was accepted on. If empty, the rule applies regardless of the port.
+    pub listen_port: OneOrMany<u16>,
+
+    /// If set, the rule only applies starting with this point in time
+    pub active_from: Option<Timestamp>,
+
+    /// If set, the rule only applies before this point in time
+    pub active_until: Option<Timestamp>,
+
+    /// Names of request headers that have to be present for the rule to apply, e.g.
+    /// `X-Internal-Auth`. A header with an empty value counts as present.
+    pub has_header: OneOrMany<String>,
+
+    /// Names of request headers that have to be absent for the rule to apply
+    pub lacks_header: OneOrMany<String>,
+
+    /// Regular expressions that request headers have to match, keyed by header name, e.g.
+    /// `{X-Beta-User: "^1$"}`. Missing headers are matched as empty strings, so that `!.` applies
+    /// to requests without the header. Multiple values of a header are joined with `, ` before
+    /// matching.
+    pub header_regex: BTreeMap<String, RegexMatch>,
+
+    /// Regular expressions that cookies have to match, keyed by cookie name, e.g.
+    /// `{region: "^eu$"}`. Missing cookies are matched as empty strings, so that `!.` applies to
+    /// requests without the cookie. If a cookie is sent multiple times, the first value counts.
+    pub cookie_regex: BTreeMap<String, RegexMatch>,
+
+    /// Regular expression that the `User-Agent` request header has to match, e.g.
+    /// `bot|crawler|spider`. It is case-insensitive unless disabled via `(?-i)`. A missing header
+    /// is matched as an empty string. Prefixing the regular expression with `!` will negate its
+    /// effect.
+    pub ua_regex: Option<RegexMatch>,
+
+    /// Additional regular expression to further restrict matching paths, e.g. `\.png$` to match
+    /// only PNG files. Prefixing the regular expression with `!` will negate its effect, e.g.
+    /// `!\.png` will match all files but PNG files. A list of regular expressions is combined
+    /// according to `regex_mode`.
+    ///
+    /// Note that restricting the path as much as possible via `from` setting first is recommended
+    /// for reasons of performance.
+    pub from_regex: OneOrMany<RegexMatch>,
+
+    /// Restricts the rule depending on whether the URI has a query string: `any` (default),
+    /// `present` (a `?` is present, even if nothing follows it), `absent` (no `?` at all) or
+    /// `empty` (a `?` with nothing following it, as in `/path?`).
+    pub query: QueryPresence,
+
+    /// Additional regular expression to restrict matches to particular query strings only. For
+    /// example `file=` will only match queries containing a `file` parameter. Prefixing the
+    /// regular expression with `!` will negate its effect, e.g. `!file=` will match all queries
+    /// but those containing a `file` parameter. A list of regular expressions is combined
+    /// according to `regex_mode`.
+    pub query_regex: OneOrMany<RegexMatch>,
+
+    /// Determines how lists of regular expressions in `from_regex` and `query_regex` are
+    /// combined, either `all` (default, each of them has to match) or `any` (one match is
+    /// sufficient). A negated entry matches if its regular expression doesn’t. Either way both
+    /// `from_regex` and `query_regex` conditions have to be met.
+    pub regex_mode: RegexMode,
+
+    /// Regular expression applied to the path and query string as received, before any decoding,
+    /// e.g. `\.php\?(.*&)?preview=1(&|$)`. Prefixing the regular expression with `!` will negate
+    /// its effect. If `from_regex` or `query_regex` are present as well, all of them have to
+    /// match.
+    pub uri_regex: Option<RegexMatch>,
+
+    /// Query parameters that the request has to contain, e.g. `{v: "2", format: json}` will only
+    /// match queries like `v=2&format=json`. Parameter names and values are compared after
+    /// decoding, further parameters are allowed unless `query_match_strict` is `true`.
+    pub query_match: Option<QueryMatch>,
+
+    /// If `true`, `query_match` will only match queries without any additional parameters
+    pub query_match_strict: bool,
+
+    /// Regular expressions that query parameters have to match, keyed by parameter name, e.g.
+    /// `{file: "\\.png$"}`. Parameter names and values are decoded before matching. If a
+    /// parameter is repeated, any of its values matching is sufficient. Missing parameters don’t
+    /// match, unless the regular expression is negated with `!`.
+    pub query_params: BTreeMap<String, RegexMatch>,
+
+    /// Media type like `application/json` that the client has to accept for the rule to apply.
+    ///
+    /// The `Accept` request header is evaluated as defined in RFC 9110: `*/*` and wildcards like
+    /// `application/*` match as well, and the most specific media range determines the quality.
+    /// A media type listed with `q=0` is not acceptable. Requests without an `Accept` header
+    /// accept any media type.
+    pub accept: Option<MediaType>,
+
+    /// Language tags like `[de, fr, en-US]`, the rule only applies if the client prefers one of
+    /// these according to the `Accept-Language` request header. The selected language tag is
+    /// available as `${lang}` in `to`.
+    ///
+    /// Language ranges are matched as defined by RFC 4647 basic filtering: `de` also matches
+    /// `de-CH`, `*` matches any language. Languages listed with `q=0` are excluded. Requests
+    /// without an `Accept-Language` header don’t match.
+    pub accept_language: OneOrMany<String>,
+
+    /// New URI to be set on match
+    ///
+    /// The following variables will be resolved:
+    ///
+    /// * `${tail}`: Only valid when matching a path prefix. This will be replaced by the path part
+    ///   matched by `*`. For example, if `from` is `/dir/*`, `to` is `/another/${tail}` and the
+    ///   actual path matched is `/dir/file.txt`, then the URI will be rewritten into
+    ///   `/another/file.txt`.
+    /// * `${tail1}`, `${tail2}`, …: The path parts matched by the individual wildcards of `from`.
+    ///   For example, if `from` is `/dir/*/subdir/*` and the actual path matched is
+    ///   `/dir/a/subdir/b/c`, then `${tail1}` is `/a` and `${tail2}` is `/b/c`. `${tail}` is the
+    ///   same as the last of these values.
+    /// * `${query}`: This allows considering the original query which is removed by default. For
+    ///   example, if `from` is `/file.txt` and `to` is `/file.html?${query}` then a request to
+    ///   `/file.txt?a=b` will be rewritten into `/file.html?a=b`.
+    /// * `${query_<name>}`: The decoded value of the first query parameter with the given name.
+    /// * `${cookie_<name>}`: The value of the first cookie with the given name.
+    /// * `${path}`, `${uri}`: The current request path, without and with the query string.
+    /// * `${original_path}`, `${original_query}`, `${original_uri}`: The request path, query
+    ///   string and both combined as received, before any decoding, normalization or rewrites.
+    /// * `${host}`, `${scheme}`, `${port}`: The request host without the port, the scheme
+    ///   (`http` or `https`) and the port the connection was accepted on.
+    /// * `${method}`: The request method in uppercase, e.g. `GET`.
+    /// * `${time_unix}`, `${time_msec}`, `${time_iso8601}`: The request time as seconds or
+    ///   milliseconds since the Unix epoch or as RFC 3339 timestamp in UTC.
+    /// * `${remote_addr}`, `${remote_addr_uri}`: The client address without the port, the latter
+    ///   with IPv6 addresses in brackets for use in URLs.
+    /// * `${http_<header>}`: This allows inserting arbitrary HTTP headers into the redirect
+    ///   target.
+    ///
+    /// Filters can be applied to variable values: `${http_x_original_url|base64decode}` will
+    /// decode the base64-encoded header value. Supported filters are `base64decode`,
+    /// `base64encode`, `base64urldecode`, `base64urlencode`, `urlencode`, `urldecode`,
+    /// `lowercase`, `uppercase` and `trim`, multiple filters are applied from left to right.
+    /// Unknown filters are a configuration error. If a filter fails, the variable will be left
+    /// unresolved.
+    ///
+    /// If unset, the new URI is `/` unless another setting like `redirect_to_host` determines it.
+    pub to: Option<VariableInterpolation>,
+
+    /// Query string of the new URI, e.g. `page=${tail}&lang=${lang}`. If set, `to` is the path
+    /// only: any `?` or `#` characters resulting from variables are percent-encoded there, and
+    /// `to` itself cannot contain a `?` character. Variable values other than `${query}` are
+    /// percent-encoded as necessary within `to_query`, including `&`, `=` and `+`. An empty
+    /// result produces a URI without a query string.
+    pub to_query: Option<VariableInterpolation>,
+
+    /// New host like `backend.example.com` or `${http_x_backend}:8080`, variables are resolved
+    /// like in `to`. For internal rewrites, this replaces the `Host` header and the authority of
+    /// the request URI. Redirects go to an absolute URL with this host instead, using the scheme
+    /// of the request. Rules producing an invalid host, e.g. containing slashes or whitespace,
+    /// aren’t applied.
+    pub to_host: Option<VariableInterpolation>,
+
+    /// If `true`, redirect targets starting with `//` like `//cdn.example.com/file` are allowed.
+    /// Browsers resolve these protocol-relative URLs to another host, so by default the rule
+    /// isn’t applied if its target starts with `//` or `/\`, e.g. because of a variable value.
+    pub protocol_relative: bool,
+
+    /// If `true`, the path prefix matched by `from` is removed, the rest of the path is used as
+    /// the new path along with the original query string. For example, `from: /service/*`
+    /// rewrites `/service/api/users?id=1` into `/api/users?id=1` and `/service` into `/`. Unlike
+    /// `to: ${tail}` the path is taken as received where possible, without any decoding or
+    /// normalization.
+    ///
+    /// This setting cannot be combined with `to`, `to_query`, `subst` or `redirect_to_host`, and
+    /// `from` patterns cannot contain wildcards other than `/*` at the end.
+    pub strip_prefix: bool,
+
+    /// If `true`, the original query string is appended to the new URI, using `?` or `&`
+    /// depending on whether the new URI already has a query string. Nothing is appended for
+    /// requests without a query string. This is the same as the `QSA` flag and cannot be
+    /// combined with a `to` value using `${query}`.
+    pub append_query: bool,
+
+    /// Names of query parameters to be removed from the original query string like
+    /// `[fbclid, utm_*]`. Names are matched exactly and case-sensitively, a `*` at the end
+    /// matches any suffix.
+    ///
+    /// `query_remove`, `query_set` and `query_add` modify the original query string before it is
+    /// used for the new URI: `${query}` resolves to the modified query string. If `to` doesn’t
+    /// use `${query}`, the modified query string is appended like with `append_query`.
+    pub query_remove: OneOrMany<String>,
+
+    /// Query parameters to be set like `{lang: en}`, replacing the first parameter with the same
+    /// name and removing any further ones. Parameters not present in the original query string
+    /// are appended. Values can contain the same variables as `to` and will be percent-encoded.
+    pub query_set: BTreeMap<String, VariableInterpolation>,
+
+    /// Query parameters to be appended to the query string like `{source: legacy}`, regardless
+    /// of whether parameters with the same name are present. Values can contain the same
+    /// variables as `to` and will be percent-encoded.
+    pub query_add: BTreeMap<String, VariableInterpolation>,
+
+    /// Host name like `www.example.com` to redirect to, preserving the path and query string of
+    /// the request. The redirect target is always an `https://` URI, e.g. a request to
+    /// `/foo?bar` will be redirected to `https://www.example.com/foo?bar`.
+    ///
+    /// This setting cannot be combined with `to`. It makes the rule a redirect: `type: internal`
+    /// (the default) results in a temporary redirect, `type: permanent` in a permanent one.
+    pub redirect_to_host: Option<String>,
+
+    /// Substitution like `s/^\/old\/(.*)\.htm$/\/new\/$1.html/` to be applied to the request
+    /// path, producing the new URI. The delimiter can be any character following `s`, e.g.
+    /// `s|^/old/|/new/|`. Supported flags are `g` (replace all matches) and `i` (case-insensitive).
+    ///
+    /// The rule only applies if the regular expression matches the path, in addition to `from`
+    /// and any other conditions. This setting cannot be combined with `to` or `redirect_to_host`.
+    pub subst: Option<Substitution>,
+
+    /// HTML page to be sent as response body for redirects instead of the standard page or
+    /// `rewrite_redirect_body`. The `${location}` or `${target}` variable will be replaced by the
+    /// HTML-escaped redirect target, e.g. `<a href="${location}">Moved here</a>`. An empty value
+    /// results in an empty response body.
+    ///
+    /// For `response` rules this is the response body, the same variables as in `to` can be
+    /// used here. The setting is ignored for other rewrite types.
+    pub body: Option<VariableInterpolation>,
+
+    /// Base64-encoded response body for `response` rules, allowing binary content. This setting
+    /// cannot be combined with `body`.
+    pub body_base64: Option<String>,
+
+    /// Value of the `Content-Type` header for `response` rules, `text/plain; charset=utf-8` by
+    /// default
+    pub content_type: Option<String>,
+
+    /// Additional headers to be sent with the response of redirects and other rewrite types
+    /// producing a response, e.g. `{Cache-Control: no-store}`. A list of values results in
+    /// multiple headers with the same name. Values can contain the same variables as `to`. These
+    /// headers replace standard headers like `Content-Type` of the same name. The setting is
+    /// ignored for internal rewrites.
+    pub headers: BTreeMap<String, OneOrMany<VariableInterpolation>>,
+
+    /// Rewriting type, one of `internal` (default), `redirect`, `permanent`, `gone`, `forbidden`,
+    /// `response`, `add_slash` or `remove_slash`. `gone` and `forbidden` respond with 410 Gone
+    /// and 403 Forbidden respectively, `response` sends a fixed response configured via
+    /// `status`, `content_type` and `body`. `add_slash` and `remove_slash` redirect to the
+    /// request URI with a trailing slash added to or removed from the path, they don’t apply if
+    /// the path already has the desired form or is `/`. `to` is ignored for these.
+    pub r#type: RewriteType,
+
+    /// If `true`, `add_slash` and `remove_slash` rules don’t apply to paths looking like files,
+    /// meaning that the last path segment contains a dot like `/style.css`.
+    pub skip_files: bool,
+
+    /// Status code of the response like `301`, overriding the default status code of `redirect`
+    /// and `permanent` (only `3xx` status codes are accepted here) as well as `response` rules
+    /// (200 by default). The setting cannot be used with other rewrite types.
+    #[pandora(deserialize_with = "deserialize_status")]
+    pub status: Option<StatusCode>,
+
+    /// Apache mod_rewrite flags like `[R=301,L]` for easier migration of existing configurations
+    ///
+    /// The following flags are supported:
+    ///
+    /// * `R` or `R=302`: Same as `type: redirect` with `status: 302`
+    /// * `R=301`: Same as `type: permanent` with `status: 301`
+    /// * `R=307`: Same as `type: redirect`
+    /// * `R=308`: Same as `type: permanent`
+    /// * `L`: Stop processing after this rule, overriding `continue`.
+    /// * `NC`: Makes `from_regex`, `query_regex` and `uri_regex` case-insensitive, see also
+    ///   `case_insensitive`.
+    /// * `QSA`: Appends the original query string to the new URI, using `?` or `&` depending on
+    ///   whether the new URI already has a query string.
+    ///
+    /// Other flags are rejected. If `status` is set, it takes precedence over the status code of
+    /// the `R` flag.
+    pub flags: Option<RewriteFlags>,
+
+    /// Name of a request header like `X-Original-URI` to receive the path and query string of the
+    /// request before the rewrite. This only applies to `internal` rewrites, allowing further
+    /// processors like an upstream server to see the original request URI.
+    #[pandora(deserialize_with = "deserialize_header_name")]
+    pub preserve_original_in: Option<HeaderName>,
+
+    /// If `true`, `Location` headers of the responses to `internal` rewrites are mapped back into
+    /// the public URL space. If the upstream responds with a `Location` starting with the path
+    /// prefix of `to` (the text preceding the first variable, or the directory if `to` contains no
+    /// variables), this prefix is replaced by the path prefix matched by `from`. For example,
+    /// with `from: /app/*` and `to: /${tail}` the `Location: /login` header becomes
+    /// `Location: /app/login`. Absolute URLs pointing to the host the request was sent to (e.g.
+    /// via `to_host`) are changed to point to the host of the original request. Relative
+    /// references like `login` are left unchanged.
+    ///
+    /// This setting is only valid for `internal` rewrites and cannot be combined with `subst`.
+    pub rewrite_response_location: bool,
+
+    /// If `true`, `Path` attributes of `Set-Cookie` headers in the responses to `internal`
+    /// rewrites are mapped back into the public URL space, the same way as with
+    /// `rewrite_response_location`. For example, with `from: /app/*` and `to: /${tail}` the
+    /// cookie attribute `Path=/` becomes `Path=/app`. Attribute names are matched
+    /// case-insensitively, cookies with paths outside the prefix of `to` are left unchanged.
+    ///
+    /// This setting is only valid for `internal` rewrites and cannot be combined with `subst`.
+    pub rewrite_cookie_path: bool,
+
+    /// If `true`, `Set-Cookie` headers without a `Path` attribute get one with the public path
+    /// prefix, so that the cookie doesn’t default to the directory of the rewritten path.
+    /// Requires `rewrite_cookie_path`.
+    pub add_cookie_path: bool,
+
+    /// If `true`, the rules are evaluated again for the new URI after an `internal` rewrite,
+    /// allowing chains like `/a` → `/b` → `/c`. By default processing stops after the first rule
+    /// applied. This setting has no effect for redirects.
+    pub r#continue: bool,
+
+    /// Evaluation order among rules applying to the same path with equally specific patterns:
+    /// rules with higher priority are evaluated first, rules with the same priority in
+    /// configuration order. The default priority is 0, negative values are allowed.
+    pub priority: i32,
+
+    /// If `true`, `from`, `from_glob` and `from_exclude` paths are matched ignoring ASCII case,
+    /// and `from_regex`, `query_regex` and `uri_regex` are case-insensitive. Variables like
+    /// `${tail}` keep the casing of the request path.
+    pub case_insensitive: bool,
+
+    /// Trailing slash handling for exact `from` patterns, one of `tolerant` (default, `/about`
+    /// and `/about/` are equivalent) or `strict` (request path has to end with a slash if and
+    /// only if `from` does)
+    pub trailing_slash: TrailingSlash,
+
+    /// If `true`, exact `from` patterns like `/pricing` match both `/pricing` and `/pricing/`
+    /// with `${tail}` being empty for either. Redirects to the URI of the request itself are
+    /// skipped for this rule, so that it cannot produce a redirect loop. Prefix patterns like
+    /// `/pricing/*` are unaffected. This setting cannot be combined with `trailing_slash: strict`.
+    pub ignore_trailing_slash: bool,
+
+    /// If `true`, variables like `${tail}` are percent-decoded before being inserted into `to`,
+    /// so that e.g. `%2F` becomes a path-structural slash. By default the encoding of the request
+    /// path is preserved.
+    pub decode_tail: bool,
+
+    /// If `true`, `from_regex` is matched against the request path as received and variables
+    /// like `${tail}` are taken from it, unaffected by `rewrite_decode_path` and
+    /// `rewrite_normalize_path`. Other conditions still use the decoded and normalized path.
+    pub raw_path: bool,
+
+    /// Log verbosity for this rule, one of `off` (default, no log messages), `match` (log when
+    /// the rule is applied) or `always` (log whenever the rule is evaluated)
+    pub log: RuleLog,
+
+    /// Optional name identifying the rule in rule metrics, the debug header and log messages (see
+    /// `rewrite_metrics` and `rewrite_debug_header`). Names cannot be empty or contain commas, and
+    /// each name can only be used by one rule.
+    pub name: Option<String>,
+
+    /// If `false`, the rule is ignored as if it weren’t listed in the configuration. Rule indexes
+    /// in metrics and the debug header don’t change however.
+    pub enabled: bool,
+}
+
+impl RewriteRule {
+    /// Returns a builder to set up a rule in code rather than deserializing it from YAML.
+    ///
+    /// ```rust
+    /// use rewrite_module::configuration::{RewriteRule, RewriteType};
+    ///
+    /// let rule = RewriteRule::builder()
+    ///     .from("/old/*")
+    ///     .from_regex("!\\.png$")?
+    ///     .to("/new${tail}")
+    ///     .r#type(RewriteType::Permanent)
+    ///     .build();
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn builder() -> RewriteRuleBuilder {
+        RewriteRuleBuilder {
+            rule: Self::default(),
+        }
+    }
+
+    /// Path patterns of the rule without the negated ones, `/*` if none are configured
+    pub(crate) fn path_patterns(&self) -> Vec<PathPattern> {
+        let patterns = self
+            .from
+            .iter()
+            .filter(|from| !from.negate)
+            .cloned()
+            .collect::<Vec<_>>();
+        if patterns.is_empty() {
+            vec!["/*".into()]
+        } else {
+            patterns
+        }
+    }
+
+    /// Checks whether the rule looks up any of the maps with `required` enabled, so that it
+    /// doesn’t apply for missing keys. Unknown maps are considered required.
+    pub(crate) fn uses_required_map(&self, maps: &BTreeMap<String, RewriteMapConf>) -> bool {
+        self.to
+            .iter()
+            .chain(&self.to_query)
+            .chain(&self.to_host)
+            .chain(self.headers.values().flat_map(|values| values.iter()))
+            .chain(self.query_set.values())
+            .chain(self.query_add.values())
+            .chain(&self.body)
+            .flat_map(VariableInterpolation::maps)
+            .any(|name| maps.get(name).map_or(true, |map| map.required))
+    }
+}
+
+impl Default for RewriteRule {
+    fn default() -> Self {
+        Self {
+            from: OneOrMany::default(),
+            from_glob: None,
+            extensions: OneOrMany::default(),
+            from_exclude: OneOrMany::default(),
+            from_host: OneOrMany::default(),
+            methods: OneOrMany::default(),
+            scheme: None,
+            from_ip: OneOrMany::default(),
+            listen_port: OneOrMany::default(),
+            active_from: None,
+            active_until: None,
+            has_header: OneOrMany::default(),
+            lacks_header: OneOrMany::default(),
+            header_regex: BTreeMap::new(),
+            cookie_regex: BTreeMap::new(),
+            ua_regex: None,
+            from_regex: OneOrMany::default(),
+            uri_regex: None,
+            query: QueryPresence::Any,
+            query_regex: OneOrMany::default(),
+            regex_mode: RegexMode::All,
+            query_match: None,
+            query_match_strict: false,
+            query_params: BTreeMap::new(),
+            accept: None,
+            accept_language: OneOrMany::default(),
+            to: None,
+            to_query: None,
+            to_host: None,
+            protocol_relative: false,
+            strip_prefix: false,
+            append_query: false,
+            query_remove: OneOrMany::default(),
+            query_set: BTreeMap::new(),
+            query_add: BTreeMap::new(),
+            redirect_to_host: None,
+            subst: None,
+            body: None,
+            body_base64: None,
+            content_type: None,
+            headers: BTreeMap::new(),
+            r#type: RewriteType::Internal,
+            skip_files: false,
+            status: None,
+            flags: None,
+            preserve_original_in: None,
+            rewrite_response_location: false,
+            rewrite_cookie_path: false,
+            add_cookie_path: false,
+            r#continue: false,
+            priority: 0,
+            case_insensitive: false,
+            trailing_slash: TrailingSlash::Tolerant,
+            ignore_trailing_slash: false,
+            decode_tail: false,
+            raw_path: false,
+            log: RuleLog::Off,
+            name: None,
+            enabled: true,
+        }
+    }
+}
+
+/// The builder used to set up a [`RewriteRule`] instance, see [`RewriteRule::builder`]
+///
+/// Methods adding to list settings like `from` or `from_regex` can be called repeatedly. Settings
+/// without a builder method can be changed on the rule returned by
+/// [`RewriteRuleBuilder::build`], all of its fields are public.
+#[derive(Debug, Clone)]
+pub struct RewriteRuleBuilder {
+    rule: RewriteRule,
+}
+
+impl RewriteRuleBuilder {
+    /// Adds a path pattern to `from` like `/dir/*`, see [`RewriteRule::from`]
+    pub fn from(mut self, pattern: impl Into<PathPattern>) -> Self {
+        self.rule.from.push(pattern.into());
+        self
+    }
+
+    /// Adds a host name to `from_host`, see [`RewriteRule::from_host`]
+    pub fn from_host(mut self, host: impl Into<String>) -> Self {
+        self.rule.from_host.push(host.into());
+        self
+    }
+
+    /// Adds a request method to `methods`, see [`RewriteRule::methods`]
+    pub fn method(mut self, method: Method) -> Self {
+        self.rule.methods.push(RequestMethod { method });
+        self
+    }
+
+    /// Sets `scheme`, see [`RewriteRule::scheme`]
+    pub fn scheme(mut self, scheme: RequestScheme) -> Self {
+        self.rule.scheme = Some(scheme);
+        self
+    }
+
+    /// Adds a header name to `has_header`, see [`RewriteRule::has_header`]
+    pub fn has_header(mut self, name: impl Into<String>) -> Self {
+        self.rule.has_header.push(name.into());
+        self
+    }
+
+    /// Adds a header name to `lacks_header`, see [`RewriteRule::lacks_header`]
+    pub fn lacks_header(mut self, name: impl Into<String>) -> Self {
+        self.rule.lacks_header.push(name.into());
+        self
+    }
+
+    /// Adds a regular expression for a header to `header_regex`, a leading `!` negates it. See
+    /// [`RewriteRule::header_regex`].
+    pub fn header_regex(
+        mut self,
+        name: impl Into<String>,
+        regex: &str,
+    ) -> Result<Self, RegexError> {
+        self.rule
+            .header_regex
+            .insert(name.into(), regex.try_into()?);
+        Ok(self)
+    }
+
+    /// Adds a regular expression to `from_regex`, a leading `!` negates it. See
+    /// [`RewriteRule::from_regex`].
+    pub fn from_regex(mut self, regex: &str) -> Result<Self, RegexError> {
+        self.rule.from_regex.push(regex.try_into()?);
+        Ok(self)
+    }
+
+    /// Sets `query`, see [`RewriteRule::query`]
+    pub fn query(mut self, query: QueryPresence) -> Self {
+        self.rule.query = query;
+        self
+    }
+
+    /// Adds a regular expression to `query_regex`, a leading `!` negates it. See
+    /// [`RewriteRule::query_regex`].
+    pub fn query_regex(mut self, regex: &str) -> Result<Self, RegexError> {
+        self.rule.query_regex.push(regex.try_into()?);
+        Ok(self)
+    }
+
+    /// Sets `regex_mode`, see [`RewriteRule::regex_mode`]
+    pub fn regex_mode(mut self, regex_mode: RegexMode) -> Self {
+        self.rule.regex_mode = regex_mode;
+        self
+    }
+
+    /// Sets `uri_regex`, a leading `!` negates the regular expression. See
+    /// [`RewriteRule::uri_regex`].
+    pub fn uri_regex(mut self, regex: &str) -> Result<Self, RegexError> {
+        self.rule.uri_regex = Some(regex.try_into()?);
+        Ok(self)
+    }
+
+    /// Sets `to`, see [`RewriteRule::to`]
+    pub fn to(mut self, to: impl Into<VariableInterpolation>) -> Self {
+        self.rule.to = Some(to.into());
+        self
+    }
+
+    /// Sets `to_query`, see [`RewriteRule::to_query`]
+    pub fn to_query(mut self, to_query: impl Into<VariableInterpolation>) -> Self {
+        self.rule.to_query = Some(to_query.into());
+        self
+    }
+
+    /// Sets `to_host`, see [`RewriteRule::to_host`]
+    pub fn to_host(mut self, to_host: impl Into<VariableInterpolation>) -> Self {
+        self.rule.to_host = Some(to_host.into());
+        self
+    }
+
+    /// Sets `strip_prefix`, see [`RewriteRule::strip_prefix`]
+    pub fn strip_prefix(mut self, strip_prefix: bool) -> Self {
+        self.rule.strip_prefix = strip_prefix;
+        self
+    }
+
+    /// Sets `append_query`, see [`RewriteRule::append_query`]
+    pub fn append_query(mut self, append_query: bool) -> Self {
+        self.rule.append_query = append_query;
+        self
+    }
+
+    /// Adds a parameter name to `query_remove`, see [`RewriteRule::query_remove`]
+    pub fn query_remove(mut self, name: impl Into<String>) -> Self {
+        self.rule.query_remove.push(name.into());
+        self
+    }
+
+    /// Adds a parameter to `query_set`, see [`RewriteRule::query_set`]
+    pub fn query_set(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<VariableInterpolation>,
+    ) -> Self {
+        self.rule.query_set.insert(name.into(), value.into());
+        self
+    }
+
+    /// Adds a parameter to `query_add`, see [`RewriteRule::query_add`]
+    pub fn query_add(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<VariableInterpolation>,
+    ) -> Self {
+        self.rule.query_add.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets `type`, see [`RewriteType`]
+    pub fn r#type(mut self, r#type: RewriteType) -> Self {
+        self.rule.r#type = r#type;
+        self
+    }
+
+    /// Sets `status`, see [`RewriteRule::status`]
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.rule.status = Some(status);
+        self
+    }
+
+    /// Sets `body`, see [`RewriteRule::body`]
+    pub fn body(mut self, body: impl Into<VariableInterpolation>) -> Self {
+        self.rule.body = Some(body.into());
+        self
+    }
+
+    /// Sets `content_type`, see [`RewriteRule::content_type`]
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.rule.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Adds a response header to `headers`, calling this repeatedly with the same name adds
+    /// multiple values. See [`RewriteRule::headers`].
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<VariableInterpolation>,
+    ) -> Self {
+        self.rule
+            .headers
+            .entry(name.into())
+            .or_default()
+            .push(value.into());
+        self
+    }
+
+    /// Sets `continue` to evaluate the rules again after an internal rewrite
+    pub fn r#continue(mut self, r#continue: bool) -> Self {
+        self.rule.r#continue = r#continue;
+        self
+    }
+
+    /// Sets `priority`, see [`RewriteRule::priority`]
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.rule.priority = priority;
+        self
+    }
+
+    /// Sets `case_insensitive`, see [`RewriteRule::case_insensitive`]
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.rule.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Sets `name`, see [`RewriteRule::name`]
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.rule.name = Some(name.into());
+        self
+    }
+
+    /// Sets `enabled`, see [`RewriteRule::enabled`]
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.rule.enabled = enabled;
+        self
+    }
+
+    /// Returns the rule set up
+    pub fn build(self) -> RewriteRule {
+        self.rule
+    }
+}
+
+/// A group of rewrite rules sharing common conditions
+///
+/// The conditions of the group are added to those of each member rule when the configuration is
+/// loaded. A member rule cannot set a condition that is already set by its group, with the
+/// exception of `header_regex`, `cookie_regex` and `query_params` where the entries are combined
+/// as long as the names differ.
+#[derive(Debug, Default, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RuleGroup {
+    /// Host names the rules are restricted to, see [`RewriteRule::from_host`]
+    pub from_host: OneOrMany<String>,
+
+    /// HTTP methods the rules are restricted to, see [`RewriteRule::methods`]
+    pub methods: OneOrMany<RequestMethod>,
+
+    /// Request scheme the rules are restricted to, see [`RewriteRule::scheme`]
+    pub scheme: Option<RequestScheme>,
+
+    /// Client IP address ranges the rules are restricted to, see [`RewriteRule::from_ip`]
+    pub from_ip: OneOrMany<IpMatch>,
+
+    /// Local ports the rules are restricted to, see [`RewriteRule::listen_port`]
+    pub listen_port: OneOrMany<u16>,
+
+    /// If set, the rules only apply starting with this point in time
+    pub active_from: Option<Timestamp>,
+
+    /// If set, the rules only apply before this point in time
+    pub active_until: Option<Timestamp>,
+
+    /// Regular expressions that request headers have to match, see
+    /// [`RewriteRule::header_regex`]
+    pub header_regex: BTreeMap<String, RegexMatch>,
+
+    /// Regular expressions that cookies have to match, see [`RewriteRule::cookie_regex`]
+    pub cookie_regex: BTreeMap<String, RegexMatch>,
+
+    /// Regular expression that the `User-Agent` request header has to match, see
+    /// [`RewriteRule::ua_regex`]
+    pub ua_regex: Option<RegexMatch>,
+
+    /// Regular expressions that query parameters have to match, see
+    /// [`RewriteRule::query_params`]
+    pub query_params: BTreeMap<String, RegexMatch>,
+
+    /// The rules belonging to the group
+    pub rewrite_rules: OneOrMany<RewriteRule>,
+}
+
+impl RuleGroup {
+    /// Adds the conditions of the group to a member rule. Returns an error message if the rule
+    /// sets a condition that is already set by the group.
+    pub(crate) fn apply_to(&self, mut rule: RewriteRule) -> Result<RewriteRule, String> {
+        fn inherit<T: Clone>(
+            name: &str,
+            group: &T,
+            rule: &mut T,
+            is_set: impl Fn(&T) -> bool,
+        ) -> Result<(), String> {
+            if is_set(group) {
+                if is_set(rule) {
+                    return Err(format!(
+                        "rule sets `{name}` which is already set by its group"
+                    ));
+                }
+                *rule = group.clone();
+            }
+            Ok(())
+        }
+
+        fn combine(
+            name: &str,
+            group: &BTreeMap<String, RegexMatch>,
+            rule: &mut BTreeMap<String, RegexMatch>,
+        ) -> Result<(), String> {
+            for (key, regex) in group {
+                if rule.insert(key.clone(), regex.clone()).is_some() {
+                    return Err(format!(
+                        "rule sets `{name}` for {key:?} which is already set by its group"
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        inherit("from_host", &self.from_host, &mut rule.from_host, |v| {
+            !v.is_empty()
+        })?;
+        inherit("methods", &self.methods, &mut rule.methods, |v| {
+            !v.is_empty()
+        })?;
+        inherit("scheme", &self.scheme, &mut rule.scheme, Option::is_some)?;
+        inherit("from_ip", &self.from_ip, &mut rule.from_ip, |v| {
+            !v.is_empty()
+        })?;
+        inherit(
+            "listen_port",
+            &self.listen_port,
+            &mut rule.listen_port,
+            |v| !v.is_empty(),
+        )?;
+        inherit(
+            "active_from",
+            &self.active_from,
+            &mut rule.active_from,
+            Option::is_some,
+        )?;
+        inherit(
+            "active_until",
+            &self.active_until,
+            &mut rule.active_until,
+            Option::is_some,
+        )?;
+        inherit(
+            "ua_regex",
+            &self.ua_regex,
+            &mut rule.ua_regex,
+            Option::is_some,
+        )?;
+        combine("header_regex", &self.header_regex, &mut rule.header_regex)?;
+        combine("cookie_regex", &self.cookie_regex, &mut rule.cookie_regex)?;
+        combine("query_params", &self.query_params, &mut rule.query_params)?;
+        Ok(rule)
+    }
+}
+
+/// Format of a rewrite map file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MapFormat {
+    /// One `key value` pair per line, separated by whitespace. Empty lines and lines starting
+    /// with `#` are ignored.
+    #[default]
+    Text,
+    /// A YAML mapping with scalar keys and values
+    Yaml,
+}
+
+/// A named map that rewrite rules can look up values in, see [`RewriteConf::rewrite_maps`]
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RewriteMapConf {
+    /// Path of the file containing the map entries
+    pub file: PathBuf,
+
+    /// Format of the map file, `text` (default) or `yaml`
+    pub format: MapFormat,
+
+    /// If `true` (default), rules looking up a key missing in the map don’t apply. Otherwise the
+    /// lookup produces an empty string.
+    pub required: bool,
+}
+
+impl Default for RewriteMapConf {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::new(),
+            format: MapFormat::default(),
+            required: true,
+        }
+    }
+}
+
+/// Configuration file settings of the rewrite module
+#[derive(Debug, Clone, PartialEq, Eq, DeserializeMap)]
+pub struct RewriteConf {
+    /// A list of rewrite rules
+    pub rewrite_rules: OneOrMany<RewriteRule>,
+
+    /// Groups of rewrite rules sharing common conditions. The rules of the groups are numbered
+    /// following those of `rewrite_rules`, in the order the groups are listed.
+    pub rule_groups: OneOrMany<RuleGroup>,
+
+    /// If `true`, count how often each rewrite rule is applied
+    pub rewrite_metrics: bool,
+
+    /// If `true`, percent-decode the request path before matching it against `from` and
+    /// `from_regex`. Encoded percent signs (`%25`) and sequences that don’t decode to valid UTF-8
+    /// stay encoded, encoded slashes (`%2F`) are handled according to `rewrite_encoded_slash`.
+    pub rewrite_decode_path: bool,
+
+    /// If `true`, normalize the request path before matching: duplicate slashes are collapsed,
+    /// `.` and `..` segments are resolved. `..` segments never go beyond the root directory. This
+    /// happens after percent-decoding if `rewrite_decode_path` is enabled.
+    pub rewrite_normalize_path: bool,
+
+    /// Handling of encoded slashes (`%2F`) if `rewrite_decode_path` is enabled, either `keep`
+    /// (default, these stay encoded) or `reject` (such requests are rejected)
+    pub rewrite_encoded_slash: EncodedSlash,
+
+    /// Address ranges of trusted reverse proxies like `10.0.0.0/8`. For requests from these
+    /// addresses, the `X-Forwarded-Proto` request header takes precedence when determining the
+    /// request scheme and the `X-Forwarded-Host` header when determining the host. The client
+    /// address is the right-most entry of `X-Forwarded-For` that doesn’t belong to a trusted
+    /// proxy.
+    pub rewrite_trusted_proxies: OneOrMany<IpMatch>,
+
+    /// Maximal number of internal rewrites applied to a request, relevant for rules with
+    /// `continue` enabled. The default is 10.
+    pub rewrite_max_internal: usize,
+
+    /// Handling of requests where `rewrite_max_internal` is exceeded, either `serve` (default,
+    /// the URI produced by the last rewrite is used) or `error` (the request is rejected)
+    pub rewrite_limit_exceeded: LimitExceeded,
+
+    /// HTML page sent as response body with redirects of all rules that don’t set `body`. The
+    /// `${location}` variable will be replaced by the HTML-escaped redirect target.
+    pub rewrite_redirect_body: Option<VariableInterpolation>,
+
+    /// If `true`, responses to requests where rewrite rules have been applied get a header
+    /// listing these rules in the order of application, identified by their `name` or index.
+    /// For internal rewrites the header is added to the response eventually produced, e.g. by the
+    /// upstream server. This is meant for debugging and shouldn’t be enabled in production.
+    pub rewrite_debug_header: bool,
+
+    /// Name of the header added with `rewrite_debug_header`, `X-Applied-Rewrite` by default
+    #[pandora(deserialize_with = "deserialize_header_name")]
+    pub rewrite_debug_header_name: Option<HeaderName>,
+
+    /// If `true`, variables that can never be resolved for a rule prevent the configuration from
+    /// loading, e.g. a misspelled variable name or `${tail}` with a `from` pattern without
+    /// wildcards. Otherwise these only produce a warning.
+    pub rewrite_strict_variables: bool,
+
+    /// Named maps loaded from files at startup. Rules can look up values in these via variables
+    /// like `${map_articles:${1}}`, where `articles` is the map name and the part following the
+    /// colon is the key. The key can contain other variables.
+    pub rewrite_maps: BTreeMap<String, RewriteMapConf>,
+
+    /// Delimiters marking variables in the `to` field of the rewrite rules
+    pub variable_delimiters: VariableDelimiters,
+}
+
+impl Default for RewriteConf {
+    fn default() -> Self {
+        Self {
+            rewrite_rules: OneOrMany::default(),
+            rule_groups: OneOrMany::default(),
+            rewrite_metrics: false,
+            rewrite_decode_path: false,
+            rewrite_normalize_path: false,
+            rewrite_encoded_slash: EncodedSlash::default(),
+            rewrite_trusted_proxies: OneOrMany::default(),
+            rewrite_max_internal: 10,
+            rewrite_limit_exceeded: LimitExceeded::default(),
+            rewrite_redirect_body: None,
+            rewrite_debug_header: false,
+            rewrite_debug_header_name: None,
+            rewrite_strict_variables: false,
+            rewrite_maps: BTreeMap::new(),
+            variable_delimiters: VariableDelimiters::default(),
+        }
+    }
+}
+
+/// A potential problem with the rewrite rules, as reported by [`RewriteConf::lint`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleWarning {
+    /// Index of the affected rule in the `rewrite_rules` list
+    pub index: usize,
+    /// Description of the problem
+    pub reason: String,
+}
+
+/// A configuration error as produced by [`RewriteConf::from_yaml_str`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationError {
+    /// Index of the rewrite rule in the `rewrite_rules` list if the error concerns a rule
+    pub rule: Option<usize>,
+    /// Line in the YAML source where the error was detected, if known (starting with 1)
+    pub line: Option<usize>,
+    /// Column in the YAML source where the error was detected, if known (starting with 1)
+    pub column: Option<usize>,
+    /// Description of the error
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(rule) = self.rule {
+            write!(f, "rewrite rule {rule}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConfigurationError {}
+
+impl FromIterator<RewriteRule> for RewriteConf {
+    /// Creates a configuration with the given rules and default settings otherwise
+    fn from_iter<T: IntoIterator<Item = RewriteRule>>(iter: T) -> Self {
+        let mut conf = Self::default();
+        conf.extend(iter);
+        conf
+    }
+}
+
+impl Extend<RewriteRule> for RewriteConf {
+    /// Adds rules to the end of `rewrite_rules`
+    fn extend<T: IntoIterator<Item = RewriteRule>>(&mut self, iter: T) {
+        self.rewrite_rules.extend(iter);
+    }
+}
+
+impl RewriteConf {
+    /// Loads configuration from a YAML string.
+    ///
+    /// Unlike `FromYaml::from_yaml()` this produces errors that indicate which rewrite rule is
+    /// invalid and the location of the problem within the YAML source.
+    pub fn from_yaml_str(yaml_conf: &str) -> Result<Self, ConfigurationError> {
+        let err = match Self::default().deserialize(serde_yaml::Deserializer::from_str(yaml_conf)) {
+            Ok(conf) => return Ok(conf),
+            Err(err) => err,
+        };
+
+        // Deserialize rules individually to find out which one is responsible for the error.
+        let rule = serde_yaml::from_str::<serde_yaml::Value>(yaml_conf)
+            .ok()
+            .and_then(|value| match value.get("rewrite_rules")? {
+                serde_yaml::Value::Sequence(rules) => rules
+                    .iter()
+                    .filter(|rule| !rule.is_null())
+                    .position(|rule| serde_yaml::from_value::<RewriteRule>(rule.clone()).is_err()),
+                rule => serde_yaml::from_value::<RewriteRule>(rule.clone())
+                    .is_err()
+                    .then_some(0),
+            });
+
+        let location = err.location();
+        Err(ConfigurationError {
+            rule,
+            line: location.as_ref().map(|location| location.line()),
+            column: location.as_ref().map(|location| location.column()),
+            message: err.to_string(),
+        })
+    }
+
+    /// Moves the rules of `rule_groups` to the end of `rewrite_rules`, adding the conditions of
+    /// the respective group to each of them.
+    pub fn flatten_rule_groups(&mut self) -> Result<(), ConfigurationError> {
+        for group in std::mem::take(&mut self.rule_groups) {
+            for rule in &group.rewrite_rules {
+                let rule = group
+                    .apply_to(rule.clone())
+                    .map_err(|message| ConfigurationError {
+                        rule: Some(self.rewrite_rules.len()),
+                        line: None,
+                        column: None,
+                        message,
+                    })?;
+                self.rewrite_rules.push(rule);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the rewrite rules for rules that can never be applied or whose precedence is
+    /// ambiguous.
+    ///
+    /// Processing always stops after the first rule applied. A rule is unreachable if each of its
+    /// `from` patterns is also listed by a rule without further conditions (`from_glob`,
+    /// `extensions`, `from_exclude` or negated `from` patterns, `from_host`, `methods`, `scheme`,
+    /// `from_ip`, `listen_port`, `active_from`, `active_until`, `from_regex`, `query`,
+    /// `query_regex`, `uri_regex`, `query_match`, `query_params`, `has_header`, `lacks_header`,
+    /// `header_regex`, `cookie_regex`, `ua_regex`, `accept`, `accept_language`, `subst`,
+    /// `case_insensitive`, `trailing_slash: strict`, `ignore_trailing_slash` or lookups in maps
+    /// with `required` enabled) that is evaluated first, due to a higher `priority` or being listed earlier with the same priority:
+    /// that rule will always be applied instead. Rules with different `from` patterns cannot shadow
+    /// each other fully, more specific patterns always take precedence. If these are equally
+    /// specific however, only `priority` and the configuration order decide between them, this is
+    /// reported for rules with the same priority. Rules with `enabled: false` are ignored.
+    pub fn lint(&self) -> Vec<RuleWarning> {
+        let mut warnings = Vec::new();
+        for (index, rule) in self.rewrite_rules.iter().enumerate() {
+            if !rule.enabled {
+                continue;
+            }
+
+            let unconditional = |other: &RewriteRule| {
+                other.from_glob.is_none()
+                    && other.extensions.is_empty()
+                    && other.from_exclude.is_empty()
+                    && other.from.iter().all(|from| !from.negate)
+                    && other.from_host.is_empty()
+                    && other.methods.is_empty()
+                    && other.scheme.is_none()
+                    && other.from_ip.is_empty()
+                    && other.listen_port.is_empty()
+                    && other.active_from.is_none()
+                    && other.active_until.is_none()
+                    && other.has_header.is_empty()
+                    && other.lacks_header.is_empty()
+                    && other.header_regex.is_empty()
+                    && other.cookie_regex.is_empty()
+                    && other.ua_regex.is_none()
+                    && other.from_regex.is_empty()
+                    && other.uri_regex.is_none()
+                    && other.query == QueryPresence::Any
+                    && other.query_regex.is_empty()
+                    && other.query_match.is_none()
+                    && other.query_params.is_empty()
+                    && other.accept.is_none()
+                    && other.accept_language.is_empty()
+                    && other.subst.is_none()
+                    && !other.case_insensitive
+                    && other.trailing_slash == TrailingSlash::Tolerant
+                    && !other.ignore_trailing_slash
+                    && !other.uses_required_map(&self.rewrite_maps)
+                    // These only apply to paths without the desired form
+                    && !other.r#type.is_slash()
+            };
+            // Rules evaluated before this one for the same path
+            let preceding = |(other_index, other): &(usize, &RewriteRule)| {
+                other.enabled
+                    && (other.priority > rule.priority
+                        || (other.priority == rule.priority && *other_index < index))
+            };
+            let shadowing = rule
+                .path_patterns()
+                .iter()
+                .map(|from| {
+                    self.rewrite_rules
+                        .iter()
+                        .enumerate()
+                        .filter(preceding)
+                        .find(|(_, other)| {
+                            unconditional(other) && other.path_patterns().contains(from)
+                        })
+                        .map(|(other_index, _)| other_index)
+                })
+                .collect::<Option<Vec<_>>>()
+                .filter(|_| rule.from_glob.is_none())
+                .and_then(|shadowing| shadowing.into_iter().min());
+            if let Some(shadowing) = shadowing {
+                warnings.push(RuleWarning {
+                    index,
+                    reason: format!(
+                        "rule is never applied, rule {shadowing} has the same `from` pattern \
+                         without any conditions and always takes precedence"
+                    ),
+                });
+                continue;
+            }
+
+            let ambiguous = self.rewrite_rules[..index].iter().position(|other| {
+                other.enabled
+                    && other.priority == rule.priority
+                    && other.from_glob.is_none()
+                    && rule.from_glob.is_none()
+                    && other.path_patterns().iter().any(|other_from| {
+                        rule.path_patterns().iter().any(|from| {
+                            other_from != from
+                                && other_from.specificity() == from.specificity()
+                                && other_from.overlaps(from)
+                        })
+                    })
+            });
+            if let Some(ambiguous) = ambiguous {
+                warnings.push(RuleWarning {
+                    index,
+                    reason: format!(
+                        "rule {ambiguous} has an equally specific `from` pattern matching some \
+                         of the same paths, it takes precedence because it is listed first"
+                    ),
+                });
+            }
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn variable_interpolation() {
+        assert_eq!(
+            VariableInterpolation::from("abcd").interpolate(|_| panic!("Unexpected lookup call")),
+            b"abcd".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("ab${xyz}cd").interpolate(|_| None),
+            b"ab${xyz}cd".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("ab${xyz}cd").interpolate(|name| {
+                if name == "xyz" {
+                    Some(b"resolved")
+                } else {
+                    None
+                }
+            }),
+            b"abresolvedcd".to_vec()
+        );
 
         assert_eq!(
             VariableInterpolation::from("a${x}${y}bc${z}d").interpolate(|name| {
@@ -299,6 +2756,807 @@ mod tests {
             }),
             b"${aresolved".to_vec()
         );
+
+        assert_eq!(
+            VariableInterpolation::from("/${x|base64decode}?${y|base64urlencode}").interpolate(
+                |name| {
+                    if name == "x" {
+                        Some(b"cGF0aA==")
+                    } else if name == "y" {
+                        Some(b"a=b?")
+                    } else {
+                        None
+                    }
+                }
+            ),
+            b"/path?YT1iPw".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("${x|base64encode|base64decode}").interpolate(|name| {
+                if name == "x" {
+                    Some(b"unchanged")
+                } else {
+                    None
+                }
+            }),
+            b"unchanged".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("/${x|base64decode}/${y|base64decode}").interpolate(
+                |name| {
+                    if name == "x" {
+                        Some(b"invalid!")
+                    } else {
+                        None
+                    }
+                }
+            ),
+            b"/${x|base64decode}/${y|base64decode}".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("${x|unknown}").interpolate(|_| Some(b"resolved")),
+            b"${x|unknown}".to_vec()
+        );
+
+        assert_eq!(
+            VariableInterpolation::from("/${x|trim|lowercase|urlencode}?${x|trim|uppercase}")
+                .interpolate(|_| Some(b" A&b#C ")),
+            b"/a%26b%23c?A&B#C".to_vec()
+        );
+    }
+
+    #[test]
+    fn variable_interpolation_unknown_filters() {
+        for (source, expected) in [
+            ("/${x}", None),
+            ("/${x|urlencode|trim}", None),
+            ("/${x|unknown}", Some("unknown")),
+            ("/${x|trim|urlEncode|other}${y|third}", Some("urlEncode")),
+            ("/${x|}", Some("")),
+            ("/${x y|unknown}", None),
+            ("/$${x|unknown}", None),
+            ("/${map_a:${x|unknown}}", Some("unknown")),
+        ] {
+            assert_eq!(
+                VariableInterpolation::from(source).unknown_filter(),
+                expected,
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn variable_interpolation_variables() {
+        for (source, expected) in [
+            ("/static", vec![]),
+            ("/${tail}?${query}", vec!["tail", "query"]),
+            ("/${1|lowercase}/${lang:-en}", vec!["1", "lang"]),
+            ("/$${escaped}/${x y}", vec![]),
+            ("/${map_a:${tail1}-${tail2}}", vec!["tail1", "tail2"]),
+        ] {
+            assert_eq!(
+                VariableInterpolation::from(source).variables(),
+                expected,
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn variable_interpolation_literals() {
+        fn literal(value: &str) -> VariableInterpolationPart {
+            VariableInterpolationPart::Literal(value.as_bytes().to_vec())
+        }
+        fn variable(name: &str) -> VariableInterpolationPart {
+            VariableInterpolationPart::Variable {
+                name: name.to_owned(),
+                filters: Vec::new(),
+                default: None,
+                source: format!("${{{name}}}").into_bytes(),
+            }
+        }
+
+        let interpolation = VariableInterpolation::from("ab${x-y}${xyz}${a|b}cd${");
+        assert_eq!(
+            interpolation.parts,
+            vec![literal("ab${x-y}"), variable("xyz"), literal("${a|b}cd${")]
+        );
+        assert_eq!(
+            interpolation.interpolate(|_| Some(b"!")),
+            b"ab${x-y}!${a|b}cd${".to_vec()
+        );
+
+        let interpolation = VariableInterpolation::from("${x}${y}");
+        assert_eq!(interpolation.parts, vec![variable("x"), variable("y")]);
+
+        assert!(VariableInterpolation::from("").parts.is_empty());
+    }
+
+    #[test]
+    fn variable_interpolation_escapes() {
+        let lookup = |name: &str| match name {
+            "var" => Some(&b"resolved"[..]),
+            _ => None,
+        };
+        for (source, expected) in [
+            ("/$${query}", "/${query}"),
+            ("$${var}${var}", "${var}resolved"),
+            ("$$${var}", "$${var}"),
+            ("$$$${var}", "$$${var}"),
+            ("/a$b$/${var}", "/a$b$/resolved"),
+            ("/${var}$$", "/resolved$$"),
+            ("$", "$"),
+            ("$${", "${"),
+            ("${a$${var}", "${a${var}"),
+            ("${map_x:$${var}}", "${map_x:$${var}}"),
+        ] {
+            assert_eq!(
+                String::from_utf8(VariableInterpolation::from(source).interpolate(lookup)).unwrap(),
+                expected,
+                "{source}"
+            );
+        }
+
+        assert_eq!(
+            VariableInterpolation::from("$${var}$${var}").parts,
+            vec![VariableInterpolationPart::Literal(b"${var}${var}".to_vec())]
+        );
+        assert!(VariableInterpolation::from("$${var}").is_literal());
+
+        let delimiters = VariableDelimiters {
+            prefix: "%{".to_owned(),
+            suffix: "}".to_owned(),
+        };
+        assert_eq!(
+            VariableInterpolation::with_delimiters("%%{var}$${var}%{var}", &delimiters)
+                .interpolate(lookup),
+            b"%{var}$${var}resolved".to_vec()
+        );
+    }
+
+    #[test]
+    fn variable_interpolation_defaults() {
+        let lookup = |name: &str| match name {
+            "x" => Some(&b"resolved"[..]),
+            "empty" => Some(&b""[..]),
+            "invalid" => Some(&b"invalid!"[..]),
+            _ => None,
+        };
+        for (source, expected) in [
+            ("/${x:-fallback}", "/resolved"),
+            ("/${y:-fallback}", "/fallback"),
+            ("/${empty:-fallback}", "/fallback"),
+            ("/${y:-}", "/"),
+            ("/${y:-a:-b|c}", "/a:-b|c"),
+            ("/${invalid|base64decode:-fallback}", "/fallback"),
+            ("/${x|unknown:-fallback}", "/${x|unknown:-fallback}"),
+            // The default value ends with the first `}` and cannot contain variables
+            ("/${y:-a}b}", "/ab}"),
+            ("/${y:-${x}}", "/${y:-resolved}"),
+        ] {
+            assert_eq!(
+                String::from_utf8(VariableInterpolation::from(source).interpolate(lookup)).unwrap(),
+                expected,
+                "{source}"
+            );
+        }
+
+        let delimiters = VariableDelimiters {
+            prefix: "%{".to_owned(),
+            suffix: "}".to_owned(),
+        };
+        assert_eq!(
+            VariableInterpolation::with_delimiters("/%{y:-${z}}", &delimiters).interpolate(lookup),
+            b"/${z}".to_vec()
+        );
+    }
+
+    #[test]
+    fn from_yaml_str() {
+        let conf = RewriteConf::from_yaml_str(
+            r#"
+                rewrite_rules:
+                    from: /dir/*
+                    from_regex: "\\.txt$"
+                    to: /a${tail}
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.rewrite_rules.len(), 1);
+
+        let err = RewriteConf::from_yaml_str(
+            r#"
+rewrite_rules:
+-
+    from: /dir/*
+    to: /a${tail}
+-
+-
+    from: /other/*
+    from_regex: "(unclosed"
+    to: /b${tail}
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.rule, Some(1));
+        assert!(err.line.is_some_and(|line| (6..=10).contains(&line)));
+        assert!(err.column.is_some());
+        #[cfg(not(feature = "fancy-regex"))]
+        assert!(err.message.contains("unclosed group"));
+        #[cfg(feature = "fancy-regex")]
+        assert!(err.message.contains("without closing parenthesis"));
+        assert!(err.to_string().starts_with("rewrite rule 1: "));
+
+        let err = RewriteConf::from_yaml_str(
+            r#"
+                rewrite_rules:
+                    from: /dir/*
+                    flags: "[X]"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(err.rule, Some(0));
+
+        let err = RewriteConf::from_yaml_str("rewrite_metrics: maybe").unwrap_err();
+        assert_eq!(err.rule, None);
+        assert_eq!(err.line, Some(1));
+    }
+
+    #[test]
+    fn rule_builder() {
+        let conf = RewriteConf::from_yaml_str(
+            r#"
+                rewrite_rules:
+                -
+                    name: legacy
+                    from: [/old/*, /legacy/*]
+                    from_host: example.com
+                    methods: [GET, HEAD]
+                    from_regex: ["!\\.png$", "^/old/"]
+                    regex_mode: any
+                    uri_regex: "id=(?P<id>\\d+)"
+                    query_set: {id: "${id}"}
+                    to: /new${tail}
+                    type: permanent
+                    priority: 2
+                -
+                    from: /robots.txt
+                    type: response
+                    status: 200
+                    content_type: text/plain
+                    body: "Host: ${host}"
+                    headers:
+                        Cache-Control: [no-cache, no-store]
+            "#,
+        )
+        .unwrap();
+
+        let mut built = [RewriteRule::builder()
+            .name("legacy")
+            .from("/old/*")
+            .from("/legacy/*")
+            .from_host("example.com")
+            .method(Method::GET)
+            .method(Method::HEAD)
+            .from_regex("!\\.png$")
+            .unwrap()
+            .from_regex("^/old/")
+            .unwrap()
+            .regex_mode(RegexMode::Any)
+            .uri_regex("id=(?P<id>\\d+)")
+            .unwrap()
+            .query_set("id", "${id}")
+            .to("/new${tail}")
+            .r#type(RewriteType::Permanent)
+            .priority(2)
+            .build()]
+        .into_iter()
+        .collect::<RewriteConf>();
+        built.extend([RewriteRule::builder()
+            .from("/robots.txt")
+            .r#type(RewriteType::Response)
+            .status(StatusCode::OK)
+            .content_type("text/plain")
+            .body("Host: ${host}")
+            .header("Cache-Control", "no-cache")
+            .header("Cache-Control", "no-store")
+            .build()]);
+        assert_eq!(built, conf);
+
+        assert!(RewriteRule::builder().from_regex("(unclosed").is_err());
+        assert!(RewriteRule::builder().query_regex("[a-").is_err());
+        assert!(RewriteRule::builder().uri_regex("!(?P<>x)").is_err());
+        assert!(RewriteRule::builder().header_regex("Host", "*").is_err());
+    }
+
+    #[test]
+    fn lint() {
+        use pandora_module_utils::FromYaml;
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*
+                    query_regex: "^a="
+                    to: /a${tail}
+                -
+                    from: /dir/*
+                    to: /b${tail}
+                -
+                    from: /dir/*
+                    from_regex: "\\.txt$"
+                    to: /c${tail}
+                -
+                    from: /dir/file.txt
+                    to: /d
+                -
+                    from: /dir/*/file.txt
+                    to: /e
+                -
+                    from: /dir/file.txt
+                    to: /f
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            conf.lint()
+                .into_iter()
+                .map(|warning| warning.index)
+                .collect::<Vec<_>>(),
+            vec![2, 5]
+        );
+        assert!(conf.lint()[0].reason.contains("rule 1"));
+        assert!(conf.lint()[1].reason.contains("rule 3"));
+
+        assert!(RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*
+                -
+                    from: /dir
+                -
+                    from: /dir/*/file.txt
+            "#,
+        )
+        .unwrap()
+        .lint()
+        .is_empty());
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*/a/*
+                -
+                    from: /dir/*/b
+                -
+                    from: /dir/*/*/c
+                -
+                    from: /dir/*/c
+            "#,
+        )
+        .unwrap();
+        let warnings = conf.lint();
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|warning| warning.index)
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert!(warnings[0].reason.contains("rule 0"));
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /a/*
+                -
+                    from: [/a/*, /b/*]
+                -
+                    from: /b/*
+                -
+                    from: [/b/*, /a/*]
+            "#,
+        )
+        .unwrap();
+        let warnings = conf.lint();
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|warning| warning.index)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(warnings[0].reason.contains("rule 1"));
+        assert!(warnings[1].reason.contains("rule 0"));
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*
+                    to: /a${tail}
+                -
+                    from: /dir/*
+                    priority: 1
+                    to: /b${tail}
+                -
+                    from: /dir/*/a/*
+                    priority: -1
+                -
+                    from: /dir/*/*/b
+                -
+                    from: /dir/*
+                    from_regex: "\\.txt$"
+                    priority: 2
+                    to: /c${tail}
+            "#,
+        )
+        .unwrap();
+        let warnings = conf.lint();
+        assert_eq!(
+            warnings
+                .iter()
+                .map(|warning| warning.index)
+                .collect::<Vec<_>>(),
+            vec![0]
+        );
+        assert!(warnings[0].reason.contains("rule 1"));
+
+        // Each of these settings makes the first rule conditional
+        for setting in [
+            "case_insensitive: true",
+            "trailing_slash: strict",
+            "ignore_trailing_slash: true",
+            "to: /${map_a:${tail}}",
+        ] {
+            let conf = RewriteConf::from_yaml(format!(
+                r#"
+                    rewrite_maps:
+                        a:
+                            file: a.txt
+                    rewrite_rules:
+                    -
+                        from: /dir
+                        {setting}
+                    -
+                        from: /dir
+                "#
+            ))
+            .unwrap();
+            assert!(conf.lint().is_empty(), "{setting}");
+        }
+
+        // Lookups in maps without `required` always succeed
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_maps:
+                    a:
+                        file: a.txt
+                        required: false
+                rewrite_rules:
+                -
+                    from: /dir
+                    to: /${map_a:${tail}}
+                -
+                    from: /dir
+            "#,
+        )
+        .unwrap();
+        assert_eq!(conf.lint().len(), 1);
+
+        // Disabled rules neither shadow other rules nor get warnings
+        assert!(RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*
+                    enabled: false
+                -
+                    from: /dir/*
+                -
+                    from: /dir/*
+                    enabled: false
+            "#,
+        )
+        .unwrap()
+        .lint()
+        .is_empty());
+    }
+
+    #[test]
+    fn path_pattern_overlaps() {
+        let overlaps = |a: &str, b: &str| {
+            let result = PathPattern::from(a).overlaps(&PathPattern::from(b));
+            assert_eq!(result, PathPattern::from(b).overlaps(&PathPattern::from(a)));
+            result
+        };
+
+        assert!(overlaps("/dir/*/a/*", "/dir/*/*/b"));
+        assert!(overlaps("/dir/*/a", "/dir/*/*"));
+        assert!(overlaps("/dir/*/a/*", "/dir/*/a/b/c"));
+        assert!(!overlaps("/dir/*/a", "/dir/*/b"));
+        assert!(!overlaps("/dir/*/a", "/dir/*/a/b"));
+        assert!(!overlaps("/dir/*/a/*", "/other/*/a/*"));
+    }
+
+    #[test]
+    fn variable_delimiters() {
+        let delimiters = VariableDelimiters {
+            prefix: "<<".to_owned(),
+            suffix: ">>".to_owned(),
+        };
+        let lookup = |name: &str| match name {
+            "xyz" => Some(b"resolved".as_slice()),
+            _ => None,
+        };
+
+        assert_eq!(
+            VariableInterpolation::with_delimiters("ab<<xyz>>cd${xyz}", &delimiters)
+                .interpolate(lookup),
+            b"abresolvedcd${xyz}".to_vec()
+        );
+        assert_eq!(
+            VariableInterpolation::with_delimiters("<<x-y>><<xyz>><<abc>>", &delimiters)
+                .interpolate(lookup),
+            b"<<x-y>>resolved<<abc>>".to_vec()
+        );
+        assert_eq!(
+            VariableInterpolation::with_delimiters("<<xyz|base64encode>>", &delimiters)
+                .interpolate(lookup),
+            b"cmVzb2x2ZWQ=".to_vec()
+        );
+
+        let delimiters = VariableDelimiters {
+            prefix: "%".to_owned(),
+            suffix: "%".to_owned(),
+        };
+        assert_eq!(
+            VariableInterpolation::with_delimiters("%%xyz%", &delimiters).interpolate(lookup),
+            b"%%xyz%".to_vec()
+        );
+        assert_eq!(
+            VariableInterpolation::with_delimiters("a%xyz%b", &delimiters).interpolate(lookup),
+            b"aresolvedb".to_vec()
+        );
+
+        let mut interpolation = VariableInterpolation::from("${xyz}%xyz%");
+        interpolation.set_delimiters(&delimiters);
+        assert_eq!(
+            interpolation.interpolate(lookup),
+            b"${xyz}resolved".to_vec()
+        );
+        interpolation.set_delimiters(&VariableDelimiters::default());
+        assert_eq!(interpolation.interpolate(lookup), b"resolved%xyz%".to_vec());
+
+        use pandora_module_utils::FromYaml;
+        assert!(RewriteConf::from_yaml("variable_delimiters:\n  prefix: \"\"").is_err());
+    }
+
+    #[test]
+    fn rewrite_type() {
+        assert_eq!(RewriteType::Internal.status_code(), None);
+        assert_eq!(
+            RewriteType::Redirect.status_code(),
+            Some(StatusCode::TEMPORARY_REDIRECT)
+        );
+        assert_eq!(
+            RewriteType::Permanent.status_code(),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+        assert_eq!(RewriteType::Gone.status_code(), Some(StatusCode::GONE));
+        assert_eq!(
+            RewriteType::Forbidden.status_code(),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(RewriteType::Response.status_code(), Some(StatusCode::OK));
+        assert_eq!(
+            RewriteType::AddSlash.status_code(),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+        assert_eq!(
+            RewriteType::RemoveSlash.status_code(),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+
+        assert!(!RewriteType::Internal.is_redirect());
+        assert!(RewriteType::Redirect.is_redirect());
+        assert!(RewriteType::Permanent.is_redirect());
+        assert!(!RewriteType::Gone.is_redirect());
+        assert!(!RewriteType::Forbidden.is_redirect());
+        assert!(!RewriteType::Response.is_redirect());
+        assert!(RewriteType::AddSlash.is_redirect());
+        assert!(RewriteType::RemoveSlash.is_redirect());
+        assert!(RewriteType::AddSlash.is_slash());
+        assert!(!RewriteType::Permanent.is_slash());
+    }
+
+    #[test]
+    fn query_match() {
+        use pandora_module_utils::FromYaml;
+
+        let rule =
+            RewriteRule::from_yaml("query_match: {v: 2, format: json, debug: true, empty: null}")
+                .unwrap();
+        let query_match = rule.query_match.unwrap();
+        assert_eq!(
+            query_match.params,
+            BTreeMap::from([
+                ("debug".to_owned(), "true".to_owned()),
+                ("empty".to_owned(), String::new()),
+                ("format".to_owned(), "json".to_owned()),
+                ("v".to_owned(), "2".to_owned()),
+            ])
+        );
+        assert!(RewriteRule::from_yaml("query_match: {v: [1, 2]}").is_err());
+
+        let query_match = QueryMatch {
+            params: BTreeMap::from([
+                ("v".to_owned(), "2".to_owned()),
+                ("format".to_owned(), "json".to_owned()),
+            ]),
+        };
+        assert!(query_match.matches(&[("format", "json"), ("v", "2")], false));
+        assert!(query_match.matches(&[("format", "json"), ("v", "2")], true));
+        assert!(query_match.matches(&[("v", "1"), ("format", "json"), ("v", "2")], false));
+        assert!(!query_match.matches(&[("v", "1"), ("format", "json"), ("v", "2")], true));
+        assert!(query_match.matches(&[("format", "json"), ("x", ""), ("v", "2")], false));
+        assert!(!query_match.matches(&[("format", "json"), ("x", ""), ("v", "2")], true));
+        assert!(!query_match.matches(&[("format", "json")], false));
+        assert!(!query_match.matches(&[("format", "xml"), ("v", "2")], false));
+        assert!(!query_match.matches::<&str, &str>(&[], false));
+    }
+
+    #[test]
+    fn path_pattern() {
+        fn capture(pattern: &str, path: &str) -> Option<Vec<String>> {
+            let pattern = PathPattern::from(pattern);
+            let path = pattern.matcher.path.remove_prefix_from(path)?;
+            Some(
+                pattern
+                    .capture(&path, false)?
+                    .into_iter()
+                    .map(|capture| String::from_utf8(capture).unwrap())
+                    .collect(),
+            )
+        }
+
+        assert_eq!(PathPattern::from("/dir/*").matcher, "/dir/*".into());
+        assert_eq!(PathPattern::from("/dir/*").specificity(), 0);
+        assert_eq!(PathPattern::from("/dir/*/a/*").matcher, "/dir/*".into());
+        assert_eq!(PathPattern::from("/dir/*/a/*").specificity(), 1);
+        assert_eq!(PathPattern::from("/dir/*/a/*/b").specificity(), 2);
+
+        assert_eq!(
+            capture("/dir/*", "/dir/file.txt"),
+            Some(vec!["/file.txt".into()])
+        );
+        assert_eq!(capture("/dir/*", "/dir"), Some(vec!["/".into()]));
+        assert_eq!(
+            PathPattern::from("/a/*/B/*").capture(b"/x/b/y", true),
+            Some(vec![b"/x".to_vec(), b"/y".to_vec()])
+        );
+        assert_eq!(
+            PathPattern::from("/a/*/B/*").capture(b"/x/b/y", false),
+            None
+        );
+
+        assert_eq!(
+            capture("/a/*/b/*", "/a/x/b/y/z"),
+            Some(vec!["/x".into(), "/y/z".into()])
+        );
+        assert_eq!(
+            capture("/a/*/b/*", "/a/x/b"),
+            Some(vec!["/x".into(), "/".into()])
+        );
+        assert_eq!(
+            capture("/a/*/b/*", "/a//x//b/"),
+            Some(vec!["/x".into(), "/".into()])
+        );
+        assert_eq!(capture("/a/*/b/*", "/a/x/c/y"), None);
+        assert_eq!(capture("/a/*/b/*", "/a/x/y/b/z"), None);
+        assert_eq!(capture("/a/*/b/*", "/a/x"), None);
+
+        assert_eq!(
+            capture("/a/*/b/*/c", "/a/x/b/y/c/"),
+            Some(vec!["/x".into(), "/y".into()])
+        );
+        assert_eq!(capture("/a/*/b/*/c", "/a/x/b/y/c/d"), None);
+    }
+
+    #[test]
+    fn rewrite_flags() {
+        assert_eq!(RewriteFlags::try_from(""), Ok(RewriteFlags::default()));
+        assert_eq!(RewriteFlags::try_from("[]"), Ok(RewriteFlags::default()));
+        assert_eq!(
+            RewriteFlags::try_from("[R]"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Redirect),
+                status: Some(StatusCode::FOUND),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[R=302]"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Redirect),
+                status: Some(StatusCode::FOUND),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[R=307]"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Redirect),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[R=301]"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Permanent),
+                status: Some(StatusCode::MOVED_PERMANENTLY),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[R=308]"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Permanent),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[L]"),
+            Ok(RewriteFlags {
+                last: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[NC]"),
+            Ok(RewriteFlags {
+                case_insensitive: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("[QSA]"),
+            Ok(RewriteFlags {
+                append_query: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from(" [r=308, l, nocase,QSA] "),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Permanent),
+                status: None,
+                last: true,
+                case_insensitive: true,
+                append_query: true,
+            })
+        );
+        assert_eq!(
+            RewriteFlags::try_from("R=301,L"),
+            Ok(RewriteFlags {
+                r#type: Some(RewriteType::Permanent),
+                status: Some(StatusCode::MOVED_PERMANENTLY),
+                last: true,
+                ..Default::default()
+            })
+        );
+
+        assert!(RewriteFlags::try_from("[F]").is_err());
+        assert!(RewriteFlags::try_from("[R=303]").is_err());
+        assert!(RewriteFlags::try_from("[L=1]").is_err());
     }
 
     #[test]
@@ -327,4 +3585,208 @@ mod tests {
         assert!(regex_match.matches("ab"));
         assert!(regex_match.matches("bc"));
     }
+
+    #[test]
+    fn regex_match_bytes() {
+        let regex_match = RegexMatch::try_from("\\.jpg$").unwrap();
+        assert!(regex_match.matches_bytes(b"/image.jpg"));
+        assert!(regex_match.matches_bytes(b"/\xFF\xFE.jpg"));
+        assert!(!regex_match.matches_bytes(b"/\xFF\xFE.png"));
+
+        // fancy-regex matches strings, invalid UTF-8 sequences are replaced before matching
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            let regex_match = RegexMatch::try_from("^/caf.$").unwrap();
+            assert!(regex_match.matches_bytes("/café".as_bytes()));
+            assert!(!regex_match.matches_bytes(b"/caf\xE9"));
+
+            let regex_match = RegexMatch::try_from("^/caf(?-u:.)$").unwrap();
+            assert!(regex_match.matches_bytes(b"/caf\xE9"));
+
+            let regex_match = RegexMatch::try_from("!^/caf(?-u:\\xE9)$").unwrap();
+            assert!(!regex_match.matches_bytes(b"/caf\xE9"));
+            assert!(regex_match.matches_bytes("/café".as_bytes()));
+
+            let regex_match = regex_match.case_insensitive().unwrap();
+            assert!(!regex_match.matches_bytes(b"/CAF\xE9"));
+        }
+    }
+
+    #[test]
+    fn regex_match_look_around() {
+        use pandora_module_utils::FromYaml;
+
+        let result = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                - from: /files/*
+                  from_regex: "^/files/(?!public/)"
+                  to: /denied
+            "#,
+        );
+
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("enable the `fancy-regex` feature"), "{err}");
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            let regex_match = &result.unwrap().rewrite_rules[0].from_regex[0];
+            assert!(regex_match.matches("/files/private/a.txt"));
+            assert!(!regex_match.matches("/files/public/a.txt"));
+
+            let regex_match = RegexMatch::try_from("!^/files/(?!public/)").unwrap();
+            assert!(!regex_match.matches("/files/private/a.txt"));
+            assert!(regex_match.matches("/files/public/a.txt"));
+
+            // Exceeding the backtracking limit fails the condition, also if negated
+            let value = format!("{}c", "a".repeat(40));
+            let regex_match = RegexMatch::try_from(r"^(a+)+(?!x)\1$").unwrap();
+            assert!(!regex_match.matches(&value));
+            let regex_match = RegexMatch::try_from(r"!^(a+)+(?!x)\1$").unwrap();
+            assert!(!regex_match.matches(&value));
+        }
+    }
+
+    #[test]
+    fn substitution() {
+        let apply = |subst: &str, path: &str| {
+            let subst = Substitution::try_from(subst).unwrap();
+            String::from_utf8(subst.apply(path)).unwrap()
+        };
+
+        assert_eq!(apply(r"s/\/old\/(.*)/\/new\/$1/", "/old/a/b"), "/new/a/b");
+        assert_eq!(apply("s|^/old/(.*)|/new/$1|", "/old/a/b"), "/new/a/b");
+        assert_eq!(apply("s|a|x|", "/a/a/a"), "/x/a/a");
+        assert_eq!(apply("s|a|x|g", "/a/a/a"), "/x/x/x");
+        assert_eq!(apply("s|A|x|", "/a/a/a"), "/a/a/a");
+        assert_eq!(apply("s|A|x|gi", "/a/A/a"), "/x/x/x");
+        assert_eq!(
+            apply("s|(?P<name>\\w+)\\.htm$|${name}.html|", "/x/page.htm"),
+            "/x/page.html"
+        );
+        assert_eq!(apply(r"s#\##-#g", "/a#b#c"), "/a-b-c");
+
+        let subst = Substitution::try_from("s|old|new|").unwrap();
+        assert!(subst.matches("/old/file"));
+        assert!(!subst.matches("/OLD/file"));
+
+        let subst = Substitution::try_from("s|old|new|i").unwrap();
+        assert!(subst.matches("/OLD/file"));
+
+        assert!(Substitution::try_from("s/old/new").is_err());
+        assert!(Substitution::try_from("s/old/new/g/").is_err());
+        assert!(Substitution::try_from("x/old/new/").is_err());
+        assert!(Substitution::try_from("sxoldxnewx").is_err());
+        assert!(Substitution::try_from("s/old/new/x").is_err());
+        assert!(Substitution::try_from("s/(/new/").is_err());
+    }
+
+    #[test]
+    fn glob() {
+        let matches = |glob: &str, path: &str| Glob::try_from(glob).unwrap().matches(path);
+
+        assert!(matches("/file?.txt", "/file1.txt"));
+        assert!(!matches("/file?.txt", "/file.txt"));
+        assert!(!matches("/file?.txt", "/file12.txt"));
+        assert!(!matches("/a?b", "/a/b"));
+
+        assert!(matches("/file[0-9].txt", "/file5.txt"));
+        assert!(!matches("/file[0-9].txt", "/filex.txt"));
+        assert!(matches("/file[!0-9].txt", "/filex.txt"));
+        assert!(!matches("/file[!0-9].txt", "/file5.txt"));
+        assert!(!matches("/a[!x]b", "/a/b"));
+        assert!(matches("/[]]", "/]"));
+
+        assert!(matches("/docs/*.md", "/docs/intro.md"));
+        assert!(!matches("/docs/*.md", "/docs/guide/intro.md"));
+        assert!(matches("/docs/**/*.md", "/docs/intro.md"));
+        assert!(matches("/docs/**/*.md", "/docs/guide/advanced/intro.md"));
+        assert!(!matches("/docs/**/*.md", "/docs/intro.html"));
+        assert!(matches("/docs/**", "/docs/a/b"));
+        assert!(!matches("/docs/*.md", "/docs/intro.md.bak"));
+        assert!(matches("/a.b+c", "/a.b+c"));
+        assert!(!matches("/a.b+c", "/axbbc"));
+
+        assert_eq!(
+            Glob::try_from("/docs/**/*.md").unwrap().pattern(),
+            "/docs/*".into()
+        );
+        assert_eq!(Glob::try_from("/file?.txt").unwrap().pattern(), "/*".into());
+        assert_eq!(
+            Glob::try_from("/file.txt").unwrap().pattern(),
+            "/file.txt".into()
+        );
+        assert_eq!(Glob::try_from("/a/*/b/c").unwrap().specificity(), 2);
+
+        assert!(Glob::try_from("/file[0-9.txt").is_err());
+        assert!(Glob::try_from("/file[].txt").is_err());
+    }
+
+    #[test]
+    fn ip_match() {
+        let contains = |range: &str, addr: &str| {
+            IpMatch::try_from(range)
+                .unwrap()
+                .contains(addr.parse().unwrap())
+        };
+
+        assert!(contains("192.168.0.0/16", "192.168.12.34"));
+        assert!(!contains("192.168.0.0/16", "192.169.0.1"));
+        assert!(contains("10.0.0.0/9", "10.127.0.1"));
+        assert!(!contains("10.0.0.0/9", "10.128.0.1"));
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("0.0.0.0/0", "1.2.3.4"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("::/0", "::1"));
+
+        // Host bits are ignored
+        assert!(contains("192.168.1.1/16", "192.168.2.2"));
+
+        // IPv4-mapped IPv6 addresses are treated as IPv4
+        assert!(contains("192.168.0.0/16", "::ffff:192.168.1.1"));
+        assert!(contains("::ffff:192.168.0.0/112", "192.168.1.1"));
+        assert!(!contains("::/0", "::ffff:192.168.1.1"));
+
+        let range = IpMatch::try_from("!10.0.0.0/8").unwrap();
+        assert!(range.negate);
+        assert_eq!(range.prefix_len, 8);
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+
+        assert!(IpMatch::try_from("").is_err());
+        assert!(IpMatch::try_from("example.com").is_err());
+        assert!(IpMatch::try_from("10.0.0.0/").is_err());
+        assert!(IpMatch::try_from("10.0.0.0/33").is_err());
+        assert!(IpMatch::try_from("10.0.0.0/-1").is_err());
+        assert!(IpMatch::try_from("fd00::/129").is_err());
+        assert!(IpMatch::try_from("::ffff:10.0.0.0/64").is_err());
+        assert!(IpMatch::try_from("10.0.0.0/8/8").is_err());
+    }
+
+    #[test]
+    fn media_type() {
+        assert_eq!(
+            MediaType::try_from("Application/JSON").unwrap(),
+            MediaType {
+                r#type: "application".to_owned(),
+                subtype: "json".to_owned(),
+            }
+        );
+        assert_eq!(
+            MediaType::try_from("image/svg+xml").unwrap(),
+            MediaType {
+                r#type: "image".to_owned(),
+                subtype: "svg+xml".to_owned(),
+            }
+        );
+        assert!(MediaType::try_from("application").is_err());
+        assert!(MediaType::try_from("application/").is_err());
+        assert!(MediaType::try_from("application/*").is_err());
+        assert!(MediaType::try_from("text/html; charset=utf-8").is_err());
+    }
 }
@@ -0,0 +1,103 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cookie parsing helpers
+
+/// Removes leading and trailing spaces and tabs.
+fn trim(mut value: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = value {
+        value = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = value {
+        value = rest;
+    }
+    value
+}
+
+/// Splits the values of the `Cookie` request header into name and value pairs in the order
+/// listed. Cookies are separated by `;`, entries without `=` are ignored. Values enclosed in
+/// double quotes are returned without the quotes, otherwise verbatim.
+pub(crate) fn parse_cookies<'a>(
+    values: impl IntoIterator<Item = &'a [u8]>,
+) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+    values
+        .into_iter()
+        .flat_map(|value| value.split(|b| *b == b';'))
+        .filter_map(|pair| {
+            let separator = pair.iter().position(|b| *b == b'=')?;
+            let value = match trim(&pair[separator + 1..]) {
+                [b'"', inner @ .., b'"'] => inner,
+                value => value,
+            };
+            Some((trim(&pair[..separator]), value))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn lookup<'a>(values: &[&'a str], name: &str) -> Option<&'a str> {
+        parse_cookies(values.iter().map(|value| value.as_bytes()))
+            .find(|(cookie_name, _)| *cookie_name == name.as_bytes())
+            .map(|(_, value)| std::str::from_utf8(value).unwrap())
+    }
+
+    #[test]
+    fn cookies() {
+        assert_eq!(lookup(&["region=eu"], "region"), Some("eu"));
+        assert_eq!(lookup(&["a=1; region=eu; b=2"], "region"), Some("eu"));
+        assert_eq!(lookup(&["a=1;region=eu"], "region"), Some("eu"));
+        assert_eq!(lookup(&["a=1", "region=eu"], "region"), Some("eu"));
+        assert_eq!(lookup(&["region=\"eu\""], "region"), Some("eu"));
+        assert_eq!(lookup(&["region=\"\""], "region"), Some(""));
+        assert_eq!(lookup(&["region="], "region"), Some(""));
+        assert_eq!(lookup(&["region=eu; region=us"], "region"), Some("eu"));
+        assert_eq!(lookup(&["region=eu", "region=us"], "region"), Some("eu"));
+        assert_eq!(lookup(&["token=a=b"], "token"), Some("a=b"));
+        assert_eq!(lookup(&["Region=eu"], "region"), None);
+        assert_eq!(lookup(&["xregion=eu"], "region"), None);
+        assert_eq!(lookup(&[], "region"), None);
+
+        assert_eq!(
+            parse_cookies([b"a=1; b=\"2\"".as_slice(), b"a=3".as_slice()]).collect::<Vec<_>>(),
+            vec![
+                (b"a".as_slice(), b"1".as_slice()),
+                (b"b".as_slice(), b"2".as_slice()),
+                (b"a".as_slice(), b"3".as_slice()),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_cookies() {
+        assert_eq!(lookup(&[""], "region"), None);
+        assert_eq!(lookup(&[";;;"], "region"), None);
+        assert_eq!(lookup(&["="], "region"), None);
+        assert_eq!(lookup(&["="], ""), Some(""));
+        assert_eq!(lookup(&["region"], "region"), None);
+        assert_eq!(lookup(&["garbage; region=eu"], "region"), Some("eu"));
+        assert_eq!(lookup(&["region=\""], "region"), Some("\""));
+        assert_eq!(lookup(&["region=\"eu"], "region"), Some("\"eu"));
+        assert_eq!(lookup(&["  region  =  eu  ;"], "region"), Some("eu"));
+
+        let invalid_utf8 = [b"region=\xFF\xFE; a=b".as_slice()];
+        assert_eq!(
+            parse_cookies(invalid_utf8).next(),
+            Some((b"region".as_slice(), b"\xFF\xFE".as_slice()))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transformations that can be applied to variable values during interpolation
+
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, Engine, GeneralPurpose, GeneralPurposeConfig};
+use percent_encoding::{percent_decode, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+const STANDARD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const URL_SAFE: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Characters encoded by `urlencode`: everything but the unreserved characters of RFC 3986
+const URLENCODE_CHARSET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A transformation like `base64decode` in `${http_x_original_url|base64decode}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Filter {
+    /// Decodes base64 using the standard alphabet
+    DecodeBase64,
+    /// Encodes as base64 using the standard alphabet, with padding
+    EncodeBase64,
+    /// Decodes base64 using the URL-safe alphabet
+    DecodeBase64Url,
+    /// Encodes as base64 using the URL-safe alphabet, without padding
+    EncodeBase64Url,
+    /// Percent-encodes all characters but the unreserved ones
+    UrlEncode,
+    /// Decodes percent-encoded characters
+    UrlDecode,
+    /// Converts ASCII letters to lowercase
+    Lowercase,
+    /// Converts ASCII letters to uppercase
+    Uppercase,
+    /// Removes leading and trailing whitespace
+    Trim,
+}
+
+impl Filter {
+    /// Looks up a filter by its name, returns `None` for unknown filters.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "base64decode" => Some(Self::DecodeBase64),
+            "base64encode" => Some(Self::EncodeBase64),
+            "base64urldecode" => Some(Self::DecodeBase64Url),
+            "base64urlencode" => Some(Self::EncodeBase64Url),
+            "urlencode" => Some(Self::UrlEncode),
+            "urldecode" => Some(Self::UrlDecode),
+            "lowercase" => Some(Self::Lowercase),
+            "uppercase" => Some(Self::Uppercase),
+            "trim" => Some(Self::Trim),
+            _ => None,
+        }
+    }
+
+    /// Applies the filter to a value, returns `None` if the value is invalid for this filter.
+    pub(crate) fn apply(&self, value: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Self::DecodeBase64 => STANDARD.decode(value).ok(),
+            Self::EncodeBase64 => Some(STANDARD.encode(value).into_bytes()),
+            Self::DecodeBase64Url => URL_SAFE.decode(value).ok(),
+            Self::EncodeBase64Url => Some(URL_SAFE.encode(value).into_bytes()),
+            Self::UrlEncode => Some(
+                percent_encode(value, URLENCODE_CHARSET)
+                    .to_string()
+                    .into_bytes(),
+            ),
+            Self::UrlDecode => Some(percent_decode(value).collect()),
+            Self::Lowercase => Some(value.to_ascii_lowercase()),
+            Self::Uppercase => Some(value.to_ascii_uppercase()),
+            Self::Trim => {
+                let start = value
+                    .iter()
+                    .position(|c| !c.is_ascii_whitespace())
+                    .unwrap_or(value.len());
+                let end = value
+                    .iter()
+                    .rposition(|c| !c.is_ascii_whitespace())
+                    .map_or(start, |end| end + 1);
+                Some(value[start..end].to_vec())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(
+            Filter::EncodeBase64.apply(b"/path?a=b"),
+            Some(b"L3BhdGg/YT1i".to_vec())
+        );
+        assert_eq!(
+            Filter::DecodeBase64.apply(b"L3BhdGg/YT1i"),
+            Some(b"/path?a=b".to_vec())
+        );
+        assert_eq!(Filter::EncodeBase64.apply(b"ab"), Some(b"YWI=".to_vec()));
+        assert_eq!(Filter::DecodeBase64.apply(b"YWI="), Some(b"ab".to_vec()));
+        assert_eq!(Filter::DecodeBase64.apply(b"YWI"), Some(b"ab".to_vec()));
+        assert_eq!(Filter::DecodeBase64.apply(b""), Some(Vec::new()));
+
+        assert_eq!(
+            Filter::EncodeBase64Url.apply(b"/path?a=b"),
+            Some(b"L3BhdGg_YT1i".to_vec())
+        );
+        assert_eq!(
+            Filter::DecodeBase64Url.apply(b"L3BhdGg_YT1i"),
+            Some(b"/path?a=b".to_vec())
+        );
+        assert_eq!(Filter::EncodeBase64Url.apply(b"ab"), Some(b"YWI".to_vec()));
+        assert_eq!(Filter::DecodeBase64Url.apply(b"YWI="), Some(b"ab".to_vec()));
+
+        assert_eq!(Filter::DecodeBase64.apply(b"L3BhdGg_YT1i"), None);
+        assert_eq!(Filter::DecodeBase64Url.apply(b"L3BhdGg/YT1i"), None);
+        assert_eq!(Filter::DecodeBase64.apply(b"not base64!"), None);
+        assert_eq!(Filter::DecodeBase64.apply(b"YWJj="), None);
+    }
+
+    #[test]
+    fn url() {
+        assert_eq!(
+            Filter::UrlEncode.apply(b"a b&c=d#e/f?g+h%i-j.k_l~m"),
+            Some(b"a%20b%26c%3Dd%23e%2Ff%3Fg%2Bh%25i-j.k_l~m".to_vec())
+        );
+        assert_eq!(
+            Filter::UrlEncode.apply("ü".as_bytes()),
+            Some(b"%C3%BC".to_vec())
+        );
+        assert_eq!(Filter::UrlEncode.apply(b""), Some(Vec::new()));
+
+        assert_eq!(
+            Filter::UrlDecode.apply(b"a%20b%26c+d%C3%BC"),
+            Some("a b&c+dü".as_bytes().to_vec())
+        );
+        assert_eq!(
+            Filter::UrlDecode.apply(b"100%-%2"),
+            Some(b"100%-%2".to_vec())
+        );
+
+        let value = b"/a b?c=d&e#f";
+        let encoded = Filter::UrlEncode.apply(value).unwrap();
+        assert_eq!(Filter::UrlDecode.apply(&encoded), Some(value.to_vec()));
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            Filter::Lowercase.apply("ABC-dEf-Ü".as_bytes()),
+            Some("abc-def-Ü".as_bytes().to_vec())
+        );
+        assert_eq!(
+            Filter::Uppercase.apply("abc-DeF-ü".as_bytes()),
+            Some("ABC-DEF-ü".as_bytes().to_vec())
+        );
+        assert_eq!(Filter::Trim.apply(b" \t a b \r\n"), Some(b"a b".to_vec()));
+        assert_eq!(Filter::Trim.apply(b"   "), Some(Vec::new()));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            Filter::from_name("base64decode"),
+            Some(Filter::DecodeBase64)
+        );
+        assert_eq!(
+            Filter::from_name("base64encode"),
+            Some(Filter::EncodeBase64)
+        );
+        assert_eq!(
+            Filter::from_name("base64urldecode"),
+            Some(Filter::DecodeBase64Url)
+        );
+        assert_eq!(
+            Filter::from_name("base64urlencode"),
+            Some(Filter::EncodeBase64Url)
+        );
+        assert_eq!(Filter::from_name("urlencode"), Some(Filter::UrlEncode));
+        assert_eq!(Filter::from_name("urldecode"), Some(Filter::UrlDecode));
+        assert_eq!(Filter::from_name("lowercase"), Some(Filter::Lowercase));
+        assert_eq!(Filter::from_name("uppercase"), Some(Filter::Uppercase));
+        assert_eq!(Filter::from_name("trim"), Some(Filter::Trim));
+        assert_eq!(Filter::from_name("base64"), None);
+        assert_eq!(Filter::from_name("UrlEncode"), None);
+    }
+}
@@ -14,500 +14,9292 @@
 
 //! Handler for the `request_filter` phase.
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use http::{HeaderValue, StatusCode};
-use log::{debug, error, trace};
-use pandora_module_utils::merger::Merger;
-use pandora_module_utils::pingora::{Error, SessionWrapper};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::AsHeaderName;
+use http::uri::{Authority, Scheme};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, Version};
+use log::{debug, error, info, trace, warn};
+use pandora_module_utils::counters::HitCounters;
+use pandora_module_utils::merger::{Merger, PathMatch, PathMatchResult, PathMatcher};
+use pandora_module_utils::pingora::{Error, ErrorType, ResponseHeader, SessionWrapper, SocketAddr};
 use pandora_module_utils::router::{Path, Router};
-use pandora_module_utils::standard_response::redirect_response;
-use pandora_module_utils::{RequestFilter, RequestFilterResult};
+use pandora_module_utils::standard_response::{error_response, response_text};
+use pandora_module_utils::{OneOrMany, RequestFilter, RequestFilterResult};
+use percent_encoding::{percent_decode, percent_encode, AsciiSet, CONTROLS, NON_ALPHANUMERIC};
+use regex::bytes::RegexSet;
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::configuration::{RegexMatch, RewriteConf, RewriteType, VariableInterpolation};
+use crate::clock::SharedClock;
+use crate::configuration::{
+    EncodedSlash, Glob, IpMatch, LimitExceeded, MediaType, PathPattern, QueryMatch, QueryPresence,
+    RegexMatch, RegexMode, RequestScheme, RewriteConf, RewriteRule, RewriteType, RuleLog,
+    Substitution, TrailingSlash, VariableDelimiters, VariableInterpolation,
+};
+use crate::cookies::parse_cookies;
+use crate::filters::Filter;
+use crate::maps::RewriteMap;
+use crate::negotiation::{accepts_media_type, preferred_language};
+use crate::regex_engine::{Regex, RegexError};
+
+/// `Content-Type` header of `response` rules not setting `content_type`
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Characters to be encoded when inserting parts of a decoded path into the rewrite target. `%`
+/// isn’t contained here: the decoded path keeps `%2F` and `%25` encoded, so any `%` character in
+/// it is still part of the original encoding.
+const TAIL_ESC_CHARSET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Characters to be encoded when inserting fully decoded path parts into the rewrite target
+const DECODED_TAIL_ESC_CHARSET: &AsciiSet = &TAIL_ESC_CHARSET.add(b'%');
+
+/// Characters to be encoded when inserting variable values into `to_query`
+const QUERY_VALUE_ESC_CHARSET: &AsciiSet = &TAIL_ESC_CHARSET.add(b'&').add(b'+').add(b'=');
+
+/// Checks whether a request path contains an encoded slash (`%2F`)
+fn has_encoded_slash(path: &str) -> bool {
+    path.as_bytes()
+        .windows(3)
+        .any(|sequence| sequence.eq_ignore_ascii_case(b"%2F"))
+}
+
+/// Percent-decodes a request path. Encoded slashes (`%2F`) are kept as they are, these shouldn’t
+/// be mistaken for path separators. Same goes for `%25` so that a `%` character in the result is
+/// never ambiguous. Bytes not forming valid UTF-8 sequences are encoded again, so that these can
+/// still be matched and are preserved in the rewrite target.
+fn decode_path(path: &str) -> Cow<'_, str> {
+    fn hex_value(byte: u8) -> Option<u8> {
+        (byte as char).to_digit(16).map(|digit| digit as u8)
+    }
+
+    if !path.contains('%') {
+        return Cow::Borrowed(path);
+    }
+
+    let mut result = Vec::with_capacity(path.len());
+    let mut bytes = path.as_bytes();
+    while let [byte, rest @ ..] = bytes {
+        if let [b'%', high, low, ..] = bytes {
+            if let (Some(high), Some(low)) = (hex_value(*high), hex_value(*low)) {
+                let value = high << 4 | low;
+                if value == b'/' || value == b'%' {
+                    result.extend_from_slice(&bytes[..3]);
+                } else {
+                    result.push(value);
+                }
+                bytes = &bytes[3..];
+                continue;
+            }
+        }
+        result.push(*byte);
+        bytes = rest;
+    }
+
+    let mut decoded = String::with_capacity(result.len());
+    let mut bytes = result.as_slice();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                decoded.push_str(valid);
+                break;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                decoded.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let (invalid, rest) = rest.split_at(err.error_len().unwrap_or(rest.len()));
+                decoded.extend(percent_encode(invalid, NON_ALPHANUMERIC));
+                bytes = rest;
+            }
+        }
+    }
+    Cow::Owned(decoded)
+}
+
+/// Normalizes a request path: duplicate slashes are collapsed, `.` segments are removed and `..`
+/// segments remove the preceding segment. `..` segments at the root are dropped, so the result
+/// never escapes the root. A trailing slash is kept, and it is added if the last segment was `.`
+/// or `..`. Returns `None` if the path is normalized already.
+fn normalize_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        // Not a regular path, e.g. `*` for `OPTIONS` requests
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "" | "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+
+    (normalized != path).then_some(normalized)
+}
+
+/// Router matcher of a rule: applies to the paths of `matcher` except for the directories
+/// excluded
+#[derive(Debug, Clone)]
+struct RuleMatcher {
+    matcher: PathMatcher,
+    /// Prefix matchers only, each excluding a path and everything within it
+    exclude: Vec<PathMatcher>,
+}
+
+impl PathMatch for RuleMatcher {
+    fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &Path)> + '_> {
+        Box::new(
+            self.matcher
+                .iter()
+                .chain(self.exclude.iter().flat_map(PathMatch::iter)),
+        )
+    }
+
+    fn matches(&self, host: &[u8], path: &Path, force_prefix: bool) -> PathMatchResult {
+        if self
+            .exclude
+            .iter()
+            .any(|exclude| exclude.matches(host, path, force_prefix).any())
+        {
+            PathMatchResult::EMPTY
+        } else {
+            self.matcher.matches(host, path, force_prefix)
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct Rule {
-    from_regex: Option<RegexMatch>,
-    query_regex: Option<RegexMatch>,
+    index: usize,
+    from: PathPattern,
+    from_glob: Option<Glob>,
+    /// Lowercase file extensions including the leading dot
+    extensions: Vec<String>,
+    /// Exclusions not handled by the router, lowercase for case-insensitive rules
+    from_exclude: Vec<PathMatcher>,
+    /// Lowercase host names, optionally starting with `*.`
+    from_host: Vec<String>,
+    methods: Vec<Method>,
+    scheme: Option<RequestScheme>,
+    from_ip: Vec<IpMatch>,
+    listen_port: Vec<u16>,
+    active_from: Option<SystemTime>,
+    active_until: Option<SystemTime>,
+    has_header: Vec<HeaderName>,
+    lacks_header: Vec<HeaderName>,
+    header_regex: Vec<(HeaderName, RegexMatch)>,
+    cookie_regex: Vec<(String, RegexMatch)>,
+    /// Always case-insensitive
+    ua_regex: Option<RegexMatch>,
+    from_regex: Vec<RegexMatch>,
+    /// Whether `from_regex` was compiled case-insensitive, due to `case_insensitive` or the `NC`
+    /// flag
+    from_regex_case_insensitive: bool,
+    query: QueryPresence,
+    query_regex: Vec<RegexMatch>,
+    regex_mode: RegexMode,
+    uri_regex: Option<RegexMatch>,
+    query_match: Option<QueryMatch>,
+    query_match_strict: bool,
+    query_params: Vec<(String, RegexMatch)>,
+    accept: Option<MediaType>,
+    accept_language: Vec<String>,
     to: VariableInterpolation,
+    to_query: Option<VariableInterpolation>,
+    to_host: Option<VariableInterpolation>,
+    protocol_relative: bool,
+    strip_prefix: bool,
+    redirect_to_host: Option<Authority>,
+    subst: Option<Substitution>,
+    body: Option<VariableInterpolation>,
+    /// Decoded `body_base64`
+    body_bytes: Option<Vec<u8>>,
+    /// `Content-Type` header value, only for `response` rules
+    content_type: Option<HeaderValue>,
+    /// Additional response headers, not used for internal rewrites
+    headers: Vec<(HeaderName, VariableInterpolation)>,
     r#type: RewriteType,
+    /// Status code of the response, `None` for internal rewrites
+    status: Option<StatusCode>,
+    append_query: bool,
+    /// Query parameter names to remove, possibly ending with a `*` wildcard
+    query_remove: Vec<String>,
+    query_set: Vec<(String, VariableInterpolation)>,
+    query_add: Vec<(String, VariableInterpolation)>,
+    preserve_original_in: Option<HeaderName>,
+    /// Rewrite maps looked up by the rule
+    maps: BTreeMap<String, Arc<RewriteMap>>,
+    /// Mapping to be applied to `Location` and `Set-Cookie` headers of upstream responses, only
+    /// for internal rewrites with `rewrite_response_location` or `rewrite_cookie_path` enabled
+    path_mapping: Option<PathMapping>,
+    /// Whether the rules should be evaluated again for the new URI, only for internal rewrites
+    r#continue: bool,
+    /// For strict exact matches: whether the request path has to end with a slash
+    trailing_slash: Option<bool>,
+    /// Whether this is an exact match with `ignore_trailing_slash` enabled
+    ignore_trailing_slash: bool,
+    /// Whether `add_slash` and `remove_slash` leave paths looking like files alone
+    skip_files: bool,
+    case_insensitive: bool,
+    decode_tail: bool,
+    /// Whether `from_regex` and `${tail}` use the path as received
+    raw_path: bool,
+    log: RuleLog,
 }
 
-/// Handler for Pingora’s `request_filter` phase
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct RewriteHandler {
-    router: Router<Vec<(Path, Rule)>>,
+/// Properties of the connection a request was received on
+#[derive(Debug, Clone, Copy, Default)]
+struct Connection {
+    /// Whether the connection uses TLS
+    tls: bool,
+    /// Address of the connected client
+    peer: Option<IpAddr>,
+    /// Port of the local address the connection was accepted on
+    local_port: Option<u16>,
+    /// HTTP version of the request
+    version: Version,
 }
 
-impl TryFrom<RewriteConf> for RewriteHandler {
-    type Error = Box<Error>;
+/// Request properties that rule conditions are checked against
+#[derive(Debug)]
+struct RequestInfo<'a> {
+    method: &'a Method,
+    scheme: RequestScheme,
+    client_ip: Option<IpAddr>,
+    /// Request host including the port if any, forwarded host if the proxy is trusted
+    host: Option<&'a str>,
+    local_port: Option<u16>,
+    now: SystemTime,
+    /// Request URI before any rewrites, also by other modules
+    original_uri: &'a Uri,
+    uri: &'a Uri,
+    /// Request path, decoded if `rewrite_decode_path` is enabled
+    path: &'a str,
+    headers: &'a HeaderMap,
+    /// Decoded query parameters of `uri`, parsed on first use
+    query_params: &'a OnceCell<Vec<(String, String)>>,
+    /// Cookies sent with the request, parsed on first use
+    cookies: &'a OnceCell<Vec<(Vec<u8>, Vec<u8>)>>,
+}
 
-    fn try_from(mut conf: RewriteConf) -> Result<Self, Self::Error> {
-        debug!("Rewrite configuration received: {conf:#?}");
+impl<'a> RequestInfo<'a> {
+    /// Returns the decoded query parameters, parsing the query string on first use.
+    fn query_params(&self) -> &'a [(String, String)] {
+        self.query_params
+            .get_or_init(|| parse_query(self.uri.query().unwrap_or("")))
+    }
 
-        let mut merger = Merger::new();
+    /// Looks up a cookie sent with the request, the first occurrence counts if it is listed
+    /// multiple times. The `Cookie` headers are parsed on first use.
+    fn cookie(&self, name: &str) -> Option<&'a [u8]> {
+        self.cookies
+            .get_or_init(|| {
+                let values = self.headers.get_all(header::COOKIE).into_iter();
+                parse_cookies(values.map(HeaderValue::as_bytes))
+                    .map(|(name, value)| (name.to_vec(), value.to_vec()))
+                    .collect()
+            })
+            .iter()
+            .find(|(cookie_name, _)| cookie_name == name.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+impl Rule {
+    /// Checks the conditions of the rule besides `from` against the request, returns the name of
+    /// the first setting not matched or `None` if the rule applies.
+    fn mismatch(&self, request: &RequestInfo<'_>) -> Option<&'static str> {
+        let RequestInfo {
+            method,
+            scheme,
+            client_ip,
+            host,
+            local_port,
+            now,
+            uri,
+            path,
+            headers,
+            ..
+        } = *request;
+
+        if self.active_from.is_some_and(|from| now < from) {
+            return Some("active_from");
+        }
+        if self.active_until.is_some_and(|until| now >= until) {
+            return Some("active_until");
+        }
+
+        if !self.methods.is_empty() && !self.methods.contains(method) {
+            return Some("methods");
+        }
 
-        // Add in reverse order, so that the first rule listed in configuration takes precedence.
-        conf.rewrite_rules.reverse();
+        if self.scheme.is_some_and(|expected| expected != scheme) {
+            return Some("scheme");
+        }
 
-        // Sort by prefix so that exact rules get priority.
-        conf.rewrite_rules.sort_by(|a, b| a.from.cmp(&b.from));
+        if !self.listen_port.is_empty()
+            && !local_port.is_some_and(|port| self.listen_port.contains(&port))
+        {
+            return Some("listen_port");
+        }
 
-        for rule in conf.rewrite_rules {
-            let path = rule.from.path.clone();
-            let from = rule.from;
-            let rule = Rule {
-                from_regex: rule.from_regex,
-                query_regex: rule.query_regex,
-                to: rule.to,
-                r#type: rule.r#type,
+        if !self.from_ip.is_empty() {
+            let Some(client_ip) = client_ip else {
+                return Some("from_ip");
+            };
+            let mut included = self.from_ip.iter().filter(|range| !range.negate).peekable();
+            if included.peek().is_some() && !included.any(|range| range.contains(client_ip)) {
+                return Some("from_ip");
+            }
+            if self
+                .from_ip
+                .iter()
+                .any(|range| range.negate && range.contains(client_ip))
+            {
+                return Some("from_ip");
+            }
+        }
+
+        if !self.from_host.is_empty() {
+            let Some(host) = host else {
+                return Some("from_host");
             };
+            if !self
+                .from_host
+                .iter()
+                .any(|pattern| host_matches(pattern, host))
+            {
+                return Some("from_host");
+            }
+        }
 
-            merger.push(from, (path, rule));
+        if let Some(trailing_slash) = self.trailing_slash {
+            if trailing_slash != (path.len() > 1 && path.ends_with('/')) {
+                return Some("trailing_slash");
+            }
         }
 
-        Ok(Self {
-            router: merger.merge(|rules| rules.cloned().collect::<Vec<_>>()),
-        })
+        // Paths already having the desired form aren’t redirected, preventing redirect loops
+        if self.r#type.is_slash() && self.slash_target(uri).is_none() {
+            return Some("type");
+        }
+
+        if !self.from_exclude.is_empty() {
+            let path = if self.case_insensitive {
+                Path::new(path.to_ascii_lowercase())
+            } else {
+                Path::new(path)
+            };
+            if self
+                .from_exclude
+                .iter()
+                .any(|exclude| exclude.matches(b"", &path, false).any())
+            {
+                return Some("from_exclude");
+            }
+        }
+
+        if let Some(from_glob) = &self.from_glob {
+            if !from_glob.matches(path) {
+                return Some("from_glob");
+            }
+        }
+
+        if !self.extensions.is_empty() {
+            let name = path.rsplit('/').next().unwrap_or(path).as_bytes();
+            if !self.extensions.iter().any(|extension| {
+                name.len() >= extension.len()
+                    && name[name.len() - extension.len()..]
+                        .eq_ignore_ascii_case(extension.as_bytes())
+            }) {
+                return Some("extensions");
+            }
+        }
+
+        if !self
+            .has_header
+            .iter()
+            .all(|name| headers.contains_key(name))
+        {
+            return Some("has_header");
+        }
+        if self
+            .lacks_header
+            .iter()
+            .any(|name| headers.contains_key(name))
+        {
+            return Some("lacks_header");
+        }
+
+        if !self
+            .regex_mode
+            .matches(&self.from_regex, self.regex_path(uri, path))
+        {
+            return Some("from_regex");
+        }
+
+        if !self.query.matches(uri.query()) {
+            return Some("query");
+        }
+
+        if !self
+            .regex_mode
+            .matches(&self.query_regex, uri.query().unwrap_or(""))
+        {
+            return Some("query_regex");
+        }
+
+        if let Some(uri_regex) = &self.uri_regex {
+            if !uri_regex.matches(path_and_query(uri)) {
+                return Some("uri_regex");
+            }
+        }
+
+        for (name, regex) in &self.header_regex {
+            if !regex.matches_bytes(&header_value(headers, name)) {
+                return Some("header_regex");
+            }
+        }
+
+        if let Some(ua_regex) = &self.ua_regex {
+            if !ua_regex.matches_bytes(&header_value(headers, &header::USER_AGENT)) {
+                return Some("ua_regex");
+            }
+        }
+
+        for (name, regex) in &self.cookie_regex {
+            if !regex.matches_bytes(request.cookie(name).unwrap_or_default()) {
+                return Some("cookie_regex");
+            }
+        }
+
+        if self.query_match.is_some() || !self.query_params.is_empty() {
+            let query = request.query_params();
+
+            if let Some(query_match) = &self.query_match {
+                if !query_match.matches(query, self.query_match_strict) {
+                    return Some("query_match");
+                }
+            }
+
+            for (name, regex) in &self.query_params {
+                let mut values = query
+                    .iter()
+                    .filter(|(param, _)| param == name)
+                    .map(|(_, value)| value)
+                    .peekable();
+                let matches = if values.peek().is_some() {
+                    values.any(|value| regex.matches(value))
+                } else {
+                    regex.negate
+                };
+                if !matches {
+                    return Some("query_params");
+                }
+            }
+        }
+
+        if let Some(subst) = &self.subst {
+            if !subst.matches(path) {
+                return Some("subst");
+            }
+        }
+
+        if let Some(accept) = &self.accept {
+            let values = headers.get_all(header::ACCEPT);
+            if !accepts_media_type(values.iter().map(HeaderValue::as_bytes), accept) {
+                return Some("accept");
+            }
+        }
+
+        if !self.accept_language.is_empty() && self.language(headers).is_none() {
+            return Some("accept_language");
+        }
+
+        None
     }
-}
 
-#[async_trait]
-impl RequestFilter for RewriteHandler {
-    type Conf = RewriteConf;
+    /// Returns the path that `from_regex` is matched against: the path as received with
+    /// `raw_path` enabled, otherwise the path as matched.
+    fn regex_path<'a>(&self, uri: &'a Uri, path: &'a str) -> &'a str {
+        if self.raw_path {
+            uri.path()
+        } else {
+            path
+        }
+    }
 
-    type CTX = ();
+    /// Determines the parts of the path as received that are matched by the wildcards of the
+    /// `from` pattern. Returns `None` if the path as received doesn’t match the pattern, e.g.
+    /// because normalization resolved `..` segments.
+    fn raw_tails(&self, uri: &Uri) -> Option<Vec<Vec<u8>>> {
+        let raw_path = uri.path();
+        let tail = if self.from.matcher.path.is_empty() {
+            raw_path.as_bytes().to_vec()
+        } else if self.case_insensitive {
+            // ASCII lowercasing preserves byte offsets, so the tail can be taken from the
+            // original path to keep its casing.
+            let prefix = Path::new(self.from.matcher.path.to_ascii_lowercase());
+            let lowercase = raw_path.to_ascii_lowercase();
+            match prefix.remove_prefix_from(&lowercase)? {
+                tail if lowercase.as_bytes().ends_with(&tail) => {
+                    raw_path.as_bytes()[raw_path.len() - tail.len()..].to_vec()
+                }
+                tail => tail,
+            }
+        } else {
+            self.from.matcher.path.remove_prefix_from(raw_path)?
+        };
+        self.from.capture(&tail, self.case_insensitive)
+    }
 
-    fn new_ctx() -> Self::CTX {}
+    /// Determines the language of `accept_language` preferred by the client.
+    fn language(&self, headers: &HeaderMap) -> Option<&str> {
+        let values = headers.get_all(header::ACCEPT_LANGUAGE);
+        preferred_language(
+            values.iter().map(HeaderValue::as_bytes),
+            &self.accept_language,
+        )
+    }
 
-    async fn request_filter(
+    /// Resolves the variables in `to` or `body` for a request, `tails` being the parts of the
+    /// path matched by the wildcards of the `from` pattern.
+    fn interpolate(
         &self,
-        session: &mut impl SessionWrapper,
-        _ctx: &mut Self::CTX,
-    ) -> Result<RequestFilterResult, Box<Error>> {
-        let path = session.uri().path();
-        trace!("Determining rewrite rules for path {path}");
+        template: &VariableInterpolation,
+        request: &RequestInfo<'_>,
+        tails: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let query = self.query(request, tails);
+        self.resolve(template, &query, request, tails, None)
+    }
 
-        let list = if let Some(list) = self.router.lookup("", path) {
-            list
-        } else {
-            trace!("No match for the path");
-            return Ok(RequestFilterResult::Unhandled);
+    /// Applies `query_remove`, `query_set` and `query_add` to the query string of the request.
+    /// Remaining parameters keep their position and encoding, parameters set replace the first
+    /// occurrence of the name or are appended like the added parameters.
+    fn query<'a>(&self, request: &RequestInfo<'a>, tails: &[Vec<u8>]) -> Cow<'a, [u8]> {
+        let query = request.uri.query().unwrap_or("");
+        if self.query_remove.is_empty() && self.query_set.is_empty() && self.query_add.is_empty() {
+            return Cow::Borrowed(query.as_bytes());
+        }
+
+        let mut result = Vec::new();
+        let mut push = |param: &[u8]| {
+            if !result.is_empty() {
+                result.push(b'&');
+            }
+            result.extend_from_slice(param);
+        };
+        let encode = |name: &str, template: &VariableInterpolation| {
+            let value = self.resolve(template, query.as_bytes(), request, tails, None);
+            let mut param = form_urlencoded::byte_serialize(name.as_bytes()).collect::<String>();
+            param.push('=');
+            param.extend(form_urlencoded::byte_serialize(&value));
+            param
         };
 
-        trace!("Applying rewrite rules: {list:?}");
+        let mut set = vec![false; self.query_set.len()];
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let name = param.split('=').next().unwrap_or(param);
+            let name = form_urlencoded::parse(name.as_bytes())
+                .next()
+                .map(|(name, _)| name)
+                .unwrap_or_default();
+            if self
+                .query_remove
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => name.starts_with(prefix),
+                    None => name == pattern.as_str(),
+                })
+            {
+                continue;
+            }
+            if let Some(index) = self
+                .query_set
+                .iter()
+                .position(|(set_name, _)| *set_name == name)
+            {
+                if !set[index] {
+                    set[index] = true;
+                    let (name, template) = &self.query_set[index];
+                    push(encode(name, template).as_bytes());
+                }
+                continue;
+            }
+            push(param.as_bytes());
+        }
+
+        for ((name, template), _) in self.query_set.iter().zip(set).filter(|(_, set)| !set) {
+            push(encode(name, template).as_bytes());
+        }
+        for (name, template) in &self.query_add {
+            push(encode(name, template).as_bytes());
+        }
+
+        Cow::Owned(result)
+    }
 
-        // Iterate in reverse order, merging puts rules in reverse order of precedence.
-        for (rule_path, rule) in list.iter().rev() {
-            if let Some(from_regex) = &rule.from_regex {
-                if !from_regex.matches(session.uri().path()) {
-                    continue;
+    /// Resolves the variables in a template, with `query` being the value of `${query}`. If
+    /// `escape` is given, the characters it contains are percent-encoded in variable values
+    /// other than `${query}`.
+    fn resolve(
+        &self,
+        template: &VariableInterpolation,
+        query: &[u8],
+        request: &RequestInfo<'_>,
+        tails: &[Vec<u8>],
+        escape: Option<&'static AsciiSet>,
+    ) -> Vec<u8> {
+        self.resolve_with(template, query, request, tails, escape, &Cell::new(false))
+    }
+
+    /// Resolves the variables like [`Rule::resolve`], setting `missing` if a key is missing in a
+    /// required map.
+    fn resolve_with(
+        &self,
+        template: &VariableInterpolation,
+        query: &[u8],
+        request: &RequestInfo<'_>,
+        tails: &[Vec<u8>],
+        escape: Option<&'static AsciiSet>,
+        missing: &Cell<bool>,
+    ) -> Vec<u8> {
+        let RequestInfo {
+            method,
+            scheme,
+            client_ip,
+            host,
+            local_port,
+            now,
+            original_uri,
+            uri,
+            path,
+            headers,
+            ..
+        } = *request;
+
+        // Capture groups of `from_regex` and `uri_regex` (in this order of precedence), unmatched
+        // groups resolve to an empty string
+        let captures = self
+            .from_regex
+            .iter()
+            .map(|regex| (regex, self.regex_path(uri, path)))
+            .chain(
+                self.uri_regex
+                    .iter()
+                    .map(|regex| (regex, path_and_query(uri))),
+            )
+            .filter_map(|(regex, value)| Some((&regex.regex, regex.captures(value)?)))
+            .collect::<Vec<_>>();
+        let capture = |name: &str| {
+            captures.iter().find_map(|(regex, captures)| {
+                let group = if let Ok(index) = name.parse::<usize>() {
+                    (index < captures.len()).then(|| captures.get(index))?
+                } else {
+                    regex
+                        .capture_names()
+                        .any(|group| group == Some(name))
+                        .then(|| captures.name(name))?
+                };
+                Some(group.unwrap_or(b""))
+            })
+        };
+
+        let port = local_port.map(|port| port.to_string());
+
+        // Time values are derived from the request time, so that all occurrences agree
+        let times = OnceCell::new();
+        let times = || {
+            times.get_or_init(|| {
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                [
+                    since_epoch.as_secs().to_string(),
+                    since_epoch.as_millis().to_string(),
+                    DateTime::<Utc>::from(now).to_rfc3339_opts(SecondsFormat::Secs, true),
+                ]
+            })
+        };
+        let method = if method.as_str().bytes().any(|c| c.is_ascii_lowercase()) {
+            Cow::Owned(method.as_str().to_ascii_uppercase())
+        } else {
+            Cow::Borrowed(method.as_str())
+        };
+        let remote_addr = client_ip.map(|ip| ip.to_string());
+        let remote_addr_uri = client_ip.map(|ip| match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        });
+        let lookup_borrowed = |name: &str| match name {
+            "tail" => tails.last().map(Vec::as_slice),
+            "query" => Some(query),
+            "path" => Some(uri.path().as_bytes()),
+            "uri" => Some(match uri.query() {
+                Some(query) if !query.is_empty() => path_and_query(uri).as_bytes(),
+                _ => uri.path().as_bytes(),
+            }),
+            "original_path" => Some(original_uri.path().as_bytes()),
+            "original_query" => Some(original_uri.query().unwrap_or("").as_bytes()),
+            "original_uri" => Some(path_and_query(original_uri).as_bytes()),
+            "host" => host.map(|host| strip_port(host).as_bytes()),
+            "scheme" => Some(match scheme {
+                RequestScheme::Http => &b"http"[..],
+                RequestScheme::Https => b"https",
+            }),
+            "port" => port.as_deref().map(str::as_bytes),
+            "method" => Some(method.as_bytes()),
+            "remote_addr" => remote_addr.as_deref().map(str::as_bytes),
+            "remote_addr_uri" => remote_addr_uri.as_deref().map(str::as_bytes),
+            "time_unix" => Some(times()[0].as_bytes()),
+            "time_msec" => Some(times()[1].as_bytes()),
+            "time_iso8601" => Some(times()[2].as_bytes()),
+            "lang" if !self.accept_language.is_empty() => self.language(headers).map(str::as_bytes),
+            name => {
+                if let Some(index) = name
+                    .strip_prefix("tail")
+                    .and_then(|index| index.parse::<usize>().ok())
+                {
+                    tails.get(index.checked_sub(1)?).map(Vec::as_slice)
+                } else if let Some(name) = name.strip_prefix("cookie_") {
+                    request.cookie(name)
+                } else if let Some(name) = name.strip_prefix("query_") {
+                    request
+                        .query_params()
+                        .iter()
+                        .find(|(param, _)| param == name)
+                        .map(|(_, value)| value.as_bytes())
+                } else {
+                    capture(name)
                 }
             }
+        };
+        let lookup = |name: &str| match name.strip_prefix("http_") {
+            Some(name) => Some(header_value(headers, name.replace('_', "-"))),
+            None => lookup_borrowed(name).map(Cow::Borrowed),
+        };
 
-            if let Some(query_regex) = &rule.query_regex {
-                if !query_regex.matches(session.uri().query().unwrap_or("")) {
-                    continue;
+        let map = |name: &str, key: &[u8]| {
+            let map = self.maps.get(name)?;
+            match map.get(key) {
+                Some(value) => Some(Cow::Borrowed(value)),
+                None if map.required => {
+                    missing.set(true);
+                    None
                 }
+                None => Some(Cow::Borrowed(&b""[..])),
             }
+        };
 
-            let tail = rule_path
-                .remove_prefix_from(path)
-                .unwrap_or(path.as_bytes().to_owned());
-            trace!(
-                "Matched rule for path `{}`, tail is: {tail:?}",
-                String::from_utf8_lossy(rule_path)
-            );
+        if let Some(escape) = escape {
+            template.interpolate_with_maps(&lookup, &map, &|name, value| {
+                if name == "query" {
+                    value
+                } else {
+                    Cow::Owned(percent_encode(&value, escape).to_string().into_bytes())
+                }
+            })
+        } else {
+            template.interpolate_with_maps(&lookup, &map, &|_, value| value)
+        }
+    }
 
-            let target = rule.to.interpolate(|name| match name {
-                "tail" => Some(&tail),
-                "query" => Some(session.uri().query().unwrap_or("").as_bytes()),
-                name => {
-                    if let Some(name) = name.strip_prefix("http_") {
-                        Some(
-                            session
-                                .req_header()
-                                .headers
-                                .get(name.replace('_', "-"))
-                                .map(HeaderValue::as_bytes)
-                                .unwrap_or(b""),
-                        )
-                    } else {
-                        None
-                    }
+    /// Lists the values of the rule that variables are resolved in.
+    fn templates(&self) -> impl Iterator<Item = &VariableInterpolation> {
+        self.request_templates().chain(&self.body)
+    }
+
+    /// Lists the values of the rule that request variables are resolved in, `body` is excluded
+    /// as it only knows `${location}` with redirects.
+    fn request_templates(&self) -> impl Iterator<Item = &VariableInterpolation> {
+        std::iter::once(&self.to)
+            .chain(&self.to_query)
+            .chain(&self.to_host)
+            .chain(self.headers.iter().map(|(_, value)| value))
+            .chain(self.query_set.iter().map(|(_, value)| value))
+            .chain(self.query_add.iter().map(|(_, value)| value))
+    }
+
+    /// Checks whether a variable can ever be resolved for this rule, mirroring the lookup in
+    /// [`Rule::resolve_with`].
+    fn knows_variable(&self, name: &str) -> bool {
+        let wildcards = self.from.wildcards();
+        match name {
+            "tail" => wildcards > 0,
+            "query" | "path" | "uri" | "original_path" | "original_query" | "original_uri"
+            | "host" | "scheme" | "port" | "method" | "remote_addr" | "remote_addr_uri"
+            | "time_unix" | "time_msec" | "time_iso8601" => true,
+            "lang" if !self.accept_language.is_empty() => true,
+            name => {
+                if let Some(index) = name
+                    .strip_prefix("tail")
+                    .and_then(|index| index.parse::<usize>().ok())
+                {
+                    (1..=wildcards).contains(&index)
+                } else if let Some(name) = ["cookie_", "query_", "http_"]
+                    .iter()
+                    .find_map(|prefix| name.strip_prefix(prefix))
+                {
+                    !name.is_empty()
+                } else {
+                    self.from_regex
+                        .iter()
+                        .chain(&self.uri_regex)
+                        .filter(|regex| !regex.negate)
+                        .any(|regex| match name.parse::<usize>() {
+                            Ok(index) => index < regex.regex.captures_len(),
+                            Err(_) => regex.regex.capture_names().any(|group| group == Some(name)),
+                        })
                 }
-            });
+            }
+        }
+    }
 
-            match rule.r#type {
-                RewriteType::Internal => {
-                    let uri = match target.as_slice().try_into() {
-                        Ok(uri) => uri,
-                        Err(err) => {
-                            error!("Could not parse {target:?} as URI: {err}");
-                            return Ok(RequestFilterResult::Unhandled);
-                        }
-                    };
-                    session.set_uri(uri);
-                    break;
+    /// Returns the first variable used by the rule that can never be resolved for it.
+    fn unknown_variable(&self) -> Option<&str> {
+        let redirect = self.r#type.is_redirect();
+        let body = self.body.iter().flat_map(|body| {
+            body.variables().into_iter().filter(move |name| {
+                if redirect {
+                    !matches!(*name, "location" | "target")
+                } else {
+                    !self.knows_variable(name)
                 }
-                RewriteType::Redirect | RewriteType::Permanent => {
-                    let location = match String::from_utf8(target) {
-                        Ok(location) => location,
-                        Err(err) => {
-                            error!("Failed converting redirect target to UTF-8: {err}");
-                            return Ok(RequestFilterResult::Unhandled);
-                        }
-                    };
-                    let status = if rule.r#type == RewriteType::Redirect {
-                        StatusCode::TEMPORARY_REDIRECT
-                    } else {
-                        StatusCode::PERMANENT_REDIRECT
-                    };
-                    redirect_response(session, status, &location).await?;
-                    return Ok(RequestFilterResult::ResponseSent);
+            })
+        });
+        self.request_templates()
+            .flat_map(VariableInterpolation::variables)
+            .filter(|name| !self.knows_variable(name))
+            .chain(body)
+            .next()
+    }
+
+    /// Checks whether resolving the values of the rule requires a key missing in a required map,
+    /// the rule doesn’t apply then.
+    fn missing_map_key(&self, request: &RequestInfo<'_>, tails: &[Vec<u8>]) -> bool {
+        if self.maps.is_empty() {
+            return false;
+        }
+
+        let missing = Cell::new(false);
+        let query = self.query(request, tails);
+        for template in self.templates() {
+            self.resolve_with(template, &query, request, tails, None, &missing);
+        }
+        missing.get()
+    }
+
+    /// Resolves `to_host` for a request, returns an error if the result isn’t a valid host.
+    fn host(
+        &self,
+        request: &RequestInfo<'_>,
+        tails: &[Vec<u8>],
+    ) -> Result<Option<Authority>, Box<Error>> {
+        let Some(to_host) = &self.to_host else {
+            return Ok(None);
+        };
+
+        let host = self.interpolate(to_host, request, tails);
+        let host = String::from_utf8_lossy(&host);
+        if host.is_empty() || host.contains(|c: char| c == '/' || c == '@' || c.is_whitespace()) {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("rewrite rule produced invalid host {host:?}"),
+            ));
+        }
+        Authority::try_from(host.as_ref()).map(Some).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                format!("rewrite rule produced invalid host {host:?}"),
+                err,
+            )
+        })
+    }
+
+    /// Produces the redirect target of `add_slash` and `remove_slash` rules: the request URI with
+    /// the trailing slash added to or removed from the path. Returns `None` for other rule types
+    /// and if the path already has the desired form, is `/` or should be left alone otherwise.
+    fn slash_target(&self, uri: &Uri) -> Option<Uri> {
+        let path = uri.path();
+        let trimmed = path.trim_end_matches('/');
+
+        // Redirecting `//example.com/` would produce a protocol-relative URL
+        if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("/\\") {
+            return None;
+        }
+        if self.skip_files
+            && trimmed
+                .rsplit('/')
+                .next()
+                .is_some_and(|segment| segment.contains('.'))
+        {
+            return None;
+        }
+
+        let mut target = match self.r#type {
+            RewriteType::AddSlash if trimmed.len() == path.len() => format!("{path}/"),
+            RewriteType::RemoveSlash if trimmed.len() < path.len() => trimmed.to_owned(),
+            _ => return None,
+        };
+        if let Some(query) = uri.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        target.parse().ok()
+    }
+
+    /// Produces the target the request should be rewritten or redirected to, `tails` being the
+    /// parts of the path matched by the wildcards of the `from` pattern.
+    fn apply(&self, request: &RequestInfo<'_>, tails: &[Vec<u8>]) -> Result<Target, Box<Error>> {
+        let RequestInfo { uri, path, .. } = *request;
+
+        if let Some(host) = &self.redirect_to_host {
+            let target = Uri::builder()
+                .scheme(Scheme::HTTPS)
+                .authority(host.clone())
+                .path_and_query(path_and_query(uri))
+                .build()
+                .map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        "failed building redirect target",
+                        err,
+                    )
+                })?;
+            return Ok(Target::redirect(target));
+        }
+
+        let query = self.query(request, tails);
+        let mut target = if let Some(subst) = &self.subst {
+            subst.apply(path)
+        } else if self.strip_prefix {
+            // Rest of the path as received if possible, otherwise as matched
+            self.raw_tails(uri)
+                .and_then(|mut raw_tails| raw_tails.pop())
+                .or_else(|| tails.last().cloned())
+                .unwrap_or_else(|| b"/".to_vec())
+        } else if let Some(to_query) = &self.to_query {
+            // `to` is only the path here, anything resembling a query string stays part of it
+            let path = self.resolve(&self.to, &query, request, tails, None);
+            let mut target = percent_encode(&path, TAIL_ESC_CHARSET)
+                .to_string()
+                .into_bytes();
+            let to_query = self.resolve(
+                to_query,
+                &query,
+                request,
+                tails,
+                Some(QUERY_VALUE_ESC_CHARSET),
+            );
+            if !to_query.is_empty() {
+                target.push(b'?');
+                target.extend_from_slice(&to_query);
+            }
+            target
+        } else {
+            self.resolve(&self.to, &query, request, tails, None)
+        };
+
+        if self.append_query && !query.is_empty() {
+            target.push(if target.contains(&b'?') { b'&' } else { b'?' });
+            target.extend_from_slice(&query);
+        }
+
+        // Control characters like CR and LF could only come from variables, e.g. decoded query
+        // parameters. Such requests are hostile, so these aren’t processed unchanged either.
+        if target.iter().any(u8::is_ascii_control) {
+            return Err(Error::explain(
+                ErrorType::HTTPStatus(500),
+                format!(
+                    "rewrite target {:?} contains control characters",
+                    String::from_utf8_lossy(&target)
+                ),
+            ));
+        }
+
+        let target = parse_target(target, self.r#type, self.protocol_relative)?;
+        match self.host(request, tails)? {
+            Some(host) if self.r#type.is_redirect() => {
+                let (Some(path), Some(location)) = (&target.uri, &target.location) else {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite target {:?} cannot be combined with a host",
+                            target.location
+                        ),
+                    ));
+                };
+                let uri = Uri::builder()
+                    .scheme(match request.scheme {
+                        RequestScheme::Http => Scheme::HTTP,
+                        RequestScheme::Https => Scheme::HTTPS,
+                    })
+                    .authority(host)
+                    .path_and_query(path_and_query(path))
+                    .build()
+                    .map_err(|err| {
+                        Error::because(
+                            ErrorType::InternalError,
+                            "failed building redirect target",
+                            err,
+                        )
+                    })?;
+                let mut location_with_host = uri.to_string();
+                if let Some((_, fragment)) = location.split_once('#') {
+                    location_with_host.push('#');
+                    location_with_host.push_str(fragment);
                 }
+                Ok(Target {
+                    uri: Some(uri),
+                    location: Some(location_with_host),
+                })
             }
+            _ => Ok(target),
         }
+    }
+}
 
-        Ok(RequestFilterResult::Unhandled)
+/// Shares compiled regular expressions between rules using identical ones
+#[derive(Debug, Default)]
+struct RegexInterner {
+    /// Compiled regular expressions by pattern and case-insensitivity
+    regexes: HashMap<(String, bool), Arc<Regex>>,
+}
+
+impl RegexInterner {
+    /// Returns the regular expression with a compiled expression shared with previous identical
+    /// ones, made case-insensitive if requested.
+    fn intern(
+        &mut self,
+        regex: &RegexMatch,
+        case_insensitive: bool,
+    ) -> Result<RegexMatch, RegexError> {
+        let key = (regex.regex.as_str().to_owned(), case_insensitive);
+        let shared = match self.regexes.entry(key) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                let compiled = if case_insensitive {
+                    regex.case_insensitive()?.regex
+                } else {
+                    regex.regex.clone()
+                };
+                entry.insert(compiled).clone()
+            }
+        };
+        Ok(RegexMatch {
+            regex: shared,
+            negate: regex.negate,
+        })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Minimal number of `from_regex` expressions among the rules looked up via the same path for a
+/// [`RegexPrefilter`] to be used, a combined search doesn’t pay off for few expressions.
+const PREFILTER_MIN_REGEXES: usize = 4;
 
-    use pandora_module_utils::pingora::{RequestHeader, TestSession};
-    use pandora_module_utils::FromYaml;
-    use test_log::test;
+/// Combined search for the `from_regex` expressions of rules looked up via the same path, so that
+/// a single search determines which of these rules can apply
+#[derive(Debug, Clone)]
+struct RegexPrefilter {
+    set: RegexSet,
+    /// For each rule in the list: the combination mode and the positions of its expressions in
+    /// `set`, `None` for rules that aren’t prefiltered
+    rules: Vec<Option<(RegexMode, Vec<usize>)>>,
+}
 
-    fn make_handler(conf: &str) -> RewriteHandler {
-        <RewriteHandler as RequestFilter>::Conf::from_yaml(conf)
-            .unwrap()
-            .try_into()
-            .unwrap()
-    }
+impl RegexPrefilter {
+    /// Creates a prefilter for the rules if enough of them use `from_regex`. Negated expressions
+    /// and expressions using look-around cannot be added to the set: rules combining all
+    /// expressions only have their other expressions prefiltered, rules requiring any expression
+    /// to match aren’t prefiltered then.
+    /// Rules with `raw_path` match a different path and aren’t prefiltered either.
+    fn new(rules: &[(Path, Rule)]) -> Option<Self> {
+        let mut patterns = Vec::new();
+        let rules = rules
+            .iter()
+            .map(|(_, rule)| {
+                let regexes = rule
+                    .from_regex
+                    .iter()
+                    .filter(|regex| !regex.negate && regex.regex.is_linear())
+                    .collect::<Vec<_>>();
+                if rule.raw_path
+                    || regexes.is_empty()
+                    || (rule.regex_mode == RegexMode::Any && regexes.len() < rule.from_regex.len())
+                {
+                    return None;
+                }
+
+                let positions = regexes
+                    .into_iter()
+                    .map(|regex| {
+                        patterns.push(if rule.from_regex_case_insensitive {
+                            format!("(?i){}", regex.regex.as_str())
+                        } else {
+                            regex.regex.as_str().to_owned()
+                        });
+                        patterns.len() - 1
+                    })
+                    .collect();
+                Some((rule.regex_mode, positions))
+            })
+            .collect();
+
+        if patterns.len() < PREFILTER_MIN_REGEXES {
+            return None;
+        }
+
+        // The expressions compiled individually, the set might still exceed the size limit
+        let set = RegexSet::new(patterns).ok()?;
+        Some(Self { set, rules })
+    }
+
+    /// Determines for each rule in the list whether it can apply to the path, considering its
+    /// `from_regex` expressions in the set only.
+    fn candidates(&self, path: &str) -> Vec<bool> {
+        let matches = self.set.matches(path.as_bytes());
+        self.rules
+            .iter()
+            .map(|rule| match rule {
+                None => true,
+                Some((RegexMode::All, positions)) => {
+                    positions.iter().all(|position| matches.matched(*position))
+                }
+                Some((RegexMode::Any, positions)) => {
+                    positions.iter().any(|position| matches.matched(*position))
+                }
+            })
+            .collect()
+    }
+}
+
+/// The rules looked up via a particular path, in reverse order of precedence
+#[derive(Debug, Clone)]
+struct RuleList {
+    rules: Vec<(Path, Rule)>,
+    prefilter: Option<RegexPrefilter>,
+}
+
+impl RuleList {
+    fn new(rules: Vec<(Path, Rule)>) -> Self {
+        let prefilter = RegexPrefilter::new(&rules);
+        Self { rules, prefilter }
+    }
+}
+
+// The prefilter is derived from the rules, there is no need to compare it
+impl PartialEq for RuleList {
+    fn eq(&self, other: &Self) -> bool {
+        self.rules == other.rules
+    }
+}
+
+impl Eq for RuleList {}
+
+/// Returns the path and query string of the request as received
+fn path_and_query(uri: &Uri) -> &str {
+    uri.path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+}
+
+/// Retrieves the value of a request header, empty for missing headers. Multiple values are joined
+/// with `, `. This is used both for header conditions and `${http_<name>}` variables.
+fn header_value(headers: &HeaderMap, name: impl AsHeaderName) -> Cow<'_, [u8]> {
+    let mut values = headers.get_all(name).iter();
+    match (values.next(), values.next()) {
+        (None, _) => Cow::Borrowed(b""),
+        (Some(value), None) => Cow::Borrowed(value.as_bytes()),
+        (Some(first), Some(second)) => {
+            let mut result = first.as_bytes().to_vec();
+            for value in std::iter::once(second).chain(values) {
+                result.extend_from_slice(b", ");
+                result.extend_from_slice(value.as_bytes());
+            }
+            Cow::Owned(result)
+        }
+    }
+}
+
+/// Determines the request host from the `Host` header or the request URI, including the port if
+/// any.
+fn request_host<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> Option<&'a str> {
+    headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            let authority = uri.authority()?.as_str();
+            authority.rsplit('@').next()
+        })
+}
+
+/// Parses a query string into decoded name and value pairs, following form encoding rules: `+`
+/// is decoded as a space, parameters without `=` have an empty value.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect()
+}
+
+/// Removes the port from a host like `example.com:8080` or `[::1]:8080`.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+/// Checks whether a host matches a lowercase `from_host` pattern. The port is only considered if
+/// the pattern specifies one.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = if strip_port(pattern).len() == pattern.len() {
+        strip_port(host)
+    } else {
+        host
+    };
+    let host = host.strip_suffix('.').unwrap_or(host);
+
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        host.len() > suffix.len() + 1
+            && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+            && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+    } else {
+        host.eq_ignore_ascii_case(pattern)
+    }
+}
+
+/// Escapes the characters with special meaning in HTML.
+fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Target produced by a rule
+#[derive(Debug)]
+struct Target {
+    /// New URI for internal rewrites. For redirects the target without fragment if it is a path
+    /// or an absolute URI, `None` for other targets like relative ones.
+    uri: Option<Uri>,
+    /// Redirect target exactly as it should be sent in the `Location` header, `None` for internal
+    /// rewrites
+    location: Option<String>,
+}
+
+impl Target {
+    /// Redirect target given as URI
+    fn redirect(uri: Uri) -> Self {
+        Self {
+            location: Some(uri.to_string()),
+            uri: Some(uri),
+        }
+    }
+}
+
+/// Checks whether a URI is a path like `/file?query` or an absolute URI like
+/// `https://example.com/file`
+fn is_path_or_absolute(uri: &Uri) -> bool {
+    let is_path =
+        uri.scheme().is_none() && uri.authority().is_none() && uri.path().starts_with('/');
+    let is_absolute = uri.scheme().is_some() && uri.authority().is_some();
+    is_path || is_absolute
+}
+
+/// Parses the rewrite target. Internal rewrites require a path like `/file?query`. Redirect
+/// targets are sent as they are and only need to be valid UTF-8, their URI is determined where
+/// possible. Redirects to protocol-relative URLs like `//example.com/file` require
+/// `protocol_relative`.
+fn parse_target(
+    target: Vec<u8>,
+    r#type: RewriteType,
+    protocol_relative: bool,
+) -> Result<Target, Box<Error>> {
+    // Browsers treat backslashes like slashes here
+    if r#type.is_redirect()
+        && !protocol_relative
+        && (target.starts_with(b"//") || target.starts_with(b"/\\"))
+    {
+        return Err(Error::explain(
+            ErrorType::InternalError,
+            format!(
+                "protocol-relative rewrite target {:?} is not allowed",
+                String::from_utf8_lossy(&target)
+            ),
+        ));
+    }
+
+    if r#type.is_redirect() {
+        let location = String::from_utf8(target).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                "redirect target is not valid UTF-8",
+                err,
+            )
+        })?;
+        // `http::Uri` has no fragments, these are only kept in the location
+        let uri = location
+            .split('#')
+            .next()
+            .and_then(|target| target.parse::<Uri>().ok())
+            .filter(is_path_or_absolute);
+        return Ok(Target {
+            uri,
+            location: Some(location),
+        });
+    }
+
+    let uri = Uri::from_maybe_shared(target).map_err(|err| {
+        Error::because(
+            ErrorType::InternalError,
+            "rewrite target is not a valid URI",
+            err,
+        )
+    })?;
+
+    if uri.scheme().is_none() && uri.authority().is_none() && uri.path().starts_with('/') {
+        Ok(Target {
+            uri: Some(uri),
+            location: None,
+        })
+    } else {
+        Err(Error::explain(
+            ErrorType::InternalError,
+            format!("rewrite target {uri} is not allowed for {type:?} rewrites", type = r#type),
+        ))
+    }
+}
+
+/// A request to be evaluated by [`RewriteHandler::apply`]
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRequest {
+    /// Request method, relevant for `methods` conditions
+    pub method: Method,
+    /// Request URI, containing path and query string
+    pub uri: Uri,
+    /// Request headers, relevant for `accept` conditions and `${http_*}` variables
+    pub headers: HeaderMap,
+    /// Whether the request was received via a TLS connection, relevant for `scheme` conditions
+    pub tls: bool,
+    /// Address of the connected client, relevant for `from_ip` conditions
+    pub client_ip: Option<IpAddr>,
+    /// Port of the local address the connection was accepted on, relevant for `listen_port`
+    /// conditions
+    pub local_port: Option<u16>,
+    /// HTTP version of the request, the URI scheme is only relevant for `scheme` conditions with
+    /// HTTP/2
+    pub version: Version,
+}
+
+/// Logs the evaluation of a rule with `log` setting enabled
+fn log_evaluation(rule: usize, path: &str, matched: bool) {
+    if matched {
+        info!("Rewrite rule {rule} applied to path {path}");
+    } else {
+        info!("Rewrite rule {rule} evaluated for path {path}, not applied");
+    }
+}
+
+/// Outcome of applying the rewrite rules to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteDecision {
+    /// Index of the applied rule in the `rewrite_rules` list
+    pub rule: usize,
+    /// Rewriting type of the applied rule, with the rule flags considered
+    pub r#type: RewriteType,
+    /// Status code of the response, `None` for internal rewrites
+    pub status: Option<StatusCode>,
+    /// New URI for internal rewrites, redirect target without fragment for redirects to paths
+    /// and absolute URIs, request URI otherwise
+    pub uri: Uri,
+    /// Redirect target as sent in the `Location` header, `None` unless the rule redirects. Unlike
+    /// `uri` this keeps the fragment and can be a relative URI.
+    pub location: Option<String>,
+    /// Request header to receive the original URI on internal rewrites
+    pub preserve_original_in: Option<HeaderName>,
+    /// New request host for internal rewrites with `to_host`
+    pub host: Option<Authority>,
+    /// Response body for redirects if configured for the rule, always present for `response`
+    /// rules
+    pub body: Option<Vec<u8>>,
+    /// `Content-Type` header of the response for `response` rules
+    pub content_type: Option<HeaderValue>,
+    /// Additional headers to be sent with the response, empty for internal rewrites
+    pub headers: HeaderMap,
+    /// Rules applied before `rule` because of their `continue` setting, in the order of
+    /// application
+    pub previous_rules: Vec<usize>,
+    /// Mappings to be applied to `Location` and `Set-Cookie` headers of upstream responses, in
+    /// the order the rules were applied
+    pub path_mappings: Vec<PathMapping>,
+}
+
+/// Simplified outcome of applying the rewrite rules to a request, e.g. for table-driven tests
+///
+/// This can be converted from the result of [`RewriteHandler::apply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RewriteOutcome {
+    /// No rule applies, the request is processed unchanged
+    Unchanged,
+    /// The request is rewritten internally
+    Rewritten {
+        /// New request URI
+        uri: Uri,
+    },
+    /// The handler responds to the request, e.g. with a redirect
+    Respond {
+        /// Status code of the response
+        status: StatusCode,
+        /// Redirect target, `None` for responses other than redirects
+        location: Option<String>,
+        /// Additional headers to be sent with the response
+        headers: HeaderMap,
+    },
+}
+
+impl From<Option<RewriteDecision>> for RewriteOutcome {
+    fn from(decision: Option<RewriteDecision>) -> Self {
+        let Some(decision) = decision else {
+            return Self::Unchanged;
+        };
+        match decision.status {
+            None => Self::Rewritten { uri: decision.uri },
+            Some(status) => Self::Respond {
+                status,
+                location: decision.location,
+                headers: decision.headers,
+            },
+        }
+    }
+}
+
+/// Path prefix mapping undoing an internal rewrite for response headers, see
+/// `rewrite_response_location` and `rewrite_cookie_path` settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathMapping {
+    /// Path prefix of the rewritten URI without the trailing slash, empty for the root
+    pub internal: String,
+    /// Path prefix of the original URI without the trailing slash, empty for the root
+    pub public: String,
+    /// Whether the mapping applies to `Location` headers
+    pub location: bool,
+    /// Whether the mapping applies to `Path` attributes of `Set-Cookie` headers
+    pub cookie_path: bool,
+    /// Whether a `Path` attribute should be added to `Set-Cookie` headers without one
+    pub add_cookie_path: bool,
+}
+
+impl PathMapping {
+    /// Replaces the internal prefix of a path by the public one, returns `None` if the path
+    /// doesn’t start with the internal prefix.
+    fn apply(&self, path: &str) -> Option<String> {
+        let rest = path
+            .strip_prefix(self.internal.as_str())
+            .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))?;
+        let mut result = self.public.clone();
+        if !rest.starts_with('/') && result.is_empty() {
+            result.push('/');
+        }
+        result.push_str(rest);
+        Some(result)
+    }
+}
+
+/// Session extension holding the debug header to be added to the upstream response, see
+/// `rewrite_debug_header` setting
+#[derive(Debug, Clone)]
+struct DebugHeader(HeaderName, HeaderValue);
+
+/// Session extension storing the information required to rewrite the `Location` and
+/// `Set-Cookie` headers of the upstream response
+#[derive(Debug, Clone)]
+struct ResponseRewrite {
+    mappings: Vec<PathMapping>,
+    /// Host the rewritten request was sent to
+    upstream_host: Option<String>,
+    /// Scheme and host of the original request like `https://example.com`
+    public_origin: Option<String>,
+}
+
+impl ResponseRewrite {
+    /// Applies the mappings selected by `filter` to a path, in reverse order of rule application.
+    fn map_path(&self, path: &str, filter: impl Fn(&PathMapping) -> bool) -> Option<String> {
+        let mut result = None;
+        for mapping in self.mappings.iter().rev().filter(|mapping| filter(mapping)) {
+            if let Some(mapped) = mapping.apply(result.as_deref().unwrap_or(path)) {
+                result = Some(mapped);
+            }
+        }
+        result
+    }
+
+    /// Produces the new `Location` header value, `None` if it should be left unchanged.
+    fn rewrite_location(&self, location: &str) -> Option<String> {
+        if !self.mappings.iter().any(|mapping| mapping.location) {
+            return None;
+        }
+        if location.starts_with('/') && !location.starts_with("//") {
+            return self.map_path(location, |mapping| mapping.location);
+        }
+
+        // Absolute URL like `http://host/path` or scheme-relative URL like `//host/path`
+        let (scheme, rest) = location.split_once("//")?;
+        let valid_scheme = scheme.is_empty()
+            || scheme.strip_suffix(':').is_some_and(|name| {
+                !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+            });
+        let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        if !valid_scheme
+            || !self
+                .upstream_host
+                .as_deref()
+                .is_some_and(|host| host.eq_ignore_ascii_case(authority))
+        {
+            return None;
+        }
+
+        let path = if path.starts_with('/') {
+            Cow::Borrowed(path)
+        } else {
+            Cow::Owned(format!("/{path}"))
+        };
+        let path = self
+            .map_path(&path, |mapping| mapping.location)
+            .unwrap_or_else(|| path.into_owned());
+        match &self.public_origin {
+            Some(origin) => Some(format!("{origin}{path}")),
+            None => Some(path),
+        }
+    }
+
+    /// Produces the new `Set-Cookie` header value with the `Path` attribute mapped or added,
+    /// `None` if it should be left unchanged.
+    fn rewrite_cookie(&self, cookie: &str) -> Option<String> {
+        // Cookie paths are matched by prefix, `/app/` wouldn’t apply to `/app` itself
+        let map_path = |path: &str| {
+            self.map_path(path, |mapping| mapping.cookie_path)
+                .map(|path| match path.trim_end_matches('/') {
+                    "" => "/".to_owned(),
+                    trimmed => trimmed.to_owned(),
+                })
+        };
+
+        let mut attributes = cookie.split(';').map(Cow::Borrowed).collect::<Vec<_>>();
+        let path_attribute = attributes.iter().skip(1).position(|attribute| {
+            attribute
+                .split('=')
+                .next()
+                .is_some_and(|name| name.trim().eq_ignore_ascii_case("path"))
+        });
+
+        match path_attribute {
+            Some(index) => {
+                let (_, path) = attributes[index + 1].split_once('=')?;
+                let path = map_path(path.trim())?;
+                attributes[index + 1] = Cow::Owned(format!(" Path={path}"));
+            }
+            None if self.mappings.iter().any(|mapping| mapping.add_cookie_path) => {
+                // The first rule applied has the prefix of the original request
+                let public = self
+                    .mappings
+                    .iter()
+                    .find(|mapping| mapping.cookie_path)
+                    .map_or("", |mapping| mapping.public.as_str());
+                let path = if public.is_empty() { "/" } else { public };
+                attributes.push(Cow::Owned(format!(" Path={path}")));
+            }
+            None => return None,
+        }
+        Some(attributes.join(";"))
+    }
+}
+
+/// Hit counter of a rewrite rule, as returned by [`RewriteHandler::metrics`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMetrics {
+    /// Index of the rule in the `rewrite_rules` list
+    pub index: usize,
+    /// The `name` setting of the rule
+    pub name: Option<String>,
+    /// Rewriting type of the rule, with the rule flags considered
+    pub r#type: RewriteType,
+    /// Number of times the rule has been applied
+    pub hits: u64,
+}
+
+/// Sends the response for a rule producing one: a redirect, a standard error page or a fixed
+/// response. Headers configured for the rule replace standard headers of the same name.
+async fn send_response(
+    session: &mut impl SessionWrapper,
+    status: StatusCode,
+    decision: RewriteDecision,
+) -> Result<(), Box<Error>> {
+    let body = decision
+        .body
+        .unwrap_or_else(|| response_text(status).into_bytes());
+    let content_type = decision
+        .content_type
+        .unwrap_or(HeaderValue::from_static("text/html; charset=utf-8"));
+
+    let mut header = ResponseHeader::build(status, Some(3 + decision.headers.len()))?;
+    header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    header.insert_header(header::CONTENT_TYPE, content_type)?;
+    if let Some(location) = decision.location {
+        header.insert_header(header::LOCATION, location)?;
+    }
+    for name in decision.headers.keys() {
+        header.remove_header(name);
+        for value in decision.headers.get_all(name) {
+            header.append_header(name.clone(), value.clone())?;
+        }
+    }
+    session.write_response_header(Box::new(header)).await?;
+
+    if session.req_header().method != Method::HEAD {
+        session.write_response_body(body.into()).await?;
+    }
+    Ok(())
+}
+
+/// Rules and settings compiled from the configuration, replaced as a whole when reloading
+#[derive(Debug)]
+struct RuleSet {
+    router: Router<RuleList>,
+    counters: Option<Arc<HitCounters>>,
+    decode_path: bool,
+    normalize_path: bool,
+    encoded_slash: EncodedSlash,
+    trusted_proxies: Vec<IpMatch>,
+    lowercase_lookup: bool,
+    max_internal: usize,
+    limit_exceeded: LimitExceeded,
+    /// Name of the debug header if `rewrite_debug_header` is enabled
+    debug_header: Option<HeaderName>,
+    /// The `name` setting of each rule, in the order the rules are listed in the configuration
+    rule_names: Vec<Option<String>>,
+    /// Rewriting type of each rule with the rule flags considered, in the same order
+    rule_types: Vec<RewriteType>,
+}
+
+/// Handler for Pingora’s `request_filter` phase
+///
+/// Clones share the rules: after [`RewriteHandler::reload`] all of them use the new rules.
+#[derive(Debug, Clone)]
+pub struct RewriteHandler {
+    rules: Arc<ArcSwap<RuleSet>>,
+    clock: SharedClock,
+}
+
+// Hit counters change with every request, only whether these are enabled is compared
+impl PartialEq for RuleSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.router == other.router
+            && self.counters.is_some() == other.counters.is_some()
+            && self.decode_path == other.decode_path
+            && self.normalize_path == other.normalize_path
+            && self.encoded_slash == other.encoded_slash
+            && self.trusted_proxies == other.trusted_proxies
+            && self.lowercase_lookup == other.lowercase_lookup
+            && self.max_internal == other.max_internal
+            && self.limit_exceeded == other.limit_exceeded
+            && self.debug_header == other.debug_header
+            && self.rule_names == other.rule_names
+            && self.rule_types == other.rule_types
+    }
+}
+
+impl Eq for RuleSet {}
+
+impl PartialEq for RewriteHandler {
+    fn eq(&self, other: &Self) -> bool {
+        *self.rules.load() == *other.rules.load()
+    }
+}
+
+impl Eq for RewriteHandler {}
+
+impl RuleSet {
+    /// Lists the rules applied for a decision in the order of application, for the debug header.
+    fn applied_rules(&self, decision: &RewriteDecision) -> String {
+        decision
+            .previous_rules
+            .iter()
+            .chain([&decision.rule])
+            .map(|&rule| match self.rule_names.get(rule) {
+                Some(Some(name)) => name.clone(),
+                _ => rule.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Checks whether the connected client is a trusted proxy, i.e. its address is listed in
+    /// `rewrite_trusted_proxies`.
+    fn trusts_peer(&self, peer: Option<IpAddr>) -> bool {
+        peer.is_some_and(|peer| self.is_trusted_proxy(peer))
+    }
+
+    /// Checks whether an address belongs to `rewrite_trusted_proxies`.
+    fn is_trusted_proxy(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|range| range.contains(addr))
+    }
+
+    /// Determines the scheme of a request. If the connected client is a trusted proxy, the
+    /// `X-Forwarded-Proto` header is considered first. For HTTP/2 requests, the scheme of the
+    /// request URI (from the `:scheme` pseudo-header) is used if known. Otherwise the connection
+    /// type decides. The scheme of HTTP/1 absolute-form URIs is chosen by the client and ignored.
+    fn request_scheme(
+        &self,
+        uri: &Uri,
+        headers: &HeaderMap,
+        connection: &Connection,
+    ) -> RequestScheme {
+        let scheme_from_name = |name: &str| {
+            if name.eq_ignore_ascii_case("https") {
+                Some(RequestScheme::Https)
+            } else if name.eq_ignore_ascii_case("http") {
+                Some(RequestScheme::Http)
+            } else {
+                None
+            }
+        };
+
+        let forwarded = || {
+            let value = headers.get("X-Forwarded-Proto")?.to_str().ok()?;
+            // Multiple proxies might have added their values, the first one is the client-facing
+            scheme_from_name(value.split(',').next()?.trim())
+        };
+
+        self.trusts_peer(connection.peer)
+            .then(forwarded)
+            .flatten()
+            .or_else(|| {
+                if connection.version == Version::HTTP_2 {
+                    scheme_from_name(uri.scheme_str()?)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(if connection.tls {
+                RequestScheme::Https
+            } else {
+                RequestScheme::Http
+            })
+    }
+
+    /// Determines the host of a request. If the connected client is a trusted proxy, a valid
+    /// `X-Forwarded-Host` header takes precedence over the `Host` header.
+    fn request_host<'a>(
+        &self,
+        uri: &'a Uri,
+        headers: &'a HeaderMap,
+        peer: Option<IpAddr>,
+    ) -> Option<&'a str> {
+        let forwarded = || {
+            let value = headers.get("X-Forwarded-Host")?.to_str().ok()?;
+            // Multiple proxies might have added their values, the first one is the client-facing
+            let host = value.split(',').next()?.trim();
+            Authority::try_from(host)
+                .ok()
+                .filter(|_| !host.is_empty() && !host.contains('@'))
+                .map(|_| host)
+        };
+
+        self.trusts_peer(peer)
+            .then(forwarded)
+            .flatten()
+            .or_else(|| request_host(uri, headers))
+    }
+
+    /// Checks whether the request should be rejected because of an encoded slash in the path,
+    /// according to the `rewrite_encoded_slash` setting.
+    fn rejects_path(&self, path: &str) -> bool {
+        self.decode_path && self.encoded_slash == EncodedSlash::Reject && has_encoded_slash(path)
+    }
+
+    /// Determines the client address of a request. If the connected client is a trusted proxy,
+    /// the `X-Forwarded-For` header is processed from the right: the first address not belonging
+    /// to `rewrite_trusted_proxies` is the client address. An invalid entry stops processing,
+    /// the last address determined is used then, falling back to the address of the connected
+    /// client.
+    fn request_client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let mut client_ip = peer;
+        if self.trusts_peer(peer) {
+            'proxies: for value in headers.get_all("X-Forwarded-For").iter().rev() {
+                let Ok(value) = value.to_str() else {
+                    break;
+                };
+                for entry in value.rsplit(',') {
+                    let Ok(addr) = entry.trim().parse::<IpAddr>() else {
+                        break 'proxies;
+                    };
+                    client_ip = Some(addr);
+                    if !self.is_trusted_proxy(addr) {
+                        break 'proxies;
+                    }
+                }
+            }
+        }
+        client_ip.map(IpMatch::canonical)
+    }
+
+    /// Finds the rule applying to a request and produces the new URI. Returns `None` if no rule
+    /// applies. `log_evaluation` is called for rule evaluations that should be logged according
+    /// to the `log` setting of the rule.
+    ///
+    /// After internal rewrites by rules with `continue` enabled, the rules are evaluated again for
+    /// the new URI, until `rewrite_max_internal` internal rewrites have been applied. Exceeding
+    /// this limit produces an `HTTPStatus(500)` error if `rewrite_limit_exceeded` is `error`.
+    ///
+    /// `original_uri` is the request URI before any modifications, e.g. by other modules. `now`
+    /// is the time used for `active_from`/`active_until` and the time variables.
+    #[allow(clippy::too_many_arguments)]
+    fn evaluate(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        original_uri: &Uri,
+        headers: &HeaderMap,
+        connection: Connection,
+        now: SystemTime,
+        mut log_evaluation: impl FnMut(usize, &str, bool),
+    ) -> Result<Option<RewriteDecision>, Box<Error>> {
+        if self.rejects_path(uri.path()) {
+            trace!("Not rewriting path with encoded slash");
+            return Ok(None);
+        }
+
+        let request = RequestInfo {
+            method,
+            scheme: self.request_scheme(uri, headers, &connection),
+            client_ip: self.request_client_ip(headers, connection.peer),
+            host: self.request_host(uri, headers, connection.peer),
+            local_port: connection.local_port,
+            now,
+            original_uri,
+            uri,
+            path: uri.path(),
+            headers,
+            query_params: &OnceCell::new(),
+            cookies: &OnceCell::new(),
+        };
+        let mut evaluate_rules =
+            |uri: &Uri| self.evaluate_rules(uri, &request, &mut log_evaluation);
+
+        let Some((mut decision, mut proceed)) = evaluate_rules(uri)? else {
+            return Ok(None);
+        };
+        while proceed {
+            if decision.previous_rules.len() + 1 >= self.max_internal {
+                let mut rules = decision.previous_rules.clone();
+                rules.push(decision.rule);
+                let message = format!(
+                    "exceeded the limit of {} internal rewrites, rules applied: {rules:?}",
+                    self.max_internal
+                );
+                if self.limit_exceeded == LimitExceeded::Error {
+                    return Err(Error::explain(ErrorType::HTTPStatus(500), message));
+                }
+                warn!("Stopped rewriting request, {message}");
+                break;
+            }
+
+            let Some((next, next_proceed)) = evaluate_rules(&decision.uri)? else {
+                break;
+            };
+            trace!("Rewritten URI {} matched rule {}", decision.uri, next.rule);
+            let mut previous_rules = std::mem::take(&mut decision.previous_rules);
+            previous_rules.push(decision.rule);
+            decision = RewriteDecision {
+                preserve_original_in: next.preserve_original_in.or(decision.preserve_original_in),
+                host: next.host.or(decision.host),
+                previous_rules,
+                path_mappings: [decision.path_mappings, next.path_mappings].concat(),
+                ..next
+            };
+            proceed = next_proceed;
+        }
+
+        Ok(Some(decision))
+    }
+
+    /// Finds the rule applying to a URI and produces the new URI, see
+    /// [`RuleSet::evaluate`]. The flag returned along with the decision indicates whether
+    /// the rules should be evaluated again for the new URI.
+    fn evaluate_rules(
+        &self,
+        uri: &Uri,
+        request: &RequestInfo<'_>,
+        log_evaluation: &mut impl FnMut(usize, &str, bool),
+    ) -> Result<Option<(RewriteDecision, bool)>, Box<Error>> {
+        let mut path = if self.decode_path {
+            decode_path(uri.path())
+        } else {
+            Cow::Borrowed(uri.path())
+        };
+        if self.normalize_path {
+            if let Some(normalized) = normalize_path(&path) {
+                path = Cow::Owned(normalized);
+            }
+        }
+        let path = path.as_ref();
+        trace!("Determining rewrite rules for path {path}");
+
+        // Rules might be looked up by lowercase path, case-sensitive rules are checked separately
+        // then.
+        let lookup_path = if self.lowercase_lookup {
+            Cow::Owned(path.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(path)
+        };
+        let list = if let Some(list) = self.router.lookup("", lookup_path.as_ref()) {
+            list
+        } else {
+            trace!("No match for the path");
+            return Ok(None);
+        };
+
+        trace!("Applying rewrite rules: {:?}", list.rules);
+
+        let query_params = OnceCell::new();
+        let request = RequestInfo {
+            uri,
+            path,
+            query_params: &query_params,
+            ..*request
+        };
+
+        // Rules rejected by the prefilter don’t need their `from_regex` expressions matched
+        // individually.
+        let candidates = OnceCell::new();
+        let candidate = |position: usize| {
+            list.prefilter.as_ref().map_or(true, |prefilter| {
+                candidates.get_or_init(|| prefilter.candidates(path))[position]
+            })
+        };
+
+        // Iterate in reverse order, merging puts rules in reverse order of precedence.
+        for (position, (rule_path, rule)) in list.rules.iter().enumerate().rev() {
+            if self.lowercase_lookup
+                && !rule.case_insensitive
+                && !rule.from.matcher.path.is_empty()
+                && rule.from.matcher.path.remove_prefix_from(path).is_none()
+            {
+                trace!("Rule {} not applied, `from` doesn’t match", rule.index);
+                continue;
+            }
+
+            // ASCII lowercasing preserves byte offsets, so the tail can be taken from the
+            // original path to keep its casing.
+            let tail = match rule_path.remove_prefix_from(lookup_path.as_ref()) {
+                Some(tail) if lookup_path.as_bytes().ends_with(&tail) => {
+                    path.as_bytes()[path.len() - tail.len()..].to_vec()
+                }
+                Some(tail) => tail,
+                None => path.as_bytes().to_owned(),
+            };
+            let tails = rule.from.capture(&tail, rule.case_insensitive);
+            let mismatch = match tails {
+                Some(_) if !candidate(position) => Some("from_regex"),
+                Some(_) => rule.mismatch(&request),
+                None => Some("from"),
+            };
+            if let Some(setting) = mismatch {
+                trace!("Rule {} not applied, `{setting}` doesn’t match", rule.index);
+            }
+            let tails = tails.filter(|_| mismatch.is_none());
+            if rule.log.applies(tails.is_some()) {
+                log_evaluation(rule.index, path, tails.is_some());
+            }
+            let Some(mut tails) = tails else {
+                continue;
+            };
+
+            // With `raw_path` the tails are taken from the path as received if possible
+            let raw_tails = rule.raw_path.then(|| rule.raw_tails(uri)).flatten();
+            let raw = raw_tails.is_some();
+            if let Some(raw_tails) = raw_tails {
+                tails = raw_tails;
+            }
+
+            trace!(
+                "Matched rule for path `{}`, tails are: {tails:?}",
+                String::from_utf8_lossy(rule_path)
+            );
+
+            if rule.ignore_trailing_slash {
+                // Exact matches produce `/` as tail, regardless of the trailing slash
+                for tail in &mut tails {
+                    tail.clear();
+                }
+            }
+
+            if rule.decode_tail {
+                // Fully decoded values might contain characters that aren’t valid in a URI
+                for tail in &mut tails {
+                    let decoded = percent_decode(tail).collect::<Vec<_>>();
+                    *tail = percent_encode(&decoded, DECODED_TAIL_ESC_CHARSET)
+                        .to_string()
+                        .into_bytes();
+                }
+            } else if self.decode_path && !raw {
+                // Decoded path might contain characters that aren’t valid in a URI
+                for tail in &mut tails {
+                    *tail = percent_encode(tail, TAIL_ESC_CHARSET)
+                        .to_string()
+                        .into_bytes();
+                }
+            }
+
+            if rule.missing_map_key(&request, &tails) {
+                trace!("Rule {} not applied, map key missing", rule.index);
+                continue;
+            }
+
+            // Processing always stops at the first matching rule. Responses without a redirect
+            // target keep the request URI.
+            let target = if let Some(target) = rule.slash_target(uri) {
+                Target::redirect(target)
+            } else if rule.r#type == RewriteType::Internal || rule.r#type.is_redirect() {
+                rule.apply(&request, &tails)?
+            } else {
+                Target {
+                    uri: None,
+                    location: None,
+                }
+            };
+            let host = if rule.r#type == RewriteType::Internal {
+                rule.host(&request, &tails)?
+            } else {
+                None
+            };
+            if rule.ignore_trailing_slash
+                && rule.r#type.is_redirect()
+                && target.uri.as_ref().is_some_and(|target| {
+                    target.authority().is_none() && path_and_query(target) == path_and_query(uri)
+                })
+            {
+                trace!("Not redirecting to the request URI itself");
+                continue;
+            }
+
+            let body = if rule.r#type == RewriteType::Response {
+                Some(if let Some(body) = &rule.body_bytes {
+                    body.clone()
+                } else if let Some(body) = &rule.body {
+                    rule.interpolate(body, &request, &tails)
+                } else {
+                    Vec::new()
+                })
+            } else {
+                rule.body
+                    .as_ref()
+                    .zip(target.location.as_deref())
+                    .map(|(body, location)| {
+                        let target = escape_html(location);
+                        body.interpolate(|name| {
+                            matches!(name, "location" | "target").then_some(target.as_bytes())
+                        })
+                    })
+            };
+
+            let mut response_headers = HeaderMap::new();
+            if rule.r#type != RewriteType::Internal {
+                for (name, value) in &rule.headers {
+                    let value = rule.interpolate(value, &request, &tails);
+                    match HeaderValue::from_bytes(&value) {
+                        Ok(value) => {
+                            response_headers.append(name.clone(), value);
+                        }
+                        Err(_) => warn!(
+                            "Rewrite rule {} produced an invalid value for header {name}, skipping",
+                            rule.index
+                        ),
+                    }
+                }
+            }
+
+            let decision = RewriteDecision {
+                rule: rule.index,
+                r#type: rule.r#type,
+                status: rule.status,
+                uri: target.uri.unwrap_or_else(|| uri.clone()),
+                location: target.location,
+                preserve_original_in: rule.preserve_original_in.clone(),
+                host,
+                body,
+                content_type: rule.content_type.clone(),
+                headers: response_headers,
+                previous_rules: Vec::new(),
+                path_mappings: rule.path_mapping.iter().cloned().collect(),
+            };
+            return Ok(Some((decision, rule.r#continue)));
+        }
+
+        Ok(None)
+    }
+}
+
+impl RewriteHandler {
+    /// Returns the hit counters of the rewrite rules along with their names and types, in the
+    /// order the rules are listed in the configuration. The list is empty unless
+    /// `rewrite_metrics` setting is enabled.
+    pub fn metrics(&self) -> Vec<RuleMetrics> {
+        let rules = self.rules.load();
+        let Some(counters) = &rules.counters else {
+            return Vec::new();
+        };
+        counters
+            .snapshot()
+            .into_iter()
+            .zip(&rules.rule_names)
+            .zip(&rules.rule_types)
+            .enumerate()
+            .map(|(index, ((hits, name), r#type))| RuleMetrics {
+                index,
+                name: name.clone(),
+                r#type: *r#type,
+                hits,
+            })
+            .collect()
+    }
+
+    /// Determines how a request would be rewritten, without modifying anything. Returns `None` if
+    /// no rule applies, the rewrite target is invalid or the request would be rejected because of
+    /// an encoded slash in the path.
+    ///
+    /// Unlike `request_filter` this doesn’t update rule metrics. The result can be converted into
+    /// a [`RewriteOutcome`] if only the effect on the request is relevant.
+    pub fn apply(&self, request: &RewriteRequest) -> Option<RewriteDecision> {
+        match self.rules.load().evaluate(
+            &request.method,
+            &request.uri,
+            &request.uri,
+            &request.headers,
+            Connection {
+                tls: request.tls,
+                peer: request.client_ip,
+                local_port: request.local_port,
+                version: request.version,
+            },
+            self.clock.now(),
+            log_evaluation,
+        ) {
+            Ok(decision) => decision,
+            Err(err) => {
+                error!("Failed applying rewrite rule: {err}");
+                None
+            }
+        }
+    }
+
+    /// Determines how each of the requests would be rewritten, see [`RewriteHandler::apply`].
+    pub fn apply_all(&self, requests: &[RewriteRequest]) -> Vec<Option<RewriteDecision>> {
+        requests.iter().map(|request| self.apply(request)).collect()
+    }
+
+    /// Replaces the rules by ones built from a new configuration. Requests already being
+    /// processed finish with the previous rules. If the new configuration is invalid, an error is
+    /// returned and the previous rules stay in effect.
+    ///
+    /// Rule metrics start from zero again after a reload, rule indexes might refer to different
+    /// rules now.
+    pub fn reload(&self, conf: RewriteConf) -> Result<(), Box<Error>> {
+        let rules = RuleSet::try_from(conf)?;
+        self.rules.store(Arc::new(rules));
+        debug!("Rewrite rules reloaded");
+        Ok(())
+    }
+}
+
+impl TryFrom<RewriteConf> for RewriteHandler {
+    type Error = Box<Error>;
+
+    fn try_from(conf: RewriteConf) -> Result<Self, Self::Error> {
+        Ok(Self {
+            rules: Arc::new(ArcSwap::from_pointee(RuleSet::try_from(conf)?)),
+            clock: SharedClock::default(),
+        })
+    }
+}
+
+impl TryFrom<RewriteConf> for RuleSet {
+    type Error = Box<Error>;
+
+    fn try_from(mut conf: RewriteConf) -> Result<Self, Self::Error> {
+        debug!("Rewrite configuration received: {conf:#?}");
+
+        // Group conditions are merged into the member rules here, so that these don’t need to be
+        // considered when handling requests.
+        conf.flatten_rule_groups()
+            .map_err(|err| Error::explain(ErrorType::InternalError, err.to_string()))?;
+
+        for warning in conf.lint() {
+            warn!("Rewrite rule {}: {}", warning.index, warning.reason);
+        }
+
+        if conf.rewrite_max_internal == 0 {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "`rewrite_max_internal` has to be at least 1",
+            ));
+        }
+
+        if conf
+            .rewrite_trusted_proxies
+            .iter()
+            .any(|range| range.negate)
+        {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                "`rewrite_trusted_proxies` cannot contain negated ranges",
+            ));
+        }
+
+        let mut redirect_body = conf.rewrite_redirect_body.take();
+        if conf.variable_delimiters != VariableDelimiters::default() {
+            if let Some(body) = &mut redirect_body {
+                body.set_delimiters(&conf.variable_delimiters);
+            }
+        }
+        if let Some(filter) = redirect_body
+            .as_ref()
+            .and_then(VariableInterpolation::unknown_filter)
+        {
+            return Err(Error::explain(
+                ErrorType::InternalError,
+                format!("`rewrite_redirect_body` uses unknown filter {filter}"),
+            ));
+        }
+
+        // Names of disabled rules are validated as well, so that enabling a rule doesn’t break
+        // the configuration.
+        let mut named_rules = HashMap::new();
+        let rule_names = conf
+            .rewrite_rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| match &rule.name {
+                Some(name)
+                    if name.trim().is_empty()
+                        || name.contains(',')
+                        || HeaderValue::from_str(name).is_err() =>
+                {
+                    Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!("rewrite rule {index} has invalid `name` {name:?}"),
+                    ))
+                }
+                Some(name) => match named_rules.entry(name) {
+                    Entry::Occupied(entry) => Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite rule {index} has the same `name` {name:?} as rule {}",
+                            entry.get()
+                        ),
+                    )),
+                    Entry::Vacant(entry) => {
+                        entry.insert(index);
+                        Ok(Some(name.clone()))
+                    }
+                },
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut rule_types = vec![RewriteType::Internal; rule_names.len()];
+        let debug_header = conf.rewrite_debug_header.then(|| {
+            conf.rewrite_debug_header_name
+                .clone()
+                .unwrap_or(HeaderName::from_static("x-applied-rewrite"))
+        });
+
+        let counters = conf
+            .rewrite_metrics
+            .then(|| Arc::new(HitCounters::new(conf.rewrite_rules.len())));
+
+        let mut maps = BTreeMap::new();
+        for (name, map) in &conf.rewrite_maps {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("invalid rewrite map name {name:?}"),
+                ));
+            }
+            maps.insert(name.clone(), Arc::new(RewriteMap::load(name, map)?));
+        }
+
+        let mut merger = Merger::new();
+        let mut unknown_variables = BTreeSet::new();
+        let mut regexes = RegexInterner::default();
+
+        // Remember the original position of each rule before reordering. A rule with multiple
+        // `from` patterns is registered separately for each of them. Disabled rules are left out
+        // entirely.
+        let mut rules = conf
+            .rewrite_rules
+            .into_iter()
+            .enumerate()
+            .filter(|(index, rule)| {
+                if !rule.enabled {
+                    debug!("Skipping disabled rewrite rule {index}");
+                }
+                rule.enabled
+            })
+            .map(|(index, mut rule)| {
+                // Negated patterns are exclusions, these cannot be looked up.
+                for from in rule.from.iter().filter(|from| from.negate) {
+                    if !from.is_simple() {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!(
+                                "rewrite rule {index} has a negated `from` pattern with wildcards"
+                            ),
+                        ));
+                    }
+                    rule.from_exclude.push(from.matcher.clone());
+                }
+
+                // Glob rules are looked up via their directory prefix.
+                if let Some(glob) = &rule.from_glob {
+                    if rule.from.iter().any(|from| !from.negate) {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} sets both `from` and `from_glob`"),
+                        ));
+                    }
+                    rule.from = vec![glob.pattern()].into();
+                }
+
+                let mut patterns = rule.path_patterns();
+                rule.from = OneOrMany::default();
+                let mut seen = Vec::new();
+                patterns.retain(|from| {
+                    let duplicate = seen.contains(from);
+                    seen.push(from.clone());
+                    !duplicate
+                });
+                Ok(patterns
+                    .into_iter()
+                    .map(|from| (index, from, rule.clone()))
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+
+        let specificity = |from: &PathPattern, rule: &RewriteRule| {
+            from.specificity() + rule.from_glob.as_ref().map_or(0, Glob::specificity)
+        };
+
+        // If there are case-insensitive rules, all rules are looked up by lowercase path.
+        let lowercase_lookup = rules.iter().any(|(_, _, rule)| rule.case_insensitive);
+        let lowercase = |matcher: &PathMatcher| PathMatcher {
+            path: Path::new(matcher.path.to_ascii_lowercase()),
+            exact: matcher.exact,
+        };
+        let lookup_key = |matcher: &PathMatcher| {
+            if lowercase_lookup {
+                lowercase(matcher)
+            } else {
+                matcher.clone()
+            }
+        };
+
+        // Sort by prefix so that exact rules get priority, then by specificity of the pattern and
+        // the `priority` setting. Rules added later take precedence, so otherwise equal rules are
+        // added in reverse order: the first rule listed in configuration wins.
+        rules.sort_by(|(a_index, a_from, a), (b_index, b_from, b)| {
+            lookup_key(&a_from.matcher)
+                .cmp(&lookup_key(&b_from.matcher))
+                .then_with(|| specificity(a_from, a).cmp(&specificity(b_from, b)))
+                .then_with(|| a.priority.cmp(&b.priority))
+                .then_with(|| b_index.cmp(a_index))
+        });
+
+        for (index, from, rule) in rules {
+            let matcher = lookup_key(&from.matcher);
+            let path = matcher.path.clone();
+            let flags = rule.flags.unwrap_or_default();
+
+            let header_names = |names: OneOrMany<String>, field: &str| {
+                names
+                    .into_iter()
+                    .map(|name| {
+                        HeaderName::try_from(name.as_str()).map_err(|err| {
+                            Error::because(
+                                ErrorType::InternalError,
+                                format!(
+                                    "rewrite rule {index} has invalid header name {name:?} in \
+                                     `{field}`"
+                                ),
+                                err,
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let has_header = header_names(rule.has_header, "has_header")?;
+            let lacks_header = header_names(rule.lacks_header, "lacks_header")?;
+
+            let mut header_regex = rule
+                .header_regex
+                .into_iter()
+                .map(|(name, regex)| {
+                    let name = HeaderName::try_from(name.as_str()).map_err(|err| {
+                        Error::because(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} has invalid header name {name:?}"),
+                            err,
+                        )
+                    })?;
+                    Ok((name, regex))
+                })
+                .collect::<Result<Vec<_>, Box<Error>>>()?;
+
+            let mut intern = |regex: &mut RegexMatch, case_insensitive: bool| {
+                *regex = regexes.intern(regex, case_insensitive).map_err(|err| {
+                    Error::because(
+                        ErrorType::InternalError,
+                        "failed compiling case-insensitive regular expression",
+                        err,
+                    )
+                })?;
+                Ok::<_, Box<Error>>(())
+            };
+
+            let mut from_regex = rule.from_regex.into_inner();
+            let mut query_regex = rule.query_regex.into_inner();
+            let mut uri_regex = rule.uri_regex;
+            let case_insensitive = flags.case_insensitive || rule.case_insensitive;
+            for regex in from_regex
+                .iter_mut()
+                .chain(&mut query_regex)
+                .chain(&mut uri_regex)
+            {
+                intern(regex, case_insensitive)?;
+            }
+
+            let mut cookie_regex = rule.cookie_regex.into_iter().collect::<Vec<_>>();
+            let mut query_params = rule.query_params.into_iter().collect::<Vec<_>>();
+            for (_, regex) in &mut header_regex {
+                intern(regex, false)?;
+            }
+            for (_, regex) in cookie_regex.iter_mut().chain(&mut query_params) {
+                intern(regex, false)?;
+            }
+
+            let mut ua_regex = rule.ua_regex;
+            if let Some(regex) = &mut ua_regex {
+                intern(regex, true)?;
+            }
+
+            let mut from_glob = rule.from_glob;
+            let mut from_exclude = rule.from_exclude.into_inner();
+            if rule.case_insensitive {
+                if let Some(glob) = &mut from_glob {
+                    *glob = glob.case_insensitive().map_err(|err| {
+                        Error::because(
+                            ErrorType::InternalError,
+                            "failed compiling case-insensitive glob",
+                            err,
+                        )
+                    })?;
+                }
+                for exclude in &mut from_exclude {
+                    *exclude = lowercase(exclude);
+                }
+            }
+
+            // Excluded directories are removed from the router unless its keys are lowercase but
+            // the rule is case-sensitive. Other exclusions are checked when evaluating the rule.
+            let mut exclude = Vec::new();
+            if rule.case_insensitive || !lowercase_lookup {
+                from_exclude.retain(|matcher| {
+                    if !matcher.exact {
+                        exclude.push(matcher.clone());
+                    }
+                    matcher.exact
+                });
+            }
+
+            let has_target = rule.to.is_some() || rule.to_query.is_some();
+            let mut to = rule.to.unwrap_or_else(|| "/".into());
+            let mut to_query = rule.to_query;
+            let mut to_host = rule.to_host;
+            let mut body = rule.body;
+            if conf.variable_delimiters != VariableDelimiters::default() {
+                for value in std::iter::once(&mut to)
+                    .chain(to_query.as_mut())
+                    .chain(to_host.as_mut())
+                    .chain(body.as_mut())
+                {
+                    value.set_delimiters(&conf.variable_delimiters);
+                }
+            }
+
+            if to_query.is_some() && to.interpolate(|_| Some(&b""[..])).contains(&b'?') {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} sets `to_query` but `to` contains a query"),
+                ));
+            }
+            if let Some(to_host) = &to_host {
+                let literal = to_host.interpolate(|_| Some(&b""[..]));
+                if literal
+                    .iter()
+                    .any(|&c| matches!(c, b'/' | b'@') || c.is_ascii_whitespace())
+                {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!("rewrite rule {index} has invalid `to_host`"),
+                    ));
+                }
+            }
+
+            let uses_query = to.uses_variable("query")
+                || to_query
+                    .as_ref()
+                    .is_some_and(|to_query| to_query.uses_variable("query"));
+
+            if rule.strip_prefix {
+                if has_target || rule.subst.is_some() || rule.redirect_to_host.is_some() {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite rule {index} combines `strip_prefix` with `to`, `to_query`, \
+                             `subst` or `redirect_to_host`"
+                        ),
+                    ));
+                }
+                if !from.is_simple() {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite rule {index} uses `strip_prefix` with wildcards in `from`"
+                        ),
+                    ));
+                }
+            }
+
+            let mut append_query = flags.append_query || rule.append_query || rule.strip_prefix;
+            if append_query && uses_query {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} appends the query but also uses `${{query}}`"),
+                ));
+            }
+
+            // A modified query is appended unless `to` places it explicitly.
+            let query_remove = rule.query_remove.into_inner();
+            let mut query_set = rule.query_set.into_iter().collect::<Vec<_>>();
+            let mut query_add = rule.query_add.into_iter().collect::<Vec<_>>();
+            if !query_remove.is_empty() || !query_set.is_empty() || !query_add.is_empty() {
+                append_query |= !uses_query;
+            }
+            if let Some(name) = query_remove
+                .iter()
+                .map(String::as_str)
+                .chain(query_set.iter().map(|(name, _)| name.as_str()))
+                .chain(query_add.iter().map(|(name, _)| name.as_str()))
+                .find(|name| name.trim_end_matches('*').is_empty() || name.contains('&'))
+            {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} has invalid query parameter name {name:?}"),
+                ));
+            }
+            if let Some(name) = query_set
+                .iter()
+                .chain(&query_add)
+                .map(|(name, _)| name)
+                .find(|name| name.contains('*'))
+            {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} cannot set query parameter {name:?}"),
+                ));
+            }
+            if conf.variable_delimiters != VariableDelimiters::default() {
+                for (_, value) in query_set.iter_mut().chain(&mut query_add) {
+                    value.set_delimiters(&conf.variable_delimiters);
+                }
+            }
+
+            let mut headers = Vec::new();
+            for (name, values) in rule.headers {
+                let name = HeaderName::try_from(&name)
+                    .ok()
+                    .filter(|name| *name != header::CONTENT_LENGTH)
+                    .ok_or_else(|| {
+                        Error::explain(
+                            ErrorType::InternalError,
+                            format!(
+                                "rewrite rule {index} has invalid header name {name:?} in \
+                                 `headers`"
+                            ),
+                        )
+                    })?;
+                for mut value in values {
+                    if conf.variable_delimiters != VariableDelimiters::default() {
+                        value.set_delimiters(&conf.variable_delimiters);
+                    }
+                    // Variable values are checked when producing the header, validate the
+                    // remaining text here.
+                    let literal = value.interpolate(|_| Some(&b""[..]));
+                    if HeaderValue::from_bytes(&literal).is_err() {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} has invalid value for header {name}"),
+                        ));
+                    }
+                    headers.push((name.clone(), value));
+                }
+            }
+
+            if has_target && rule.subst.is_some() {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} sets both `to` and `subst`"),
+                ));
+            }
+
+            let redirect_to_host = rule
+                .redirect_to_host
+                .map(|host| {
+                    if has_target || to_host.is_some() || rule.subst.is_some() {
+                        return Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!(
+                                "rewrite rule {index} combines `redirect_to_host` with `to`, \
+                                 `to_host` or `subst`"
+                            ),
+                        ));
+                    }
+                    Authority::try_from(host.as_str())
+                        .ok()
+                        .filter(|_| !host.is_empty() && !host.contains('@'))
+                        .ok_or_else(|| {
+                            Error::explain(
+                                ErrorType::InternalError,
+                                format!(
+                                    "rewrite rule {index} has invalid `redirect_to_host` {host:?}"
+                                ),
+                            )
+                        })
+                })
+                .transpose()?;
+
+            let active_from = rule.active_from.map(|timestamp| timestamp.time);
+            let active_until = rule.active_until.map(|timestamp| timestamp.time);
+            if let (Some(from), Some(until)) = (active_from, active_until) {
+                if from >= until {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!("rewrite rule {index} has `active_until` before `active_from`"),
+                    ));
+                }
+            }
+
+            let from_host = rule
+                .from_host
+                .iter()
+                .map(|host| {
+                    let name = host.strip_prefix("*.").unwrap_or(host);
+                    if name.is_empty() || name.contains(['*', '/', '@']) {
+                        Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} has invalid `from_host` {host:?}"),
+                        ))
+                    } else {
+                        Ok(host.to_ascii_lowercase())
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let extensions = rule
+                .extensions
+                .iter()
+                .map(|extension| {
+                    let name = extension.strip_prefix('.').unwrap_or(extension);
+                    if name.is_empty() || name.contains('/') {
+                        Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} has invalid extension {extension:?}"),
+                        ))
+                    } else {
+                        Ok(format!(".{}", name.to_ascii_lowercase()))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let accept_language = rule
+                .accept_language
+                .into_iter()
+                .map(|language| {
+                    let valid = !language.is_empty()
+                        && language
+                            .split('-')
+                            .all(|subtag| !subtag.is_empty() && subtag.bytes().all(|b| b.is_ascii_alphanumeric()));
+                    if valid {
+                        Ok(language)
+                    } else {
+                        Err(Error::explain(
+                            ErrorType::InternalError,
+                            format!(
+                                "rewrite rule {index} has invalid `accept_language` tag {language:?}"
+                            ),
+                        ))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut r#type = flags.r#type.unwrap_or(rule.r#type);
+            if redirect_to_host.is_some() && r#type == RewriteType::Internal {
+                r#type = RewriteType::Redirect;
+            }
+            rule_types[index] = r#type;
+
+            let rule_status = rule.status.or(flags.status);
+            match rule_status {
+                Some(status) if r#type.is_redirect() && !status.is_redirection() => {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!("rewrite rule {index} sets `status` {status} for a redirect"),
+                    ));
+                }
+                Some(_) if !r#type.is_redirect() && r#type != RewriteType::Response => {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite rule {index} sets `status` but is neither a redirect nor a \
+                             response"
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+            let status = rule_status.or(r#type.status_code());
+
+            if rule.body_base64.is_some() && body.is_some() {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} sets both `body` and `body_base64`"),
+                ));
+            }
+            let body_bytes = rule
+                .body_base64
+                .map(|body| {
+                    Filter::DecodeBase64.apply(body.as_bytes()).ok_or_else(|| {
+                        Error::explain(
+                            ErrorType::InternalError,
+                            format!(
+                                "rewrite rule {index} has invalid base64 data in `body_base64`"
+                            ),
+                        )
+                    })
+                })
+                .transpose()?;
+            let content_type = (r#type == RewriteType::Response)
+                .then(|| {
+                    let content_type = rule.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+                    HeaderValue::from_str(content_type).map_err(|err| {
+                        Error::because(
+                            ErrorType::InternalError,
+                            format!("rewrite rule {index} has invalid `content_type`"),
+                            err,
+                        )
+                    })
+                })
+                .transpose()?;
+
+            if r#type.is_redirect() && body.is_none() {
+                body.clone_from(&redirect_body);
+            }
+
+            if rule.skip_files && !r#type.is_slash() {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!(
+                        "rewrite rule {index} sets `skip_files` but is neither `add_slash` nor \
+                         `remove_slash`"
+                    ),
+                ));
+            }
+
+            if rule.add_cookie_path && !rule.rewrite_cookie_path {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!(
+                        "rewrite rule {index} sets `add_cookie_path` without `rewrite_cookie_path`"
+                    ),
+                ));
+            }
+            let path_mapping = if rule.rewrite_response_location || rule.rewrite_cookie_path {
+                if r#type != RewriteType::Internal || rule.subst.is_some() {
+                    return Err(Error::explain(
+                        ErrorType::InternalError,
+                        format!(
+                            "rewrite rule {index} rewrites response paths but is not an internal \
+                             rewrite or uses `subst`"
+                        ),
+                    ));
+                }
+                let internal = if rule.strip_prefix {
+                    String::new()
+                } else {
+                    // Text preceding the first variable like `/backend` for `/backend${tail}`,
+                    // the directory if `to` is a fixed path like `/backend/index.php`
+                    let literal = String::from_utf8_lossy(to.literal_prefix());
+                    match literal.split_once(['?', '#']) {
+                        Some((path, _)) => path[..path.rfind('/').unwrap_or(0)].to_owned(),
+                        None if to.is_literal() => {
+                            literal[..literal.rfind('/').unwrap_or(0)].to_owned()
+                        }
+                        None => literal.trim_end_matches('/').to_owned(),
+                    }
+                };
+                let public = if from.matcher.path.is_empty() {
+                    String::new()
+                } else {
+                    format!("/{}", String::from_utf8_lossy(&from.matcher.path))
+                };
+                Some(PathMapping {
+                    internal,
+                    public,
+                    location: rule.rewrite_response_location,
+                    cookie_path: rule.rewrite_cookie_path,
+                    add_cookie_path: rule.add_cookie_path,
+                })
+            } else {
+                None
+            };
+
+            let trailing_slash = (rule.trailing_slash == TrailingSlash::Strict
+                && from.matcher.exact)
+                .then_some(from.trailing_slash);
+            if rule.ignore_trailing_slash && rule.trailing_slash == TrailingSlash::Strict {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!(
+                        "rewrite rule {index} combines `ignore_trailing_slash` with \
+                         `trailing_slash: strict`"
+                    ),
+                ));
+            }
+            let ignore_trailing_slash = rule.ignore_trailing_slash && from.matcher.exact;
+
+            let mut rule = Rule {
+                index,
+                from,
+                from_glob,
+                extensions,
+                from_exclude,
+                from_host,
+                methods: rule
+                    .methods
+                    .into_iter()
+                    .map(|method| method.method)
+                    .collect(),
+                scheme: rule.scheme,
+                from_ip: rule.from_ip.into_iter().collect(),
+                listen_port: rule.listen_port.into_inner(),
+                active_from,
+                active_until,
+                has_header,
+                lacks_header,
+                header_regex,
+                cookie_regex,
+                ua_regex,
+                from_regex,
+                from_regex_case_insensitive: case_insensitive,
+                query: rule.query,
+                query_regex,
+                regex_mode: rule.regex_mode,
+                uri_regex,
+                query_match: rule.query_match,
+                query_match_strict: rule.query_match_strict,
+                query_params,
+                accept: rule.accept,
+                accept_language,
+                to,
+                to_query,
+                to_host,
+                protocol_relative: rule.protocol_relative,
+                strip_prefix: rule.strip_prefix,
+                redirect_to_host,
+                subst: rule.subst,
+                body,
+                body_bytes,
+                content_type,
+                headers,
+                r#type,
+                status,
+                append_query,
+                query_remove,
+                query_set,
+                query_add,
+                preserve_original_in: rule.preserve_original_in,
+                maps: BTreeMap::new(),
+                path_mapping,
+                r#continue: rule.r#continue && !flags.last && r#type == RewriteType::Internal,
+                trailing_slash,
+                ignore_trailing_slash,
+                skip_files: rule.skip_files,
+                case_insensitive: rule.case_insensitive,
+                decode_tail: rule.decode_tail,
+                raw_path: rule.raw_path,
+                log: rule.log,
+            };
+
+            if let Some(filter) = rule
+                .templates()
+                .find_map(VariableInterpolation::unknown_filter)
+            {
+                return Err(Error::explain(
+                    ErrorType::InternalError,
+                    format!("rewrite rule {index} uses unknown filter {filter}"),
+                ));
+            }
+
+            let used_maps = rule
+                .templates()
+                .flat_map(VariableInterpolation::maps)
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            for name in used_maps {
+                let map = maps.get(&name).ok_or_else(|| {
+                    Error::explain(
+                        ErrorType::InternalError,
+                        format!("rewrite rule {index} uses unknown map {name}"),
+                    )
+                })?;
+                rule.maps.insert(name, map.clone());
+            }
+
+            // Rules with multiple `from` patterns are checked for each, warn only once
+            if let Some(name) = rule.unknown_variable() {
+                let rule_name = match &rule_names[index] {
+                    Some(rule_name) => format!("rewrite rule {index} ({rule_name})"),
+                    None => format!("rewrite rule {index}"),
+                };
+                let message = format!(
+                    "{rule_name} uses variable {}{name}{} that can never be resolved",
+                    conf.variable_delimiters.prefix, conf.variable_delimiters.suffix
+                );
+                if conf.rewrite_strict_variables {
+                    return Err(Error::explain(ErrorType::InternalError, message));
+                }
+                if unknown_variables.insert((index, name.to_owned())) {
+                    warn!("{message}");
+                }
+            }
+
+            merger.push(RuleMatcher { matcher, exclude }, (path, rule));
+        }
+
+        Ok(Self {
+            router: merger.merge(|rules| RuleList::new(rules.cloned().collect())),
+            counters,
+            decode_path: conf.rewrite_decode_path,
+            normalize_path: conf.rewrite_normalize_path,
+            encoded_slash: conf.rewrite_encoded_slash,
+            trusted_proxies: conf.rewrite_trusted_proxies.into_inner(),
+            lowercase_lookup,
+            max_internal: conf.rewrite_max_internal,
+            limit_exceeded: conf.rewrite_limit_exceeded,
+            debug_header,
+            rule_names,
+            rule_types,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestFilter for RewriteHandler {
+    type Conf = RewriteConf;
+
+    type CTX = ();
+
+    fn new_ctx() -> Self::CTX {}
+
+    async fn request_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        _ctx: &mut Self::CTX,
+    ) -> Result<RequestFilterResult, Box<Error>> {
+        // A reload shouldn’t affect requests already being processed
+        let rules = self.rules.load_full();
+        if rules.rejects_path(session.uri().path()) {
+            debug!("Rejecting request with encoded slash in the path");
+            error_response(session, StatusCode::BAD_REQUEST).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        let connection = Connection {
+            tls: session
+                .digest()
+                .and_then(|digest| digest.ssl_digest.as_ref())
+                .is_some(),
+            peer: match session.client_addr() {
+                Some(SocketAddr::Inet(addr)) => Some(addr.ip()),
+                _ => None,
+            },
+            local_port: match session.server_addr() {
+                Some(SocketAddr::Inet(addr)) => Some(addr.port()),
+                _ => None,
+            },
+            version: session.req_header().version,
+        };
+        let mut decision = match rules.evaluate(
+            &session.req_header().method,
+            session.uri(),
+            session.original_uri(),
+            &session.req_header().headers,
+            connection,
+            self.clock.now(),
+            log_evaluation,
+        ) {
+            Ok(Some(decision)) => decision,
+            Ok(None) => return Ok(RequestFilterResult::Unhandled),
+            Err(err) => {
+                error!("Failed applying rewrite rule: {err}");
+                if let ErrorType::HTTPStatus(status) = err.etype {
+                    let status =
+                        StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    error_response(session, status).await?;
+                    return Ok(RequestFilterResult::ResponseSent);
+                }
+                return Ok(RequestFilterResult::Unhandled);
+            }
+        };
+
+        if let Some(counters) = &rules.counters {
+            for rule in decision.previous_rules.iter().chain([&decision.rule]) {
+                counters.increment(*rule);
+            }
+        }
+
+        if let Some(name) = &rules.debug_header {
+            // Rule names are validated, so the value is always valid
+            if let Ok(value) = HeaderValue::try_from(rules.applied_rules(&decision)) {
+                if decision.status.is_some() {
+                    decision.headers.insert(name.clone(), value);
+                } else {
+                    session
+                        .extensions_mut()
+                        .insert(DebugHeader(name.clone(), value));
+                }
+            }
+        }
+
+        if let Some(status) = decision.status {
+            send_response(session, status, decision).await?;
+            return Ok(RequestFilterResult::ResponseSent);
+        }
+
+        if let Some(name) = &decision.preserve_original_in {
+            let original = session
+                .uri()
+                .path_and_query()
+                .map(|path_and_query| path_and_query.as_str())
+                .unwrap_or("/")
+                .to_owned();
+            session.req_header_mut().insert_header(name, original)?;
+        }
+
+        if !decision.path_mappings.is_empty() {
+            let headers = &session.req_header().headers;
+            let public_origin = rules
+                .request_host(session.uri(), headers, connection.peer)
+                .map(|host| {
+                    let scheme = match rules.request_scheme(session.uri(), headers, &connection) {
+                        RequestScheme::Http => "http",
+                        RequestScheme::Https => "https",
+                    };
+                    format!("{scheme}://{host}")
+                });
+            let upstream_host = decision
+                .host
+                .as_ref()
+                .map(Authority::as_str)
+                .or_else(|| request_host(session.uri(), headers))
+                .map(str::to_owned);
+            session.extensions_mut().insert(ResponseRewrite {
+                mappings: decision.path_mappings,
+                upstream_host,
+                public_origin,
+            });
+        }
+
+        let mut uri = decision.uri;
+        if let Some(host) = decision.host {
+            session
+                .req_header_mut()
+                .insert_header(header::HOST, host.as_str())?;
+            if let Some(scheme) = session.uri().scheme().cloned() {
+                // The request URI is absolute (e.g. HTTP/2), its authority has to change as well
+                uri = Uri::builder()
+                    .scheme(scheme)
+                    .authority(host)
+                    .path_and_query(path_and_query(&uri))
+                    .build()
+                    .map_err(|err| {
+                        Error::because(ErrorType::InternalError, "failed building request URI", err)
+                    })?;
+            }
+        }
+        session.set_uri(uri);
+
+        Ok(RequestFilterResult::Unhandled)
+    }
+
+    fn response_filter(
+        &self,
+        session: &mut impl SessionWrapper,
+        response: &mut ResponseHeader,
+        _ctx: Option<&mut <Self as RequestFilter>::CTX>,
+    ) {
+        if let Some(DebugHeader(name, value)) = session.extensions().get::<DebugHeader>() {
+            // Conversion from HeaderName/HeaderValue is infallible, ignore errors.
+            let _ = response.insert_header(name.clone(), value.clone());
+        }
+
+        let Some(rewrite) = session.extensions().get::<ResponseRewrite>() else {
+            return;
+        };
+
+        if let Some(location) = response
+            .headers
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+        {
+            if let Some(rewritten) = rewrite.rewrite_location(location) {
+                trace!("Rewriting response location {location} into {rewritten}");
+                // Invalid values cannot be produced from a valid header value, ignore errors.
+                let _ = response.insert_header(header::LOCATION, rewritten);
+            }
+        }
+
+        if rewrite.mappings.iter().any(|mapping| mapping.cookie_path) {
+            let mut changed = false;
+            let cookies = response
+                .headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|cookie| {
+                    let rewritten = cookie.to_str().ok().and_then(|cookie| {
+                        let rewritten = rewrite.rewrite_cookie(cookie)?;
+                        trace!("Rewriting response cookie {cookie} into {rewritten}");
+                        HeaderValue::try_from(rewritten).ok()
+                    });
+                    changed |= rewritten.is_some();
+                    rewritten.unwrap_or_else(|| cookie.clone())
+                })
+                .collect::<Vec<_>>();
+            if changed {
+                response.remove_header(&header::SET_COOKIE);
+                for cookie in cookies {
+                    // Conversion from HeaderValue is infallible, ignore errors.
+                    let _ = response.append_header(header::SET_COOKIE, cookie);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::StatusCode;
+    use pandora_module_utils::pingora::{RequestHeader, TestSession};
+    use pandora_module_utils::FromYaml;
+    use test_log::test;
+
+    fn make_handler(conf: &str) -> RewriteHandler {
+        <RewriteHandler as RequestFilter>::Conf::from_yaml(conf)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    async fn make_session(path: &str) -> TestSession {
+        let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+
+        TestSession::from(header).await
+    }
+
+    #[test]
+    fn target_uri() {
+        let target = parse_target(b"/file.txt?a=b".to_vec(), RewriteType::Internal, false).unwrap();
+        let uri = target.uri.unwrap();
+        assert_eq!(uri.path(), "/file.txt");
+        assert_eq!(uri.query(), Some("a=b"));
+        assert_eq!(target.location, None);
+        let target = parse_target(b"/file.txt".to_vec(), RewriteType::Redirect, false).unwrap();
+        assert_eq!(target.uri.unwrap().to_string(), "/file.txt");
+        assert_eq!(target.location.as_deref(), Some("/file.txt"));
+
+        let target = parse_target(
+            b"https://example.com/file.txt".to_vec(),
+            RewriteType::Permanent,
+            false,
+        )
+        .unwrap();
+        let uri = target.uri.unwrap();
+        assert_eq!(uri.host(), Some("example.com"));
+        assert_eq!(uri.to_string(), "https://example.com/file.txt");
+
+        // Redirect targets are sent as they are, only the URI lacks the fragment
+        let target = parse_target(b"/page#section".to_vec(), RewriteType::Redirect, false).unwrap();
+        assert_eq!(target.uri.unwrap().to_string(), "/page");
+        assert_eq!(target.location.as_deref(), Some("/page#section"));
+        for location in ["file.txt", "/stra\u{df}e", "/file name.txt"] {
+            let target =
+                parse_target(location.as_bytes().to_vec(), RewriteType::Redirect, false).unwrap();
+            assert_eq!(target.uri, None);
+            assert_eq!(target.location.as_deref(), Some(location));
+        }
+
+        assert!(parse_target(
+            b"https://example.com/file.txt".to_vec(),
+            RewriteType::Internal,
+            false
+        )
+        .is_err());
+        assert!(parse_target(b"/file name.txt".to_vec(), RewriteType::Internal, false).is_err());
+        assert!(parse_target(b"/file\xFF.txt".to_vec(), RewriteType::Redirect, false).is_err());
+
+        for target in [&b"//example.com/file"[..], b"/\\example.com/file"] {
+            assert!(parse_target(target.to_vec(), RewriteType::Redirect, false).is_err());
+            assert!(parse_target(target.to_vec(), RewriteType::Internal, false).is_ok());
+        }
+        let target =
+            parse_target(b"//example.com/file".to_vec(), RewriteType::Redirect, true).unwrap();
+        assert_eq!(target.location.as_deref(), Some("//example.com/file"));
+    }
+
+    #[test(tokio::test)]
+    async fn hostile_targets() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /go
+                    to: ${query_next}
+                    type: redirect
+                -
+                    from: /header
+                    to: /${http_x_target}
+                    type: redirect
+                -
+                    from: /query
+                    to: /target?${query}
+                    type: redirect
+                -
+                    from: /cdn/*
+                    to: //cdn.example.com${tail}
+                    type: redirect
+                    protocol_relative: true
+            "#,
+        );
+
+        let location = |session: &TestSession| {
+            session
+                .response_written()
+                .and_then(|response| response.headers.get("Location"))
+                .map(|value| value.to_str().unwrap().to_owned())
+        };
+
+        // Decoded control characters produce an error response
+        for (path, header) in [
+            ("/go?next=/a%0D%0ASet-Cookie:%20a=b", None),
+            ("/go?next=/a%00b", None),
+            ("/header", Some("a\tb")),
+        ] {
+            let mut session = make_session(path).await;
+            if let Some(header) = header {
+                session.req_header_mut().insert_header("X-Target", header)?;
+            }
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent,
+                "{path}"
+            );
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, 500, "{path}");
+            assert!(response.headers.get("Set-Cookie").is_none());
+            assert!(response.headers.get("Location").is_none());
+        }
+
+        // Encoded control characters are fine
+        let mut session = make_session("/query?a=%0D%0A").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        assert_eq!(location(&session).as_deref(), Some("/target?a=%0D%0A"));
+
+        // Protocol-relative targets require opt-in
+        for path in ["/go?next=//evil.com/", "/go?next=/%5Cevil.com/"] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled,
+                "{path}"
+            );
+            assert!(session.response_written().is_none());
+        }
+
+        let mut session = make_session("/go?next=/local").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        assert_eq!(location(&session).as_deref(), Some("/local"));
+
+        let mut session = make_session("/cdn/file.js").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        assert_eq!(
+            location(&session).as_deref(),
+            Some("//cdn.example.com/file.js")
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn invalid_target() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    to: /${http_x_target}
+            "#,
+        );
+
+        let mut session = make_session("/file.txt").await;
+        session
+            .req_header_mut()
+            .insert_header("X-Target", "file name.txt")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/file.txt");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn internal_redirect() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /path/*
+                    to: /another${tail}
+            "#,
+        );
+
+        let mut session = make_session("/").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/");
+        assert_eq!(session.original_uri(), "/");
+
+        let mut session = make_session("/path").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/");
+        assert_eq!(session.original_uri(), "/path");
+
+        let mut session = make_session("/path/").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/");
+        assert_eq!(session.original_uri(), "/path/");
+
+        let mut session = make_session("/path/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/file.txt");
+        assert_eq!(session.original_uri(), "/path/file.txt");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_status() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /moved
+                    to: /new
+                    type: permanent
+                    status: 301
+                -
+                    from: /found
+                    to: /new
+                    type: redirect
+                    status: 302
+                -
+                    from: /submitted
+                    to: /thanks
+                    type: redirect
+                    status: 303
+                -
+                    from: /permanent
+                    to: /new
+                    type: permanent
+                -
+                    from: /temporary
+                    to: /new
+                    type: redirect
+            "#,
+        );
+
+        for (path, status) in [
+            ("/moved", 301),
+            ("/found", 302),
+            ("/submitted", 303),
+            ("/permanent", 308),
+            ("/temporary", 307),
+        ] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent
+            );
+            assert_eq!(
+                session.response_written().map(|r| r.status.as_u16()),
+                Some(status),
+                "status for {path}"
+            );
+        }
+
+        assert_eq!(
+            handler
+                .apply(&RewriteRequest {
+                    uri: "/found".try_into().unwrap(),
+                    ..Default::default()
+                })
+                .and_then(|decision| decision.status),
+            Some(StatusCode::FOUND)
+        );
+
+        assert!(RewriteConf::from_yaml("rewrite_rules: {type: redirect, status: 100}").is_err());
+        assert!(RewriteConf::from_yaml("rewrite_rules: {type: redirect, status: 1000}").is_err());
+
+        for conf in [
+            "rewrite_rules: {type: redirect, status: 200}",
+            "rewrite_rules: {type: redirect, status: 404}",
+            "rewrite_rules: {to: /new, status: 301}",
+        ] {
+            let conf = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(conf).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn terminal_types() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /retired/*
+                    type: gone
+                -
+                    from: /scraped/*
+                    from_regex: "\\.json$"
+                    query_regex: "!^token="
+                    type: forbidden
+                -
+                    from: /*
+                    to: /public${tail}
+            "#,
+        );
+
+        for (method, path, expected) in [
+            ("GET", "/retired/page.html", Some(410)),
+            ("HEAD", "/retired/page.html", Some(410)),
+            ("GET", "/scraped/data.json", Some(403)),
+            ("HEAD", "/scraped/data.json", Some(403)),
+            ("GET", "/scraped/data.json?token=1", None),
+            ("GET", "/scraped/page.html", None),
+        ] {
+            let header = RequestHeader::build(method, path.as_bytes(), None)?;
+            let mut session = TestSession::from(header).await;
+            let result = handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?;
+            if let Some(status) = expected {
+                assert_eq!(result, RequestFilterResult::ResponseSent, "{method} {path}");
+                let response = session.response_written().unwrap();
+                assert_eq!(response.status.as_u16(), status, "{method} {path}");
+                assert!(response.headers.get("Location").is_none());
+                let length = response
+                    .headers
+                    .get("Content-Length")
+                    .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+                let body = session.response_body.clone();
+                if method == "HEAD" {
+                    assert!(body.is_empty(), "{method} {path}");
+                    assert!(length.is_some_and(|length| length > 0));
+                } else {
+                    assert_eq!(length, Some(body.len()), "{method} {path}");
+                }
+            } else {
+                assert_eq!(result, RequestFilterResult::Unhandled, "{method} {path}");
+                assert!(session.uri().path().starts_with("/public/"));
+            }
+        }
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/retired/page.html?a=b".try_into().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.r#type, RewriteType::Gone);
+        assert_eq!(decision.status, Some(StatusCode::GONE));
+        assert_eq!(decision.uri, "/retired/page.html?a=b");
+
+        let conf = RewriteConf::from_yaml("rewrite_rules: {type: gone, status: 301}")?;
+        assert!(RewriteHandler::try_from(conf).is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn response_type() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /robots.txt
+                    query: absent
+                    type: response
+                    body: "Sitemap: https://${http_host}/sitemap.xml\n"
+                -
+                    from: /robots.txt
+                    query_regex: "^preview$"
+                    type: response
+                    body: "User-agent: *\nDisallow: /\n"
+                -
+                    from: /maintenance/*
+                    from_regex: "^/maintenance/(?P<page>[^/]+)$"
+                    type: response
+                    status: 503
+                    content_type: text/html
+                    body: "<p>${page} is unavailable</p>"
+                -
+                    from: /favicon.ico
+                    type: response
+                    content_type: image/x-icon
+                    body_base64: AAEAAP8=
+                -
+                    from: /empty
+                    type: response
+                    status: 204
+            "#,
+        );
+
+        for (method, path, status, content_type, body) in [
+            (
+                "GET",
+                "/robots.txt",
+                200,
+                Some("text/plain; charset=utf-8"),
+                "Sitemap: https://localhost/sitemap.xml\n".as_bytes(),
+            ),
+            (
+                "GET",
+                "/robots.txt?preview",
+                200,
+                Some("text/plain; charset=utf-8"),
+                "User-agent: *\nDisallow: /\n".as_bytes(),
+            ),
+            (
+                "HEAD",
+                "/maintenance/shop",
+                503,
+                Some("text/html"),
+                "<p>shop is unavailable</p>".as_bytes(),
+            ),
+            (
+                "GET",
+                "/maintenance/shop",
+                503,
+                Some("text/html"),
+                "<p>shop is unavailable</p>".as_bytes(),
+            ),
+            (
+                "GET",
+                "/favicon.ico",
+                200,
+                Some("image/x-icon"),
+                b"\x00\x01\x00\x00\xff".as_slice(),
+            ),
+            (
+                "GET",
+                "/empty",
+                204,
+                Some("text/plain; charset=utf-8"),
+                b"".as_slice(),
+            ),
+        ] {
+            let mut header = RequestHeader::build(method, path.as_bytes(), None)?;
+            header.insert_header("Host", "localhost")?;
+            let mut session = TestSession::from(header).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent,
+                "{method} {path}"
+            );
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status.as_u16(), status, "{method} {path}");
+            assert_eq!(
+                response
+                    .headers
+                    .get("Content-Type")
+                    .and_then(|value| value.to_str().ok()),
+                content_type,
+                "{method} {path}"
+            );
+            assert_eq!(
+                response.headers.get("Content-Length").unwrap(),
+                &body.len().to_string(),
+                "{method} {path}"
+            );
+            if method == "HEAD" {
+                assert!(session.response_body.is_empty(), "{method} {path}");
+            } else {
+                assert_eq!(session.response_body, body, "{method} {path}");
+            }
+        }
+
+        // Conditions not met
+        let mut session = make_session("/maintenance/shop/cart").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert!(session.response_written().is_none());
+
+        for conf in [
+            "rewrite_rules: {type: response, body: a, body_base64: YQ==}",
+            "rewrite_rules: {type: response, body_base64: \"not base64\"}",
+            "rewrite_rules: {type: response, content_type: \"text/plain\\n\"}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn multiple_wildcards() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /users/*/files/*
+                    to: /storage${tail1}${tail2}
+                -
+                    from: /users/*/files/*/raw
+                    to: /raw${tail1}${tail2}
+                -
+                    from: /users/*
+                    to: /profile${tail}
+            "#,
+        );
+
+        let mut session = make_session("/users/me/files/dir/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/storage/me/dir/file.txt");
+
+        let mut session = make_session("/users/me/files/file.txt/raw").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/raw/me/file.txt");
+
+        let mut session = make_session("/users/me/settings").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/profile/me/settings");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn conditions() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /path/*
+                    from_regex: "\\.jpg$"
+                    to: /another${tail}
+                -
+                    from: /path/image.jpg
+                    query_regex: "query"
+                    to: /nowhere
+                -
+                    from: /path/*
+                    query_regex: "!^file="
+                    to: /different?${query}
+                -
+                    from: /*
+                    from_regex: ^/file\.txt$
+                    query_regex: "!no_redirect"
+                    to: /other.txt
+            "#,
+        );
+
+        let mut session = make_session("/path/image.jpg").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/image.jpg");
+
+        let mut session = make_session("/path/?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/different?a=b");
+
+        let mut session = make_session("/path/image.png?a=b&file=c").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/different?a=b&file=c");
+
+        let mut session = make_session("/path/image.png?file=c").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/path/image.png?file=c");
+
+        let mut session = make_session("/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/other.txt");
+
+        let mut session = make_session("/file.txt?no_redirect").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/file.txt?no_redirect");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn query_match() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /api
+                    query_match: {v: 2, format: json}
+                    query_match_strict: true
+                    to: /api/v2.json
+                -
+                    from: /api
+                    query_match: {v: 2}
+                    to: /api/v2
+                -
+                    from: /api
+                    query_match: {name: "a b&c"}
+                    to: /api/name
+            "#,
+        );
+
+        for (uri, expected) in [
+            ("/api?format=json&v=2", "/api/v2.json"),
+            ("/api?v=2&format=%6Ason", "/api/v2.json"),
+            ("/api?v=2&format=json&debug", "/api/v2"),
+            ("/api?v=2", "/api/v2"),
+            ("/api?v=1&v=2", "/api/v2"),
+            ("/api?name=a+b%26c", "/api/name"),
+            ("/api?name=a+b&c", "/api?name=a+b&c"),
+            ("/api?v=3&format=json", "/api?v=3&format=json"),
+            ("/api", "/api"),
+        ] {
+            let mut session = make_session(uri).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            assert_eq!(session.uri(), expected, "rewriting {uri}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_params() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /img/*
+                    query_params:
+                        file: "\\.png$"
+                    to: /png
+                -
+                    from: /img/*
+                    query_params:
+                        user name: "^John Doe$"
+                        lang: "!^de$"
+                    to: /user
+            "#,
+        );
+
+        let apply = |uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(apply("/img/?file=a.png").as_deref(), Some("/png"));
+        assert_eq!(apply("/img/?file=a%2Epng").as_deref(), Some("/png"));
+        assert_eq!(apply("/img/?file=a.gif"), None);
+        assert_eq!(apply("/img/?files=a.png"), None);
+        assert_eq!(apply("/img/"), None);
+
+        // Any of the repeated values can match
+        assert_eq!(
+            apply("/img/?file=a.gif&x=1&file=b.png").as_deref(),
+            Some("/png")
+        );
+
+        // `+` is a space, names are decoded as well
+        assert_eq!(apply("/img/?user+name=John+Doe").as_deref(), Some("/user"));
+        assert_eq!(
+            apply("/img/?user%20name=John%20Doe").as_deref(),
+            Some("/user")
+        );
+        assert_eq!(apply("/img/?user%2Bname=John+Doe"), None);
+        assert_eq!(apply("/img/?user+name=John%2BDoe"), None);
+
+        // Missing parameters only match negated regular expressions
+        assert_eq!(
+            apply("/img/?user+name=John+Doe&lang=en").as_deref(),
+            Some("/user")
+        );
+        assert_eq!(apply("/img/?user+name=John+Doe&lang=de"), None);
+    }
+
+    #[test(tokio::test)]
+    async fn accept() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    accept: application/json
+                    to: https://api.example.com${tail}
+                    type: redirect
+            "#,
+        );
+
+        let mut session = make_session("/data").await;
+        session
+            .req_header_mut()
+            .insert_header("Accept", "application/*")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://api.example.com/data")
+        );
+
+        let mut session = make_session("/data").await;
+        session.req_header_mut().insert_header("Accept", "*/*")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+
+        let mut session = make_session("/data").await;
+        session
+            .req_header_mut()
+            .insert_header("Accept", "text/html, */*;q=0.8, application/json;q=0")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/data");
+
+        let mut session = make_session("/data").await;
+        session.req_header_mut().insert_header("Accept", "text/*")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/data");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn interpolation() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /path/*
+                    to: /another${tail}?${query}&host=${http_host}&test=${http_test_header}
+            "#,
+        );
+
+        let mut session = make_session("/path/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/file.txt?&host=&test=");
+
+        let mut session = make_session("/path/file.txt?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/file.txt?a=b&host=&test=");
+
+        let mut session = make_session("/path/file.txt?a=b").await;
+        session
+            .req_header_mut()
+            .insert_header("Host", "localhost")?;
+        session
+            .req_header_mut()
+            .insert_header("Test-Header", "successful")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(
+            session.uri(),
+            "/another/file.txt?a=b&host=localhost&test=successful"
+        );
+
+        // Multiple header values are joined
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    to: /target?test=${http_test_header}
+                    type: redirect
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.append("Test-Header", HeaderValue::from_static("first"));
+        headers.append("Test-Header", HeaderValue::from_static("second"));
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: Uri::from_static("/file"),
+                headers,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            decision.location.as_deref(),
+            Some("/target?test=first, second")
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn filters() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    to: ${http_x_original_url|base64decode}
+                    type: redirect
+            "#,
+        );
+
+        let mut session = make_session("/").await;
+        session
+            .req_header_mut()
+            .insert_header("X-Original-URL", "aHR0cHM6Ly9leGFtcGxlLmNvbS9wYXRo")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/path")
+        );
+
+        let mut session = make_session("/").await;
+        session
+            .req_header_mut()
+            .insert_header("X-Original-URL", "not base64")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("${http_x_original_url|base64decode}")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn text_filters() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /search
+                    to: /find
+                    to_query: q=${http_referer|urldecode|trim|urlencode}
+                -
+                    from: /users/*
+                    to: /u${tail|lowercase}?ref=${http_referer|urlencode}
+            "#,
+        );
+
+        for (path, referer, expected) in [
+            ("/search", "%20A%26B%20", "/find?q=A%26B"),
+            (
+                "/users/JohnDoe",
+                "https://example.com/?a=b&c#d",
+                "/u/johndoe?ref=https%3A%2F%2Fexample.com%2F%3Fa%3Db%26c%23d",
+            ),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::REFERER, HeaderValue::from_static(referer));
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {to: \"/${tail|urlEncode}\"}",
+            "rewrite_rules: {to: /a, type: redirect, headers: {X-Test: \"${tail|unknown}\"}}",
+            "rewrite_redirect_body: \"${location|escape}\"",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        // Not a variable with custom delimiters
+        make_handler(
+            r#"
+                rewrite_rules:
+                    to: "/%{tail}?${tail|unknown}"
+                variable_delimiters:
+                    prefix: "%{"
+                    suffix: "}"
+            "#,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_variables() -> Result<(), Box<Error>> {
+        for rule in [
+            "{from: /about, to: \"/info${tail}\"}",
+            "{from: /dir/*, to: \"/info${tial}\"}",
+            "{from: /dir/*, to: \"/info${tail2}\"}",
+            "{from: /dir/*/sub/*, to: \"/info${tail0}\"}",
+            "{from: /dir/*, to: \"/info/${1}\"}",
+            "{from: /dir/*, from_regex: \"!^/dir/(.*)\", to: \"/info/${1}\"}",
+            "{from: /dir/*, from_regex: \"^/dir/(.*)\", to: \"/info/${2}\"}",
+            "{from: /dir/*, uri_regex: \"^/dir/(?P<page>.*)\", to: \"/info/${name}\"}",
+            "{from: /dir/*, to: \"/info/${lang}\"}",
+            "{from: /dir/*, to: /info, query_set: {a: \"${http_}\"}}",
+            "{from: /dir/*, to: /info, type: redirect, body: \"${tail}\"}",
+        ] {
+            // Only a warning by default
+            make_handler(&format!("rewrite_rules: {rule}"));
+
+            let conf = format!("rewrite_strict_variables: true\nrewrite_rules: {rule}");
+            let parsed = RewriteConf::from_yaml(&conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{rule}");
+        }
+
+        for rule in [
+            "{from: /dir/*/sub/*, to: \"/info${tail}${tail1}${tail2}\"}",
+            "{from: /dir/*, from_regex: \"^/dir/(?P<page>.*)\", to: \"/${0}/${1}/${page}\"}",
+            "{from: /dir/*, accept_language: [en, de], to: \"/${lang}${tail}\"}",
+            "{from: /about, to: \"/${http_host}/${query_a}/${cookie_b}/${time_unix}\"}",
+            "{from: /about, to: \"/${map_a:${remote_addr}}\"}",
+            "{from: /about, to: /info, type: redirect, body: \"${location}\"}",
+            "{from: /about, type: response, body: \"${original_uri}\"}",
+        ] {
+            let conf = format!(
+                "rewrite_strict_variables: true\nrewrite_maps: {{a: {{file: /dev/null}}}}\n\
+                 rewrite_rules: {rule}"
+            );
+            make_handler(&conf);
+        }
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_strict_variables: true
+                rewrite_rules:
+                -
+                    from: /*
+                    to: /${tail}
+                -
+                    name: about
+                    from: /about
+                    to: /info${tail}
+            "#,
+        )?;
+        let err = RewriteHandler::try_from(conf).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("rewrite rule 1 (about) uses variable ${tail}"),
+            "{err}"
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn variable_delimiters() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    to: /another%{tail}?${query}&host=%{http_host}
+                variable_delimiters:
+                    prefix: "%{"
+                    suffix: "}"
+            "#,
+        );
+
+        let mut session = make_session("/file.txt?a=b").await;
+        session
+            .req_header_mut()
+            .insert_header("Host", "localhost")?;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(
+            session.uri().to_string(),
+            "/another/file.txt?${query}&host=localhost"
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn flags() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old/*
+                    to: /new${tail}
+                    flags: "[R=301,L]"
+                -
+                    from: /temporary/*
+                    to: /new${tail}
+                    flags: "[R]"
+                -
+                    from: /images/*
+                    from_regex: "\\.jpg$"
+                    to: /jpeg${tail}
+                    flags: "[NC]"
+                -
+                    from: /*
+                    to: /index.php?page=${tail}
+                    flags: "[QSA]"
+            "#,
+        );
+
+        let mut session = make_session("/old/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::MOVED_PERMANENTLY)
+        );
+
+        let mut session = make_session("/temporary/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::FOUND)
+        );
+
+        let mut session = make_session("/images/image.JPG").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/jpeg/image.JPG");
+
+        let mut session = make_session("/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/index.php?page=/file.txt");
+
+        let mut session = make_session("/file.txt?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/index.php?page=/file.txt&a=b");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn append_query() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /search
+                    to: /find
+                    type: redirect
+                    append_query: true
+                -
+                    from: /view/*
+                    to: /index.php?page=${tail}
+                    append_query: true
+            "#,
+        );
+
+        for (path, expected) in [
+            ("/search?q=test", "/find?q=test"),
+            ("/search?", "/find"),
+            ("/search", "/find"),
+            ("/view/file?a=b&c=d", "/index.php?page=/file&a=b&c=d"),
+            ("/view/file", "/index.php?page=/file"),
+        ] {
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {to: \"/new?${query}\", append_query: true}",
+            "rewrite_rules: {to: \"/new?q=${query|base64encode}\", flags: \"[QSA]\"}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn strip_prefix() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_decode_path: true
+                rewrite_rules:
+                -
+                    from: [/service, /service/*]
+                    strip_prefix: true
+                -
+                    from: /raw/*
+                    strip_prefix: true
+            "#,
+        );
+
+        for (path, expected) in [
+            ("/service", "/"),
+            ("/service/", "/"),
+            ("/service?x=1", "/?x=1"),
+            ("/service/api/users?id=1", "/api/users?id=1"),
+            ("/raw/a%20b%2Fc?q", "/a%20b%2Fc?q"),
+        ] {
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {from: /a/*, to: /b, strip_prefix: true}",
+            "rewrite_rules: {from: /a/*, to_query: b, strip_prefix: true}",
+            "rewrite_rules: {from: /a/*/b/*, strip_prefix: true}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn maps() -> Result<(), Box<Error>> {
+        let dir = std::env::temp_dir().join(format!("rewrite-maps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let articles = dir.join("articles.txt");
+        std::fs::write(&articles, "# Legacy IDs\n123 new-slug\n456 other-slug\n").unwrap();
+        let sections = dir.join("sections.yaml");
+        std::fs::write(&sections, "/news: latest\n").unwrap();
+
+        let handler = make_handler(&format!(
+            r#"
+                rewrite_maps:
+                    articles:
+                        file: {}
+                    sections:
+                        file: {}
+                        format: yaml
+                        required: false
+                rewrite_rules:
+                -
+                    from: /article.php
+                    uri_regex: 'id=(?<id>\d+)'
+                    to: /articles/${{map_articles:${{id}}}}
+                -
+                    from: /article.php
+                    to: /articles/unknown
+                -
+                    from: /section/*
+                    to: /s/${{map_sections:${{tail1}}}}
+            "#,
+            articles.display(),
+            sections.display()
+        ));
+
+        for (path, expected) in [
+            ("/article.php?id=123", "/articles/new-slug"),
+            ("/article.php?id=456", "/articles/other-slug"),
+            ("/article.php?id=789", "/articles/unknown"),
+            ("/section/news", "/s/latest"),
+            ("/section/sports", "/s/"),
+        ] {
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {to: \"/${map_articles:${tail}}\"}".to_owned(),
+            format!(
+                "rewrite_maps: {{articles: {{file: {}}}}}",
+                dir.join("missing.txt").display()
+            ),
+            format!(
+                "rewrite_maps: {{articles: {{file: {}, format: yaml}}}}",
+                articles.display()
+            ),
+            format!("rewrite_maps: {{a-b: {{file: {}}}}}", articles.display()),
+        ] {
+            let parsed = RewriteConf::from_yaml(&conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn connection_variables() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /old/*
+                    to: "${scheme}://${host}:${port}/new${tail}"
+                    type: redirect
+            "#,
+        );
+
+        let apply = |uri: &str, host: Option<&'static str>, tls: bool| {
+            let mut headers = HeaderMap::new();
+            if let Some(host) = host {
+                headers.insert(header::HOST, HeaderValue::from_static(host));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    headers,
+                    tls,
+                    local_port: Some(8443),
+                    // HTTP/2 requests always carry an absolute URI
+                    version: if uri.starts_with('/') {
+                        Version::HTTP_11
+                    } else {
+                        Version::HTTP_2
+                    },
+                    ..Default::default()
+                })
+                .and_then(|decision| decision.location)
+        };
+
+        assert_eq!(
+            apply("/old/file.txt", Some("example.com:8080"), false),
+            Some("http://example.com:8443/new/file.txt".to_owned())
+        );
+        assert_eq!(
+            apply("/old/file.txt", Some("[::1]:8080"), true),
+            Some("https://[::1]:8443/new/file.txt".to_owned())
+        );
+
+        // HTTP/2 requests provide host and scheme via pseudo-headers
+        assert_eq!(
+            apply("https://example.net:8080/old/file.txt", None, false),
+            Some("https://example.net:8443/new/file.txt".to_owned())
+        );
+        assert_eq!(
+            apply("https://user@example.net/old/file.txt", None, true),
+            Some("https://example.net:8443/new/file.txt".to_owned())
+        );
+
+        // Without a host, the variable stays unresolved
+        assert_eq!(
+            apply("/old/file.txt", None, false),
+            Some("http://${host}:8443/new/file.txt".to_owned())
+        );
+
+        let handler = make_handler(
+            r#"
+                rewrite_trusted_proxies: 10.0.0.0/8
+                rewrite_rules:
+                    from: /*
+                    to: "/${scheme}/${host}"
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("localhost"));
+        headers.insert("X-Forwarded-Proto", HeaderValue::from_static("https"));
+        headers.insert(
+            "X-Forwarded-Host",
+            HeaderValue::from_static("example.com:443"),
+        );
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/".try_into().unwrap(),
+                headers,
+                client_ip: Some("10.0.0.1".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "/https/example.com");
+    }
+
+    #[test]
+    fn client_variables() {
+        let handler = make_handler(
+            r#"
+                rewrite_trusted_proxies: 10.0.0.0/8
+                rewrite_rules:
+                -
+                    from: /debug/*
+                    to: "/collect?m=${method}&ip=${remote_addr}"
+                -
+                    from: /back
+                    to: "http://${remote_addr_uri}:8080/"
+                    type: redirect
+            "#,
+        );
+
+        let apply = |path: &str, method: &str, peer: &str, forwarded: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(forwarded) = forwarded {
+                headers.insert("X-Forwarded-For", HeaderValue::from_static(forwarded));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    method: Method::from_bytes(method.as_bytes()).unwrap(),
+                    uri: path.parse().unwrap(),
+                    headers,
+                    client_ip: Some(peer.parse().unwrap()),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        for (path, method, peer, forwarded, expected) in [
+            (
+                "/debug/",
+                "POST",
+                "192.0.2.1",
+                None,
+                "/collect?m=POST&ip=192.0.2.1",
+            ),
+            (
+                "/debug/",
+                "purge",
+                "192.0.2.1",
+                None,
+                "/collect?m=PURGE&ip=192.0.2.1",
+            ),
+            (
+                "/debug/",
+                "GET",
+                "2001:db8::1",
+                None,
+                "/collect?m=GET&ip=2001:db8::1",
+            ),
+            (
+                "/debug/",
+                "GET",
+                "10.0.0.1",
+                Some("192.0.2.7"),
+                "/collect?m=GET&ip=192.0.2.7",
+            ),
+            (
+                "/debug/",
+                "GET",
+                "192.0.2.1",
+                Some("192.0.2.7"),
+                "/collect?m=GET&ip=192.0.2.1",
+            ),
+            ("/back", "GET", "192.0.2.1", None, "http://192.0.2.1:8080/"),
+            (
+                "/back",
+                "GET",
+                "2001:db8::1",
+                None,
+                "http://[2001:db8::1]:8080/",
+            ),
+            (
+                "/back",
+                "GET",
+                "10.0.0.1",
+                Some("2001:db8::2"),
+                "http://[2001:db8::2]:8080/",
+            ),
+        ] {
+            assert_eq!(
+                apply(path, method, peer, forwarded).as_deref(),
+                Some(expected),
+                "{method} {path} from {peer}, forwarded for {forwarded:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn query_variables() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /jump
+                    to: "${query_url}"
+                    type: redirect
+                -
+                    from: /item
+                    to: /items/${query_id|urlencode}
+                    to_query: "name=${query_name}&flag=${query_flag}&empty=${query_empty}"
+            "#,
+        );
+
+        for (uri, expected) in [
+            (
+                "/jump?url=https%3A%2F%2Fexample.com%2Fa",
+                Some("https://example.com/a"),
+            ),
+            ("/jump?x=1&url=/a&url=/b", Some("/a")),
+            // Missing parameter leaves the variable unresolved
+            ("/jump", Some("${query_url}")),
+            (
+                "/item?id=a+b&name=x%20y&flag&empty=",
+                Some("/items/a%20b?name=x%20y&flag=&empty="),
+            ),
+            (
+                "/item?id=1&id=2&name=n&flag=1&empty=",
+                Some("/items/1?name=n&flag=1&empty="),
+            ),
+        ] {
+            assert_eq!(
+                handler
+                    .apply(&RewriteRequest {
+                        uri: uri.try_into().unwrap(),
+                        ..Default::default()
+                    })
+                    .map(|decision| decision.location.unwrap_or(decision.uri.to_string()))
+                    .as_deref(),
+                expected,
+                "target for {uri}"
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn to_host() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /api/*
+                    to: /v1${tail}
+                    to_host: api.internal:8080
+                -
+                    from: /tenant/*
+                    to: /app${tail}
+                    to_host: ${http_x_tenant}.example.com
+                -
+                    from: /moved/*
+                    to: /new${tail}
+                    type: redirect
+                    to_host: www.example.com
+            "#,
+        );
+
+        for (path, tenant, expected_uri, expected_host) in [
+            ("/api/users?a=b", None, "/v1/users", "api.internal:8080"),
+            (
+                "/tenant/page",
+                Some("acme"),
+                "/app/page",
+                "acme.example.com",
+            ),
+            ("/tenant/page", Some("a b"), "/tenant/page", "localhost"),
+            (
+                "/tenant/page",
+                Some("evil.com/x"),
+                "/tenant/page",
+                "localhost",
+            ),
+        ] {
+            let mut header = RequestHeader::build("GET", path.as_bytes(), None)?;
+            header.insert_header("Host", "localhost")?;
+            if let Some(tenant) = tenant {
+                header.insert_header("X-Tenant", tenant)?;
+            }
+            let mut session = TestSession::from(header).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            assert_eq!(session.uri(), expected_uri, "URI for {path}");
+            assert_eq!(
+                session.req_header().headers.get("Host").unwrap(),
+                expected_host,
+                "host for {path}"
+            );
+        }
+
+        let mut session = make_session("/moved/file").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().unwrap().headers.get("Location"),
+            Some(&HeaderValue::from_static("http://www.example.com/new/file"))
+        );
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/moved/file".try_into().unwrap(),
+                tls: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "https://www.example.com/new/file");
+        assert_eq!(decision.host, None);
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/api/users".try_into().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "/v1/users");
+        assert_eq!(decision.host, Some("api.internal:8080".try_into().unwrap()));
+
+        for conf in [
+            "rewrite_rules: {to_host: a/b}",
+            "rewrite_rules: {to_host: \"a b\"}",
+            "rewrite_rules: {to_host: a, redirect_to_host: b}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rewrite_response_location() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    to: /${tail}
+                    rewrite_response_location: true
+                -
+                    from: /service/*
+                    to: /backend${tail}
+                    to_host: internal.example.com
+                    rewrite_response_location: true
+                -
+                    from: /plain/*
+                    to: /${tail}
+            "#,
+        );
+
+        for (path, location, expected) in [
+            ("/app/x", "/login", "/app/login"),
+            ("/app/x", "/?next=1", "/app/?next=1"),
+            ("/app/x", "login", "login"),
+            (
+                "/app/x",
+                "http://localhost/login",
+                "http://localhost/app/login",
+            ),
+            (
+                "/app/x",
+                "https://other.example.com/login",
+                "https://other.example.com/login",
+            ),
+            ("/service/x", "/backend/login#top", "/service/login#top"),
+            ("/service/x", "/backend", "/service"),
+            ("/service/x", "/backend2/login", "/backend2/login"),
+            (
+                "/service/x",
+                "http://internal.example.com/backend/login?a=b",
+                "http://localhost/service/login?a=b",
+            ),
+            (
+                "/service/x",
+                "//internal.example.com/elsewhere",
+                "http://localhost/elsewhere",
+            ),
+            ("/plain/x", "/login", "/login"),
+        ] {
+            let mut header = RequestHeader::build("GET", path.as_bytes(), None)?;
+            header.insert_header("Host", "localhost")?;
+            let mut session = TestSession::from(header).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+
+            let mut response = ResponseHeader::build(302, None)?;
+            response.insert_header(header::LOCATION, location)?;
+            handler.response_filter(&mut session, &mut response, None);
+            assert_eq!(
+                response.headers.get(header::LOCATION).unwrap(),
+                expected,
+                "location {location} for {path}"
+            );
+        }
+
+        for conf in [
+            "rewrite_rules: {from: /a/*, to: /b, type: redirect, rewrite_response_location: true}",
+            "rewrite_rules: {from: /a/*, subst: s/a/b/, rewrite_response_location: true}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rewrite_cookie_path() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    to: /${tail}
+                    rewrite_cookie_path: true
+                -
+                    from: /service/*
+                    to: /internal${tail}
+                    rewrite_cookie_path: true
+                    add_cookie_path: true
+            "#,
+        );
+
+        for (path, cookies, expected) in [
+            (
+                "/app/x",
+                vec!["a=1; Path=/", "b=2; path=/account; HttpOnly", "c=3"],
+                vec!["a=1; Path=/app", "b=2; Path=/app/account; HttpOnly", "c=3"],
+            ),
+            (
+                "/service/x",
+                vec!["a=1; PATH=/internal/", "b=2; Path=/other", "c=3; Secure"],
+                vec![
+                    "a=1; Path=/service",
+                    "b=2; Path=/other",
+                    "c=3; Secure; Path=/service",
+                ],
+            ),
+        ] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+
+            let mut response = ResponseHeader::build(200, None)?;
+            for cookie in cookies {
+                response.append_header(header::SET_COOKIE, cookie)?;
+            }
+            handler.response_filter(&mut session, &mut response, None);
+            assert_eq!(
+                response
+                    .headers
+                    .get_all(header::SET_COOKIE)
+                    .iter()
+                    .collect::<Vec<_>>(),
+                expected,
+                "cookies for {path}"
+            );
+        }
+
+        for conf in [
+            "rewrite_rules: {from: /a/*, to: /b, add_cookie_path: true}",
+            "rewrite_rules: {from: /a/*, to: /b, type: redirect, rewrite_cookie_path: true}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn to_query() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /docs/*
+                    to: /view${tail}
+                    to_query: page=${tail}&name=${http_x_name}
+                    append_query: true
+                -
+                    from: /raw/*
+                    to: /raw/${http_x_name}
+                    to_query: ${query}
+            "#,
+        );
+
+        for (path, name, expected) in [
+            (
+                "/docs/intro?x=1",
+                "a?b#c&d=e+f",
+                "/view/intro?page=/intro&name=a%3Fb%23c%26d%3De%2Bf&x=1",
+            ),
+            ("/docs/intro", "", "/view/intro?page=/intro&name="),
+            ("/raw/x?a=b&c=d", "a?b", "/raw/a%3Fb?a=b&c=d"),
+            ("/raw/x", "n", "/raw/n"),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Name", HeaderValue::from_static(name));
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {to: /new?a=b, to_query: c=d}",
+            "rewrite_rules: {subst: s/a/b/, to_query: c=d}",
+            "rewrite_rules: {to_query: \"q=${query}\", append_query: true}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_manipulation() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /track/*
+                    to: /page${tail}
+                    query_remove: [fbclid, utm_*]
+                -
+                    from: /set/*
+                    to: /page${tail}
+                    query_set:
+                        lang: ${http_x_lang}
+                        page: a b&c
+                    query_add:
+                        source: legacy
+                -
+                    from: /explicit
+                    to: /new?${query}&v=2
+                    query_add: {x: "1"}
+            "#,
+        );
+
+        for (path, lang, expected) in [
+            (
+                "/track/a?utm_source=x&id=1&fbclid=abc&utm_medium=y&flag&id=2",
+                None,
+                "/page/a?id=1&flag&id=2",
+            ),
+            ("/track/a?utm_source=x", None, "/page/a"),
+            (
+                "/track/a?fbclidx=1&Fbclid=2",
+                None,
+                "/page/a?fbclidx=1&Fbclid=2",
+            ),
+            (
+                "/set/x?page=1&q=2&page=3&lang",
+                Some("de"),
+                "/page/x?page=a+b%26c&q=2&lang=de&source=legacy",
+            ),
+            ("/set/x", None, "/page/x?lang=&page=a+b%26c&source=legacy"),
+            (
+                "/set/x?source=new",
+                None,
+                "/page/x?source=new&lang=&page=a+b%26c&source=legacy",
+            ),
+            ("/explicit?a=b", None, "/new?a=b&x=1&v=2"),
+        ] {
+            let mut headers = HeaderMap::new();
+            if let Some(lang) = lang {
+                headers.insert("X-Lang", HeaderValue::from_static(lang));
+            }
+            let decision = handler
+                .apply(&RewriteRequest {
+                    uri: path.try_into().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(decision.uri, expected, "target for {path}");
+        }
+
+        for conf in [
+            "rewrite_rules: {query_remove: \"*\"}",
+            "rewrite_rules: {query_set: {\"utm_*\": x}}",
+            "rewrite_rules: {query_add: {\"a&b\": x}}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn preserve_original() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /path/*
+                    to: /another${tail}
+                    preserve_original_in: X-Original-URI
+                -
+                    from: /redirect
+                    to: /another
+                    type: redirect
+                    preserve_original_in: X-Original-URI
+            "#,
+        );
+
+        let mut session = make_session("/path/file.txt?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/another/file.txt");
+        assert_eq!(
+            session.req_header().headers.get("X-Original-URI"),
+            Some(&HeaderValue::from_static("/path/file.txt?a=b"))
+        );
+
+        let mut session = make_session("/redirect").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(session.req_header().headers.get("X-Original-URI"), None);
+
+        assert!(<RewriteHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+                rewrite_rules:
+                    preserve_original_in: "X Original"
+            "#
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn external_redirect() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /path/*
+                    to: /another${tail}
+                    type: permanent
+                -
+                    from: /file.txt
+                    to: https://example.com/?${query}
+                    type: redirect
+            "#,
+        );
+
+        let mut session = make_session("/path/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("/another/file.txt")
+        );
+
+        let mut session = make_session("/file.txt?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::TEMPORARY_REDIRECT)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://example.com/?a=b")
+        );
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_to_host() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old/*
+                    redirect_to_host: old.example.com
+                    type: permanent
+                -
+                    from: /*
+                    redirect_to_host: www.example.com
+            "#,
+        );
+
+        let mut session = make_session("/foo?bar").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::TEMPORARY_REDIRECT)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://www.example.com/foo?bar")
+        );
+
+        let mut session = make_session("/old/dir/file.txt?a=b&c=d").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::PERMANENT_REDIRECT)
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("https://old.example.com/old/dir/file.txt?a=b&c=d")
+        );
+
+        let make_conf =
+            |conf: &str| <RewriteHandler as RequestFilter>::Conf::from_yaml(conf).unwrap();
+        for to in ["/new", "/"] {
+            assert!(RewriteHandler::try_from(make_conf(&format!(
+                "rewrite_rules: {{from: /*, to: {to}, redirect_to_host: www.example.com}}"
+            )))
+            .is_err());
+        }
+        assert!(RewriteHandler::try_from(make_conf(
+            "rewrite_rules: {from: /*, redirect_to_host: \"www.example.com/path\"}"
+        ))
+        .is_err());
+        assert!(RewriteHandler::try_from(make_conf(
+            "rewrite_rules: {from: /*, redirect_to_host: \"user@example.com\"}"
+        ))
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn redirect_body() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old/*
+                    to: /new${tail}?${query}
+                    type: permanent
+                    body: '<a href="${target}">Moved to ${target}</a>'
+                -
+                    from: /internal
+                    to: /other
+                    body: ignored
+            "#,
+        );
+
+        let mut session = make_session("/old/file.txt?a=b&c=d").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers.get("Location").unwrap(),
+            "/new/file.txt?a=b&c=d"
+        );
+        assert_eq!(
+            response.headers.get("Content-Type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let body = r#"<a href="/new/file.txt?a=b&amp;c=d">Moved to /new/file.txt?a=b&amp;c=d</a>"#;
+        assert_eq!(
+            response.headers.get("Content-Length").unwrap(),
+            &body.len().to_string()
+        );
+        assert_eq!(session.response_body, body);
+
+        let mut session = make_session("/internal").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/other");
+        assert!(session.response_body.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn default_redirect_body() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_redirect_body: '<a href="${location}">Continue</a>'
+                rewrite_rules:
+                -
+                    from: /old/*
+                    to: /new${tail}?${query}
+                    type: redirect
+                -
+                    from: /custom
+                    to: /new
+                    type: redirect
+                    body: 'Moved to ${location}'
+                -
+                    from: /empty
+                    to: /new
+                    type: redirect
+                    body: ""
+                -
+                    from: /internal
+                    to: /other
+            "#,
+        );
+
+        for (path, body) in [
+            (
+                "/old/file.txt?a='x'&b=1",
+                r#"<a href="/new/file.txt?a=&#39;x&#39;&amp;b=1">Continue</a>"#,
+            ),
+            ("/custom", "Moved to /new"),
+            ("/empty", ""),
+        ] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent
+            );
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(
+                response.headers.get("Content-Type").unwrap(),
+                "text/html; charset=utf-8"
+            );
+            assert_eq!(
+                response.headers.get("Content-Length").unwrap(),
+                &body.len().to_string()
+            );
+            assert_eq!(session.response_body, body, "body for {path}");
+        }
+
+        let mut session = make_session("/internal").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/other");
+        assert!(session.response_body.is_empty());
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn response_headers() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /variant/*
+                    to: /b${tail}
+                    type: redirect
+                    headers:
+                        Cache-Control: no-store
+                        Set-Cookie: ["variant=b; Path=/", "page=${tail}"]
+                -
+                    from: /retired
+                    type: gone
+                    headers: {X-Reason: retired}
+                -
+                    from: /data
+                    type: response
+                    body: "{}"
+                    headers: {Content-Type: application/json}
+                -
+                    from: /internal
+                    to: /other
+                    headers: {Cache-Control: no-store}
+            "#,
+        );
+
+        let mut session = make_session("/variant/page").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers.get("Location").unwrap(), "/b/page");
+        assert_eq!(response.headers.get("Cache-Control").unwrap(), "no-store");
+        assert_eq!(
+            response
+                .headers
+                .get_all("Set-Cookie")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["variant=b; Path=/", "page=/page"]
+        );
+
+        let mut session = make_session("/retired").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        let response = session.response_written().unwrap();
+        assert_eq!(response.status, StatusCode::GONE);
+        assert_eq!(response.headers.get("X-Reason").unwrap(), "retired");
+
+        let mut session = make_session("/data").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        let response = session.response_written().unwrap();
+        assert_eq!(
+            response
+                .headers
+                .get_all("Content-Type")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["application/json"]
+        );
+
+        let mut session = make_session("/internal").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/other");
+        assert!(session.response_written().is_none());
+        assert!(handler
+            .apply(&RewriteRequest {
+                uri: "/internal".try_into().unwrap(),
+                ..Default::default()
+            })
+            .is_some_and(|decision| decision.headers.is_empty()));
+
+        for conf in [
+            "rewrite_rules: {type: redirect, headers: {\"Bad Name\": x}}",
+            "rewrite_rules: {type: redirect, headers: {Content-Length: \"0\"}}",
+            "rewrite_rules: {type: redirect, headers: {X-Value: \"a\\nb\"}}",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn debug_header() -> Result<(), Box<Error>> {
+        let conf = r#"
+            rewrite_rules:
+            -
+                from: /a
+                to: /b
+                continue: true
+                name: first
+            -
+                from: /b
+                to: /c
+            -
+                from: /old
+                to: /new
+                type: redirect
+        "#;
+        let handler = make_handler(&format!("{conf}\n            rewrite_debug_header: true"));
+
+        let mut session = make_session("/a").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/c");
+        let mut response = ResponseHeader::build(200, None)?;
+        handler.response_filter(&mut session, &mut response, None);
+        assert_eq!(
+            response.headers.get("X-Applied-Rewrite").unwrap(),
+            "first, 1"
+        );
+
+        let mut session = make_session("/old").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        let response = session.response_written().unwrap();
+        assert_eq!(response.headers.get("X-Applied-Rewrite").unwrap(), "2");
+
+        let mut session = make_session("/other").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        let mut response = ResponseHeader::build(200, None)?;
+        handler.response_filter(&mut session, &mut response, None);
+        assert!(response.headers.get("X-Applied-Rewrite").is_none());
+
+        let handler = make_handler(&format!(
+            "{conf}
+            rewrite_debug_header: true
+            rewrite_debug_header_name: X-Debug"
+        ));
+        let mut session = make_session("/old").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        let response = session.response_written().unwrap();
+        assert_eq!(response.headers.get("X-Debug").unwrap(), "2");
+
+        let handler = make_handler(conf);
+        let mut session = make_session("/old").await;
+        handler
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        let response = session.response_written().unwrap();
+        assert!(response.headers.get("X-Applied-Rewrite").is_none());
+
+        for conf in [
+            "rewrite_rules: {from: /a, to: /b, name: ''}",
+            "rewrite_rules: {from: /a, to: /b, name: 'a, b'}",
+            "rewrite_rules: [{from: /a, to: /b, name: x}, {from: /c, to: /d, name: x}]",
+            "rewrite_rules: [{from: /a, to: /b, name: x}, {from: /c, name: x, enabled: false}]",
+        ] {
+            let parsed = RewriteConf::from_yaml(conf)?;
+            assert!(RewriteHandler::try_from(parsed).is_err(), "{conf}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_rules() {
+        let conf = |enabled: bool| {
+            format!(
+                r#"
+                    rewrite_rules:
+                    -
+                        from: /docs/*
+                        to: /documentation${{tail}}
+                    -
+                        from: /docs/old.html
+                        to: /archive.html
+                        name: archive
+                        enabled: {enabled}
+                    -
+                        from: /docs/legacy/*
+                        to: /legacy${{tail}}
+                        enabled: false
+                "#
+            )
+        };
+        let apply = |handler: &RewriteHandler, path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| (decision.rule, decision.uri.to_string()))
+        };
+
+        let handler = make_handler(&conf(true));
+        assert_eq!(
+            apply(&handler, "/docs/old.html"),
+            Some((1, "/archive.html".to_owned()))
+        );
+
+        // The next closest rule applies, rule indexes don’t change
+        let handler = make_handler(&conf(false));
+        assert_eq!(
+            apply(&handler, "/docs/old.html"),
+            Some((0, "/documentation/old.html".to_owned()))
+        );
+        assert_eq!(
+            apply(&handler, "/docs/legacy/a.html"),
+            Some((0, "/documentation/legacy/a.html".to_owned()))
+        );
+
+        // Disabled rules aren’t validated beyond their names
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /a
+                    to: /b${unknown}
+                    enabled: false
+                rewrite_strict_variables: true
+            "#,
+        );
+        assert_eq!(apply(&handler, "/a"), None);
+    }
+
+    #[test(tokio::test)]
+    async fn from_glob() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from_glob: /docs/**/*.md
+                    to: /markdown${tail}
+                -
+                    from_glob: /docs/v[0-9]/intro.md
+                    to: /versioned${tail}
+                -
+                    from_glob: /file?.txt
+                    to: /files${tail}
+            "#,
+        );
+
+        let mut session = make_session("/docs/guide/intro.md").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/markdown/guide/intro.md");
+
+        // More literal segments take precedence
+        let mut session = make_session("/docs/v2/intro.md").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/versioned/v2/intro.md");
+
+        let mut session = make_session("/docs/intro.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/docs/intro.txt");
+
+        let mut session = make_session("/file1.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/files/file1.txt");
+
+        let mut session = make_session("/file10.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/file10.txt");
+
+        // Explicit `from` patterns conflict with `from_glob`, even the default `/*`
+        for from in ["/docs/*", "/*"] {
+            assert!(RewriteHandler::try_from(
+                RewriteConf::from_yaml(format!(
+                    r#"
+                        rewrite_rules:
+                            from: {from}
+                            from_glob: /docs/*.md
+                    "#
+                ))
+                .unwrap()
+            )
+            .is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn from_host() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /docs/*
+                    from_host: [Example.com, "*.example.net"]
+                    from_regex: "\\.html$"
+                    to: /documentation${tail}
+                -
+                    from: /docs/*
+                    from_host: localhost:8080
+                    to: /local${tail}
+            "#,
+        );
+
+        let apply = |host: Option<&str>, uri: &str| {
+            let mut headers = HeaderMap::new();
+            if let Some(host) = host {
+                headers.insert(header::HOST, host.try_into().unwrap());
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let documentation = Some("/documentation/intro.html");
+        assert_eq!(
+            apply(Some("example.com"), "/docs/intro.html").as_deref(),
+            documentation
+        );
+        assert_eq!(
+            apply(Some("EXAMPLE.COM:8443"), "/docs/intro.html").as_deref(),
+            documentation
+        );
+        assert_eq!(
+            apply(Some("www.example.net"), "/docs/intro.html").as_deref(),
+            documentation
+        );
+        assert_eq!(
+            apply(Some("a.b.example.net"), "/docs/intro.html").as_deref(),
+            documentation
+        );
+        assert_eq!(
+            apply(None, "https://example.com/docs/intro.html").as_deref(),
+            documentation
+        );
+
+        // All conditions have to hold
+        assert_eq!(apply(Some("example.com"), "/docs/intro.txt"), None);
+        assert_eq!(apply(Some("example.com"), "/other/intro.html"), None);
+        assert_eq!(apply(Some("example.net"), "/docs/intro.html"), None);
+        assert_eq!(apply(Some("badexample.net"), "/docs/intro.html"), None);
+        assert_eq!(apply(Some("example.org"), "/docs/intro.html"), None);
+        assert_eq!(apply(None, "/docs/intro.html"), None);
+
+        // Explicit port in the pattern has to match
+        assert_eq!(
+            apply(Some("localhost:8080"), "/docs/intro.txt").as_deref(),
+            Some("/local/intro.txt")
+        );
+        assert_eq!(apply(Some("localhost"), "/docs/intro.txt"), None);
+        assert_eq!(apply(Some("localhost:8081"), "/docs/intro.txt"), None);
+
+        assert!(RewriteHandler::try_from(
+            RewriteConf::from_yaml(
+                r#"
+                    rewrite_rules:
+                        from_host: www.*.com
+                "#
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn methods() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old-api/*
+                    methods: [get, HEAD]
+                    to: /new-api${tail}
+                    type: permanent
+                -
+                    from: /old-api/*
+                    methods: Post
+                    to: /legacy${tail}
+            "#,
+        );
+
+        let make_session = |method: &'static str, path: &'static str| async move {
+            let header = RequestHeader::build(method, path.as_bytes(), None).unwrap();
+            TestSession::from(header).await
+        };
+
+        for method in ["GET", "HEAD"] {
+            let mut session = make_session(method, "/old-api/users").await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::ResponseSent
+            );
+            let response = session.response_written().unwrap();
+            assert_eq!(response.status, StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(response.headers.get("Location").unwrap(), "/new-api/users");
+        }
+
+        let mut session = make_session("POST", "/old-api/users").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/legacy/users");
+
+        let mut session = make_session("PUT", "/old-api/users").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/old-api/users");
+
+        let err = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    methods: [GET, PSOT]
+            "#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("`PSOT` is not a known HTTP method"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scheme() {
+        let mut conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    from_regex: "!^/health$"
+                    scheme: http
+                    to: https://example.com${tail}
+                    type: permanent
+                -
+                    from: /secure/*
+                    scheme: https
+                    to: /tls${tail}
+            "#,
+        )
+        .unwrap();
+
+        let apply_version = |handler: &RewriteHandler,
+                             uri: &str,
+                             tls: bool,
+                             forwarded: Option<&str>,
+                             version: Version| {
+            let mut headers = HeaderMap::new();
+            if let Some(forwarded) = forwarded {
+                headers.insert("X-Forwarded-Proto", forwarded.try_into().unwrap());
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    headers,
+                    tls,
+                    client_ip: Some("10.0.0.1".parse().unwrap()),
+                    version,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+        let apply = |handler: &RewriteHandler, uri: &str, tls: bool, forwarded: Option<&str>| {
+            apply_version(handler, uri, tls, forwarded, Version::HTTP_11)
+        };
+
+        let handler = RewriteHandler::try_from(conf.clone()).unwrap();
+        let redirect = Some("https://example.com/file.txt");
+        assert_eq!(
+            apply(&handler, "/file.txt", false, None).as_deref(),
+            redirect
+        );
+        assert_eq!(apply(&handler, "/health", false, None), None);
+        assert_eq!(apply(&handler, "/file.txt", true, None), None);
+        assert_eq!(
+            apply(&handler, "/secure/file.txt", true, None).as_deref(),
+            Some("/tls/file.txt")
+        );
+
+        // URI scheme from HTTP/2 `:scheme` pseudo-header takes precedence
+        assert_eq!(
+            apply_version(
+                &handler,
+                "https://example.com/file.txt",
+                false,
+                None,
+                Version::HTTP_2
+            ),
+            None
+        );
+        assert_eq!(
+            apply_version(
+                &handler,
+                "http://example.com/file.txt",
+                true,
+                None,
+                Version::HTTP_2
+            )
+            .as_deref(),
+            redirect
+        );
+
+        // HTTP/1 absolute-form URIs cannot override the connection type
+        assert_eq!(
+            apply(&handler, "https://example.com/file.txt", false, None).as_deref(),
+            redirect
+        );
+        assert_eq!(
+            apply(&handler, "http://example.com/file.txt", true, None),
+            None
+        );
+
+        // X-Forwarded-Proto is ignored unless the proxy is trusted
+        assert_eq!(
+            apply(&handler, "/file.txt", false, Some("https")).as_deref(),
+            redirect
+        );
+
+        conf.rewrite_trusted_proxies = vec!["10.0.0.0/8".try_into().unwrap()].into();
+        let handler = RewriteHandler::try_from(conf).unwrap();
+        assert_eq!(apply(&handler, "/file.txt", false, Some("HTTPS")), None);
+        assert_eq!(
+            apply(&handler, "/file.txt", false, Some("https, http")),
+            None
+        );
+        assert_eq!(
+            apply(&handler, "/file.txt", true, Some("http")).as_deref(),
+            redirect
+        );
+        assert_eq!(
+            apply(&handler, "/file.txt", false, Some("invalid")).as_deref(),
+            redirect
+        );
+    }
+
+    #[test]
+    fn from_ip() {
+        let mut conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /internal/*
+                    from_ip: [10.0.0.0/8, "fd00::/8", "!10.1.0.0/16"]
+                    to: /intranet${tail}
+                -
+                    from: /*
+                    from_ip: "!192.168.1.1"
+                    to: /public${tail}
+            "#,
+        )
+        .unwrap();
+
+        let apply = |handler: &RewriteHandler, peer: Option<&str>, forwarded: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(forwarded) = forwarded {
+                headers.insert("X-Forwarded-For", forwarded.try_into().unwrap());
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: "/internal/file.txt".parse().unwrap(),
+                    headers,
+                    client_ip: peer.map(|peer| peer.parse().unwrap()),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = RewriteHandler::try_from(conf.clone()).unwrap();
+        let intranet = Some("/intranet/file.txt");
+        let public = Some("/public/internal/file.txt");
+        assert_eq!(apply(&handler, Some("10.2.3.4"), None).as_deref(), intranet);
+        assert_eq!(apply(&handler, Some("fd12::1"), None).as_deref(), intranet);
+        assert_eq!(apply(&handler, Some("10.1.2.3"), None).as_deref(), public);
+        assert_eq!(apply(&handler, Some("11.2.3.4"), None).as_deref(), public);
+        assert_eq!(apply(&handler, Some("fe80::1"), None).as_deref(), public);
+        assert_eq!(apply(&handler, Some("192.168.1.1"), None), None);
+        assert_eq!(apply(&handler, None, None), None);
+
+        // IPv4-mapped IPv6 addresses match IPv4 ranges
+        assert_eq!(
+            apply(&handler, Some("::ffff:10.2.3.4"), None).as_deref(),
+            intranet
+        );
+        assert_eq!(apply(&handler, Some("::ffff:192.168.1.1"), None), None);
+
+        // X-Forwarded-For is ignored unless the proxy is trusted
+        assert_eq!(apply(&handler, Some("192.168.1.1"), Some("10.2.3.4")), None);
+
+        conf.rewrite_trusted_proxies = vec!["192.168.1.1".try_into().unwrap()].into();
+        let handler = RewriteHandler::try_from(conf).unwrap();
+        assert_eq!(
+            apply(&handler, Some("192.168.1.1"), Some("10.2.3.4")).as_deref(),
+            intranet
+        );
+        assert_eq!(
+            apply(&handler, Some("192.168.1.1"), Some("10.2.3.4, 11.2.3.4")).as_deref(),
+            public
+        );
+        assert_eq!(
+            apply(&handler, Some("192.168.1.1"), Some("11.2.3.4, 10.2.3.4")).as_deref(),
+            intranet
+        );
+        assert_eq!(apply(&handler, None, Some("10.2.3.4")), None);
+
+        // Invalid X-Forwarded-For values fall back to the client address
+        assert_eq!(apply(&handler, Some("192.168.1.1"), Some("unknown")), None);
+        assert_eq!(apply(&handler, Some("192.168.1.1"), Some("")), None);
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let handler = make_handler(
+            r#"
+                rewrite_trusted_proxies: [10.0.0.0/8, "fd00::/8"]
+                rewrite_rules:
+                -
+                    from: /internal/*
+                    from_ip: 192.168.0.0/16
+                    to: /intranet${tail}
+                -
+                    from: /secure/*
+                    scheme: https
+                    to: /tls${tail}
+                -
+                    from: /*
+                    from_host: example.com
+                    to: /example${tail}
+            "#,
+        );
+
+        let apply = |path: &str, peer: &str, headers: &[(&'static str, &'static str)]| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| {
+                            (
+                                HeaderName::from_static(name),
+                                HeaderValue::from_static(value),
+                            )
+                        })
+                        .collect(),
+                    client_ip: Some(peer.parse().unwrap()),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        // Client address from the right-most untrusted entry
+        let intranet = Some("/intranet/file.txt");
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-for", "192.168.1.1")]
+            )
+            .as_deref(),
+            intranet
+        );
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-for", "1.2.3.4, 192.168.1.1, 10.0.0.2, fd00::1")]
+            )
+            .as_deref(),
+            intranet
+        );
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "::ffff:10.0.0.1",
+                &[
+                    ("x-forwarded-for", "192.168.1.1"),
+                    ("x-forwarded-for", "10.0.0.3")
+                ]
+            )
+            .as_deref(),
+            intranet
+        );
+
+        // Spoofed entries left of the client address are ignored
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-for", "192.168.1.1, 1.2.3.4")]
+            ),
+            None
+        );
+
+        // Forwarded headers from untrusted peers are ignored
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "1.2.3.4",
+                &[("x-forwarded-for", "192.168.1.1")]
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/secure/file.txt",
+                "1.2.3.4",
+                &[("x-forwarded-proto", "https")]
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/file.txt",
+                "1.2.3.4",
+                &[("host", "example.net"), ("x-forwarded-host", "example.com")]
+            ),
+            None
+        );
+
+        // Malformed entries stop processing, the last address determined is used
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-for", "192.168.1.1, unknown, 10.0.0.2")]
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-for", "1.2.3.4, 192.168.1.1, ")]
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/internal/file.txt",
+                "192.168.1.1",
+                &[("x-forwarded-for", "garbage")]
+            )
+            .as_deref(),
+            intranet
+        );
+
+        // Scheme and host from trusted peers
+        assert_eq!(
+            apply(
+                "/secure/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-proto", "https")]
+            )
+            .as_deref(),
+            Some("/tls/file.txt")
+        );
+        assert_eq!(
+            apply(
+                "/secure/file.txt",
+                "10.0.0.1",
+                &[("x-forwarded-proto", "gopher")]
+            ),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/file.txt",
+                "10.0.0.1",
+                &[
+                    ("host", "example.net"),
+                    ("x-forwarded-host", "example.com:8080")
+                ]
+            )
+            .as_deref(),
+            Some("/example/file.txt")
+        );
+        assert_eq!(
+            apply(
+                "/file.txt",
+                "10.0.0.1",
+                &[
+                    ("host", "example.com"),
+                    ("x-forwarded-host", "example.net/x")
+                ]
+            )
+            .as_deref(),
+            Some("/example/file.txt")
+        );
+        assert_eq!(
+            apply(
+                "/file.txt",
+                "10.0.0.1",
+                &[("host", "example.com"), ("x-forwarded-host", "example.net")]
+            ),
+            None
+        );
+
+        let conf = RewriteConf::from_yaml("rewrite_trusted_proxies: \"!10.0.0.0/8\"").unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn listen_port() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    listen_port: [8080, 8443]
+                    to: /alt${tail}
+                -
+                    from: /admin/*
+                    listen_port: 9000
+                    to: /console${tail}
+            "#,
+        );
+
+        let apply = |uri: &str, local_port: Option<u16>| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    local_port,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(
+            apply("/file.txt", Some(8080)).as_deref(),
+            Some("/alt/file.txt")
+        );
+        assert_eq!(
+            apply("/file.txt", Some(8443)).as_deref(),
+            Some("/alt/file.txt")
+        );
+        assert_eq!(apply("/file.txt", Some(80)), None);
+        assert_eq!(apply("/file.txt", None), None);
+        assert_eq!(
+            apply("/admin/index.html", Some(9000)).as_deref(),
+            Some("/console/index.html")
+        );
+        assert_eq!(
+            apply("/admin/index.html", Some(8080)).as_deref(),
+            Some("/alt/admin/index.html")
+        );
+        assert_eq!(apply("/admin/index.html", Some(80)), None);
+        assert_eq!(apply("/file.txt", Some(9000)), None);
+    }
+
+    #[derive(Debug)]
+    struct FixedClock(SystemTime);
+
+    impl crate::clock::Clock for FixedClock {
+        fn now(&self) -> SystemTime {
+            self.0
+        }
+    }
+
+    #[test]
+    fn time_variables() {
+        let mut handler = make_handler(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    to: "${tail}?v=${time_unix}&ms=${time_msec}&again=${time_unix}"
+                    type: redirect
+                    headers:
+                        X-Time: ${time_iso8601}
+            "#,
+        );
+        let time = DateTime::parse_from_rfc3339("2024-06-01T12:30:45.678+02:00").unwrap();
+        handler.clock = SharedClock::new(FixedClock(time.into()));
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/page".parse().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            decision.uri,
+            "/page?v=1717237845&ms=1717237845678&again=1717237845"
+        );
+        assert_eq!(
+            decision.headers.get("X-Time").unwrap(),
+            "2024-06-01T10:30:45Z"
+        );
+    }
+
+    #[test]
+    fn active_window() {
+        let mut handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /maintenance/*
+                    active_from: 2024-06-01T22:00:00Z
+                    active_until: "2024-06-02T02:00:00+02:00"
+                    to: /closed.html
+                -
+                    from: /sale/*
+                    active_until: 2024-06-01T00:00:00Z
+                    to: /sale-ended.html
+                    type: redirect
+                -
+                    from: /launch/*
+                    active_from: 2024-06-03T12:00:00.5Z
+                    to: /product.html
+            "#,
+        );
+
+        let mut apply = |time: &str, path: &str| {
+            let time = DateTime::parse_from_rfc3339(time).unwrap().into();
+            handler.clock = SharedClock::new(FixedClock(time));
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let closed = Some("/closed.html");
+        assert_eq!(apply("2024-06-01T21:59:59Z", "/maintenance/x"), None);
+        assert_eq!(
+            apply("2024-06-01T22:00:00Z", "/maintenance/x").as_deref(),
+            closed
+        );
+        assert_eq!(
+            apply("2024-06-01T23:59:59Z", "/maintenance/x").as_deref(),
+            closed
+        );
+        assert_eq!(apply("2024-06-02T00:00:00Z", "/maintenance/x"), None);
+
+        // Only `active_until` set: active right away, then expires
+        assert_eq!(
+            apply("2000-01-01T00:00:00Z", "/sale/x").as_deref(),
+            Some("/sale-ended.html")
+        );
+        assert_eq!(apply("2024-06-01T00:00:00Z", "/sale/x"), None);
+
+        // Only `active_from` set: inactive until then
+        assert_eq!(apply("2024-06-03T12:00:00Z", "/launch/x"), None);
+        assert_eq!(
+            apply("2024-06-03T12:00:00.5Z", "/launch/x").as_deref(),
+            Some("/product.html")
+        );
+        assert_eq!(
+            apply("2100-01-01T00:00:00Z", "/launch/x").as_deref(),
+            Some("/product.html")
+        );
+
+        assert!(RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    active_from: 2024-06-01
+                    to: /closed.html
+            "#,
+        )
+        .is_err());
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: /*
+                    active_from: 2024-06-02T00:00:00Z
+                    active_until: 2024-06-01T00:00:00Z
+                    to: /closed.html
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn multiple_from() {
+        let handler = make_handler(
+            r#"
+                rewrite_metrics: true
+                rewrite_rules:
+                -
+                    from: [/old/*, /legacy/*, /v1/*, /old/*]
+                    to: /new${tail}
+                    type: permanent
+                -
+                    from: /legacy/special/*
+                    to: /special${tail}
+                -
+                    from: [/v1/*/file.txt, /v1/exact]
+                    to: /file
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| (decision.rule, decision.uri.to_string()))
+        };
+
+        let new = |path: &str| Some((0, path.to_owned()));
+        assert_eq!(apply("/old/a/b.txt"), new("/new/a/b.txt"));
+        assert_eq!(apply("/legacy/a/b.txt"), new("/new/a/b.txt"));
+        assert_eq!(apply("/v1/a/b.txt"), new("/new/a/b.txt"));
+        assert_eq!(apply("/v1"), new("/new/"));
+        assert_eq!(apply("/other/a/b.txt"), None);
+
+        // Closer matches still take precedence for each of the paths
+        assert_eq!(
+            apply("/legacy/special/x"),
+            Some((1, "/special/x".to_owned()))
+        );
+        assert_eq!(apply("/v1/a/file.txt"), Some((2, "/file".to_owned())));
+        assert_eq!(apply("/v1/exact"), Some((2, "/file".to_owned())));
+        assert_eq!(apply("/v1/exact/x"), new("/new/exact/x"));
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: [/a/*, /b/*]
+                    from_glob: /docs/*.md
+                    to: /file
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn extensions() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /images/*
+                    extensions: [png, jpg, .webp]
+                    to: /optimized${tail}
+                -
+                    extensions: tar.gz
+                    to: /archive${tail}
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(
+            apply("/images/logo.png").as_deref(),
+            Some("/optimized/logo.png")
+        );
+        assert_eq!(
+            apply("/images/a/photo.JPG").as_deref(),
+            Some("/optimized/a/photo.JPG")
+        );
+        assert_eq!(
+            apply("/images/photo.webp").as_deref(),
+            Some("/optimized/photo.webp")
+        );
+        assert_eq!(apply("/images/photo.jpeg"), None);
+        assert_eq!(apply("/images/png"), None);
+        assert_eq!(apply("/images/logo.png.bak"), None);
+        assert_eq!(apply("/images/dir.png/"), None);
+        assert_eq!(apply("/images/dir.png/file"), None);
+        assert_eq!(apply("/other/logo.png"), None);
+
+        assert_eq!(
+            apply("/downloads/src.TAR.GZ").as_deref(),
+            Some("/archive/downloads/src.TAR.GZ")
+        );
+        assert_eq!(apply("/downloads/src.gz"), None);
+
+        for extension in ["\"\"", "\".\"", "a/b"] {
+            let conf = RewriteConf::from_yaml(format!("rewrite_rules:\n  extensions: {extension}"))
+                .unwrap();
+            assert!(RewriteHandler::try_from(conf).is_err());
+        }
+    }
+
+    #[test]
+    fn from_exclude() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    from_exclude: [/app/static/*, /app/health]
+                    to: /index.html
+                -
+                    from: /app/static/fonts/*
+                    to: /fonts${tail}
+                -
+                    from: /docs/*
+                    from_exclude: /docs/static
+                    from_regex: "\\.txt$"
+                    to: /docs.txt
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let index = Some("/index.html");
+        assert_eq!(apply("/app").as_deref(), index);
+        assert_eq!(apply("/app/page").as_deref(), index);
+        assert_eq!(apply("/app/statics").as_deref(), index);
+        assert_eq!(apply("/app/static"), None);
+        assert_eq!(apply("/app/static/"), None);
+        assert_eq!(apply("/app/static/app.js"), None);
+        assert_eq!(apply("/app//static/app.js"), None);
+        assert_eq!(apply("/app/health"), None);
+        assert_eq!(apply("/app/health/"), None);
+        assert_eq!(apply("/app/health/details").as_deref(), index);
+
+        // A separate rule for a nested path isn’t affected by the exclusion
+        assert_eq!(
+            apply("/app/static/fonts/font.woff").as_deref(),
+            Some("/fonts/font.woff")
+        );
+
+        // Exact exclusions only exclude the path itself
+        assert_eq!(apply("/docs/static"), None);
+        assert_eq!(apply("/docs/static/file.txt").as_deref(), Some("/docs.txt"));
+        assert_eq!(apply("/docs/static/file.html"), None);
+    }
+
+    #[test]
+    fn negated_from() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: [/app/*, "!/app/internal/*", "!/app/health"]
+                    to: /index.html
+                -
+                    from: /app/internal/public/*
+                    to: /public${tail}
+                -
+                    from: /app/internal
+                    from_regex: "^/none$"
+                -
+                    from: "!/app/*"
+                    to: /other
+                -
+                    from: [/admin/*, "!/admin/*"]
+                    to: /admin.html
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let index = Some("/index.html");
+        assert_eq!(apply("/app").as_deref(), index);
+        assert_eq!(apply("/app/page").as_deref(), index);
+        assert_eq!(apply("/app/internals").as_deref(), index);
+        assert_eq!(apply("/app/internal"), None);
+        assert_eq!(apply("/app/internal/"), None);
+        assert_eq!(apply("/app/internal/page"), None);
+        assert_eq!(apply("/app/health"), None);
+        assert_eq!(apply("/app/health/details").as_deref(), index);
+        assert_eq!(
+            apply("/app/internal/public/file.txt").as_deref(),
+            Some("/public/file.txt")
+        );
+        assert_eq!(apply("/").as_deref(), Some("/other"));
+        assert_eq!(apply("/application").as_deref(), Some("/other"));
+        assert_eq!(apply("/admin/page").as_deref(), Some("/other"));
+
+        // Excluded directories are removed from the router, only exact exclusions have to be
+        // checked when evaluating the rule.
+        let rules = handler.rules.load();
+        let candidates = |path: &str| {
+            rules
+                .router
+                .lookup("", path)
+                .map(|list| {
+                    list.rules
+                        .iter()
+                        .map(|(_, rule)| rule.index)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        assert_eq!(candidates("/app/page"), vec![0]);
+        assert_eq!(candidates("/app/internal"), vec![2]);
+        assert!(candidates("/app/internal/page").is_empty());
+        assert_eq!(candidates("/app/internal/public/file.txt"), vec![1]);
+        assert_eq!(candidates("/app/health"), vec![0]);
+        assert_eq!(candidates("/admin/page"), vec![3]);
+
+        // Exclusions of case-sensitive rules cannot be handled by a lowercase router.
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: [/app/*, "!/app/internal/*"]
+                    to: /index.html
+                -
+                    from: /other/*
+                    case_insensitive: true
+                    to: /other
+            "#,
+        );
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+        assert_eq!(apply("/app/internal/page"), None);
+        assert_eq!(apply("/app/Internal/page").as_deref(), index);
+        assert_eq!(apply("/OTHER/page").as_deref(), Some("/other"));
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: [/app/*, "!/app/*/internal"]
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /download/*/Files/*
+                    case_insensitive: true
+                    to: /files${tail1}${tail2}
+                -
+                    from: /Download/*
+                    from_exclude: /download/private/*
+                    from_regex: "\\.ZIP$"
+                    case_insensitive: true
+                    to: /downloads${tail}
+                -
+                    from: /Download/*
+                    to: /other${tail}
+                -
+                    from: /Docs/*
+                    to: /documentation${tail}
+                -
+                    from_glob: /Images/*.PNG
+                    case_insensitive: true
+                    to: /img${tail}
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        // `${tail}` keeps the casing of the request
+        assert_eq!(
+            apply("/download/Archive.zip").as_deref(),
+            Some("/downloads/Archive.zip")
+        );
+        assert_eq!(
+            apply("/DOWNLOAD/Archive.Zip").as_deref(),
+            Some("/downloads/Archive.Zip")
+        );
+        assert_eq!(
+            apply("/DownLoad/x/FILES/Doc.ZIP").as_deref(),
+            Some("/files/x/Doc.ZIP")
+        );
+
+        // Exclusions are case-insensitive as well
+        assert_eq!(
+            apply("/Download/Private/Archive.zip").as_deref(),
+            Some("/other/Private/Archive.zip")
+        );
+        assert_eq!(
+            apply("/Download/file.txt").as_deref(),
+            Some("/other/file.txt")
+        );
+        assert_eq!(apply("/download/file.txt"), None);
+
+        // Case-sensitive rules still require the exact casing
+        assert_eq!(
+            apply("/Docs/Intro.html").as_deref(),
+            Some("/documentation/Intro.html")
+        );
+        assert_eq!(apply("/docs/Intro.html"), None);
+        assert_eq!(apply("/DOCS"), None);
+
+        assert_eq!(apply("/images/Logo.png").as_deref(), Some("/img/Logo.png"));
+        assert_eq!(apply("/images/sub/Logo.png"), None);
+    }
+
+    #[test]
+    fn header_regex() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    header_regex:
+                        X-Beta-User: "^1$"
+                    to: /beta${tail}
+                -
+                    from: /app/*
+                    header_regex:
+                        User-Agent: "MyApp/[12]\\."
+                        x-client-version: "!."
+                    to: /legacy${tail}
+                -
+                    from: /api/*
+                    header_regex:
+                        Accept-Language: "de, fr"
+                    to: /api/multilingual${tail}
+            "#,
+        );
+
+        let apply = |uri: &str, headers: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.append(*name, HeaderValue::from_static(value));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    headers: map,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(
+            apply("/app/index.html", &[("X-Beta-User", "1")]).as_deref(),
+            Some("/beta/index.html")
+        );
+        assert_eq!(apply("/app/index.html", &[("X-Beta-User", "0")]), None);
+        assert_eq!(apply("/app/index.html", &[]), None);
+
+        assert_eq!(
+            apply("/app/index.html", &[("User-Agent", "MyApp/2.5")]).as_deref(),
+            Some("/legacy/index.html")
+        );
+        assert_eq!(
+            apply("/app/index.html", &[("User-Agent", "MyApp/3.0")]),
+            None
+        );
+        assert_eq!(
+            apply(
+                "/app/index.html",
+                &[("User-Agent", "MyApp/2.5"), ("X-Client-Version", "2")]
+            ),
+            None
+        );
+
+        // Multiple values are matched as a comma-separated list
+        assert_eq!(
+            apply(
+                "/api/data",
+                &[("Accept-Language", "de"), ("Accept-Language", "fr")]
+            )
+            .as_deref(),
+            Some("/api/multilingual/data")
+        );
+        assert_eq!(apply("/api/data", &[("Accept-Language", "de")]), None);
+
+        assert!(RewriteHandler::try_from(
+            RewriteConf::from_yaml(
+                r#"
+                    rewrite_rules:
+                        header_regex:
+                            "invalid header": "."
+                "#
+            )
+            .unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn has_header() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /internal/*
+                    lacks_header: X-Internal-Auth
+                    to: /login
+                -
+                    from: /api/*
+                    has_header: [X-Api-Key, X-Client]
+                    to: /v2${tail}
+            "#,
+        );
+
+        let apply = |path: &str, headers: &[(&'static str, &'static str)]| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| {
+                            (
+                                HeaderName::from_static(name),
+                                HeaderValue::from_static(value),
+                            )
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(apply("/internal/page", &[]).as_deref(), Some("/login"));
+        assert_eq!(
+            apply("/internal/page", &[("x-internal-auth", "token")]),
+            None
+        );
+        assert_eq!(apply("/internal/page", &[("x-internal-auth", "")]), None);
+        assert_eq!(
+            apply(
+                "/internal/page",
+                &[("x-internal-auth", "a"), ("x-internal-auth", "b")]
+            ),
+            None
+        );
+
+        assert_eq!(apply("/api/users", &[("x-api-key", "secret")]), None);
+        assert_eq!(
+            apply("/api/users", &[("x-api-key", ""), ("x-client", "")]).as_deref(),
+            Some("/v2/users")
+        );
+        assert_eq!(
+            apply(
+                "/api/users",
+                &[("x-api-key", "a"), ("x-api-key", "b"), ("x-client", "c")]
+            )
+            .as_deref(),
+            Some("/v2/users")
+        );
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    has_header: "X Api Key"
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    lacks_header: ""
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn cookie_regex() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    cookie_regex:
+                        region: "^eu$"
+                    to: /eu${tail}
+                -
+                    from: /*
+                    cookie_regex:
+                        consent: "!."
+                    to: /consent
+            "#,
+        );
+
+        let apply = |cookies: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for cookie in cookies {
+                headers.append(header::COOKIE, HeaderValue::from_static(cookie));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: "/page.html".parse().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(apply(&["region=eu"]).as_deref(), Some("/eu/page.html"));
+        assert_eq!(
+            apply(&["consent=1; region=\"eu\""]).as_deref(),
+            Some("/eu/page.html")
+        );
+        assert_eq!(
+            apply(&["consent=1", "region=eu; region=us"]).as_deref(),
+            Some("/eu/page.html")
+        );
+        assert_eq!(apply(&["consent=1; region=us"]), None);
+        assert_eq!(apply(&["consent=1; region=eu-west"]), None);
+
+        // Missing cookie is matched as empty value
+        assert_eq!(apply(&[]).as_deref(), Some("/consent"));
+        assert_eq!(apply(&["region=us"]).as_deref(), Some("/consent"));
+        assert_eq!(apply(&["consent="]).as_deref(), Some("/consent"));
+
+        // Garbage doesn’t cause trouble
+        assert_eq!(apply(&[";;=;region"]).as_deref(), Some("/consent"));
+        assert_eq!(
+            apply(&["consent=1;;garbage;region=eu"]).as_deref(),
+            Some("/eu/page.html")
+        );
+    }
+
+    #[test]
+    fn cookie_variables() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    cookie_regex:
+                        shard: "^[a-z0-9]+$"
+                    to: /shard/${cookie_shard}${tail}
+                -
+                    from: /*
+                    to: "https://${cookie_tenant}.example.com/"
+                    type: redirect
+            "#,
+        );
+
+        let apply = |cookies: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for cookie in cookies {
+                headers.append(header::COOKIE, HeaderValue::from_static(cookie));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: "/page.html".parse().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .map(|decision| decision.location.unwrap_or(decision.uri.to_string()))
+        };
+
+        assert_eq!(
+            apply(&["a=1; shard=s1"]).as_deref(),
+            Some("/shard/s1/page.html")
+        );
+        assert_eq!(
+            apply(&["shard=\"s2\"", "shard=s3"]).as_deref(),
+            Some("/shard/s2/page.html")
+        );
+        assert_eq!(
+            apply(&["shard=Upper; tenant=acme"]).as_deref(),
+            Some("https://acme.example.com/")
+        );
+        assert_eq!(
+            apply(&["tenant=\"acme\"; tenant=other"]).as_deref(),
+            Some("https://acme.example.com/")
+        );
+
+        // Missing cookie leaves the variable unresolved
+        assert_eq!(
+            apply(&["shard="]).as_deref(),
+            Some("https://${cookie_tenant}.example.com/")
+        );
+    }
+
+    #[test]
+    fn ua_regex() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    from_regex: "\\.(css|js)$"
+                    to: /static${tail}
+                -
+                    from: /app/*
+                    ua_regex: "bot|crawler|spider"
+                    to: /prerendered${tail}
+                -
+                    from: /app/*
+                    ua_regex: "!bot|crawler|spider"
+                    to: /index.html
+                -
+                    from: /strict/*
+                    ua_regex: "(?-i)^Mozilla/"
+                    to: /browser
+            "#,
+        );
+
+        let apply = |path: &str, user_agent: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in user_agent {
+                headers.append(header::USER_AGENT, HeaderValue::from_static(value));
+            }
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    headers,
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let browser = "Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0";
+        let crawler = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+        assert_eq!(
+            apply("/app/page", &[crawler]).as_deref(),
+            Some("/prerendered/page")
+        );
+        assert_eq!(
+            apply("/app/page", &["Some-Spider/1.0"]).as_deref(),
+            Some("/prerendered/page")
+        );
+        assert_eq!(
+            apply("/app/page", &[browser]).as_deref(),
+            Some("/index.html")
+        );
+        assert_eq!(
+            apply("/app/page", &[browser, "BOT"]).as_deref(),
+            Some("/prerendered/page")
+        );
+
+        // Missing header is matched as empty string
+        assert_eq!(apply("/app/page", &[]).as_deref(), Some("/index.html"));
+
+        // Other conditions are evaluated independently
+        assert_eq!(
+            apply("/app/main.js", &[crawler]).as_deref(),
+            Some("/static/main.js")
+        );
+
+        // Case-insensitive matching can be disabled
+        assert_eq!(
+            apply("/strict/page", &[browser]).as_deref(),
+            Some("/browser")
+        );
+        assert_eq!(apply("/strict/page", &["mozilla/5.0"]), None);
+    }
+
+    #[test]
+    fn accept_language() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /
+                    accept_language: [de, fr, en-US]
+                    to: /${lang}/
+                    type: redirect
+                -
+                    from: /docs/*
+                    accept_language: de-CH
+                    to: /docs/${lang}${tail}
+            "#,
+        );
+
+        let apply = |uri: &str, accept_language: Option<&'static str>| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    headers: accept_language
+                        .map(|value| (header::ACCEPT_LANGUAGE, HeaderValue::from_static(value)))
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(apply("/", Some("de")).as_deref(), Some("/de/"));
+        assert_eq!(
+            apply("/", Some("fr-CA, fr;q=0.9, de;q=0.8")).as_deref(),
+            Some("/fr/")
+        );
+        assert_eq!(
+            apply("/", Some("ja, en;q=0.5, de;q=0.2")).as_deref(),
+            Some("/en-US/")
+        );
+        assert_eq!(apply("/", Some("en-GB")), None);
+        assert_eq!(apply("/", Some("*")).as_deref(), Some("/de/"));
+        assert_eq!(apply("/", Some("*, de;q=0")).as_deref(), Some("/fr/"));
+        assert_eq!(apply("/", Some("*;q=0")), None);
+        assert_eq!(apply("/", None), None);
+
+        assert_eq!(
+            apply("/docs/index.html", Some("de")).as_deref(),
+            Some("/docs/de-CH/index.html")
+        );
+        assert_eq!(apply("/docs/index.html", Some("de-AT")), None);
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    accept_language: [de, "de_CH"]
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+    }
+
+    #[test]
+    fn query_presence() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /present/*
+                    query: present
+                    to: /canonical${tail}
+                -
+                    from: /absent/*
+                    query: absent
+                    to: /plain${tail}
+                -
+                    from: /empty/*
+                    query: empty
+                    to: /bare${tail}
+                -
+                    from: /combined/*
+                    query: present
+                    query_regex: "!."
+                    to: /combined${tail}
+            "#,
+        );
+
+        let apply = |uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        // The URI parser keeps a bare `?` as an empty query
+        assert_eq!("/path?".parse::<Uri>().unwrap().query(), Some(""));
+        assert_eq!("/path".parse::<Uri>().unwrap().query(), None);
+
+        assert_eq!(
+            apply("/present/file.txt?utm_source=x").as_deref(),
+            Some("/canonical/file.txt")
+        );
+        assert_eq!(
+            apply("/present/file.txt?").as_deref(),
+            Some("/canonical/file.txt")
+        );
+        assert_eq!(apply("/present/file.txt"), None);
+
+        assert_eq!(
+            apply("/absent/file.txt").as_deref(),
+            Some("/plain/file.txt")
+        );
+        assert_eq!(apply("/absent/file.txt?"), None);
+        assert_eq!(apply("/absent/file.txt?a=b"), None);
+
+        assert_eq!(apply("/empty/file.txt?").as_deref(), Some("/bare/file.txt"));
+        assert_eq!(apply("/empty/file.txt"), None);
+        assert_eq!(apply("/empty/file.txt?a=b"), None);
+
+        // `query_regex` can't tell a missing query from an empty one, `query` can
+        assert_eq!(
+            apply("/combined/file.txt?").as_deref(),
+            Some("/combined/file.txt")
+        );
+        assert_eq!(apply("/combined/file.txt"), None);
+        assert_eq!(apply("/combined/file.txt?a=b"), None);
+    }
+
+    #[test]
+    fn rule_groups() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /pricing
+                    to: /plans
+                rule_groups:
+                -
+                    from_host: example.com
+                    header_regex:
+                        X-Beta-User: "^1$"
+                    rewrite_rules:
+                    -
+                        from: /docs/*
+                        to: /beta/docs${tail}
+                    -
+                        from: /pricing
+                        methods: GET
+                        header_regex:
+                            X-Region: "^eu$"
+                        to: /beta/pricing-eu
+                    -
+                        from: /pricing
+                        priority: 1
+                        to: /beta/pricing
+            "#,
+        );
+
+        let apply = |uri: &str, headers: &[(&'static str, &'static str)]| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| {
+                            (
+                                HeaderName::from_static(name),
+                                HeaderValue::from_static(value),
+                            )
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .map(|decision| (decision.rule, decision.uri.to_string()))
+        };
+
+        let beta = [("host", "example.com"), ("x-beta-user", "1")];
+        assert_eq!(
+            apply("/docs/intro", &beta),
+            Some((1, "/beta/docs/intro".to_owned()))
+        );
+        assert_eq!(
+            apply("/pricing", &beta),
+            Some((3, "/beta/pricing".to_owned()))
+        );
+        assert_eq!(apply("/docs/intro", &[("host", "example.com")]), None);
+        assert_eq!(
+            apply(
+                "/docs/intro",
+                &[("host", "example.net"), ("x-beta-user", "1")]
+            ),
+            None
+        );
+
+        // Ungrouped rules still apply if group conditions aren’t met
+        assert_eq!(
+            apply("/pricing", &[("host", "example.com")]),
+            Some((0, "/plans".to_owned()))
+        );
+
+        // Group conditions are combined with those of the member rule
+        let handler = make_handler(
+            r#"
+                rule_groups:
+                    header_regex:
+                        X-Beta-User: "^1$"
+                    rewrite_rules:
+                        from: /pricing
+                        header_regex:
+                            X-Region: "^eu$"
+                        to: /beta/pricing-eu
+            "#,
+        );
+        let apply = |headers: &[(&'static str, &'static str)]| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: "/pricing".try_into().unwrap(),
+                    headers: headers
+                        .iter()
+                        .map(|(name, value)| {
+                            (
+                                HeaderName::from_static(name),
+                                HeaderValue::from_static(value),
+                            )
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+        assert_eq!(
+            apply(&[("x-beta-user", "1"), ("x-region", "eu")]).as_deref(),
+            Some("/beta/pricing-eu")
+        );
+        assert_eq!(apply(&[("x-beta-user", "1")]), None);
+        assert_eq!(apply(&[("x-region", "eu")]), None);
+
+        // Member rules cannot override group conditions
+        for conf in [
+            r#"
+                rule_groups:
+                    from_host: example.com
+                    rewrite_rules:
+                        from_host: example.net
+            "#,
+            r#"
+                rule_groups:
+                    header_regex:
+                        X-Beta-User: "^1$"
+                    rewrite_rules:
+                        header_regex:
+                            X-Beta-User: "^0$"
+            "#,
+        ] {
+            let conf = RewriteConf::from_yaml(conf).unwrap();
+            let err = RewriteHandler::try_from(conf).unwrap_err();
+            assert!(err.to_string().contains("rewrite rule 0"));
+        }
+    }
+
+    #[test]
+    fn regex_mode() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /all/*
+                    from_regex: ["^/all/img/", "!\\.svg$"]
+                    to: /all
+                -
+                    from: /any/*
+                    from_regex: ["^/any/img/", "!\\.svg$"]
+                    regex_mode: any
+                    to: /any
+                -
+                    from: /none/*
+                    from_regex: ["!^/none/img/", "!\\.svg$"]
+                    regex_mode: any
+                    to: /none
+                -
+                    from: /query/*
+                    query_regex: ["(^|&)a=", "(^|&)b="]
+                    regex_mode: any
+                    from_regex: "\\.html$"
+                    to: /query
+            "#,
+        );
+
+        let apply = |uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        // `all`: under /img/ and not an SVG file
+        assert_eq!(apply("/all/img/a.png").as_deref(), Some("/all"));
+        assert_eq!(apply("/all/img/a.svg"), None);
+        assert_eq!(apply("/all/doc/a.png"), None);
+        assert_eq!(apply("/all/doc/a.svg"), None);
+
+        // `any`: under /img/ or not an SVG file
+        assert_eq!(apply("/any/img/a.png").as_deref(), Some("/any"));
+        assert_eq!(apply("/any/img/a.svg").as_deref(), Some("/any"));
+        assert_eq!(apply("/any/doc/a.png").as_deref(), Some("/any"));
+        assert_eq!(apply("/any/doc/a.svg"), None);
+
+        // `any` with only negated entries: not both under /img/ and an SVG file
+        assert_eq!(apply("/none/img/a.png").as_deref(), Some("/none"));
+        assert_eq!(apply("/none/img/a.svg"), None);
+        assert_eq!(apply("/none/doc/a.svg").as_deref(), Some("/none"));
+
+        // `regex_mode` applies to each list, both conditions still have to be met
+        assert_eq!(apply("/query/a.html?b=1").as_deref(), Some("/query"));
+        assert_eq!(apply("/query/a.html?c=1&a=1").as_deref(), Some("/query"));
+        assert_eq!(apply("/query/a.html?c=1"), None);
+        assert_eq!(apply("/query/a.txt?a=1"), None);
+    }
+
+    #[test]
+    fn from_regex_captures() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /blog/*
+                    from_regex: "^/blog/(\\d{4})/(?P<slug>[^/]+?)(\\.html)?$"
+                    to: /posts/${slug}?year=${1}&ext=${3}&unknown=${4}${missing}
+                -
+                    from: /other/*
+                    from_regex: "!^/other/(\\d+)$"
+                    to: /not-numeric?value=${1}
+                -
+                    from: /multi/*
+                    from_regex: ["!^/multi/x/", "^/multi/(\\w+)/", "(?P<ext>\\.\\w+)$", "(\\w+)"]
+                    to: /${1}?ext=${ext}
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        assert_eq!(
+            apply("/blog/2024/hello.html").as_deref(),
+            Some("/posts/hello?year=2024&ext=.html&unknown=${4}${missing}")
+        );
+
+        // Unmatched optional group resolves to an empty string
+        assert_eq!(
+            apply("/blog/2024/hello").as_deref(),
+            Some("/posts/hello?year=2024&ext=&unknown=${4}${missing}")
+        );
+
+        assert_eq!(apply("/blog/latest").as_deref(), None);
+
+        // Negated regular expressions don’t provide captures
+        assert_eq!(
+            apply("/other/abc").as_deref(),
+            Some("/not-numeric?value=${1}")
+        );
+
+        // With multiple regular expressions, the first one defining a group counts
+        assert_eq!(
+            apply("/multi/dir/file.txt").as_deref(),
+            Some("/dir?ext=.txt")
+        );
+    }
+
+    #[test]
+    fn uri_regex() {
+        let mut conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    from_regex: "\\.php$"
+                    query_regex: "(^|&)preview=1(&|$)"
+                    uri_regex: "^/(?P<page>[^?]+)\\.php\\?(.*&)?id=(\\d+)"
+                    to: /preview/${page}?id=${3}
+                -
+                    from: /docs/*
+                    from_regex: "^/docs/(\\w+)"
+                    uri_regex: "!\\?v=1(&|$)"
+                    to: /docs-v2/${1}
+            "#,
+        )
+        .unwrap();
+
+        let apply = |handler: &RewriteHandler, uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = RewriteHandler::try_from(conf.clone()).unwrap();
+
+        // All of `from_regex`, `query_regex` and `uri_regex` have to match
+        assert_eq!(
+            apply(&handler, "/page.php?preview=1&id=12").as_deref(),
+            Some("/preview/page?id=12")
+        );
+        assert_eq!(
+            apply(&handler, "/page.php?id=12&preview=1").as_deref(),
+            Some("/preview/page?id=12")
+        );
+        assert_eq!(apply(&handler, "/page.php?preview=1"), None);
+        assert_eq!(apply(&handler, "/page.php?id=12"), None);
+        assert_eq!(apply(&handler, "/page.html?preview=1&id=12"), None);
+
+        // Capture groups of `from_regex` take precedence, negated expressions don’t capture
+        assert_eq!(
+            apply(&handler, "/docs/intro?v=2").as_deref(),
+            Some("/docs-v2/intro")
+        );
+        assert_eq!(apply(&handler, "/docs/intro?v=1"), None);
+
+        // The URI is matched as received, even if the path is decoded for other conditions
+        conf.rewrite_decode_path = true;
+        let handler = RewriteHandler::try_from(conf).unwrap();
+        assert_eq!(
+            apply(&handler, "/caf%C3%A9.php?preview=1&id=3").as_deref(),
+            Some("/preview/caf%C3%A9?id=3")
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn subst() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /docs/*
+                    subst: s|^/docs/(.*)\.htm$|/documentation/$1.html|i
+                -
+                    from: /images/*
+                    subst: s|_|-|g
+                    type: redirect
+                    flags: QSA
+            "#,
+        );
+
+        let mut session = make_session("/docs/Intro.HTM?a=b").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/documentation/Intro.html");
+
+        // Substitution doesn’t match, so the rule doesn’t apply
+        let mut session = make_session("/docs/intro.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/docs/intro.txt");
+
+        let mut session = make_session("/images/my_new_image.png?x=y").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session
+                .response_written()
+                .and_then(|r| r.headers.get("Location"))
+                .map(|h| h.to_str().unwrap()),
+            Some("/images/my-new-image.png?x=y")
+        );
+
+        let make_conf = |conf| <RewriteHandler as RequestFilter>::Conf::from_yaml(conf).unwrap();
+        assert!(RewriteHandler::try_from(make_conf(
+            "rewrite_rules: {from: /*, to: /new, subst: s|a|b|}"
+        ))
+        .is_err());
+        assert!(RewriteHandler::try_from(make_conf(
+            "rewrite_rules: {from: /*, redirect_to_host: example.com, subst: s|a|b|}"
+        ))
+        .is_err());
+        assert!(<RewriteHandler as RequestFilter>::Conf::from_yaml(
+            "rewrite_rules: {subst: s|a|b|x}"
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn equal_specificity() {
+        let make_request = |uri: &str| RewriteRequest {
+            uri: uri.try_into().unwrap(),
+            ..Default::default()
+        };
+        let target = |handler: &RewriteHandler, uri| {
+            handler
+                .apply(&make_request(uri))
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*/a/*
+                    to: /first
+                -
+                    from: /dir/*/*/b
+                    to: /second
+            "#,
+        );
+        assert_eq!(target(&handler, "/dir/x/a/b").as_deref(), Some("/first"));
+        assert_eq!(target(&handler, "/dir/x/y/b").as_deref(), Some("/second"));
+
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*/*/b
+                    to: /second
+                -
+                    from: /dir/*/a/*
+                    to: /first
+            "#,
+        );
+        assert_eq!(target(&handler, "/dir/x/a/b").as_deref(), Some("/second"));
+        assert_eq!(target(&handler, "/dir/x/a/c").as_deref(), Some("/first"));
+
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /dir/*/*/b
+                    to: /second
+                -
+                    from: /dir/*/a/*
+                    priority: 1
+                    to: /first
+            "#,
+        );
+        assert_eq!(target(&handler, "/dir/x/a/b").as_deref(), Some("/first"));
+        assert_eq!(target(&handler, "/dir/x/y/b").as_deref(), Some("/second"));
+    }
+
+    #[test]
+    fn continue_rewriting() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /a/*
+                    to: /b/x${tail}
+                    continue: true
+                    preserve_original_in: X-Original-URI
+                -
+                    from: /b/*
+                    to: /c${tail}
+                    continue: true
+                -
+                    from: /b/x/stop
+                    to: /stopped
+                -
+                    from: /c/*
+                    from_regex: "\\.php$"
+                    to: /index.php?path=${tail}
+                -
+                    from: /c/x/redirect
+                    to: /a/redirect
+                    type: redirect
+                    continue: true
+                -
+                    from: /last/*
+                    to: /c${tail}
+                    flags: "[L]"
+                    continue: true
+                -
+                    from: /loop/*
+                    to: /loop${tail}
+                    continue: true
+            "#,
+        );
+        let apply = |uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| {
+                    (
+                        decision.uri.to_string(),
+                        decision.previous_rules,
+                        decision.rule,
+                    )
+                })
+        };
+
+        // `${tail}` of the second rule is determined from the intermediate URI `/b/x/file.txt`
+        assert_eq!(
+            apply("/a/file.txt"),
+            Some(("/c/x/file.txt".into(), vec![0], 1))
+        );
+        assert_eq!(
+            apply("/a/file.php"),
+            Some(("/index.php?path=/x/file.php".into(), vec![0, 1], 3))
+        );
+        assert_eq!(apply("/a/stop"), Some(("/stopped".into(), vec![0], 2)));
+        assert_eq!(
+            apply("/b/file.txt"),
+            Some(("/c/file.txt".into(), vec![], 1))
+        );
+        assert_eq!(
+            apply("/a/redirect"),
+            Some(("/a/redirect".into(), vec![0, 1], 4))
+        );
+        assert_eq!(
+            apply("/last/file.php"),
+            Some(("/c/file.php".into(), vec![], 5))
+        );
+        assert_eq!(
+            apply("/loop/file"),
+            Some(("/loop/file".into(), vec![6; 9], 6))
+        );
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/a/file.txt".try_into().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            decision.preserve_original_in,
+            Some(HeaderName::from_static("x-original-uri"))
+        );
+        assert_eq!(decision.r#type, RewriteType::Internal);
+    }
+
+    #[test(tokio::test)]
+    async fn rewrite_loop() -> Result<(), Box<Error>> {
+        let conf = |max_internal: usize, limit_exceeded: &str| {
+            format!(
+                r#"
+                    rewrite_rules:
+                    -
+                        from: /x/*
+                        to: /y${{tail}}
+                        continue: true
+                    -
+                        from: /y/*
+                        to: /x${{tail}}
+                        continue: true
+                    rewrite_max_internal: {max_internal}
+                    rewrite_limit_exceeded: {limit_exceeded}
+                "#
+            )
+        };
+        let apply = |handler: &RewriteHandler, uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| {
+                    let mut rules = decision.previous_rules;
+                    rules.push(decision.rule);
+                    (decision.uri.to_string(), rules)
+                })
+        };
+
+        let handler = make_handler(&conf(10, "serve"));
+        assert_eq!(
+            apply(&handler, "/x/file"),
+            Some(("/x/file".into(), [0, 1].repeat(5)))
+        );
+
+        let handler = make_handler(&conf(3, "serve"));
+        assert_eq!(
+            apply(&handler, "/x/file"),
+            Some(("/y/file".into(), vec![0, 1, 0]))
+        );
+
+        let mut session = make_session("/x/file").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/y/file");
+
+        let handler = make_handler(&conf(3, "error"));
+        assert_eq!(apply(&handler, "/x/file"), None);
+
+        let mut session = make_session("/x/file").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+
+        let conf = RewriteConf::from_yaml(conf(0, "serve")).unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn priority() {
+        let apply = |handler: &RewriteHandler, uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /images/*
+                    to: /fallback.png
+                -
+                    from: /images/*
+                    from_regex: "\\.png$"
+                    priority: 10
+                    to: /png${tail}
+                -
+                    from: /images/*
+                    from_regex: "\\.gif$"
+                    priority: 10
+                    to: /gif${tail}
+                -
+                    from: /images/*
+                    from_regex: "logo"
+                    priority: 10
+                    to: /logo${tail}
+            "#,
+        );
+        assert_eq!(
+            apply(&handler, "/images/photo.png").as_deref(),
+            Some("/png/photo.png")
+        );
+        assert_eq!(
+            apply(&handler, "/images/photo.gif").as_deref(),
+            Some("/gif/photo.gif")
+        );
+        assert_eq!(
+            apply(&handler, "/images/logo.png").as_deref(),
+            Some("/png/logo.png")
+        );
+        assert_eq!(
+            apply(&handler, "/images/logo.jpg").as_deref(),
+            Some("/logo/logo.jpg")
+        );
+        assert_eq!(
+            apply(&handler, "/images/photo.jpg").as_deref(),
+            Some("/fallback.png")
+        );
+
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    priority: -1
+                    to: /last
+                -
+                    from: /*
+                    query_regex: "a"
+                    to: /first
+                -
+                    from: /dir/*
+                    priority: -5
+                    to: /dir
+            "#,
+        );
+        assert_eq!(apply(&handler, "/file?a").as_deref(), Some("/first"));
+        assert_eq!(apply(&handler, "/file").as_deref(), Some("/last"));
+        assert_eq!(apply(&handler, "/dir/file?a").as_deref(), Some("/dir"));
+    }
+
+    #[test(tokio::test)]
+    async fn rule_order() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /*
+                    query_regex: "1"
+                    to: /1
+                -
+                    from: /path/*
+                    query_regex: "2"
+                    to: /2
+                -
+                    from: /path/*
+                    query_regex: "3"
+                    to: /3
+                -
+                    from: /path
+                    query_regex: "4"
+                    to: /4
+                -
+                    from: /path
+                    query_regex: "5"
+                    to: /5
+            "#,
+        );
+
+        let mut session = make_session("/path?12345").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/4");
+
+        let mut session = make_session("/path?1235").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/5");
+
+        let mut session = make_session("/path?123").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/2");
 
-    async fn make_session(path: &str) -> TestSession {
-        let header = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        let mut session = make_session("/path?13").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/3");
 
-        TestSession::from(header).await
+        let mut session = make_session("/path?1").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/1");
+
+        Ok(())
     }
 
     #[test(tokio::test)]
-    async fn internal_redirect() -> Result<(), Box<Error>> {
-        let handler = make_handler(
+    async fn decode_path() -> Result<(), Box<Error>> {
+        let mut conf = <RewriteHandler as RequestFilter>::Conf::from_yaml(
             r#"
                 rewrite_rules:
-                    from: /path/*
-                    to: /another${tail}
+                -
+                    from: /café/*
+                    to: /cafe${tail}
+                -
+                    from: /*
+                    from_regex: "é\\.txt$"
+                    to: /accented${tail}
             "#,
+        )
+        .unwrap();
+
+        let handler = RewriteHandler::try_from(conf.clone())?;
+        let mut session = make_session("/caf%C3%A9/file.txt").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
         );
+        assert_eq!(session.uri(), "/caf%C3%A9/file.txt");
 
-        let mut session = make_session("/").await;
+        conf.rewrite_decode_path = true;
+        let handler = RewriteHandler::try_from(conf)?;
+
+        let mut session = make_session("/caf%C3%A9/file.txt").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/");
-        assert_eq!(session.original_uri(), "/");
+        assert_eq!(session.uri(), "/cafe/file.txt");
 
-        let mut session = make_session("/path").await;
+        let mut session = make_session("/caf%C3%A9/d%C3%A9j%C3%A0%20vu%3F.txt").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/another/");
-        assert_eq!(session.original_uri(), "/path");
+        assert_eq!(session.uri(), "/cafe/d%C3%A9j%C3%A0%20vu%3F.txt");
 
-        let mut session = make_session("/path/").await;
+        let mut session = make_session("/dir/r%C3%A9sum%C3%A9.txt").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/another/");
-        assert_eq!(session.original_uri(), "/path/");
+        assert_eq!(session.uri(), "/accented/dir/r%C3%A9sum%C3%A9.txt");
 
-        let mut session = make_session("/path/file.txt").await;
+        let mut session = make_session("/dir/invalid%FF%zz.txt").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/another/file.txt");
-        assert_eq!(session.original_uri(), "/path/file.txt");
+        assert_eq!(session.uri(), "/dir/invalid%FF%zz.txt");
+
+        // Bytes that aren’t valid UTF-8 stay encoded
+        let mut session = make_session("/caf%C3%A9/%FF%C3%A9%FE.bin").await;
+        assert_eq!(
+            handler
+                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                .await?,
+            RequestFilterResult::Unhandled
+        );
+        assert_eq!(session.uri(), "/cafe/%FF%C3%A9%FE.bin");
 
         Ok(())
     }
 
-    #[test(tokio::test)]
-    async fn conditions() -> Result<(), Box<Error>> {
+    #[test]
+    fn path_variables() {
         let handler = make_handler(
             r#"
                 rewrite_rules:
                 -
-                    from: /path/*
-                    from_regex: "\\.jpg$"
-                    to: /another${tail}
+                    from: /old/*
+                    to: /new${tail}?${query}
+                    continue: true
                 -
-                    from: /path/image.jpg
-                    query_regex: "query"
-                    to: /nowhere
+                    from: /new/*
+                    to: "https://example.com${uri}"
+                    type: redirect
                 -
-                    from: /path/*
-                    query_regex: "!^file="
-                    to: /different?${query}
+                    from: /docs/*
+                    to: /docs/index.html?path=${path|urlencode}&tail=${tail|urlencode}
                 -
                     from: /*
-                    from_regex: ^/file\.txt$
-                    query_regex: "!no_redirect"
-                    to: /other.txt
+                    from_regex: "[^/]$"
+                    to: ${path}/
+                    type: redirect
             "#,
         );
 
-        let mut session = make_session("/path/image.jpg").await;
+        for (uri, expected) in [
+            // Variables refer to the URI after previous internal rewrites
+            ("/old/a%20b?x=1", "https://example.com/new/a%20b?x=1"),
+            ("/old/a?", "https://example.com/new/a"),
+            ("/new/a?x=1&y", "https://example.com/new/a?x=1&y"),
+            // `${tail}` is only the part matched by the wildcard
+            (
+                "/docs/api/intro",
+                "/docs/index.html?path=%2Fdocs%2Fapi%2Fintro&tail=%2Fapi%2Fintro",
+            ),
+            ("/about?lang=de", "/about/"),
+        ] {
+            assert_eq!(
+                handler
+                    .apply(&RewriteRequest {
+                        uri: uri.parse().unwrap(),
+                        ..Default::default()
+                    })
+                    .map(|decision| decision.uri.to_string())
+                    .as_deref(),
+                Some(expected),
+                "target for {uri}"
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn original_uri() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /a
+                    to: /b?x=1
+                    continue: true
+                -
+                    from: /b
+                    to: /c${original_path}?query=${original_query|urlencode}&uri=${original_uri|urlencode}
+                    continue: true
+                -
+                    from: /c/*
+                    query: absent
+                    to: /login?return=${original_uri|urlencode}
+                    type: redirect
+            "#,
+        );
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/a?y=2".parse().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "/c/a?query=y%3D2&uri=%2Fa%3Fy%3D2");
+        assert_eq!(decision.previous_rules, vec![0]);
+
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/a".parse().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "/c/a?query=&uri=%2Fa");
+
+        // URI modified by another module before
+        let mut session = make_session("/vhost/a?y=2").await;
+        session.set_uri("/a?y=2".parse().unwrap());
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/another/image.jpg");
+        assert_eq!(
+            session.uri(),
+            "/c/vhost/a?query=y%3D2&uri=%2Fvhost%2Fa%3Fy%3D2"
+        );
+        assert_eq!(session.original_uri(), "/vhost/a?y=2");
 
-        let mut session = make_session("/path/?a=b").await;
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/c/a".parse().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.uri, "/login?return=%2Fc%2Fa");
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_path() {
+        let mut conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /old/*
+                    to: /moved?from=${original_path}
+                    type: redirect
+                -
+                    from: /*
+                    to: /normalized${tail}
+            "#,
+        )
+        .unwrap();
+
+        let apply = |handler: &RewriteHandler, path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = RewriteHandler::try_from(conf.clone()).unwrap();
+        assert_eq!(
+            apply(&handler, "/x/../old/file").as_deref(),
+            Some("/normalized/x/../old/file")
+        );
+
+        conf.rewrite_normalize_path = true;
+        let handler = RewriteHandler::try_from(conf.clone()).unwrap();
+        assert_eq!(
+            apply(&handler, "/a//b/./c/../d").as_deref(),
+            Some("/normalized/a/b/d")
+        );
+        assert_eq!(
+            apply(&handler, "/x/../old/file").as_deref(),
+            Some("/moved?from=/x/../old/file")
+        );
+
+        // Trailing slashes are kept, `.` and `..` as last segment produce one
+        assert_eq!(
+            apply(&handler, "/a/b/").as_deref(),
+            Some("/normalized/a/b/")
+        );
+        assert_eq!(
+            apply(&handler, "/a/b//").as_deref(),
+            Some("/normalized/a/b/")
+        );
+        assert_eq!(
+            apply(&handler, "/a/b/.").as_deref(),
+            Some("/normalized/a/b/")
+        );
+        assert_eq!(
+            apply(&handler, "/a/b/..").as_deref(),
+            Some("/normalized/a/")
+        );
+        assert_eq!(apply(&handler, "/a/b").as_deref(), Some("/normalized/a/b"));
+
+        // `..` at the beginning cannot escape the root
+        assert_eq!(apply(&handler, "/..").as_deref(), Some("/normalized/"));
+        assert_eq!(
+            apply(&handler, "/../../etc/passwd").as_deref(),
+            Some("/normalized/etc/passwd")
+        );
+        assert_eq!(
+            apply(&handler, "/a/../../old/file").as_deref(),
+            Some("/moved?from=/a/../../old/file")
+        );
+
+        // Encoded dots are only resolved if the path is decoded first
+        assert_eq!(
+            apply(&handler, "/a/%2e%2e/old/file").as_deref(),
+            Some("/normalized/a/%2e%2e/old/file")
+        );
+        conf.rewrite_decode_path = true;
+        let handler = RewriteHandler::try_from(conf).unwrap();
+        assert_eq!(
+            apply(&handler, "/a/%2e%2e/old/file").as_deref(),
+            Some("/moved?from=/a/%2e%2e/old/file")
+        );
+    }
+
+    #[test]
+    fn raw_path() {
+        let handler = make_handler(
+            r#"
+                rewrite_decode_path: true
+                rewrite_normalize_path: true
+                rewrite_rules:
+                -
+                    from: /files/*
+                    from_regex: "(?i)%2e"
+                    raw_path: true
+                    to: /blocked
+                -
+                    from: /files/*
+                    from_regex: "\\.\\."
+                    to: /dots
+                -
+                    from: /files/*
+                    to: /storage${tail}
+                -
+                    from: /raw/*
+                    raw_path: true
+                    to: /target${tail}
+                -
+                    from: /decoded/*
+                    to: /target${tail}
+            "#,
+        );
+
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        // Encoded dots are only visible in the path as received
+        assert_eq!(apply("/files/a/%2e%2e/b.txt").as_deref(), Some("/blocked"));
+        assert_eq!(apply("/files/a%2Eb.txt").as_deref(), Some("/blocked"));
+
+        // `..` segments are resolved before `from_regex` sees the path unless `raw_path` is set
+        assert_eq!(
+            apply("/files/a.b/../c.txt").as_deref(),
+            Some("/storage/c.txt")
+        );
+        assert_eq!(apply("/files/a..b.txt").as_deref(), Some("/dots"));
+        assert_eq!(
+            apply("/files/a.b/c.txt").as_deref(),
+            Some("/storage/a.b/c.txt")
+        );
+
+        // Tails keep the encoding of the request
+        assert_eq!(
+            apply("/raw/a%2Eb.txt").as_deref(),
+            Some("/target/a%2Eb.txt")
+        );
+        assert_eq!(
+            apply("/decoded/a%2Eb.txt").as_deref(),
+            Some("/target/a.b.txt")
+        );
+        assert_eq!(
+            apply("/raw/a/%2e%2e/x.txt").as_deref(),
+            Some("/target/a/%2e%2e/x.txt")
+        );
+
+        // If the path as received doesn’t match `from`, the normalized path is used
+        assert_eq!(apply("/x/../raw/a.txt").as_deref(), Some("/target/a.txt"));
+    }
+
+    #[test]
+    fn rule_log() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /quiet/*
+                    to: /quiet.txt
+                -
+                    from: /matched/*
+                    from_regex: "\\.txt$"
+                    to: /matched.txt
+                    log: match
+                -
+                    from: /verbose/*
+                    from_regex: "\\.txt$"
+                    to: /verbose.txt
+                    log: always
+            "#,
+        );
+
+        let rules = handler.rules.load();
+        let evaluate = |path: &str| {
+            let mut events = Vec::new();
+            let uri = path.parse().unwrap();
+            rules
+                .evaluate(
+                    &Method::GET,
+                    &uri,
+                    &uri,
+                    &HeaderMap::new(),
+                    Connection::default(),
+                    SystemTime::now(),
+                    |rule, path, matched| events.push((rule, path.to_owned(), matched)),
+                )
+                .unwrap();
+            events
+        };
+
+        assert_eq!(evaluate("/quiet/file.txt"), Vec::new());
+        assert_eq!(evaluate("/matched/file.html"), Vec::new());
+        assert_eq!(
+            evaluate("/matched/file.txt"),
+            vec![(1, "/matched/file.txt".to_owned(), true)]
+        );
+        assert_eq!(
+            evaluate("/verbose/file.html"),
+            vec![(2, "/verbose/file.html".to_owned(), false)]
+        );
+        assert_eq!(
+            evaluate("/verbose/file.txt"),
+            vec![(2, "/verbose/file.txt".to_owned(), true)]
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn encoded_slash_in_tail() -> Result<(), Box<Error>> {
+        let mut conf = <RewriteHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+                rewrite_rules:
+                -
+                    from: /proxy/*
+                    to: /fetch?url=${tail}
+                -
+                    from: /decoded/*
+                    to: /fetch${tail}
+                    decode_tail: true
+                -
+                    from: /a/b/*
+                    to: /nested${tail}
+            "#,
+        )
+        .unwrap();
+
+        for decode_path in [false, true] {
+            conf.rewrite_decode_path = decode_path;
+            let handler = RewriteHandler::try_from(conf.clone())?;
+
+            let mut session = make_session("/proxy/http%3A%2F%2Fexample.com").await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            if decode_path {
+                assert_eq!(session.uri(), "/fetch?url=/http:%2F%2Fexample.com");
+            } else {
+                assert_eq!(session.uri(), "/fetch?url=/http%3A%2F%2Fexample.com");
+            }
+
+            let mut session = make_session("/decoded/dir%2Ffile%20name%253F.txt").await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            assert_eq!(session.uri(), "/fetch/dir/file%20name%253F.txt");
+
+            // Encoded slash isn’t a path separator
+            let mut session = make_session("/a%2Fb/file.txt").await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            assert_eq!(session.uri(), "/a%2Fb/file.txt");
+        }
+
+        // Encoded slashes can be rejected instead, this requires path decoding to be enabled
+        conf.rewrite_decode_path = false;
+        conf.rewrite_encoded_slash = EncodedSlash::Reject;
+        let handler = RewriteHandler::try_from(conf.clone())?;
+        let mut session = make_session("/proxy/http%3A%2f%2Fexample.com").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/different?a=b");
+        assert_eq!(session.uri(), "/fetch?url=/http%3A%2f%2Fexample.com");
 
-        let mut session = make_session("/path/image.png?a=b&file=c").await;
+        conf.rewrite_decode_path = true;
+        let handler = RewriteHandler::try_from(conf)?;
+        let mut session = make_session("/proxy/http%3A%2f%2Fexample.com").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
-            RequestFilterResult::Unhandled
+            RequestFilterResult::ResponseSent
+        );
+        assert_eq!(
+            session.response_written().map(|r| r.status),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            handler.apply(&RewriteRequest {
+                uri: "/proxy/http%3A%2F%2Fexample.com".parse().unwrap(),
+                ..Default::default()
+            }),
+            None
         );
-        assert_eq!(session.uri(), "/different?a=b&file=c");
 
-        let mut session = make_session("/path/image.png?file=c").await;
+        let mut session = make_session("/proxy/http%3A//example.com").await;
         assert_eq!(
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
                 .await?,
             RequestFilterResult::Unhandled
         );
-        assert_eq!(session.uri(), "/path/image.png?file=c");
+        assert_eq!(session.uri(), "/fetch?url=/http://example.com");
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn trailing_slash() -> Result<(), Box<Error>> {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /about
+                    to: /about.html${tail}
+                -
+                    from: /contact
+                    to: /contact.html${tail}
+                    trailing_slash: strict
+                -
+                    from: /docs/
+                    to: /docs/index.html
+                    trailing_slash: strict
+                -
+                    from: /*
+                    to: /fallback${tail}
+                    trailing_slash: strict
+            "#,
+        );
+
+        for (path, expected) in [
+            ("/about", "/about.html/"),
+            ("/about/", "/about.html/"),
+            ("/contact", "/contact.html/"),
+            ("/contact/", "/fallback/contact/"),
+            ("/docs/", "/docs/index.html"),
+            ("/docs", "/fallback/docs"),
+            ("/other/", "/fallback/other/"),
+        ] {
+            let mut session = make_session(path).await;
+            assert_eq!(
+                handler
+                    .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+                    .await?,
+                RequestFilterResult::Unhandled
+            );
+            assert_eq!(session.uri(), expected, "rewriting {path}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_trailing_slash() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /pricing
+                    to: /plans${tail}.html
+                    ignore_trailing_slash: true
+                -
+                    from: /pricing/*
+                    to: /pricing-prefix${tail}
+                -
+                    from: /docs
+                    to: /docs/
+                    type: permanent
+                    ignore_trailing_slash: true
+                -
+                    from: /docs/*
+                    to: /docs-index${tail}
+                -
+                    from: /blog
+                    to: /blog/
+                    type: redirect
+                    ignore_trailing_slash: true
+            "#,
+        );
+
+        let apply = |uri: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| (decision.r#type, decision.uri.to_string()))
+        };
+
+        assert_eq!(
+            apply("/pricing"),
+            Some((RewriteType::Internal, "/plans.html".to_owned()))
+        );
+        assert_eq!(
+            apply("/pricing/"),
+            Some((RewriteType::Internal, "/plans.html".to_owned()))
+        );
+
+        // Prefix rules keep their semantics
+        assert_eq!(
+            apply("/pricing/extra"),
+            Some((RewriteType::Internal, "/pricing-prefix/extra".to_owned()))
+        );
 
-        let mut session = make_session("/file.txt").await;
+        // No redirect to the identical location, the next rule applies instead
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            apply("/docs"),
+            Some((RewriteType::Permanent, "/docs/".to_owned()))
         );
-        assert_eq!(session.uri(), "/other.txt");
+        assert_eq!(
+            apply("/docs/"),
+            Some((RewriteType::Internal, "/docs-index/".to_owned()))
+        );
+        assert_eq!(
+            apply("/blog"),
+            Some((RewriteType::Redirect, "/blog/".to_owned()))
+        );
+        assert_eq!(apply("/blog/"), None);
 
-        let mut session = make_session("/file.txt?no_redirect").await;
+        // A different query string makes it a different location
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            apply("/blog/?page=2"),
+            Some((RewriteType::Redirect, "/blog/".to_owned()))
         );
-        assert_eq!(session.uri(), "/file.txt?no_redirect");
 
-        Ok(())
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: /pricing
+                    ignore_trailing_slash: true
+                    trailing_slash: strict
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
     }
 
-    #[test(tokio::test)]
-    async fn interpolation() -> Result<(), Box<Error>> {
+    #[test]
+    fn slash_types() {
         let handler = make_handler(
             r#"
                 rewrite_rules:
-                    from: /path/*
-                    to: /another${tail}?${query}&host=${http_host}&test=${http_test_header}
+                -
+                    from: /blog/*
+                    type: remove_slash
+                -
+                    from: /docs/*
+                    type: add_slash
+                    skip_files: true
+                -
+                    from: /docs/raw/*
+                    type: add_slash
             "#,
         );
+        let apply = |path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| {
+                    assert_eq!(decision.status, Some(StatusCode::PERMANENT_REDIRECT));
+                    (decision.r#type, decision.uri.to_string())
+                })
+        };
 
-        let mut session = make_session("/path/file.txt").await;
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            apply("/blog/post/?page=2"),
+            Some((RewriteType::RemoveSlash, "/blog/post?page=2".to_owned()))
         );
-        assert_eq!(session.uri(), "/another/file.txt?&host=&test=");
-
-        let mut session = make_session("/path/file.txt?a=b").await;
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            apply("/blog//"),
+            Some((RewriteType::RemoveSlash, "/blog".to_owned()))
         );
-        assert_eq!(session.uri(), "/another/file.txt?a=b&host=&test=");
-
-        let mut session = make_session("/path/file.txt?a=b").await;
-        session
-            .req_header_mut()
-            .insert_header("Host", "localhost")?;
-        session
-            .req_header_mut()
-            .insert_header("Test-Header", "successful")?;
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            apply("/docs/guide?"),
+            Some((RewriteType::AddSlash, "/docs/guide/?".to_owned()))
         );
         assert_eq!(
-            session.uri(),
-            "/another/file.txt?a=b&host=localhost&test=successful"
+            apply("/docs"),
+            Some((RewriteType::AddSlash, "/docs/".to_owned()))
+        );
+        assert_eq!(
+            apply("/docs/raw/file.txt"),
+            Some((RewriteType::AddSlash, "/docs/raw/file.txt/".to_owned()))
         );
 
-        Ok(())
+        // Redirect targets aren’t redirected again
+        for path in [
+            "/blog/post?page=2",
+            "/blog",
+            "/docs/guide/?",
+            "/docs/",
+            "/docs/raw/file.txt/",
+        ] {
+            assert_eq!(apply(path), None, "{path}");
+        }
+
+        assert_eq!(apply("/docs/style.css"), None);
+
+        // The root is left alone, as are paths that would produce protocol-relative URLs
+        for kind in ["add_slash", "remove_slash"] {
+            let handler = make_handler(&format!("rewrite_rules: {{from: /*, type: {kind}}}"));
+            for path in ["/", "//example.com/", "//example.com"] {
+                let decision = handler.apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                });
+                assert!(decision.is_none(), "{kind} {path}");
+            }
+        }
+
+        // Other rules for the same path still apply
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /app/*
+                    type: add_slash
+                -
+                    from: /app/*
+                    to: /index.php
+            "#,
+        );
+        let decision = handler
+            .apply(&RewriteRequest {
+                uri: "/app/page/".parse().unwrap(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(decision.rule, 1);
+        assert_eq!(decision.uri, "/index.php");
+
+        let conf = RewriteConf::from_yaml(
+            r#"
+                rewrite_rules:
+                    from: /docs/*
+                    type: permanent
+                    skip_files: true
+            "#,
+        )
+        .unwrap();
+        assert!(RewriteHandler::try_from(conf).is_err());
     }
 
-    #[test(tokio::test)]
-    async fn external_redirect() -> Result<(), Box<Error>> {
+    #[test]
+    fn outcome() {
         let handler = make_handler(
             r#"
                 rewrite_rules:
                 -
-                    from: /path/*
-                    to: /another${tail}
+                    from: /old/*
+                    to: /new${tail}
                     type: permanent
+                    headers: {Cache-Control: no-store}
                 -
-                    from: /file.txt
-                    to: https://example.com/?${query}
-                    type: redirect
+                    from: /docs/*
+                    to: /documentation${tail}
+                -
+                    from: /private/*
+                    type: forbidden
             "#,
         );
 
-        let mut session = make_session("/path/file.txt").await;
-        assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::ResponseSent
-        );
-        assert_eq!(
-            session.response_written().map(|r| r.status),
-            Some(StatusCode::PERMANENT_REDIRECT)
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        for (uri, expected) in [
+            ("/index.html", RewriteOutcome::Unchanged),
+            (
+                "/docs/intro.html",
+                RewriteOutcome::Rewritten {
+                    uri: Uri::from_static("/documentation/intro.html"),
+                },
+            ),
+            (
+                "/old/page.html",
+                RewriteOutcome::Respond {
+                    status: StatusCode::PERMANENT_REDIRECT,
+                    location: Some("/new/page.html".to_owned()),
+                    headers: headers.clone(),
+                },
+            ),
+            (
+                "/private/file.txt",
+                RewriteOutcome::Respond {
+                    status: StatusCode::FORBIDDEN,
+                    location: None,
+                    headers: HeaderMap::new(),
+                },
+            ),
+        ] {
+            let decision = handler.apply(&RewriteRequest {
+                uri: uri.try_into().unwrap(),
+                ..Default::default()
+            });
+            assert_eq!(RewriteOutcome::from(decision), expected, "{uri}");
+        }
+    }
+
+    #[test]
+    fn regex_prefilter() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from_regex: "\\.png$"
+                    to: /png
+                -
+                    from_regex: ["^/docs/", "!\\.pdf$"]
+                    to: /docs
+                -
+                    from_regex: ["\\.css$", "\\.js$"]
+                    regex_mode: any
+                    to: /assets
+                -
+                    from_regex: "\\.GIF$"
+                    case_insensitive: true
+                    to: /gif
+                -
+                    from_regex: "^/raw%20"
+                    raw_path: true
+                    to: /raw
+                -
+                    from_regex: ["^/api/v2/", "!^/api/"]
+                    regex_mode: any
+                    to: /fallback
+                rewrite_decode_path: true
+            "#,
         );
+
+        let rules = handler.rules.load();
+        let list = rules.router.lookup("", "/file").unwrap();
+        let prefilter = list.prefilter.as_ref().unwrap();
+        assert_eq!(prefilter.set.len(), 5);
         assert_eq!(
-            session
-                .response_written()
-                .and_then(|r| r.headers.get("Location"))
-                .map(|h| h.to_str().unwrap()),
-            Some("/another/file.txt")
+            prefilter
+                .rules
+                .iter()
+                .filter(|positions| positions.is_none())
+                .count(),
+            2
         );
 
-        let mut session = make_session("/file.txt?a=b").await;
-        assert_eq!(
+        let apply = |uri: &str| {
             handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::ResponseSent
+                .apply(&RewriteRequest {
+                    uri: uri.try_into().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+        for (uri, expected) in [
+            ("/logo.png", Some("/png")),
+            ("/docs/intro.html", Some("/docs")),
+            ("/docs/intro.pdf", Some("/fallback")),
+            ("/style.css", Some("/assets")),
+            ("/app.js", Some("/assets")),
+            ("/image.gif", Some("/gif")),
+            ("/raw%20file", Some("/raw")),
+            ("/api/v1/users", None),
+            ("/api/v2/users", Some("/fallback")),
+        ] {
+            assert_eq!(apply(uri).as_deref(), expected, "{uri}");
+        }
+
+        // No prefilter for few expressions
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from_regex: "\\.png$"
+                    to: /png
+                -
+                    from_regex: "\\.css$"
+                    to: /css
+            "#,
+        );
+        assert!(handler
+            .rules
+            .load()
+            .router
+            .lookup("", "/file")
+            .unwrap()
+            .prefilter
+            .is_none());
+    }
+
+    #[test]
+    fn regex_interning() {
+        let handler = make_handler(
+            r#"
+                rewrite_rules:
+                -
+                    from: /images/*
+                    from_regex: "\\.(png|jpe?g)$"
+                    to: /img${tail}
+                -
+                    from: [/photos/*, /gallery/*]
+                    from_regex: ["\\.(png|jpe?g)$", "!^/photos/private/"]
+                    query_params: {format: "\\.(png|jpe?g)$"}
+                    to: /img${tail}
+                -
+                    from: /icons/*
+                    from_regex: "\\.(png|jpe?g)$"
+                    case_insensitive: true
+                    to: /img${tail}
+            "#,
         );
+
+        let rules = handler.rules.load();
+        let rule = |path: &str| {
+            let list = rules.router.lookup("", path).unwrap();
+            list.rules.last().unwrap().1.clone()
+        };
+        let images = rule("/images/a.png");
+        let photos = rule("/photos/a.png");
+        let gallery = rule("/gallery/a.png");
+        let icons = rule("/icons/a.png");
+
+        assert!(Arc::ptr_eq(
+            &images.from_regex[0].regex,
+            &photos.from_regex[0].regex
+        ));
+        assert!(Arc::ptr_eq(
+            &images.from_regex[0].regex,
+            &gallery.from_regex[0].regex
+        ));
+        assert!(Arc::ptr_eq(
+            &images.from_regex[0].regex,
+            &photos.query_params[0].1.regex
+        ));
+        assert!(!Arc::ptr_eq(
+            &photos.from_regex[0].regex,
+            &photos.from_regex[1].regex
+        ));
+        assert!(photos.from_regex[1].negate);
+
+        // Case-insensitive expressions are compiled separately
+        assert!(!Arc::ptr_eq(
+            &images.from_regex[0].regex,
+            &icons.from_regex[0].regex
+        ));
         assert_eq!(
-            session.response_written().map(|r| r.status),
-            Some(StatusCode::TEMPORARY_REDIRECT)
+            icons.from_regex[0].regex.is_match(b"/icons/A.PNG"),
+            Ok(true)
         );
         assert_eq!(
-            session
-                .response_written()
-                .and_then(|r| r.headers.get("Location"))
-                .map(|h| h.to_str().unwrap()),
-            Some("https://example.com/?a=b")
+            images.from_regex[0].regex.is_match(b"/images/A.PNG"),
+            Ok(false)
         );
-
-        Ok(())
     }
 
-    #[test(tokio::test)]
-    async fn rule_order() -> Result<(), Box<Error>> {
+    #[test]
+    fn apply_all() {
         let handler = make_handler(
             r#"
                 rewrite_rules:
+                -
+                    from: /images/*
+                    from_regex: "\\.jpg$"
+                    to: https://cdn.example.com${tail}
+                    type: permanent
+                -
+                    from: /api/*
+                    accept: application/json
+                    to: /api.json?path=${tail}
                 -
                     from: /*
-                    query_regex: "1"
-                    to: /1
+                    query_regex: "^page="
+                    to: /index.php?${query}
+                    preserve_original_in: X-Original-URI
                 -
-                    from: /path/*
-                    query_regex: "2"
-                    to: /2
+                    from: /invalid/*
+                    to: /${http_x_target}
+            "#,
+        );
+        let decision = |rule, r#type, uri: &str, preserve_original_in: Option<&str>| {
+            Some(RewriteDecision {
+                rule,
+                r#type,
+                status: r#type.status_code(),
+                uri: uri.try_into().unwrap(),
+                location: r#type.is_redirect().then(|| uri.to_owned()),
+                preserve_original_in: preserve_original_in.map(|name| name.try_into().unwrap()),
+                host: None,
+                body: None,
+                content_type: None,
+                headers: HeaderMap::new(),
+                previous_rules: Vec::new(),
+                path_mappings: Vec::new(),
+            })
+        };
+
+        let request = |uri: &str, headers: &[(&'static str, &'static str)]| RewriteRequest {
+            uri: uri.try_into().unwrap(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            handler.apply_all(&[
+                request("/images/photo.jpg", &[]),
+                request("/images/photo.png", &[]),
+                request("/api/users", &[("accept", "application/json")]),
+                request("/api/users", &[("accept", "text/html")]),
+                request("/dir/file.txt?page=2", &[]),
+                request("/invalid/file.txt", &[("x-target", "file name.txt")]),
+                request("/images/photo.png?page=3", &[]),
+            ]),
+            vec![
+                decision(
+                    0,
+                    RewriteType::Permanent,
+                    "https://cdn.example.com/photo.jpg",
+                    None
+                ),
+                None,
+                decision(1, RewriteType::Internal, "/api.json?path=/users", None),
+                None,
+                decision(
+                    2,
+                    RewriteType::Internal,
+                    "/index.php?page=2",
+                    Some("x-original-uri")
+                ),
+                None,
+                decision(
+                    2,
+                    RewriteType::Internal,
+                    "/index.php?page=3",
+                    Some("x-original-uri")
+                ),
+            ]
+        );
+        assert!(handler.metrics().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn rule_metrics() -> Result<(), Box<Error>> {
+        let mut conf = <RewriteHandler as RequestFilter>::Conf::from_yaml(
+            r#"
+                rewrite_rules:
                 -
                     from: /path/*
-                    query_regex: "3"
-                    to: /3
+                    to: /another${tail}
                 -
-                    from: /path
-                    query_regex: "4"
-                    to: /4
+                    from: /path/file.txt
+                    to: /file.txt
                 -
-                    from: /path
-                    query_regex: "5"
-                    to: /5
+                    from: /unused
+                    to: /
             "#,
-        );
+        )
+        .unwrap();
 
-        let mut session = make_session("/path?12345").await;
-        assert_eq!(
+        let hits = |handler: &RewriteHandler| {
             handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
-        );
-        assert_eq!(session.uri(), "/4");
+                .metrics()
+                .into_iter()
+                .map(|metrics| metrics.hits)
+                .collect::<Vec<_>>()
+        };
 
-        let mut session = make_session("/path?1235").await;
-        assert_eq!(
+        let handler = RewriteHandler::try_from(conf.clone())?;
+        assert!(hits(&handler).is_empty());
+
+        conf.rewrite_metrics = true;
+        let handler = RewriteHandler::try_from(conf.clone())?;
+        assert_eq!(hits(&handler), vec![0, 0, 0]);
+
+        for path in ["/path/file.txt", "/path/other.txt", "/path", "/other"] {
+            let mut session = make_session(path).await;
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
-        );
-        assert_eq!(session.uri(), "/5");
+                .await?;
+        }
+        assert_eq!(hits(&handler), vec![2, 1, 0]);
 
-        let mut session = make_session("/path?123").await;
-        assert_eq!(
+        // Hit counts don’t affect handler comparisons
+        assert_eq!(handler, RewriteHandler::try_from(conf)?);
+
+        // Clones share counters
+        let clone = handler.clone();
+        let mut session = make_session("/path/file.txt").await;
+        clone
+            .request_filter(&mut session, &mut RewriteHandler::new_ctx())
+            .await?;
+        assert_eq!(hits(&handler), vec![2, 2, 0]);
+
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn metrics() -> Result<(), Box<Error>> {
+        let conf = r#"
+            rewrite_rules:
+            -
+                from: /old/*
+                to: /new${tail}
+                type: permanent
+                name: old-pages
+            -
+                from: /app/*
+                to: /index.php
+            -
+                from: /retired
+                type: gone
+                name: retired
+        "#;
+        assert!(make_handler(conf).metrics().is_empty());
+
+        let handler = make_handler(&format!("{conf}\n            rewrite_metrics: true"));
+        for path in ["/old/a", "/old/b", "/app/x", "/retired", "/other"] {
+            let mut session = make_session(path).await;
             handler
                 .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
-        );
-        assert_eq!(session.uri(), "/2");
+                .await?;
+        }
 
-        let mut session = make_session("/path?13").await;
+        let metric = |index, name: Option<&str>, r#type, hits| RuleMetrics {
+            index,
+            name: name.map(str::to_owned),
+            r#type,
+            hits,
+        };
         assert_eq!(
-            handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+            handler.metrics(),
+            vec![
+                metric(0, Some("old-pages"), RewriteType::Permanent, 2),
+                metric(1, None, RewriteType::Internal, 1),
+                metric(2, Some("retired"), RewriteType::Gone, 1),
+            ]
         );
-        assert_eq!(session.uri(), "/3");
 
-        let mut session = make_session("/path?1").await;
+        Ok(())
+    }
+
+    fn reload_conf(suffix: &str) -> RewriteConf {
+        RewriteConf::from_yaml(format!(
+            r#"
+                rewrite_rules:
+                -
+                    from: /start
+                    to: /step-{suffix}
+                    continue: true
+                -
+                    from: /step-{suffix}
+                    to: /end-{suffix}
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn reload() {
+        let apply = |handler: &RewriteHandler, path: &str| {
+            handler
+                .apply(&RewriteRequest {
+                    uri: path.parse().unwrap(),
+                    ..Default::default()
+                })
+                .map(|decision| decision.uri.to_string())
+        };
+
+        let handler = RewriteHandler::try_from(reload_conf("a")).unwrap();
+        let clone = handler.clone();
+        assert_eq!(apply(&handler, "/start").as_deref(), Some("/end-a"));
+
+        // Clones share the rules
+        clone.reload(reload_conf("b")).unwrap();
+        assert_eq!(apply(&handler, "/start").as_deref(), Some("/end-b"));
+        assert_eq!(apply(&handler, "/step-a"), None);
+        assert_eq!(handler, clone);
+
+        // Invalid configuration keeps the previous rules
+        let mut conf = reload_conf("c");
+        conf.rewrite_max_internal = 0;
+        let err = handler.reload(conf).unwrap_err();
+        assert!(err.to_string().contains("rewrite_max_internal"), "{err}");
+        assert_eq!(apply(&handler, "/start").as_deref(), Some("/end-b"));
+
+        // Metrics are reset
+        let mut conf = reload_conf("d");
+        conf.rewrite_metrics = true;
+        handler.reload(conf).unwrap();
         assert_eq!(
             handler
-                .request_filter(&mut session, &mut RewriteHandler::new_ctx())
-                .await?,
-            RequestFilterResult::Unhandled
+                .metrics()
+                .into_iter()
+                .map(|metrics| metrics.hits)
+                .collect::<Vec<_>>(),
+            vec![0, 0]
         );
-        assert_eq!(session.uri(), "/1");
+    }
 
-        Ok(())
+    #[test]
+    fn concurrent_reload() {
+        let handler = RewriteHandler::try_from(reload_conf("a")).unwrap();
+        let request = RewriteRequest {
+            uri: "/start".parse().unwrap(),
+            ..Default::default()
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..2000 {
+                        // Internal rewrites never mix rules from different configurations
+                        let uri = handler.apply(&request).unwrap().uri;
+                        assert!(uri == "/end-a" || uri == "/end-b", "{uri}");
+                    }
+                });
+            }
+
+            for i in 0..200 {
+                handler
+                    .reload(reload_conf(if i % 2 == 0 { "b" } else { "a" }))
+                    .unwrap();
+            }
+        });
     }
 }
@@ -38,24 +38,564 @@
 //! The following parameters can be defined for a rule:
 //!
 //! * `from` restricts the rule to a specific path or a path prefix (if the value ends with `/*`).
+//!   Additional wildcards can be used in the middle of the path like `/users/*/files/*`, each
+//!   matching exactly one path segment. A list like `from: [/old/*, /legacy/*]` makes the rule
+//!   apply to each of these paths, `${tail}` then refers to the part of the path following the
+//!   prefix that matched. Entries starting with `!` exclude paths like `from_exclude` does, e.g.
+//!   `from: [/app/*, "!/app/internal/*"]`. These cannot contain wildcards other than `/*` at the
+//!   end. If all entries are negated, the rule applies to all other paths.
+//! * `from_glob` is an alternative to `from` using a shell-like glob that the entire path has to
+//!   match, e.g. `/docs/**/*.md`. `?` matches any single character, `*` any number of characters
+//!   within a path segment and `**` any number of path segments. Character classes like `[abc]`,
+//!   `[a-z]` or `[!abc]` are supported as well. For precedence and `${tail}`, the rule is treated
+//!   like `from: /docs/*`, i.e. the directory preceding the first special character. Among such
+//!   rules, globs with more literal path segments are preferred. Setting both `from` and
+//!   `from_glob` is an error.
+//! * `from_exclude` removes paths from the rule’s coverage, e.g. `from: /app/*` with
+//!   `from_exclude: /app/static/*`. The values are interpreted like `from`: `/app/static/*`
+//!   excludes `/app/static` and everything within it, `/app/static` excludes only this path.
+//!   Separate rules for paths within the excluded ones like `/app/static/fonts/*` still apply.
+//! * `extensions` restricts the rule to particular file types, e.g. `extensions: [png, jpg, webp]`
+//!   matches paths like `/logo.PNG` but not `/logo.png.bak` or `/logo.png/`. Extensions are
+//!   compared case-insensitively, the dot is added automatically. Combined with `from` this is a
+//!   cheaper alternative to a regular expression like `from_regex: "\\.(png|jpg|webp)$"`.
+//! * `from_host` restricts the rule to requests for particular hosts, e.g.
+//!   `from_host: [example.com, "*.example.net"]`. A leading `*.` matches any subdomain. Host
+//!   names are compared case-insensitively and the port is ignored unless the value specifies one
+//!   like `localhost:8080`. The rule applies to all hosts if this setting is omitted.
+//! * `methods` restricts the rule to particular HTTP request methods, e.g. `methods: [GET, HEAD]`.
+//!   Method names are case-insensitive, unknown methods are rejected when the configuration is
+//!   loaded.
+//! * `scheme` restricts the rule to `http` or `https` requests, e.g. to redirect plaintext
+//!   requests to HTTPS. For HTTP/2 requests, the scheme is taken from the `:scheme`
+//!   pseudo-header if known, otherwise from the connection type. If the server runs
+//!   behind a reverse proxy, the `X-Forwarded-Proto` request header can take precedence, see
+//!   below.
+//! * `from_ip` restricts the rule to particular client addresses, e.g.
+//!   `from_ip: [10.0.0.0/8, "fd00::/8", "!10.1.0.0/16"]`. Both single addresses and CIDR ranges
+//!   can be listed, IPv4-mapped IPv6 addresses match IPv4 ranges. Putting `!` before a range
+//!   excludes its addresses. Behind a reverse proxy, the client address can be taken from the
+//!   `X-Forwarded-For` request header, see below.
+//! * `listen_port` restricts the rule to connections accepted on particular local ports, e.g.
+//!   `listen_port: [8080, 8443]` when the server listens on several ports. This is the port of
+//!   the server-side address, not the port in the `Host` header.
+//! * `active_from` and `active_until` restrict the rule to a time window given as RFC 3339
+//!   timestamps, e.g. `active_from: 2024-06-01T22:00:00Z` and
+//!   `active_until: "2024-06-02T02:00:00+02:00"` for a maintenance window. Either can be
+//!   omitted: a rule with only `active_until` applies right away and expires later, a rule with
+//!   only `active_from` is inactive until the given time. No configuration reload is necessary.
 //! * `from_regex` allows further refining the path restriction via a regular expression. Putting
 //!   `!` before the regular expression makes the rule apply to paths *not* matched by the regular
-//!   expression.
+//!   expression. A list like `from_regex: ["^/img/", "!\\.svg$"]` combines multiple regular
+//!   expressions, see `regex_mode`. If many rules share the same `from` pattern, their regular
+//!   expressions are matched in a single combined search to determine which rules can apply.
+//!   Look-around like `^/files/(?!public/)` and backreferences require the `fancy-regex` feature
+//!   of this crate which gives up the linear-time matching guarantee for such expressions.
+//! * `query` restricts the rule depending on whether a query string is present: `present`
+//!   requires a `?` in the URI (`/path?` counts), `absent` requires there to be none, `empty`
+//!   only matches a `?` with nothing following it. The default `any` places no restriction.
 //! * `query_regex` restricts the rule to particular query strings only. Putting `!` before the
 //!   regular expression makes the rule apply to query strings *not* matched by the regular
-//!   expression.
+//!   expression. Multiple regular expressions can be listed here as well.
+//! * `regex_mode` determines how lists in `from_regex` and `query_regex` are combined: with
+//!   `all` (default) each regular expression has to match, with `any` one match is sufficient.
+//!   A negated entry counts as a match if its regular expression doesn’t match. So
+//!   `from_regex: ["^/img/", "!\\.svg$"]` applies to non-SVG files under `/img/` with `all` but
+//!   to all files that are under `/img/` *or* not SVG files with `any`.
+//! * `uri_regex` matches a regular expression against the path and query string together, exactly
+//!   as received (not decoded), e.g. `uri_regex: "\\.php\\?(.*&)?preview=1(&|$)"`. Putting `!`
+//!   before the regular expression negates it. When combined with `from_regex` and `query_regex`,
+//!   all of them have to match.
+//! * `query_match` restricts the rule to requests with particular query parameters, e.g.
+//!   `{v: "2", format: json}` requires the query to contain both `v=2` and `format=json`.
+//!   Parameters are compared after decoding, so `format=%6Ason` matches as well. Further
+//!   parameters are allowed unless `query_match_strict: true` is set.
+//! * `query_params` matches individual query parameters against regular expressions, e.g.
+//!   `{file: "\\.png$"}` instead of `query_regex: "(^|&)file=[^&]*\\.png($|&)"`. Names and
+//!   values are decoded first (`+` meaning a space). A repeated parameter matches if any of its
+//!   values does. A missing parameter doesn’t match unless the regular expression is negated by
+//!   putting `!` before it.
+//! * `has_header` and `lacks_header` restrict the rule to requests with or without particular
+//!   headers, e.g. `lacks_header: X-Internal-Auth`. A header with an empty value counts as present.
+//!   Multiple header names can be listed, all of them have to be present or absent respectively.
+//! * `header_regex` restricts the rule to requests with particular header values, e.g.
+//!   `{X-Beta-User: "^1$"}`. Each header listed has to match its regular expression, putting `!`
+//!   before the regular expression negates it. Missing headers are matched as empty strings, so
+//!   `!.` requires the header to be absent. Multiple values of a header are joined with `, `.
+//! * `cookie_regex` restricts the rule to requests with particular cookie values in the same way,
+//!   e.g. `{region: "^eu$"}`. Missing cookies are matched as empty strings. If the request
+//!   contains the same cookie multiple times, only its first value is considered.
+//! * `ua_regex` restricts the rule to requests with a matching `User-Agent` header, e.g.
+//!   `ua_regex: "bot|crawler|spider"` to serve prerendered pages to crawlers. The regular
+//!   expression is case-insensitive unless `(?-i)` is used, putting `!` before it negates it. A
+//!   missing header is matched as an empty string.
+//! * `accept` restricts the rule to requests accepting the given media type like
+//!   `application/json`, according to their `Accept` header.
+//! * `accept_language` restricts the rule to clients preferring one of the given languages like
+//!   `[de, fr, en]` according to their `Accept-Language` header. Quality values are considered,
+//!   and language ranges are matched as defined by RFC 4647 basic filtering: `de` also matches
+//!   `de-CH`, `*` matches any language. Requests without the header don’t match. The selected
+//!   language is available as `${lang}` in `to`, e.g. `from: /` with `to: /${lang}/`.
 //! * `to` is the new path and query string to be used if the rule is applied. Some variables will
 //!   are replaced here:
 //!   * `${tail}`: The part of the original path matched by `/*` in `from`
+//!   * `${tail1}`, `${tail2}`, …: The parts of the original path matched by the individual
+//!     wildcards in `from`, e.g. `/users/*/files/*` and `/users/me/files/dir/file.txt` result in
+//!     `${tail1}` being `/me` and `${tail2}` being `/dir/file.txt`
 //!   * `${query}`: The original query string
+//!   * `${query_<name>}`: The value of the first query parameter with the given name, e.g.
+//!     `${query_id}` for `?id=123`. The value is decoded according to form encoding rules, `+`
+//!     resolving to a space. Parameters without a value resolve to an empty string, missing
+//!     parameters leave the variable unresolved.
+//!   * `${path}`: The request path as evaluated, including the part matched by `from` unlike
+//!     `${tail}`. After internal rewrites by rules with `continue: true`, this is the rewritten
+//!     path. The path isn’t decoded or normalized, so it can be used in URLs directly, e.g.
+//!     `to: "https://example.com${path}"`.
+//!   * `${uri}`: Same as `${path}` but followed by `?` and the query string unless the query
+//!     string is empty
+//!   * `${original_path}`: The request path as received, before decoding, normalization or
+//!     rewrites
+//!   * `${original_query}`: The query string as received, before rewrites
+//!   * `${original_uri}`: Path and query string as received, e.g. for
+//!     `to: /login?return=${original_uri|urlencode}`. These values don’t change when rules with
+//!     `continue: true` rewrite the request or another module like Virtual Hosts modified the
+//!     request URI before. Other modules can retrieve the original URI via
+//!     `SessionWrapper::original_uri()`.
+//!   * `${host}`: The request host without the port, taken from the `Host` header or the
+//!     `:authority` pseudo-header of HTTP/2 requests. With a trusted proxy, `X-Forwarded-Host` is
+//!     considered first. Requests without a host leave the variable unresolved.
+//!   * `${scheme}`: `http` or `https`, considering `X-Forwarded-Proto` with a trusted proxy
+//!   * `${port}`: The port the connection was accepted on, e.g. for
+//!     `to: "${scheme}://${host}:${port}/new${tail}"`
+//!   * `${method}`: The request method in uppercase, e.g. `GET`
+//!   * `${remote_addr}`: The client address without the port, e.g. `192.0.2.1` or `2001:db8::1`.
+//!     With a trusted proxy, this is the address determined from `X-Forwarded-For`.
+//!   * `${remote_addr_uri}`: Same as `${remote_addr}` but with IPv6 addresses in brackets like
+//!     `[2001:db8::1]`, for use in the host part of URLs
+//!   * `${time_unix}`, `${time_msec}`: The time the request was received as seconds or
+//!     milliseconds since the Unix epoch, e.g. for cache-busting parameters
+//!   * `${time_iso8601}`: The time the request was received in UTC, formatted according to
+//!     RFC 3339 like `2024-06-01T12:00:00Z`. All time variables of a request refer to the same
+//!     point in time.
+//!   * `${lang}`: The language selected via `accept_language`
+//!   * `${1}`, `${2}`, …, `${name}`: The numbered and named capture groups of `from_regex`, e.g.
+//!     `from_regex: "^/blog/(\\d{4})/(?P<slug>[^/]+)$"` with `to: /posts/${slug}?year=${1}`.
+//!     Optional groups that didn’t participate in the match resolve to an empty string. Negated
+//!     regular expressions don’t provide any capture groups. Other variables take precedence
+//!     over named groups with the same name like `tail`. Capture groups of `uri_regex` can be
+//!     used the same way, groups of `from_regex` take precedence if both define a group. If
+//!     multiple regular expressions define a group, the first one matched counts.
 //!   * `${http_<header>}`: The value of an HTTP header, e.g. `${http_host}` will be replaced by
-//!     the value of the `Host` header
+//!     the value of the `Host` header. Multiple values of a header are joined with `, `.
+//!   * `${cookie_<name>}`: The value of the first cookie with the given name, e.g. for
+//!     `to: /shard/${cookie_shard}${tail}`. The value is used verbatim, only surrounding double
+//!     quotes are removed. Missing cookies leave the variable unresolved.
+//!
+//!   Variable values can be transformed by appending filters, e.g.
+//!   `${http_x_original_url|base64decode}`. Supported filters are:
+//!
+//!   * `base64decode` and `base64encode` (standard base64 alphabet)
+//!   * `base64urldecode` and `base64urlencode` (URL-safe base64 alphabet, encoded without padding)
+//!   * `urlencode`: Percent-encodes everything but the unreserved characters of RFC 3986, including
+//!     `&`, `#` and `/`, e.g. to embed a value into a query parameter
+//!   * `urldecode`: Decodes percent-encoded characters
+//!   * `lowercase` and `uppercase`: Convert ASCII letters to lowercase or uppercase
+//!   * `trim`: Removes leading and trailing whitespace
+//!
+//!   Multiple filters like `${tail|urldecode|lowercase}` are applied from left to right. Unknown
+//!   filters are a configuration error. If a filter fails, e.g. due to invalid base64 data, the
+//!   variable is left unresolved.
+//!
+//!   A default value can be given after `:-`, e.g. `${http_x_forwarded_host:-example.com}`. It is
+//!   used if the variable is empty or cannot be resolved, including failing filters. The default
+//!   value is inserted literally, it ends with the first `}` and cannot contain further variables.
+//!
+//!   Variables that can never be resolved for a rule produce a warning when the configuration is
+//!   loaded, e.g. a misspelled name, `${tail}` with a `from` pattern without wildcards or `${2}`
+//!   if `from_regex` has only one capture group. With `rewrite_strict_variables: true` these are a
+//!   configuration error instead.
+//!
+//!   For internal rewrites the result has to be a valid path like `/file.txt?a=b`, otherwise the
+//!   rule is ignored and the request is processed unchanged. Redirect targets are sent in the
+//!   `Location` header as they are, e.g. `https://example.com/file.txt` or `/page#section`.
+//!   Results containing control characters like CR or LF, e.g. from a decoded `${query_next}`
+//!   value, are rejected with status 500 to prevent response header injection.
+//! * `to_query` sets the query string of the new URI separately, e.g.
+//!   `to_query: page=${tail}&lang=${lang}`. `to` is then only the path and cannot contain `?`,
+//!   `?` or `#` characters in its variable values are percent-encoded. Within `to_query`,
+//!   variable values other than `${query}` are percent-encoded as necessary, including `&`, `=`
+//!   and `+`. If the result is empty, the new URI has no query string.
+//! * `to_host` changes the host, e.g. `to_host: backend.example.com` or
+//!   `to_host: ${http_x_tenant}.example.com`. For internal rewrites the `Host` header and the
+//!   authority of the request URI are replaced, allowing upstream servers to route accordingly.
+//!   Redirects go to an absolute URL with this host and the request’s scheme. If the result isn’t
+//!   a valid host, e.g. because it contains slashes or whitespace, the rule isn’t applied.
+//!   `to_host` cannot be combined with `redirect_to_host`.
+//! * `protocol_relative: true` allows redirect targets starting with `//` like
+//!   `to: //cdn.example.com${tail}`. Browsers resolve these URLs relative to the current scheme but
+//!   to another host. By default, rules producing such a target, e.g. because of a variable like
+//!   `${query_next}` resolving to `//evil.example.com/`, are not applied. This also applies to
+//!   targets starting with `/\` which browsers treat the same way.
+//! * `strip_prefix: true` removes the prefix matched by `from` and keeps the rest of the path
+//!   along with the query string, e.g. `from: /service/*` rewrites `/service/api?id=1` into
+//!   `/api?id=1` and `/service` into `/`. The path is taken as received where possible, without
+//!   decoding or normalization. This cannot be combined with `to`, `to_query`, `subst` or
+//!   `redirect_to_host`, `from` cannot contain wildcards other than `/*` at the end.
+//! * `append_query: true` appends the original query string to `to`, using `?` or `&`
+//!   depending on whether `to` already contains a query string. Unlike `?${query}` this adds
+//!   nothing for requests without a query string. Using `${query}` in `to` as well is an error.
+//! * `query_remove`, `query_set` and `query_add` modify the original query string, e.g.
+//!   `query_remove: [fbclid, utm_*]` removes tracking parameters (a `*` at the end matches any
+//!   suffix), `query_set: {lang: en}` replaces the first `lang` parameter and removes further
+//!   ones (the parameter is appended if missing), `query_add: {source: legacy}` always appends a
+//!   parameter. Names are matched exactly and case-sensitively, values can contain the same
+//!   variables as `to` and are percent-encoded. Remaining parameters keep their order. `${query}`
+//!   resolves to the modified query string, if `to` doesn’t use it the modified query string is
+//!   appended like with `append_query`.
+//! * `redirect_to_host` is an alternative to `to` for redirects to another host, e.g.
+//!   `redirect_to_host: www.example.com` will redirect a request to `/foo?bar` to
+//!   `https://www.example.com/foo?bar`. The path and query string are preserved and the scheme is
+//!   always `https`. Rules with this setting are redirects even if `type` is left at `internal`.
+//!   Setting both `to` and `redirect_to_host` is an error.
+//! * `subst` is an alternative to `to` for migrating sed-like or nginx-style rewrites:
+//!   `subst: s|^/docs/(.*)\.htm$|/documentation/$1.html|` replaces the matching part of the request
+//!   path, with `$1`, `$2` etc. referring to capture groups. Any character following `s` can be
+//!   used as delimiter. The flags `g` (replace all matches) and `i` (case-insensitive) are
+//!   supported. The rule only applies if the regular expression matches the path, in addition
+//!   to `from` and other conditions. Like with `to`, the query string is removed unless the `QSA`
+//!   flag is given. `subst` cannot be combined with `to` or `redirect_to_host`.
+//! * `body` replaces the standard HTML page sent with redirect responses, e.g.
+//!   `body: '<a href="${location}">Moved here</a>'`. The `${location}` variable (also available
+//!   as `${target}`) is replaced by the HTML-escaped redirect target. `body: ""` sends an empty
+//!   body. For redirects without `body`, `rewrite_redirect_body` applies, see below. For `response`
+//!   rules this is the response body, with the same variables available as in `to`. This setting
+//!   has no effect on other rewrite types.
+//! * `body_base64` is a base64-encoded alternative to `body` for `response` rules, allowing
+//!   binary content.
+//! * `content_type` sets the `Content-Type` header of `response` rules,
+//!   `text/plain; charset=utf-8` by default.
+//! * `headers` adds headers to the response of rules that don’t rewrite internally, e.g.
+//!   `headers: {Cache-Control: no-store, Set-Cookie: [variant=b, "page=${tail}"]}`. A list of
+//!   values produces multiple headers of the same name. Values can contain the same variables
+//!   as `to`, standard headers like `Content-Type` are replaced by headers of the same name.
 //! * `type` is the rewrite type, one of `internal` (default, internal redirect), `redirect`
-//!   (temporary redirect) or `permanent` (permanent redirect)
+//!   (307 Temporary Redirect), `permanent` (308 Permanent Redirect), `gone` (410 Gone),
+//!   `forbidden` (403 Forbidden) or `response` (a fixed response). `gone` and `forbidden` respond
+//!   with a standard error page, `response` with the configured `status`, `content_type` and
+//!   `body`. `to` is ignored for these types, and no further modules are run.
+//! * `type: add_slash` and `type: remove_slash` canonicalize trailing slashes: the request is
+//!   redirected (308 Permanent Redirect) to the same URI with a trailing slash added to or removed
+//!   from the path, keeping the query string. These rules don’t apply if the path already has the
+//!   desired form or is `/`, so the redirect target isn’t redirected again and other rules for the
+//!   path are considered instead. `to` is ignored, `status` can be used like for other redirects.
+//!   With `skip_files: true` paths looking like files are left alone, meaning that the last path
+//!   segment contains a dot like `/docs/style.css`.
+//! * `status` overrides the status code of redirects, e.g. `status: 301` for clients that don’t
+//!   support 308, and sets the status code of `response` rules (200 by default). Only `3xx`
+//!   status codes are accepted for redirects, setting `status` for other rewrite types is an
+//!   error.
+//! * `flags` accepts a subset of Apache mod_rewrite flags for easier migration: `R` (same as
+//!   `type: redirect` with `status: 302`, `R=301` meaning `type: permanent` with `status: 301`,
+//!   `R=307` and `R=308` keeping the default status codes), `L` (processing stops after this
+//!   rule, overriding `continue`), `NC` (case-insensitive `from_regex`, `query_regex` and
+//!   `uri_regex`) and `QSA` (append the original query string to `to`). Other flags are rejected.
+//! * `continue: true` makes the rules evaluated again for the new URI after an internal
+//!   rewrite, so that e.g. rules for `/a` → `/b` and `/b` → `/c` rewrite `/a` to `/c`. Variables
+//!   like `${tail}` are determined from the intermediate URI then. By default processing stops
+//!   after the first rule applied, and redirects always stop it. See below for the limit on chained
+//!   rewrites.
+//! * `preserve_original_in` names a request header like `X-Original-URI` that should receive the
+//!   original path and query string on internal rewrites.
+//! * `rewrite_response_location: true` maps `Location` headers of upstream responses back into
+//!   the public URL space on internal rewrites. If the `Location` path starts with the prefix of
+//!   `to` (the text before the first variable, or its directory if `to` has no variables), this
+//!   prefix is replaced by the prefix matched by `from`: with `from: /app/*` and `to: /${tail}`
+//!   the upstream redirect to `/login` becomes `/app/login`. Absolute URLs pointing to the host
+//!   the request was sent to (e.g. set via `to_host`) are changed to point to the original host.
+//!   Relative references like `login` are left unchanged.
+//! * `rewrite_cookie_path: true` maps `Path` attributes of upstream `Set-Cookie` headers back
+//!   into the public URL space the same way, e.g. `Path=/` becomes `Path=/app` for the rule above.
+//!   With `add_cookie_path: true` cookies without a `Path` attribute get the public prefix added.
+//! * `decode_tail` makes variables like `${tail}` percent-decoded before they are inserted into
+//!   `to`, see below.
+//! * `raw_path: true` makes `from_regex` and variables like `${tail}` use the request path as
+//!   received, see below.
+//! * `log` controls log messages for this rule: `off` (default) produces none, `match` logs
+//!   whenever the rule is applied and `always` logs every evaluation of the rule, including those
+//!   where its conditions aren’t met. Messages are logged at the `info` level, allowing to debug
+//!   particular rules without the noise of high-traffic rules.
+//! * `name` identifies the rule in rule metrics and the debug header, see below. Each name can only
+//!   be used once.
+//! * `enabled: false` turns off a rule without removing it from the configuration, e.g. to rule out
+//!   a suspect rule while investigating a problem. The rule is ignored when loading the
+//!   configuration, so the closest other rule applies instead. Other rules keep their indexes.
+//! * `trailing_slash` determines whether exact `from` paths like `/about` also match `/about/`.
+//!   With the default `tolerant` both are considered equivalent. With `strict` the request path
+//!   has to end with a slash if and only if `from` does.
+//! * `ignore_trailing_slash: true` makes an exact `from` path like `/pricing` match `/pricing/` as
+//!   well, with `${tail}` being empty in both cases. Prefix paths like `/pricing/*` are unaffected.
+//!   For redirects, the rule is skipped if the target is identical to the request URI, so that a rule
+//!   like `from: /docs`, `to: /docs/` doesn’t cause a redirect loop.
+//! * `priority` is an integer determining the evaluation order of rules that are equally
+//!   specific, higher values being evaluated first, see below.
+//! * `case_insensitive: true` makes the rule match paths regardless of ASCII letter case, e.g.
+//!   `from: /download/*` also matches `/Download/file.zip`. This applies to `from`, `from_glob`
+//!   and `from_exclude`, and makes `from_regex`, `query_regex` and `uri_regex` case-insensitive.
+//!   Variables like `${tail}` keep the casing of the original request path.
 //!
 //! If multiple rules potentially apply to a particular request, the rule with the longer path in
 //! the `from` field is applied. If multiple rules with the same path in `from` exist, exact
-//! matches are preferred over prefix matches.
+//! matches are preferred over prefix matches. For rules with additional wildcards, only the path
+//! up to the first wildcard counts here, and patterns with more literal segments are preferred.
+//! If all of this is identical, the rule with the highest `priority` is evaluated first (default
+//! is 0, negative values are allowed). Among rules with the same priority, the rule listed first
+//! in the configuration is evaluated first. The first rule with all conditions met is applied. A
+//! rule with multiple `from` paths is considered separately for each of them, exactly like
+//! multiple rules with one path each.
+//!
+//! ```yaml
+//! rewrite_rules:
+//! - from: /images/*
+//!   to: /fallback.png
+//! - from: /images/*
+//!   from_regex: "\\.png$"
+//!   priority: 10
+//!   to: /png${tail}
+//! ```
+//!
+//! Here the second rule is evaluated first despite being listed later, `/images/logo.png` is
+//! rewritten to `/png/logo.png` whereas `/images/logo.gif` gets `/fallback.png`.
+//!
+//! Rules that can never be applied because a rule with the same `from` pattern and no further
+//! conditions takes precedence are reported as warnings when the configuration is loaded. The
+//! same goes for rules with different but equally specific `from` patterns and the same
+//! priority that can match the same path, e.g. `/dir/*/a/*` and `/dir/*/*/b`, as only the
+//! configuration order decides between these. `RewriteConf::lint()` can be used to run these
+//! checks explicitly.
+//!
+//! ## Rule groups
+//!
+//! Rules sharing the same conditions can be listed in a group under `rule_groups`. The group
+//! defines the common conditions, the member rules are listed in its `rewrite_rules` setting:
+//!
+//! ```yaml
+//! rule_groups:
+//! - from_host: example.com
+//!   header_regex:
+//!     X-Beta-User: "^1$"
+//!   rewrite_rules:
+//!   - from: /docs/*
+//!     to: /beta/docs${tail}
+//!   - from: /pricing
+//!     to: /beta/pricing
+//! ```
+//!
+//! Supported group conditions are `from_host`, `methods`, `scheme`, `from_ip`, `listen_port`,
+//! `active_from`, `active_until`, `header_regex`, `cookie_regex`, `ua_regex` and `query_params`.
+//! These apply to each member rule in addition to its own conditions. A member rule cannot set a
+//! condition already set by its group, only `header_regex`, `cookie_regex` and `query_params` can
+//! be extended with further names. Groups are merged into the rule list when the configuration is
+//! loaded, their rules are numbered following those listed in `rewrite_rules`.
+//!
+//! ## Percent-encoded paths
+//!
+//! Request paths are usually percent-encoded, e.g. `/caf%C3%A9`. By default, `from` and
+//! `from_regex` are matched against the path as it is. With `rewrite_decode_path: true` in the
+//! configuration the path is percent-decoded first, so that `from: /café/*` or a regular
+//! expression containing `é` will match. Bytes that don’t form valid UTF-8 sequences stay
+//! percent-encoded, e.g. `%FF` in the path can be matched by `from_regex: "%FF"`. Values like
+//! `${tail}` are taken from the decoded path, characters not allowed in a URI are percent-encoded
+//! again when they are inserted into `to`.
+//!
+//! Encoded slashes (`%2F`) and percent signs (`%25`) are never decoded here. An encoded slash is
+//! not a path separator, so `/a%2Fb/file.txt` won’t match `from: /a/b/*`. Consequently, with a
+//! request like `/proxy/http%3A%2F%2Fexample.com` the value of `${tail}` keeps the slashes
+//! encoded, regardless of the `rewrite_decode_path` setting. Setting `decode_tail: true` on a
+//! rule changes that: variables like `${tail}` are fully percent-decoded then, so that `%2F`
+//! becomes `/` in the rewrite target.
+//!
+//! Alternatively, setting `rewrite_encoded_slash: reject` in the configuration makes requests with
+//! encoded slashes in the path fail with 400 Bad Request if `rewrite_decode_path` is enabled.
+//!
+//! ## Path normalization
+//!
+//! Setting `rewrite_normalize_path: true` in the configuration normalizes the request path before
+//! the rules are evaluated: duplicate slashes are collapsed, `.` segments are removed and `..`
+//! segments remove the preceding segment. So `/a//b/./c/../d` is matched as `/a/b/d`. `..` at the
+//! beginning of the path cannot go beyond the root, `/../etc/passwd` becomes `/etc/passwd`. A
+//! trailing slash is preserved, and a path ending with `.` or `..` like `/a/b/..` results in a
+//! trailing slash as well: `/a/`. If `rewrite_decode_path` is enabled, normalization happens after
+//! decoding, so that `%2e%2e` is resolved as well. The path as received is available to `to` as
+//! `${original_path}`.
+//!
+//! Rules with `raw_path: true` see the request path exactly as received instead, regardless of
+//! `rewrite_decode_path` and `rewrite_normalize_path`: `from_regex` is matched against it and
+//! variables like `${tail}` are taken from it, e.g. to detect `%2e%2e%2f` sequences. The rule is
+//! still looked up via the decoded and normalized path, so `from`, `from_glob`, `from_exclude` and
+//! `subst` are unaffected. If the path as received doesn’t match `from` because normalization
+//! changed its prefix, `${tail}` is taken from the normalized path. `uri_regex` always applies to
+//! the path and query string as received. After an internal rewrite with `continue: true`, the
+//! path as received is the path produced by that rewrite.
+//!
+//! ## Trusted proxies
+//!
+//! If the server runs behind a reverse proxy, the connected client is always that proxy.
+//! `rewrite_trusted_proxies` lists the address ranges of the trusted proxies, e.g.
+//! `rewrite_trusted_proxies: [10.0.0.0/8, "fd00::/8"]`. For requests from these addresses the
+//! rules consider the headers added by the proxy instead: `X-Forwarded-Proto` for `scheme`,
+//! `X-Forwarded-Host` for `from_host` and `X-Forwarded-For` for `from_ip`. For other requests
+//! these headers are ignored as potentially spoofed.
+//!
+//! The client address is determined by processing `X-Forwarded-For` from the right, skipping the
+//! addresses of trusted proxies. For a chain of proxies `10.0.0.1` and `10.0.0.2`, a value like
+//! `203.0.113.5, 198.51.100.7, 10.0.0.2` results in `198.51.100.7`, the value left of it might
+//! have been sent by the client. Processing stops at invalid entries, the last valid address is
+//! used then. Invalid `X-Forwarded-Proto` and `X-Forwarded-Host` values are ignored.
+//!
+//! ## Rewrite loops
+//!
+//! Rules with `continue: true` can rewrite into each other, e.g. `/a` → `/b` and `/b` → `/a`. To
+//! prevent endless processing, at most 10 internal rewrites are applied to a request, this limit
+//! can be changed via the `rewrite_max_internal` setting. When the limit is reached, a warning
+//! listing the rules applied is logged and the URI produced by the last rewrite is used. With
+//! `rewrite_limit_exceeded: error` in the configuration, such requests fail with 500 Internal
+//! Server Error instead.
+//!
+//! ## Redirect pages
+//!
+//! Redirect responses come with a standard HTML page by default. The `rewrite_redirect_body`
+//! setting replaces it for all redirect rules that don’t set `body` themselves:
+//!
+//! ```yaml
+//! rewrite_redirect_body: '<p>This page has moved <a href="${location}">here</a>.</p>'
+//! ```
+//!
+//! The `${location}` variable is replaced by the HTML-escaped redirect target. The response is
+//! sent with `Content-Type: text/html; charset=utf-8`.
+//!
+//! ## Variable delimiters
+//!
+//! Variables in `to` are marked with `${` and `}` by default. This can be changed via the
+//! `variable_delimiters` setting, e.g. if the target URLs contain `${` literally:
+//!
+//! ```yaml
+//! variable_delimiters:
+//!   prefix: "%{"
+//!   suffix: "}"
+//! rewrite_rules:
+//! - from: /old/*
+//!   to: /new%{tail}
+//! ```
+//!
+//! Alternatively, a single `${` can be escaped by repeating its first character: `$${query}`
+//! produces the literal text `${query}`. A `$` that isn’t followed by `${` stays unchanged. With
+//! custom delimiters, the first character of the prefix is repeated instead, e.g. `%%{`.
+//!
+//! ## Rewrite maps
+//!
+//! Large lists of one-to-one mappings, e.g. legacy article IDs, can be loaded from files instead of
+//! listing a rule for each of them. Each entry of `rewrite_maps` names a map and its `file`, the
+//! default `text` format expects a `key value` pair per line with `#` starting comment lines,
+//! `format: yaml` expects a YAML mapping instead. The variable `${map_<name>:<key>}` resolves to
+//! the value for the key, which can itself contain variables:
+//!
+//! ```yaml
+//! rewrite_maps:
+//!   articles:
+//!     file: /etc/pingora/articles.txt
+//! rewrite_rules:
+//! - from: /article.php
+//!   uri_regex: 'id=(?<id>\d+)'
+//!   to: /articles/${map_articles:${id}}
+//! - from: /article.php
+//!   to: /articles/
+//! ```
+//!
+//! If the key is missing in the map, the rule isn’t applied and the next rule is evaluated.
+//! With `required: false` set for the map, missing keys resolve to an empty string instead. Maps
+//! are loaded when the configuration is loaded, unreadable or invalid files produce an error then.
+//! Filters cannot be applied to map variables.
+//!
+//! ## Rule metrics
+//!
+//! Setting `rewrite_metrics: true` in the configuration makes the handler count how often each
+//! rule is applied. `RewriteHandler::metrics()` returns these counts along with the `name` and type
+//! of each rule, in the order the rules are listed in the configuration. This allows identifying
+//! rules that are never applied or exporting the counts to a monitoring system. Counters are
+//! updated with relaxed atomic operations and shared by all clones of the handler.
+//!
+//! ## Debugging rules
+//!
+//! Setting `rewrite_debug_header: true` makes the handler add an `X-Applied-Rewrite` header to
+//! responses, listing the rules applied to the request in the order of application. Rules are
+//! identified by their `name` setting if present, otherwise by their index. The header name can be
+//! changed via `rewrite_debug_header_name`. For internal rewrites the header is added to the
+//! response eventually produced, e.g. by the upstream server. With trace-level logging enabled,
+//! each evaluated rule that isn’t applied is logged along with the setting that didn’t match.
+//!
+//! ## Setting up rules in code
+//!
+//! Rules can be set up in code rather than deserialized from YAML, e.g. when generating them from
+//! a database. `RewriteRule::builder()` provides methods for the most common settings, methods
+//! like `from_regex()` return an error for invalid regular expressions. A `RewriteConf` can be
+//! collected from the rules and extended with more of them:
+//!
+//! ```rust
+//! use rewrite_module::configuration::{RewriteConf, RewriteRule, RewriteType};
+//! use rewrite_module::RewriteHandler;
+//!
+//! let mut conf = [RewriteRule::builder()
+//!     .from("/old/*")
+//!     .from_regex("!\\.png$")
+//!     .unwrap()
+//!     .to("/new${tail}")
+//!     .r#type(RewriteType::Permanent)
+//!     .build()]
+//! .into_iter()
+//! .collect::<RewriteConf>();
+//! conf.extend([RewriteRule::builder().from("/").to("/index.html").build()]);
+//!
+//! let handler = RewriteHandler::try_from(conf).unwrap();
+//! ```
+//!
+//! ## Evaluating rules without a server
+//!
+//! `RewriteHandler::apply()` determines how a `RewriteRequest` (method, URI and request headers)
+//! would be rewritten without requiring a server session, returning the applied rule, rewrite type
+//! and new URI. `RewriteHandler::apply_all()` does the same for a list of requests, which is useful for
+//! regression testing configuration changes. Rule metrics aren’t updated by these functions. The
+//! same logic handles requests received by the server, so the results match. A decision can be
+//! converted into a `RewriteOutcome` (`Unchanged`, `Rewritten` with the new URI or `Respond` with
+//! status, redirect target and headers) for simpler comparisons in table-driven tests.
+//!
+//! ## Reloading rules
+//!
+//! `RewriteHandler::reload()` replaces the rules with ones built from a new `RewriteConf` without
+//! restarting the server. Requests already being processed finish with the previous rules. If the
+//! new configuration is invalid, an error is returned and the previous rules stay in effect. Rule
+//! metrics start from zero after a reload.
+//!
+//! Clones of the handler share the rules, so an application can keep a clone of the handler and
+//! trigger reloading from a signal handler or a file watcher. When combining handlers of multiple
+//! modules, create the handler with `RequestFilter::new()` and pass it to `DefaultApp::new()` rather
+//! than using `DefaultApp::from_conf()`:
+//!
+//! ```rust
+//! use pandora_module_utils::FromYaml;
+//! use rewrite_module::configuration::RewriteConf;
+//! use rewrite_module::RewriteHandler;
+//!
+//! let conf = RewriteConf::from_yaml("rewrite_rules:\n  from: /old\n  to: /new").unwrap();
+//! let handler = RewriteHandler::try_from(conf).unwrap();
+//! let reload_handle = handler.clone();
+//!
+//! // Later, e.g. on SIGHUP
+//! let result =
+//!     RewriteConf::load_from_yaml("rewrite.yaml").and_then(|conf| reload_handle.reload(conf));
+//! if let Err(err) = result {
+//!     eprintln!("Failed reloading rewrite rules, keeping the previous ones: {err}");
+//! }
+//! ```
 //!
 //! ## Code example
 //!
@@ -99,7 +639,15 @@
 //! // Do something with the server here, e.g. call server.run_forever()
 //! ```
 
+mod clock;
 pub mod configuration;
+mod cookies;
+mod filters;
 mod handler;
+mod maps;
+mod negotiation;
+pub mod regex_engine;
 
-pub use handler::RewriteHandler;
+pub use handler::{
+    PathMapping, RewriteDecision, RewriteHandler, RewriteOutcome, RewriteRequest, RuleMetrics,
+};
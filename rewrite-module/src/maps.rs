@@ -0,0 +1,185 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rewrite maps loaded from files
+
+use pandora_module_utils::pingora::{Error, ErrorType};
+use pandora_module_utils::serde_yaml::{self, Value};
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use crate::configuration::{MapFormat, RewriteMapConf};
+
+/// A problem with the contents of a map file, with the line number if known (starting with 1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ParseError {
+    line: Option<usize>,
+    message: String,
+}
+
+/// Key-value pairs loaded from a map file, see `rewrite_maps` setting
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct RewriteMap {
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    /// Whether rules looking up a missing key should not apply
+    pub(crate) required: bool,
+}
+
+impl Debug for RewriteMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Maps can be large, don’t list the entries
+        f.debug_struct("RewriteMap")
+            .field("entries", &self.entries.len())
+            .field("required", &self.required)
+            .finish()
+    }
+}
+
+impl RewriteMap {
+    /// Loads the map from the file configured, errors mention the file and the line if possible.
+    pub(crate) fn load(name: &str, conf: &RewriteMapConf) -> Result<Self, Box<Error>> {
+        let file = conf.file.display();
+        let data = std::fs::read_to_string(&conf.file).map_err(|err| {
+            Error::because(
+                ErrorType::InternalError,
+                format!("failed reading rewrite map {name} from {file}"),
+                err,
+            )
+        })?;
+
+        let entries = match conf.format {
+            MapFormat::Text => parse_text(&data),
+            MapFormat::Yaml => parse_yaml(&data),
+        }
+        .map_err(|err| {
+            let location = match err.line {
+                Some(line) => format!("{file}:{line}"),
+                None => file.to_string(),
+            };
+            Error::explain(
+                ErrorType::InternalError,
+                format!("invalid rewrite map {name} in {location}: {}", err.message),
+            )
+        })?;
+
+        Ok(Self {
+            entries,
+            required: conf.required,
+        })
+    }
+
+    /// Looks up the value for a key
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+}
+
+/// Parses `key value` lines, ignoring empty lines and comments.
+fn parse_text(data: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, ParseError> {
+    let mut entries = HashMap::new();
+    for (index, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |message: &str| ParseError {
+            line: Some(index + 1),
+            message: message.to_owned(),
+        };
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(value), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(error("expected `key value`"));
+        };
+        if entries
+            .insert(key.as_bytes().to_vec(), value.as_bytes().to_vec())
+            .is_some()
+        {
+            return Err(error(&format!("duplicate key {key}")));
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses a YAML mapping, numbers and booleans are converted to strings.
+fn parse_yaml(data: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, ParseError> {
+    fn scalar(value: Value) -> Option<Vec<u8>> {
+        match value {
+            Value::String(value) => Some(value.into_bytes()),
+            Value::Number(value) => Some(value.to_string().into_bytes()),
+            Value::Bool(value) => Some(value.to_string().into_bytes()),
+            _ => None,
+        }
+    }
+
+    let mapping: serde_yaml::Mapping = serde_yaml::from_str(data).map_err(|err| ParseError {
+        line: err.location().map(|location| location.line()),
+        message: err.to_string(),
+    })?;
+    mapping
+        .into_iter()
+        .map(|(key, value)| {
+            scalar(key).zip(scalar(value)).ok_or_else(|| ParseError {
+                line: None,
+                message: "map keys and values have to be scalars".to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    fn entries(list: &[(&str, &str)]) -> HashMap<Vec<u8>, Vec<u8>> {
+        list.iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            parse_text("# Legacy articles\n\n123 new-slug\n  456\tother-slug  \n"),
+            Ok(entries(&[("123", "new-slug"), ("456", "other-slug")]))
+        );
+        assert_eq!(parse_text(""), Ok(HashMap::new()));
+
+        for (data, line) in [
+            ("123 new-slug\n456\n", 2),
+            ("123 a b", 1),
+            ("# comment\n\n1 a\n1 b", 4),
+        ] {
+            assert_eq!(parse_text(data).unwrap_err().line, Some(line), "{data}");
+        }
+    }
+
+    #[test]
+    fn yaml() {
+        assert_eq!(
+            parse_yaml("123: new-slug\nabc: 456\nflag: true\n"),
+            Ok(entries(&[
+                ("123", "new-slug"),
+                ("abc", "456"),
+                ("flag", "true")
+            ]))
+        );
+
+        assert_eq!(parse_yaml("a: b\nc: [d]").unwrap_err().line, None);
+        assert_eq!(parse_yaml("a: b\nc: d: e").unwrap_err().line, Some(2));
+        assert!(parse_yaml("- a\n- b").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content negotiation helpers
+
+use std::cmp::Reverse;
+
+use crate::configuration::MediaType;
+
+/// Parses a quality value like `0.5` into thousandths, `None` for invalid values.
+fn parse_quality(value: &str) -> Option<u16> {
+    let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let fraction = format!("{fraction:0<3}").parse::<u16>().ok()?;
+    match integer {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Splits an element of a header like `Accept` into the value and its quality (in thousandths).
+/// Elements with invalid quality values are ignored.
+fn parse_element(element: &str) -> Option<(&str, u16)> {
+    let mut params = element.split(';');
+    let value = params.next()?.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let mut quality = 1000;
+    for param in params {
+        if let Some((name, param_value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = parse_quality(param_value.trim())?;
+            }
+        }
+    }
+    Some((value, quality))
+}
+
+/// Checks whether a media type is acceptable according to the values of the `Accept` header.
+///
+/// The most specific media range matching the media type determines its quality, the media type
+/// is acceptable if this quality isn’t zero. A missing or empty `Accept` header accepts everything.
+pub(crate) fn accepts_media_type(
+    accept: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    media_type: &MediaType,
+) -> bool {
+    let mut seen_any = false;
+    let mut best: Option<(u8, u16)> = None;
+    for value in accept {
+        let value = String::from_utf8_lossy(value.as_ref());
+        for element in value.split(',') {
+            if element.trim().is_empty() {
+                continue;
+            }
+            seen_any = true;
+
+            let Some((range, quality)) = parse_element(element) else {
+                continue;
+            };
+            let Some((r#type, subtype)) = range.split_once('/') else {
+                continue;
+            };
+            let specificity = if r#type == "*" && subtype == "*" {
+                0
+            } else if r#type.eq_ignore_ascii_case(&media_type.r#type) {
+                if subtype == "*" {
+                    1
+                } else if subtype.eq_ignore_ascii_case(&media_type.subtype) {
+                    2
+                } else {
+                    continue;
+                }
+            } else {
+                continue;
+            };
+
+            if best.map_or(true, |(best_specificity, _)| specificity > best_specificity) {
+                best = Some((specificity, quality));
+            }
+        }
+    }
+
+    if seen_any {
+        best.is_some_and(|(_, quality)| quality > 0)
+    } else {
+        true
+    }
+}
+
+/// Determines the language preferred by the client among the given language tags, according to
+/// the values of the `Accept-Language` header.
+///
+/// Language ranges are matched as defined by RFC 4647 basic filtering: `de` matches `de` and
+/// `de-CH` but `de-CH` doesn’t match `de`, `*` matches any language. The most specific range
+/// matching a language determines its quality, languages with quality zero are excluded. Among
+/// the remaining languages, the one with the highest quality is selected, then the one matched by
+/// a range listed earlier, then the one listed first in `languages`. Returns `None` if the header
+/// is missing or no language is acceptable.
+pub(crate) fn preferred_language(
+    accept_language: impl IntoIterator<Item = impl AsRef<[u8]>>,
+    languages: &[String],
+) -> Option<&str> {
+    let mut ranges = Vec::new();
+    for value in accept_language {
+        let value = String::from_utf8_lossy(value.as_ref());
+        ranges.extend(
+            value
+                .split(',')
+                .filter_map(parse_element)
+                .map(|(range, quality)| (range.to_owned(), quality)),
+        );
+    }
+
+    let specificity = |range: &str| if range == "*" { 0 } else { range.len() + 1 };
+    let matches = |range: &str, language: &str| {
+        range == "*"
+            || language
+                .get(..range.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(range))
+                && matches!(language.as_bytes().get(range.len()), None | Some(b'-'))
+    };
+
+    languages
+        .iter()
+        .filter_map(|language| {
+            // Most specific range first, then earlier ranges
+            let (position, quality) = ranges
+                .iter()
+                .enumerate()
+                .filter(|(_, (range, _))| matches(range, language))
+                .min_by_key(|(position, (range, _))| (Reverse(specificity(range)), *position))
+                .map(|(position, (_, quality))| (position, *quality))?;
+            (quality > 0).then_some((language, quality, position))
+        })
+        .min_by_key(|(_, quality, position)| (Reverse(*quality), *position))
+        .map(|(language, _, _)| language.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(accept: &[&str], media_type: &str) -> bool {
+        accepts_media_type(
+            accept.iter().map(|value| value.as_bytes()),
+            &media_type.try_into().unwrap(),
+        )
+    }
+
+    #[test]
+    fn quality() {
+        assert_eq!(parse_quality("0"), Some(0));
+        assert_eq!(parse_quality("0.5"), Some(500));
+        assert_eq!(parse_quality("0.25"), Some(250));
+        assert_eq!(parse_quality("0.125"), Some(125));
+        assert_eq!(parse_quality("1"), Some(1000));
+        assert_eq!(parse_quality("1.000"), Some(1000));
+        assert_eq!(parse_quality("1.5"), None);
+        assert_eq!(parse_quality("0.1234"), None);
+        assert_eq!(parse_quality("2"), None);
+        assert_eq!(parse_quality("abc"), None);
+    }
+
+    #[test]
+    fn media_type_acceptable() {
+        assert!(accepts(&[], "application/json"));
+        assert!(accepts(&[""], "application/json"));
+        assert!(accepts(&["application/json"], "application/json"));
+        assert!(accepts(&["Application/JSON"], "application/json"));
+        assert!(accepts(&["application/*"], "application/json"));
+        assert!(accepts(&["*/*"], "application/json"));
+        assert!(accepts(
+            &["text/html, application/xhtml+xml;q=0.9, */*;q=0.8"],
+            "application/json"
+        ));
+        assert!(accepts(
+            &["text/html", "application/json"],
+            "application/json"
+        ));
+
+        assert!(!accepts(&["text/html"], "application/json"));
+        assert!(!accepts(&["text/*"], "application/json"));
+        assert!(!accepts(&["application/xml"], "application/json"));
+        assert!(!accepts(&["application/json;q=0"], "application/json"));
+        assert!(!accepts(&["*/*, application/json;q=0"], "application/json"));
+        assert!(!accepts(&["*/*, application/*;q=0.0"], "application/json"));
+        assert!(accepts(
+            &["application/*;q=0, application/json"],
+            "application/json"
+        ));
+        assert!(!accepts(&["application/json;q=abc"], "application/json"));
+    }
+
+    #[test]
+    fn language_preferred() {
+        let preferred = |accept_language: &[&str], languages: &[&str]| {
+            let languages = languages
+                .iter()
+                .map(|language| (*language).to_owned())
+                .collect::<Vec<_>>();
+            preferred_language(
+                accept_language.iter().map(|value| value.as_bytes()),
+                &languages,
+            )
+            .map(str::to_owned)
+        };
+
+        assert_eq!(preferred(&[], &["de", "fr"]), None);
+        assert_eq!(preferred(&[""], &["de", "fr"]), None);
+        assert_eq!(preferred(&["de"], &["de", "fr"]).as_deref(), Some("de"));
+        assert_eq!(preferred(&["FR"], &["de", "fr"]).as_deref(), Some("fr"));
+        assert_eq!(preferred(&["en"], &["de", "fr"]), None);
+
+        // Quality and header order
+        assert_eq!(
+            preferred(&["fr;q=0.8, de;q=0.9"], &["de", "fr"]).as_deref(),
+            Some("de")
+        );
+        assert_eq!(preferred(&["fr, de"], &["de", "fr"]).as_deref(), Some("fr"));
+        assert_eq!(
+            preferred(&["en", "fr;q=0.5, de;q=0.1"], &["de", "fr"]).as_deref(),
+            Some("fr")
+        );
+        assert_eq!(
+            preferred(&["fr;q=abc, de;q=0.5"], &["de", "fr"]).as_deref(),
+            Some("de")
+        );
+
+        // Basic filtering: ranges match languages with the same prefix, not the other way round
+        assert_eq!(
+            preferred(&["de"], &["de-CH", "fr"]).as_deref(),
+            Some("de-CH")
+        );
+        assert_eq!(preferred(&["de-CH"], &["de", "fr"]), None);
+        assert_eq!(
+            preferred(&["de-CH, de;q=0.5"], &["de", "de-ch"]).as_deref(),
+            Some("de-ch")
+        );
+        assert_eq!(preferred(&["de"], &["den", "fr"]), None);
+
+        // Wildcard and exclusions
+        assert_eq!(preferred(&["*"], &["de", "fr"]).as_deref(), Some("de"));
+        assert_eq!(
+            preferred(&["en, *;q=0.5"], &["de", "fr"]).as_deref(),
+            Some("de")
+        );
+        assert_eq!(
+            preferred(&["*, de;q=0"], &["de", "fr"]).as_deref(),
+            Some("fr")
+        );
+        assert_eq!(
+            preferred(&["de, de-CH;q=0"], &["de-CH", "de-AT"]).as_deref(),
+            Some("de-AT")
+        );
+        assert_eq!(preferred(&["*;q=0"], &["de", "fr"]), None);
+        assert_eq!(preferred(&["de;q=0, fr;q=0"], &["de", "fr"]), None);
+    }
+}
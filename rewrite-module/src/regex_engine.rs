@@ -0,0 +1,280 @@
+// Copyright 2024 Wladimir Palant
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Regular expression engine used by [`RegexMatch`](crate::configuration::RegexMatch)
+//!
+//! By default, the `regex` crate is used which guarantees matching in linear time. With the
+//! `fancy-regex` feature enabled, the `fancy-regex` crate is used instead, adding support for
+//! look-around and backreferences at the cost of backtracking for such expressions.
+
+use std::fmt::{Debug, Display};
+
+/// Message added to errors caused by look-around if the `fancy-regex` feature is disabled
+#[cfg(not(feature = "fancy-regex"))]
+const LOOK_AROUND_HINT: &str =
+    "enable the `fancy-regex` feature of the rewrite module for look-around support";
+
+/// An error compiling a regular expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError(String);
+
+impl Display for RegexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+#[cfg(not(feature = "fancy-regex"))]
+impl From<regex::Error> for RegexError {
+    fn from(err: regex::Error) -> Self {
+        let message = err.to_string();
+        if message.contains("look-around") {
+            Self(format!("{message}\n{LOOK_AROUND_HINT}"))
+        } else {
+            Self(message)
+        }
+    }
+}
+
+#[cfg(feature = "fancy-regex")]
+impl From<fancy_regex::Error> for RegexError {
+    fn from(err: fancy_regex::Error) -> Self {
+        Self(err.to_string())
+    }
+}
+
+/// A compiled regular expression
+///
+/// Values are matched as bytes, so that values which aren’t valid UTF-8 can be matched as well.
+/// With the `fancy-regex` feature, invalid UTF-8 sequences are replaced by U+FFFD before matching.
+#[derive(Clone)]
+pub struct Regex {
+    #[cfg(not(feature = "fancy-regex"))]
+    regex: regex::bytes::Regex,
+    #[cfg(feature = "fancy-regex")]
+    regex: fancy_regex::Regex,
+    /// Whether the expression is supported by the `regex` crate, e.g. doesn’t use look-around
+    #[cfg(feature = "fancy-regex")]
+    linear: bool,
+    /// The regular expression as given, without flags added for case-insensitivity
+    pattern: String,
+}
+
+impl Debug for Regex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Regex").field(&self.pattern).finish()
+    }
+}
+
+impl Regex {
+    /// Compiles a regular expression
+    pub fn new(pattern: &str) -> Result<Self, RegexError> {
+        Self::build(pattern, false)
+    }
+
+    /// Compiles a regular expression ignoring case when matching
+    pub fn new_case_insensitive(pattern: &str) -> Result<Self, RegexError> {
+        Self::build(pattern, true)
+    }
+
+    #[cfg(not(feature = "fancy-regex"))]
+    fn build(pattern: &str, case_insensitive: bool) -> Result<Self, RegexError> {
+        Ok(Self {
+            regex: regex::bytes::RegexBuilder::new(pattern)
+                .case_insensitive(case_insensitive)
+                .build()?,
+            pattern: pattern.to_owned(),
+        })
+    }
+
+    #[cfg(feature = "fancy-regex")]
+    fn build(pattern: &str, case_insensitive: bool) -> Result<Self, RegexError> {
+        let regex = if case_insensitive {
+            fancy_regex::Regex::new(&format!("(?i){pattern}"))?
+        } else {
+            fancy_regex::Regex::new(pattern)?
+        };
+        Ok(Self {
+            regex,
+            linear: regex_syntax::Parser::new().parse(pattern).is_ok(),
+            pattern: pattern.to_owned(),
+        })
+    }
+
+    /// Returns the regular expression as given
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Checks whether the `regex` crate supports this expression, so that it can be added to a
+    /// `RegexSet`.
+    #[cfg_attr(not(feature = "fancy-regex"), allow(clippy::unused_self))]
+    pub(crate) fn is_linear(&self) -> bool {
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            true
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            self.linear
+        }
+    }
+
+    /// Checks whether the value is matched. With the `fancy-regex` feature, an error is returned
+    /// if matching exceeds the backtracking limit, so that the result is neither a match nor a
+    /// mismatch.
+    pub fn is_match(&self, value: &[u8]) -> Result<bool, RegexError> {
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            Ok(self.regex.is_match(value))
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            Ok(self.regex.is_match(&String::from_utf8_lossy(value))?)
+        }
+    }
+
+    /// Returns the capture groups if the value is matched
+    pub(crate) fn captures<'a>(&self, value: &'a str) -> Option<Captures<'a>> {
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            self.regex.captures(value.as_bytes()).map(Captures)
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            self.regex.captures(value).ok().flatten().map(Captures)
+        }
+    }
+
+    /// Number of capture groups including the implicit group 0 for the entire match
+    pub fn captures_len(&self) -> usize {
+        self.regex.captures_len()
+    }
+
+    /// Names of the capture groups in the order of their numbers, `None` for unnamed groups
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.regex.capture_names()
+    }
+}
+
+/// Capture groups of a match
+pub(crate) struct Captures<'a>(
+    #[cfg(not(feature = "fancy-regex"))] regex::bytes::Captures<'a>,
+    #[cfg(feature = "fancy-regex")] fancy_regex::Captures<'a>,
+);
+
+impl<'a> Captures<'a> {
+    /// Number of capture groups including the implicit group 0 for the entire match
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the value of a numbered group, `None` if the group didn’t participate in the match
+    pub(crate) fn get(&self, index: usize) -> Option<&'a [u8]> {
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            self.0.get(index).map(|group| group.as_bytes())
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            self.0.get(index).map(|group| group.as_str().as_bytes())
+        }
+    }
+
+    /// Returns the value of a named group, `None` if the group didn’t participate in the match
+    pub(crate) fn name(&self, name: &str) -> Option<&'a [u8]> {
+        #[cfg(not(feature = "fancy-regex"))]
+        {
+            self.0.name(name).map(|group| group.as_bytes())
+        }
+
+        #[cfg(feature = "fancy-regex")]
+        {
+            self.0.name(name).map(|group| group.as_str().as_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_log::test;
+
+    #[test]
+    fn matching() {
+        let regex = Regex::new(r"^/files/(?P<name>[^/]+)\.(txt|md)$").unwrap();
+        assert_eq!(regex.as_str(), r"^/files/(?P<name>[^/]+)\.(txt|md)$");
+        assert_eq!(regex.is_match(b"/files/readme.md"), Ok(true));
+        assert_eq!(regex.is_match(b"/files/README.MD"), Ok(false));
+        assert_eq!(regex.is_match(b"/files/\xFF.png"), Ok(false));
+        assert_eq!(regex.captures_len(), 3);
+        assert_eq!(
+            regex.capture_names().collect::<Vec<_>>(),
+            vec![None, Some("name"), None]
+        );
+
+        let captures = regex.captures("/files/notes.txt").unwrap();
+        assert_eq!(captures.len(), 3);
+        assert_eq!(captures.get(0), Some(&b"/files/notes.txt"[..]));
+        assert_eq!(captures.get(2), Some(&b"txt"[..]));
+        assert_eq!(captures.get(3), None);
+        assert_eq!(captures.name("name"), Some(&b"notes"[..]));
+        assert_eq!(captures.name("other"), None);
+        assert!(regex.captures("/files/notes.pdf").is_none());
+
+        let regex = Regex::new_case_insensitive(r"^/files/(a)?b").unwrap();
+        assert_eq!(regex.as_str(), r"^/files/(a)?b");
+        assert_eq!(regex.is_match(b"/FILES/B"), Ok(true));
+        assert_eq!(regex.captures("/files/b").unwrap().get(1), None);
+
+        assert!(Regex::new("(unclosed").is_err());
+        assert!(regex.is_linear());
+    }
+
+    #[cfg(not(feature = "fancy-regex"))]
+    #[test]
+    fn look_around() {
+        let err = Regex::new("^/files/(?!public/)").unwrap_err();
+        assert!(err.to_string().contains("fancy-regex"), "{err}");
+
+        let err = Regex::new("(unclosed").unwrap_err();
+        assert!(!err.to_string().contains("fancy-regex"), "{err}");
+    }
+
+    #[cfg(feature = "fancy-regex")]
+    #[test]
+    fn look_around() {
+        let regex = Regex::new("^/files/(?!public/)").unwrap();
+        assert_eq!(regex.is_match(b"/files/private/a.txt"), Ok(true));
+        assert_eq!(regex.is_match(b"/files/public/a.txt"), Ok(false));
+        assert!(!regex.is_linear());
+
+        let regex = Regex::new(r"^/(\w+)/\1$").unwrap();
+        assert_eq!(regex.is_match(b"/dir/dir"), Ok(true));
+        assert_eq!(regex.is_match(b"/dir/other"), Ok(false));
+        assert_eq!(regex.captures("/a/a").unwrap().get(1), Some(&b"a"[..]));
+
+        let regex = Regex::new(r"^(a+)+(?!x)\1$").unwrap();
+        assert!(regex
+            .is_match(format!("{}c", "a".repeat(40)).as_bytes())
+            .is_err());
+    }
+}